@@ -1,6 +1,17 @@
 use clap::{command, Args, Subcommand};
 
+use crate::redox::Redox;
+
+pub mod alert_rule;
+pub mod artifact;
+pub mod campaign;
+pub mod derived_key;
 pub mod device;
+pub mod enrollment;
+pub mod model;
+pub mod route;
+pub mod token;
+pub mod webhook;
 
 #[derive(Args)]
 pub struct CreateCmd {
@@ -12,10 +23,41 @@ pub struct CreateCmd {
 pub enum CreateCmds {
     /// create device
     Device(device::DeviceCmd),
+    /// create redox bearer token
+    Token(token::TokenCmd),
+    /// create enrollment group for device provisioning
+    Enrollment(enrollment::EnrollmentCmd),
+    /// create device keys derived from an enrollment group secret
+    DerivedKey(derived_key::DerivedKeyCmd),
+    /// create or version a device model
+    Model(model::ModelCmd),
+    /// create or replace an alert rule
+    AlertRule(alert_rule::AlertRuleCmd),
+    /// create or replace a route forwarding messages to a destination
+    Route(route::RouteCmd),
+    /// create or replace a webhook notified of twin and connection events
+    Webhook(webhook::WebhookCmd),
+    /// register a firmware or software artifact for ota updates
+    Artifact(artifact::ArtifactCmd),
+    /// create an ota campaign updating the targeted devices
+    Campaign(campaign::CampaignCmd),
 }
 
-pub async fn run_create_cmd(create_cmd: &CreateCmd, target: String) -> Result<(), String> {
+pub async fn run_create_cmd(create_cmd: &CreateCmd, redox: &Redox) -> Result<(), String> {
     match &create_cmd.command {
-        CreateCmds::Device(device_cmd) => device::run_device_cmd(device_cmd, target).await,
+        CreateCmds::Device(device_cmd) => device::run_device_cmd(device_cmd, redox).await,
+        CreateCmds::Token(token_cmd) => token::run_token_cmd(token_cmd).await,
+        CreateCmds::Enrollment(enrollment_cmd) => {
+            enrollment::run_enrollment_cmd(enrollment_cmd, redox).await
+        }
+        CreateCmds::DerivedKey(derived_key_cmd) => {
+            derived_key::run_derived_key_cmd(derived_key_cmd).await
+        }
+        CreateCmds::Model(model_cmd) => model::run_model_cmd(model_cmd, redox).await,
+        CreateCmds::AlertRule(rule_cmd) => alert_rule::run_alert_rule_cmd(rule_cmd, redox).await,
+        CreateCmds::Route(route_cmd) => route::run_route_cmd(route_cmd, redox).await,
+        CreateCmds::Webhook(webhook_cmd) => webhook::run_webhook_cmd(webhook_cmd, redox).await,
+        CreateCmds::Artifact(artifact_cmd) => artifact::run_artifact_cmd(artifact_cmd, redox).await,
+        CreateCmds::Campaign(campaign_cmd) => campaign::run_campaign_cmd(campaign_cmd, redox).await,
     }
 }
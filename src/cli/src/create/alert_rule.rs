@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use libs::{
+    models::alert::{AlertCondition, NewAlertRuleReq},
+    utils::cli::get_stdin_from_pipe,
+};
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct AlertRuleCmd {
+    /// list of alert rules to create or replace. If . read from stdin.
+    rule_ids: Vec<String>,
+
+    #[arg(short, long)]
+    description: Option<String>,
+    /// sensor id the rule watches
+    #[arg(short, long, default_value_t = 0)]
+    sensor_id: i64,
+    #[arg(short, long, value_enum, default_value_t = AlertCondition::Above)]
+    condition: AlertCondition,
+    /// value, or change per second for rate conditions
+    #[arg(short, long, default_value_t = 0.0)]
+    threshold: f64,
+    /// seconds the condition must hold before the alert fires
+    #[arg(short, long, default_value_t = 0)]
+    for_secs: u64,
+    /// device tags the rule applies to as json, {"building": 43}. All devices if not set.
+    #[arg(long)]
+    tags: Option<String>,
+}
+
+pub async fn run_alert_rule_cmd(rule_cmd: &AlertRuleCmd, redox: &Redox) -> Result<(), String> {
+    let mut rule_req: Vec<NewAlertRuleReq> = Vec::new();
+    if rule_cmd.rule_ids.len() == 1 && rule_cmd.rule_ids[0] == "." {
+        rule_req = serde_json::from_str(get_stdin_from_pipe().as_str())
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        let tags: HashMap<String, Value> = match &rule_cmd.tags {
+            Some(x) => serde_json::from_str(x).map_err(|e| format!("Error: {:?}", e))?,
+            None => HashMap::new(),
+        };
+        for rule_id in rule_cmd.rule_ids.clone() {
+            rule_req.push(NewAlertRuleReq {
+                rule_id,
+                description: rule_cmd.description.clone().unwrap_or_default(),
+                tags: tags.clone(),
+                sensor_id: rule_cmd.sensor_id,
+                condition: rule_cmd.condition,
+                threshold: rule_cmd.threshold,
+                for_secs: rule_cmd.for_secs,
+            });
+        }
+    }
+
+    let mut rules = json!([]);
+    for req in rule_req {
+        let rule = create_alert_rule_request(redox, req)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        rules.as_array_mut().unwrap().push(rule);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&rules).unwrap());
+
+    Ok(())
+}
+
+async fn create_alert_rule_request(
+    redox: &Redox,
+    rule_req: NewAlertRuleReq,
+) -> Result<Value, reqwest::Error> {
+    let resp = redox.post("/alertrules").json(&rule_req).send().await?;
+    resp.json::<Value>().await
+}
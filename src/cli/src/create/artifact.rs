@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use serde_json::Value;
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct ArtifactCmd {
+    /// artifact to register, devices download it by this id
+    artifact_id: String,
+
+    /// firmware or software file
+    file: PathBuf,
+
+    /// version installed by the artifact
+    #[arg(short, long)]
+    version: String,
+
+    /// hex sha256 of the file, verified by redox when set
+    #[arg(short, long)]
+    checksum: Option<String>,
+}
+
+pub async fn run_artifact_cmd(artifact_cmd: &ArtifactCmd, redox: &Redox) -> Result<(), String> {
+    let content = std::fs::read(&artifact_cmd.file)
+        .map_err(|e| format!("Error: can't read {}: {}", artifact_cmd.file.display(), e))?;
+    let mut params = vec![
+        ("artifact_id", artifact_cmd.artifact_id.clone()),
+        ("version", artifact_cmd.version.clone()),
+    ];
+    if let Some(x) = &artifact_cmd.checksum {
+        params.push(("checksum", x.clone()));
+    }
+
+    let artifact = redox
+        .post("/artifacts")
+        .query(&params)
+        .body(content)
+        .send()
+        .await
+        .map_err(|e| format!("Error: {:?}", e))?
+        .json::<Value>()
+        .await
+        .map_err(|e| format!("Error: {:?}", e))?;
+    print!("{}", serde_json::to_string_pretty(&artifact).unwrap());
+
+    Ok(())
+}
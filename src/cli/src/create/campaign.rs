@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use libs::{
+    models::ota::{CampaignTarget, NewCampaignReq},
+    utils::cli::get_stdin_from_pipe,
+};
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct CampaignCmd {
+    /// list of campaigns to create. If . read from stdin.
+    campaign_ids: Vec<String>,
+
+    /// artifact installed by the campaign
+    #[arg(short, long)]
+    artifact_id: Option<String>,
+    /// only these devices
+    #[arg(short, long, value_delimiter = ',')]
+    device_ids: Vec<String>,
+    /// only the devices of this model
+    #[arg(short, long)]
+    model_id: Option<String>,
+    /// device tags the campaign targets as json, {"building": 43}. All devices if not set.
+    #[arg(long)]
+    tags: Option<String>,
+}
+
+pub async fn run_campaign_cmd(campaign_cmd: &CampaignCmd, redox: &Redox) -> Result<(), String> {
+    let mut campaign_req: Vec<NewCampaignReq> = Vec::new();
+    if campaign_cmd.campaign_ids.len() == 1 && campaign_cmd.campaign_ids[0] == "." {
+        campaign_req = serde_json::from_str(get_stdin_from_pipe().as_str())
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        let Some(artifact_id) = campaign_cmd.artifact_id.clone() else {
+            return Err("Error: --artifact-id is required".to_string());
+        };
+        let tags: HashMap<String, Value> = match &campaign_cmd.tags {
+            Some(x) => serde_json::from_str(x).map_err(|e| format!("Error: {:?}", e))?,
+            None => HashMap::new(),
+        };
+        for campaign_id in campaign_cmd.campaign_ids.clone() {
+            campaign_req.push(NewCampaignReq {
+                campaign_id,
+                artifact_id: artifact_id.clone(),
+                target: CampaignTarget {
+                    device_ids: campaign_cmd.device_ids.clone(),
+                    model_id: campaign_cmd.model_id.clone().unwrap_or_default(),
+                    tags: tags.clone(),
+                },
+            });
+        }
+    }
+
+    let mut campaigns = json!([]);
+    for req in campaign_req {
+        let campaign = redox
+            .post("/campaigns")
+            .json(&req)
+            .send()
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        campaigns.as_array_mut().unwrap().push(campaign);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&campaigns).unwrap());
+
+    Ok(())
+}
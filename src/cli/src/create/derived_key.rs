@@ -0,0 +1,24 @@
+use clap::Args;
+use libs::utils::auth::derive_device_key;
+
+#[derive(Args)]
+pub struct DerivedKeyCmd {
+    /// registration ids of the devices to flash with a key
+    registration_ids: Vec<String>,
+
+    /// enrollment group attestation secret
+    #[arg(short, long)]
+    secret: String,
+}
+
+pub async fn run_derived_key_cmd(derived_key_cmd: &DerivedKeyCmd) -> Result<(), String> {
+    let keys: Vec<(String, String)> = derived_key_cmd
+        .registration_ids
+        .iter()
+        .map(|id| (id.clone(), derive_device_key(&derived_key_cmd.secret, id)))
+        .collect();
+
+    print!("{}", serde_json::to_string_pretty(&keys).unwrap());
+
+    Ok(())
+}
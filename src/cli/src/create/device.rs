@@ -3,9 +3,10 @@ use libs::{
     models::device_twin::{NewDeviceReq, Status},
     utils::cli::get_stdin_from_pipe,
 };
-use reqwest::Client;
 use serde_json::{json, Value};
 
+use crate::redox::Redox;
+
 #[derive(Args)]
 pub struct DeviceCmd {
     /// list of devices to print. If empty, print all devices. If . read from stdin.
@@ -26,7 +27,7 @@ pub struct DeviceCmd {
     reported: bool,
 }
 
-pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<(), String> {
+pub async fn run_device_cmd(device_cmd: &DeviceCmd, redox: &Redox) -> Result<(), String> {
     let mut device_req: Vec<NewDeviceReq> = Vec::new();
     if device_cmd.device_ids.len() == 1 && device_cmd.device_ids[0] == "." {
         // TODO: do it
@@ -55,7 +56,7 @@ pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<()
 
     let mut devices = json!([]);
     for req in device_req {
-        let mut device = create_device_request(target.clone(), req)
+        let mut device = create_device_request(redox, req)
             .await
             .map_err(|e| format!("Error: {:?}", e))?;
 
@@ -91,13 +92,10 @@ pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<()
 }
 
 async fn create_device_request(
-    url: String,
+    redox: &Redox,
     device_req: NewDeviceReq,
 ) -> Result<Value, reqwest::Error> {
-    let url = format!("http://{}/devicetwins", url);
-
-    let client = Client::new();
-    let resp = client.post(url).json(&device_req).send().await?;
+    let resp = redox.post("/devicetwins").json(&device_req).send().await?;
     let x = resp.json::<Value>().await?;
     // TODO: add device id call ?device_id=maxi2
 
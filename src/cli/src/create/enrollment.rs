@@ -0,0 +1,79 @@
+use clap::Args;
+use libs::{models::enrollment::NewEnrollmentGroupReq, utils::cli::get_stdin_from_pipe};
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct EnrollmentCmd {
+    /// list of enrollment groups to create. If . read from stdin.
+    group_ids: Vec<String>,
+
+    #[arg(short, long)]
+    model_id: Option<String>,
+    /// tag properties given to provisioned devices
+    #[arg(short, long)]
+    tags: Option<String>,
+    /// desired properties given to provisioned devices
+    #[arg(short, long)]
+    desired: Option<String>,
+}
+
+pub async fn run_enrollment_cmd(
+    enrollment_cmd: &EnrollmentCmd,
+    redox: &Redox,
+) -> Result<(), String> {
+    let mut group_req: Vec<NewEnrollmentGroupReq> = Vec::new();
+    if enrollment_cmd.group_ids.len() == 1 && enrollment_cmd.group_ids[0] == "." {
+        group_req = serde_json::from_str(get_stdin_from_pipe().as_str())
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        let model_id = if let Some(x) = enrollment_cmd.model_id.clone() {
+            x
+        } else {
+            return Err(format!("flag 'model_id' is mandatory"));
+        };
+        let tag_properties = parse_properties(&enrollment_cmd.tags)?;
+        let desired_properties = parse_properties(&enrollment_cmd.desired)?;
+
+        for group_id in enrollment_cmd.group_ids.clone() {
+            group_req.push(NewEnrollmentGroupReq {
+                group_id,
+                model_id: model_id.clone(),
+                tag_properties: tag_properties.clone(),
+                desired_properties: desired_properties.clone(),
+            });
+        }
+    }
+
+    let mut groups = json!([]);
+    for req in group_req {
+        let group = create_enrollment_request(redox, req)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        groups.as_array_mut().unwrap().push(group);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&groups).unwrap());
+
+    Ok(())
+}
+
+fn parse_properties(properties: &Option<String>) -> Result<Value, String> {
+    match properties {
+        Some(x) => serde_json::from_str(x).map_err(|e| format!("Error: {:?}", e)),
+        None => Ok(json!({})),
+    }
+}
+
+async fn create_enrollment_request(
+    redox: &Redox,
+    group_req: NewEnrollmentGroupReq,
+) -> Result<Value, reqwest::Error> {
+    let resp = redox
+        .post("/enrollmentgroups")
+        .json(&group_req)
+        .send()
+        .await?;
+    resp.json::<Value>().await
+}
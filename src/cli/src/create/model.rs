@@ -0,0 +1,80 @@
+use clap::Args;
+use libs::{models::device_model::NewDeviceModelReq, utils::cli::get_stdin_from_pipe};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct ModelCmd {
+    /// list of device models to create or version. If . read from stdin.
+    model_ids: Vec<String>,
+
+    #[arg(short, long)]
+    description: Option<String>,
+    /// sensors as json, [{"id": 1, "name": "temperature", "unit": "C", "kind": "float"}]
+    #[arg(short, long)]
+    sensors: Option<String>,
+    /// json schema of the desired properties
+    #[arg(long)]
+    desired_schema: Option<String>,
+    /// json schema of the reported properties
+    #[arg(long)]
+    reported_schema: Option<String>,
+    /// json schema of the commands
+    #[arg(long)]
+    commands_schema: Option<String>,
+}
+
+pub async fn run_model_cmd(model_cmd: &ModelCmd, redox: &Redox) -> Result<(), String> {
+    let mut model_req: Vec<NewDeviceModelReq> = Vec::new();
+    if model_cmd.model_ids.len() == 1 && model_cmd.model_ids[0] == "." {
+        model_req = serde_json::from_str(get_stdin_from_pipe().as_str())
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        let sensors = parse_json(&model_cmd.sensors)?.unwrap_or_default();
+        let desired_schema = parse_json(&model_cmd.desired_schema)?.unwrap_or_default();
+        let reported_schema = parse_json(&model_cmd.reported_schema)?.unwrap_or_default();
+        let commands_schema = parse_json(&model_cmd.commands_schema)?.unwrap_or_default();
+
+        for model_id in model_cmd.model_ids.clone() {
+            model_req.push(NewDeviceModelReq {
+                model_id,
+                description: model_cmd.description.clone().unwrap_or_default(),
+                sensors: sensors.clone(),
+                desired_schema: desired_schema.clone(),
+                reported_schema: reported_schema.clone(),
+                commands_schema: commands_schema.clone(),
+            });
+        }
+    }
+
+    let mut models = json!([]);
+    for req in model_req {
+        let model = create_model_request(redox, req)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        models.as_array_mut().unwrap().push(model);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&models).unwrap());
+
+    Ok(())
+}
+
+fn parse_json<T: DeserializeOwned>(value: &Option<String>) -> Result<Option<T>, String> {
+    match value {
+        Some(x) => serde_json::from_str(x)
+            .map(Some)
+            .map_err(|e| format!("Error: {:?}", e)),
+        None => Ok(None),
+    }
+}
+
+async fn create_model_request(
+    redox: &Redox,
+    model_req: NewDeviceModelReq,
+) -> Result<Value, reqwest::Error> {
+    let resp = redox.post("/devicemodels").json(&model_req).send().await?;
+    resp.json::<Value>().await
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use libs::{
+    models::route::{NewRouteReq, RouteDestination, RoutePredicate},
+    utils::cli::get_stdin_from_pipe,
+};
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct RouteCmd {
+    /// list of routes to create or replace. If . read from stdin.
+    route_ids: Vec<String>,
+
+    #[arg(short, long)]
+    description: Option<String>,
+    /// amqp topic pattern without the tenant, * is one word and # zero or more
+    #[arg(short, long, default_value = "#.telemetry.v1")]
+    routing_key: String,
+    /// device tags the route applies to as json, {"building": 43}. All devices if not set.
+    #[arg(long)]
+    tags: Option<String>,
+    /// payload predicate as json, {"pointer": "/telemetry/floats/2", "op": "gt", "value": 80}
+    #[arg(short, long)]
+    predicate: Option<String>,
+    /// destination as json, one of
+    /// {"kind": "amqp", "exchange": "x", "routing_key": "y"},
+    /// {"kind": "http", "url": "https://x", "headers": {}} or
+    /// {"kind": "file", "path": "x.jsonl"}
+    #[arg(long)]
+    destination: Option<String>,
+    /// create the routes disabled
+    #[arg(long)]
+    disabled: bool,
+}
+
+pub async fn run_route_cmd(route_cmd: &RouteCmd, redox: &Redox) -> Result<(), String> {
+    let mut route_req: Vec<NewRouteReq> = Vec::new();
+    if route_cmd.route_ids.len() == 1 && route_cmd.route_ids[0] == "." {
+        route_req = serde_json::from_str(get_stdin_from_pipe().as_str())
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        let tags: HashMap<String, Value> = match &route_cmd.tags {
+            Some(x) => serde_json::from_str(x).map_err(|e| format!("Error: {:?}", e))?,
+            None => HashMap::new(),
+        };
+        let predicate: Option<RoutePredicate> = match &route_cmd.predicate {
+            Some(x) => Some(serde_json::from_str(x).map_err(|e| format!("Error: {:?}", e))?),
+            None => None,
+        };
+        let destination: RouteDestination = match &route_cmd.destination {
+            Some(x) => serde_json::from_str(x).map_err(|e| format!("Error: {:?}", e))?,
+            None => return Err("Error: --destination is required".to_string()),
+        };
+        for route_id in route_cmd.route_ids.clone() {
+            route_req.push(NewRouteReq {
+                route_id,
+                description: route_cmd.description.clone().unwrap_or_default(),
+                routing_key: route_cmd.routing_key.clone(),
+                tags: tags.clone(),
+                predicate: predicate.clone(),
+                destination: destination.clone(),
+                enabled: !route_cmd.disabled,
+            });
+        }
+    }
+
+    let mut routes = json!([]);
+    for req in route_req {
+        let route = create_route_request(redox, req)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        routes.as_array_mut().unwrap().push(route);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&routes).unwrap());
+
+    Ok(())
+}
+
+async fn create_route_request(
+    redox: &Redox,
+    route_req: NewRouteReq,
+) -> Result<Value, reqwest::Error> {
+    let resp = redox.post("/routes").json(&route_req).send().await?;
+    resp.json::<Value>().await
+}
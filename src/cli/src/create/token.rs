@@ -0,0 +1,40 @@
+use chrono::Utc;
+use clap::Args;
+use libs::utils::auth::{create_token, Claims, Role};
+
+#[derive(Args)]
+pub struct TokenCmd {
+    /// identity of the caller using the token
+    subject: String,
+
+    #[arg(short, long, value_enum, default_value_t = Role::Reader)]
+    role: Role,
+    /// redox token secret used to sign
+    #[arg(short, long)]
+    secret: String,
+    /// time to live in seconds, 0 never expires
+    #[arg(long, default_value_t = 0)]
+    ttl: i64,
+    /// restrict the token to a tenant, empty allows every tenant
+    #[arg(long, default_value = "")]
+    tenant: String,
+}
+
+pub async fn run_token_cmd(token_cmd: &TokenCmd) -> Result<(), String> {
+    let exp = if token_cmd.ttl > 0 {
+        Utc::now().timestamp() + token_cmd.ttl
+    } else {
+        0
+    };
+    let claims = Claims {
+        sub: token_cmd.subject.clone(),
+        role: token_cmd.role,
+        exp,
+        tenant: token_cmd.tenant.clone(),
+    };
+
+    let token = create_token(&token_cmd.secret, &claims).map_err(|e| format!("Error: {:?}", e))?;
+    print!("{}", token);
+
+    Ok(())
+}
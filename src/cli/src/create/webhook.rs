@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use libs::{
+    models::webhook::{NewWebhookReq, WebhookEventKind, WebhookFilter},
+    utils::cli::get_stdin_from_pipe,
+};
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct WebhookCmd {
+    /// list of webhooks to create or replace. If . read from stdin.
+    webhook_ids: Vec<String>,
+
+    /// url the events are posted to
+    #[arg(short, long)]
+    url: Option<String>,
+    /// events to post, all events if not set
+    #[arg(short, long, value_enum, value_delimiter = ',')]
+    events: Vec<WebhookEventKind>,
+    /// only the events of these devices
+    #[arg(short, long, value_delimiter = ',')]
+    device_ids: Vec<String>,
+    /// device tags the webhook applies to as json, {"building": 43}. All devices if not set.
+    #[arg(long)]
+    tags: Option<String>,
+    /// secret the events are signed with. Generated if not set, kept on replace.
+    #[arg(short, long)]
+    secret: Option<String>,
+    /// create the webhooks disabled
+    #[arg(long)]
+    disabled: bool,
+}
+
+pub async fn run_webhook_cmd(webhook_cmd: &WebhookCmd, redox: &Redox) -> Result<(), String> {
+    let mut webhook_req: Vec<NewWebhookReq> = Vec::new();
+    if webhook_cmd.webhook_ids.len() == 1 && webhook_cmd.webhook_ids[0] == "." {
+        webhook_req = serde_json::from_str(get_stdin_from_pipe().as_str())
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        let Some(url) = webhook_cmd.url.clone() else {
+            return Err("Error: --url is required".to_string());
+        };
+        let tags: HashMap<String, Value> = match &webhook_cmd.tags {
+            Some(x) => serde_json::from_str(x).map_err(|e| format!("Error: {:?}", e))?,
+            None => HashMap::new(),
+        };
+        for webhook_id in webhook_cmd.webhook_ids.clone() {
+            webhook_req.push(NewWebhookReq {
+                webhook_id,
+                url: url.clone(),
+                events: webhook_cmd.events.clone(),
+                filter: WebhookFilter {
+                    device_ids: webhook_cmd.device_ids.clone(),
+                    tags: tags.clone(),
+                },
+                secret: webhook_cmd.secret.clone().unwrap_or_default(),
+                enabled: !webhook_cmd.disabled,
+            });
+        }
+    }
+
+    let mut webhooks = json!([]);
+    for req in webhook_req {
+        let webhook = create_webhook_request(redox, req)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        webhooks.as_array_mut().unwrap().push(webhook);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&webhooks).unwrap());
+
+    Ok(())
+}
+
+async fn create_webhook_request(
+    redox: &Redox,
+    webhook_req: NewWebhookReq,
+) -> Result<Value, reqwest::Error> {
+    let resp = redox.post("/webhooks").json(&webhook_req).send().await?;
+    resp.json::<Value>().await
+}
@@ -1,6 +1,12 @@
 use clap::{command, Args, Subcommand};
 
+use crate::redox::Redox;
+
+pub mod alert_rule;
 pub mod device;
+pub mod enrollment;
+pub mod route;
+pub mod webhook;
 
 #[derive(Args)]
 pub struct DeleteCmd {
@@ -12,10 +18,24 @@ pub struct DeleteCmd {
 pub enum DeleteCmds {
     /// create device
     Device(device::DeviceCmd),
+    /// delete enrollment group
+    Enrollment(enrollment::EnrollmentCmd),
+    /// delete alert rule
+    AlertRule(alert_rule::AlertRuleCmd),
+    /// delete route
+    Route(route::RouteCmd),
+    /// delete webhook
+    Webhook(webhook::WebhookCmd),
 }
 
-pub async fn run_delete_cmd(delete_cmd: &DeleteCmd, target: String) -> Result<(), String> {
+pub async fn run_delete_cmd(delete_cmd: &DeleteCmd, redox: &Redox) -> Result<(), String> {
     match &delete_cmd.command {
-        DeleteCmds::Device(device_cmd) => device::run_device_cmd(device_cmd, target).await,
+        DeleteCmds::Device(device_cmd) => device::run_device_cmd(device_cmd, redox).await,
+        DeleteCmds::Enrollment(enrollment_cmd) => {
+            enrollment::run_enrollment_cmd(enrollment_cmd, redox).await
+        }
+        DeleteCmds::AlertRule(rule_cmd) => alert_rule::run_alert_rule_cmd(rule_cmd, redox).await,
+        DeleteCmds::Route(route_cmd) => route::run_route_cmd(route_cmd, redox).await,
+        DeleteCmds::Webhook(webhook_cmd) => webhook::run_webhook_cmd(webhook_cmd, redox).await,
     }
 }
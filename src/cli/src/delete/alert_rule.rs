@@ -0,0 +1,34 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct AlertRuleCmd {
+    /// list of alert rules to delete. Rules from the redox config can't be deleted.
+    rule_ids: Vec<String>,
+}
+
+pub async fn run_alert_rule_cmd(rule_cmd: &AlertRuleCmd, redox: &Redox) -> Result<(), String> {
+    let mut rules = json!([]);
+    for id in rule_cmd.rule_ids.clone() {
+        let rule = delete_alert_rule_request(redox, id)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        rules.as_array_mut().unwrap().push(rule);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&rules).unwrap());
+
+    Ok(())
+}
+
+async fn delete_alert_rule_request(
+    redox: &Redox,
+    rule_id: String,
+) -> Result<Value, reqwest::Error> {
+    let path = format!("/alertrules?rule_id={}", rule_id);
+    let resp = redox.delete(&path).send().await?;
+
+    resp.json::<Value>().await
+}
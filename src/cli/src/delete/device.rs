@@ -2,6 +2,8 @@ use clap::Args;
 use libs::utils::cli::get_stdin_from_pipe;
 use serde_json::{json, Value};
 
+use crate::redox::Redox;
+
 #[derive(Args)]
 pub struct DeviceCmd {
     /// list of devices to delete. If . read from stdin.
@@ -17,7 +19,7 @@ pub struct DeviceCmd {
     reported: bool,
 }
 
-pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<(), String> {
+pub async fn run_device_cmd(device_cmd: &DeviceCmd, redox: &Redox) -> Result<(), String> {
     let mut ids: Vec<String> = device_cmd.device_ids.clone();
     if device_cmd.device_ids.len() == 1 && device_cmd.device_ids[0] == "." {
         ids = serde_json::from_str(get_stdin_from_pipe().as_str()).unwrap();
@@ -25,7 +27,7 @@ pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<()
 
     let mut devices = json!([]);
     for id in ids {
-        let mut device = delete_device_request(target.clone(), id)
+        let mut device = delete_device_request(redox, id)
             .await
             .map_err(|e| format!("Error: {:?}", e))?;
 
@@ -60,10 +62,9 @@ pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<()
     Ok(())
 }
 
-async fn delete_device_request(url: String, device_id: String) -> Result<Value, reqwest::Error> {
-    let url = format!("http://{}/devicetwins?device_id={}", url, device_id);
-    let client = reqwest::Client::new();
-    let resp = client.delete(url).send().await?;
+async fn delete_device_request(redox: &Redox, device_id: String) -> Result<Value, reqwest::Error> {
+    let path = format!("/devicetwins?device_id={}", device_id);
+    let resp = redox.delete(&path).send().await?;
 
     Ok(resp.json::<Value>().await?)
 }
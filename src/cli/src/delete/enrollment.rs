@@ -0,0 +1,37 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct EnrollmentCmd {
+    /// list of enrollment groups to delete. Provisioned devices are kept.
+    group_ids: Vec<String>,
+}
+
+pub async fn run_enrollment_cmd(
+    enrollment_cmd: &EnrollmentCmd,
+    redox: &Redox,
+) -> Result<(), String> {
+    let mut groups = json!([]);
+    for id in enrollment_cmd.group_ids.clone() {
+        let group = delete_enrollment_request(redox, id)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        groups.as_array_mut().unwrap().push(group);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&groups).unwrap());
+
+    Ok(())
+}
+
+async fn delete_enrollment_request(
+    redox: &Redox,
+    group_id: String,
+) -> Result<Value, reqwest::Error> {
+    let path = format!("/enrollmentgroups?group_id={}", group_id);
+    let resp = redox.delete(&path).send().await?;
+
+    resp.json::<Value>().await
+}
@@ -0,0 +1,31 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct RouteCmd {
+    /// list of routes to delete
+    route_ids: Vec<String>,
+}
+
+pub async fn run_route_cmd(route_cmd: &RouteCmd, redox: &Redox) -> Result<(), String> {
+    let mut routes = json!([]);
+    for id in route_cmd.route_ids.clone() {
+        let route = delete_route_request(redox, id)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        routes.as_array_mut().unwrap().push(route);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&routes).unwrap());
+
+    Ok(())
+}
+
+async fn delete_route_request(redox: &Redox, route_id: String) -> Result<Value, reqwest::Error> {
+    let path = format!("/routes?route_id={}", route_id);
+    let resp = redox.delete(&path).send().await?;
+
+    resp.json::<Value>().await
+}
@@ -0,0 +1,34 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct WebhookCmd {
+    /// list of webhooks to delete, their delivery log is kept
+    webhook_ids: Vec<String>,
+}
+
+pub async fn run_webhook_cmd(webhook_cmd: &WebhookCmd, redox: &Redox) -> Result<(), String> {
+    let mut webhooks = json!([]);
+    for id in webhook_cmd.webhook_ids.clone() {
+        let webhook = delete_webhook_request(redox, id)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        webhooks.as_array_mut().unwrap().push(webhook);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&webhooks).unwrap());
+
+    Ok(())
+}
+
+async fn delete_webhook_request(
+    redox: &Redox,
+    webhook_id: String,
+) -> Result<Value, reqwest::Error> {
+    let path = format!("/webhooks?webhook_id={}", webhook_id);
+    let resp = redox.delete(&path).send().await?;
+
+    resp.json::<Value>().await
+}
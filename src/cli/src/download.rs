@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use libs::models::upload::DeviceUpload;
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct DownloadCmd {
+    /// upload to download, see `mir list uploads`
+    upload_id: String,
+
+    /// file to write [default: name of the uploaded file]
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+pub async fn run_download_cmd(download_cmd: &DownloadCmd, redox: &Redox) -> Result<(), String> {
+    let output = match &download_cmd.output {
+        Some(x) => x.clone(),
+        None => {
+            let upload = get_upload(redox, &download_cmd.upload_id)
+                .await
+                .map_err(|e| format!("Error: {:?}", e))?
+                .ok_or(format!(
+                    "Error: upload '{}' not found",
+                    download_cmd.upload_id
+                ))?;
+            Path::new(&upload.name)
+                .file_name()
+                .map(PathBuf::from)
+                .unwrap_or(PathBuf::from(&upload.upload_id))
+        }
+    };
+
+    let content = get_upload_content(redox, &download_cmd.upload_id)
+        .await
+        .map_err(|e| format!("Error: {:?}", e))?;
+    std::fs::write(&output, &content)
+        .map_err(|e| format!("Error: can't write {}: {}", output.display(), e))?;
+    println!("{} bytes written to {}", content.len(), output.display());
+
+    Ok(())
+}
+
+async fn get_upload(
+    redox: &Redox,
+    upload_id: &str,
+) -> Result<Option<DeviceUpload>, reqwest::Error> {
+    let uploads = redox
+        .get("/uploads")
+        .query(&[("upload_id", upload_id)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<DeviceUpload>>()
+        .await?;
+    Ok(uploads.into_iter().next())
+}
+
+// Only complete uploads have content
+async fn get_upload_content(redox: &Redox, upload_id: &str) -> Result<Vec<u8>, reqwest::Error> {
+    let content = redox
+        .get("/uploads/content")
+        .query(&[("upload_id", upload_id)])
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(content.to_vec())
+}
@@ -1,6 +1,16 @@
 use clap::{command, Args, Subcommand};
 
+use crate::redox::Redox;
+
+pub mod alerts;
+pub mod artifacts;
+pub mod campaigns;
 pub mod devices;
+pub mod enrollments;
+pub mod models;
+pub mod routes;
+pub mod uploads;
+pub mod webhooks;
 
 #[derive(Args)]
 pub struct ListCmd {
@@ -12,10 +22,40 @@ pub struct ListCmd {
 pub enum ListCmds {
     /// list devices
     Devices(devices::DevicesCmd),
+    /// list enrollment groups
+    Enrollments(enrollments::EnrollmentsCmd),
+    /// list device models
+    Models(models::ModelsCmd),
+    /// list alerts or alert rules
+    Alerts(alerts::AlertsCmd),
+    /// list message routes
+    Routes(routes::RoutesCmd),
+    /// list webhooks or their deliveries
+    Webhooks(webhooks::WebhooksCmd),
+    /// list files uploaded by devices
+    Uploads(uploads::UploadsCmd),
+    /// list ota artifacts
+    Artifacts(artifacts::ArtifactsCmd),
+    /// list ota campaigns or their rollout status
+    Campaigns(campaigns::CampaignsCmd),
 }
 
-pub async fn run_list_cmd(list_cmd: &ListCmd, target: String) -> Result<(), String> {
+pub async fn run_list_cmd(list_cmd: &ListCmd, redox: &Redox) -> Result<(), String> {
     match &list_cmd.command {
-        ListCmds::Devices(device_cmd) => devices::run_devices_cmd(device_cmd, target).await,
+        ListCmds::Devices(device_cmd) => devices::run_devices_cmd(device_cmd, redox).await,
+        ListCmds::Enrollments(enrollments_cmd) => {
+            enrollments::run_enrollments_cmd(enrollments_cmd, redox).await
+        }
+        ListCmds::Models(models_cmd) => models::run_models_cmd(models_cmd, redox).await,
+        ListCmds::Alerts(alerts_cmd) => alerts::run_alerts_cmd(alerts_cmd, redox).await,
+        ListCmds::Routes(routes_cmd) => routes::run_routes_cmd(routes_cmd, redox).await,
+        ListCmds::Webhooks(webhooks_cmd) => webhooks::run_webhooks_cmd(webhooks_cmd, redox).await,
+        ListCmds::Uploads(uploads_cmd) => uploads::run_uploads_cmd(uploads_cmd, redox).await,
+        ListCmds::Artifacts(artifacts_cmd) => {
+            artifacts::run_artifacts_cmd(artifacts_cmd, redox).await
+        }
+        ListCmds::Campaigns(campaigns_cmd) => {
+            campaigns::run_campaigns_cmd(campaigns_cmd, redox).await
+        }
     }
 }
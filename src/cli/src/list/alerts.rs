@@ -0,0 +1,76 @@
+use clap::Args;
+use libs::models::alert::AlertState;
+use serde_json::Value;
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct AlertsCmd {
+    /// only the alerts of these rules. If empty, print alerts of all rules.
+    rule_ids: Vec<String>,
+
+    /// only the alerts of this device
+    #[arg(short, long)]
+    device_id: Option<String>,
+
+    /// alerts in this state [default: firing]
+    #[arg(short, long)]
+    state: Option<String>,
+
+    /// print the rules instead of the alerts
+    #[arg(long)]
+    rules: bool,
+}
+
+pub async fn run_alerts_cmd(alerts_cmd: &AlertsCmd, redox: &Redox) -> Result<(), String> {
+    let path = if alerts_cmd.rules {
+        "/alertrules"
+    } else {
+        "/alerts"
+    };
+    let mut params: Vec<(&str, String)> = vec![];
+    if let Some(x) = &alerts_cmd.device_id {
+        params.push(("device_id", x.clone()));
+    }
+    if let Some(x) = &alerts_cmd.state {
+        serde_json::from_value::<AlertState>(Value::String(x.clone())).map_err(|_| {
+            format!(
+                "Error: invalid state '{}', expected ok, pending or firing",
+                x
+            )
+        })?;
+        params.push(("state", x.clone()));
+    }
+
+    let mut results: Vec<Value> = vec![];
+    if alerts_cmd.rule_ids.is_empty() {
+        results.extend(get_alerts_data(redox, path, &params).await?);
+    } else {
+        for rule_id in alerts_cmd.rule_ids.iter() {
+            let mut rule_params = params.clone();
+            rule_params.push(("rule_id", rule_id.clone()));
+            results.extend(get_alerts_data(redox, path, &rule_params).await?);
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&results).unwrap());
+
+    Ok(())
+}
+
+async fn get_alerts_data(
+    redox: &Redox,
+    path: &str,
+    params: &[(&str, String)],
+) -> Result<Vec<Value>, String> {
+    let data = redox
+        .get(path)
+        .query(params)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| format!("Error: {:?}", e))?
+        .json::<Value>()
+        .await
+        .map_err(|e| format!("Error: {:?}", e))?;
+    Ok(data.as_array().cloned().unwrap_or_default())
+}
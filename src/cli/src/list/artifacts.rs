@@ -0,0 +1,53 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct ArtifactsCmd {
+    /// list of artifacts to print. If empty, print all artifacts.
+    artifact_ids: Vec<String>,
+}
+
+pub async fn run_artifacts_cmd(artifacts_cmd: &ArtifactsCmd, redox: &Redox) -> Result<(), String> {
+    let ids: Vec<Option<String>> = if artifacts_cmd.artifact_ids.is_empty() {
+        vec![None]
+    } else {
+        artifacts_cmd
+            .artifact_ids
+            .iter()
+            .cloned()
+            .map(Some)
+            .collect()
+    };
+
+    let mut artifacts = json!([]);
+    for artifact_id in ids {
+        let data = get_artifacts_data(redox, artifact_id)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        if let Some(x) = data.as_array() {
+            artifacts.as_array_mut().unwrap().extend(x.clone());
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&artifacts).unwrap());
+
+    Ok(())
+}
+
+async fn get_artifacts_data(
+    redox: &Redox,
+    artifact_id: Option<String>,
+) -> Result<Value, reqwest::Error> {
+    let mut params: Vec<(&str, String)> = vec![];
+    if let Some(id) = artifact_id {
+        params.push(("artifact_id", id));
+    }
+    redox
+        .get("/artifacts")
+        .query(&params)
+        .send()
+        .await?
+        .json::<Value>()
+        .await
+}
@@ -0,0 +1,68 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct CampaignsCmd {
+    /// list of campaigns to print. If empty, print all campaigns.
+    campaign_ids: Vec<String>,
+
+    /// print the rollout status of each device instead
+    #[arg(long)]
+    status: bool,
+}
+
+pub async fn run_campaigns_cmd(campaigns_cmd: &CampaignsCmd, redox: &Redox) -> Result<(), String> {
+    if campaigns_cmd.status && campaigns_cmd.campaign_ids.is_empty() {
+        return Err("Error: --status needs campaign ids".to_string());
+    }
+    let ids: Vec<Option<String>> = if campaigns_cmd.campaign_ids.is_empty() {
+        vec![None]
+    } else {
+        campaigns_cmd
+            .campaign_ids
+            .iter()
+            .cloned()
+            .map(Some)
+            .collect()
+    };
+
+    let mut campaigns = json!([]);
+    for campaign_id in ids {
+        let data = get_campaigns_data(redox, campaign_id, campaigns_cmd.status)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        match data {
+            Value::Array(x) => campaigns.as_array_mut().unwrap().extend(x),
+            x => campaigns.as_array_mut().unwrap().push(x),
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&campaigns).unwrap());
+
+    Ok(())
+}
+
+async fn get_campaigns_data(
+    redox: &Redox,
+    campaign_id: Option<String>,
+    status: bool,
+) -> Result<Value, reqwest::Error> {
+    let mut params: Vec<(&str, String)> = vec![];
+    if let Some(id) = campaign_id {
+        params.push(("campaign_id", id));
+    }
+    let path = if status {
+        "/campaigns/status"
+    } else {
+        "/campaigns"
+    };
+    redox
+        .get(path)
+        .query(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await
+}
@@ -3,6 +3,8 @@ use libs::utils::cli::get_stdin_from_pipe;
 use serde_json::{json, Value};
 use string_builder::Builder;
 
+use crate::redox::Redox;
+
 #[derive(Args)]
 pub struct DevicesCmd {
     /// list of devices to print. If empty, print all devices. If . read from stdin.
@@ -18,7 +20,7 @@ pub struct DevicesCmd {
     reported: bool,
 }
 
-pub async fn run_devices_cmd(device_cmd: &DevicesCmd, target: String) -> Result<(), String> {
+pub async fn run_devices_cmd(device_cmd: &DevicesCmd, redox: &Redox) -> Result<(), String> {
     let mut ids: Vec<String> = device_cmd.device_ids.clone();
     if device_cmd.device_ids.len() == 1 && device_cmd.device_ids[0] == "." {
         // TODO: find how to make it non blocking if empty
@@ -29,7 +31,7 @@ pub async fn run_devices_cmd(device_cmd: &DevicesCmd, target: String) -> Result<
         ids = serde_json::from_str(get_stdin_from_pipe().as_str()).unwrap();
     }
     list_all_devices(
-        target,
+        redox,
         ids,
         device_cmd.meta,
         device_cmd.tag,
@@ -42,7 +44,7 @@ pub async fn run_devices_cmd(device_cmd: &DevicesCmd, target: String) -> Result<
 }
 
 async fn list_all_devices(
-    url: String,
+    redox: &Redox,
     device_ids: Vec<String>,
     meta: bool,
     tag: bool,
@@ -50,7 +52,7 @@ async fn list_all_devices(
     reported: bool,
 ) {
     if device_ids.len() == 0 {
-        let devices = match get_devices_data(url, None).await {
+        let devices = match get_devices_data(redox, None).await {
             Ok(d) => d,
             Err(e) => {
                 println!("Error: {:?}", e);
@@ -63,7 +65,7 @@ async fn list_all_devices(
 
     let mut devices = json!([]);
     for device_id in device_ids {
-        let device = match get_devices_data(url.clone(), Some(device_id.clone())).await {
+        let device = match get_devices_data(redox, Some(device_id.clone())).await {
             Ok(d) => d,
             Err(e) => {
                 println!("Error: {:?}", e);
@@ -126,15 +128,22 @@ fn stringify_tag_prop(twin: Value) -> String {
     x
 }
 
-async fn get_devices_data(url: String, device_id: Option<String>) -> Result<Value, reqwest::Error> {
+async fn get_devices_data(
+    redox: &Redox,
+    device_id: Option<String>,
+) -> Result<Value, reqwest::Error> {
     if let Some(id) = device_id {
-        let url = format!("http://{}/devicetwins?device_id={}", url, id);
-        let res = reqwest::get(url).await?.json::<Value>().await?;
+        let path = format!("/devicetwins?device_id={}", id);
+        let res = redox.get(&path).send().await?.json::<Value>().await?;
         return Ok(res);
     }
 
-    let url = format!("http://{}/devicetwins", url);
-    let res = reqwest::get(url).await?.json::<Value>().await?;
+    let res = redox
+        .get("/devicetwins")
+        .send()
+        .await?
+        .json::<Value>()
+        .await?;
 
     // TODO: add device id call ?device_id=maxi2
 
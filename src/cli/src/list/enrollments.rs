@@ -0,0 +1,47 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct EnrollmentsCmd {
+    /// list of enrollment groups to print. If empty, print all groups.
+    group_ids: Vec<String>,
+}
+
+pub async fn run_enrollments_cmd(
+    enrollments_cmd: &EnrollmentsCmd,
+    redox: &Redox,
+) -> Result<(), String> {
+    if enrollments_cmd.group_ids.is_empty() {
+        let groups = get_enrollments_data(redox, None)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        print!("{}", serde_json::to_string_pretty(&groups).unwrap());
+        return Ok(());
+    }
+
+    let mut groups = json!([]);
+    for group_id in enrollments_cmd.group_ids.clone() {
+        let group = get_enrollments_data(redox, Some(group_id))
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        if let Some(x) = group.as_array() {
+            groups.as_array_mut().unwrap().extend(x.clone());
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&groups).unwrap());
+
+    Ok(())
+}
+
+async fn get_enrollments_data(
+    redox: &Redox,
+    group_id: Option<String>,
+) -> Result<Value, reqwest::Error> {
+    let path = match group_id {
+        Some(id) => format!("/enrollmentgroups?group_id={}", id),
+        None => String::from("/enrollmentgroups"),
+    };
+    redox.get(&path).send().await?.json::<Value>().await
+}
@@ -0,0 +1,55 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct ModelsCmd {
+    /// list of device models to print. If empty, print all models.
+    model_ids: Vec<String>,
+
+    /// only print the sensors of the models
+    #[arg(long)]
+    sensors: bool,
+}
+
+pub async fn run_models_cmd(models_cmd: &ModelsCmd, redox: &Redox) -> Result<(), String> {
+    let mut models = json!([]);
+    if models_cmd.model_ids.is_empty() {
+        models = get_models_data(redox, None)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        for model_id in models_cmd.model_ids.clone() {
+            let model = get_models_data(redox, Some(model_id))
+                .await
+                .map_err(|e| format!("Error: {:?}", e))?;
+            if let Some(x) = model.as_array() {
+                models.as_array_mut().unwrap().extend(x.clone());
+            }
+        }
+    }
+
+    if models_cmd.sensors {
+        if let Some(x) = models.as_array_mut() {
+            for model in x.iter_mut() {
+                *model = json!({
+                    "model_id": model["model_id"],
+                    "version": model["version"],
+                    "sensors": model["sensors"],
+                });
+            }
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&models).unwrap());
+
+    Ok(())
+}
+
+async fn get_models_data(redox: &Redox, model_id: Option<String>) -> Result<Value, reqwest::Error> {
+    let path = match model_id {
+        Some(id) => format!("/devicemodels?model_id={}", id),
+        None => String::from("/devicemodels"),
+    };
+    redox.get(&path).send().await?.json::<Value>().await
+}
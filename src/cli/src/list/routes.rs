@@ -0,0 +1,39 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct RoutesCmd {
+    /// list of routes to print. If empty, print all routes.
+    route_ids: Vec<String>,
+}
+
+pub async fn run_routes_cmd(routes_cmd: &RoutesCmd, redox: &Redox) -> Result<(), String> {
+    let mut routes = json!([]);
+    if routes_cmd.route_ids.is_empty() {
+        routes = get_routes_data(redox, None)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+    } else {
+        for route_id in routes_cmd.route_ids.clone() {
+            let route = get_routes_data(redox, Some(route_id))
+                .await
+                .map_err(|e| format!("Error: {:?}", e))?;
+            if let Some(x) = route.as_array() {
+                routes.as_array_mut().unwrap().extend(x.clone());
+            }
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&routes).unwrap());
+
+    Ok(())
+}
+
+async fn get_routes_data(redox: &Redox, route_id: Option<String>) -> Result<Value, reqwest::Error> {
+    let path = match route_id {
+        Some(id) => format!("/routes?route_id={}", id),
+        None => String::from("/routes"),
+    };
+    redox.get(&path).send().await?.json::<Value>().await
+}
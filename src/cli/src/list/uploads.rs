@@ -0,0 +1,56 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct UploadsCmd {
+    /// list of uploads to print. If empty, print all uploads.
+    upload_ids: Vec<String>,
+
+    /// only the uploads of this device
+    #[arg(short, long)]
+    device_id: Option<String>,
+}
+
+pub async fn run_uploads_cmd(uploads_cmd: &UploadsCmd, redox: &Redox) -> Result<(), String> {
+    let ids: Vec<Option<String>> = if uploads_cmd.upload_ids.is_empty() {
+        vec![None]
+    } else {
+        uploads_cmd.upload_ids.iter().cloned().map(Some).collect()
+    };
+
+    let mut uploads = json!([]);
+    for upload_id in ids {
+        let data = get_uploads_data(redox, upload_id, uploads_cmd.device_id.clone())
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        if let Some(x) = data.as_array() {
+            uploads.as_array_mut().unwrap().extend(x.clone());
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&uploads).unwrap());
+
+    Ok(())
+}
+
+async fn get_uploads_data(
+    redox: &Redox,
+    upload_id: Option<String>,
+    device_id: Option<String>,
+) -> Result<Value, reqwest::Error> {
+    let mut params: Vec<(&str, String)> = vec![];
+    if let Some(id) = upload_id {
+        params.push(("upload_id", id));
+    }
+    if let Some(id) = device_id {
+        params.push(("device_id", id));
+    }
+    redox
+        .get("/uploads")
+        .query(&params)
+        .send()
+        .await?
+        .json::<Value>()
+        .await
+}
@@ -0,0 +1,63 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct WebhooksCmd {
+    /// list of webhooks to print. If empty, print all webhooks.
+    webhook_ids: Vec<String>,
+
+    /// print the delivery log instead, most recent first
+    #[arg(long)]
+    deliveries: bool,
+
+    /// deliveries to print per webhook
+    #[arg(short, long, default_value_t = 100)]
+    limit: usize,
+}
+
+pub async fn run_webhooks_cmd(webhooks_cmd: &WebhooksCmd, redox: &Redox) -> Result<(), String> {
+    let ids: Vec<Option<String>> = if webhooks_cmd.webhook_ids.is_empty() {
+        vec![None]
+    } else {
+        webhooks_cmd.webhook_ids.iter().cloned().map(Some).collect()
+    };
+
+    let mut webhooks = json!([]);
+    for webhook_id in ids {
+        let data = get_webhooks_data(redox, webhook_id, webhooks_cmd)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        if let Some(x) = data.as_array() {
+            webhooks.as_array_mut().unwrap().extend(x.clone());
+        }
+    }
+    print!("{}", serde_json::to_string_pretty(&webhooks).unwrap());
+
+    Ok(())
+}
+
+async fn get_webhooks_data(
+    redox: &Redox,
+    webhook_id: Option<String>,
+    webhooks_cmd: &WebhooksCmd,
+) -> Result<Value, reqwest::Error> {
+    let mut params: Vec<(&str, String)> = vec![];
+    if let Some(id) = webhook_id {
+        params.push(("webhook_id", id));
+    }
+    let path = if webhooks_cmd.deliveries {
+        params.push(("limit", webhooks_cmd.limit.to_string()));
+        "/webhooks/deliveries"
+    } else {
+        "/webhooks"
+    };
+    redox
+        .get(path)
+        .query(&params)
+        .send()
+        .await?
+        .json::<Value>()
+        .await
+}
@@ -1,6 +1,8 @@
 use clap::Args;
 use libs::{clients::amqp::Amqp, utils::cli::get_stdin_from_pipe};
 
+pub mod webhook;
+
 //const RMQ_TWIN_EXCHANGE_NAME: &str = "iot-twin";
 //const RMQ_TWIN_HEARTHBEAT_QUEUE_NAME: &str = "iot-q-hearthbeat";
 //const RMQ_TWIN_HEATHBEAT_ROUTING_KEY: &str = "#.hearthbeat.v1";
@@ -23,9 +25,20 @@ pub struct ListenCmd {
     desired: bool,
     #[arg(long)]
     reported: bool,
+
+    /// run a local http stub on this port printing the webhook events it receives
+    #[arg(long, value_name = "PORT")]
+    webhook: Option<u16>,
+    /// secret to verify the webhook event signatures with
+    #[arg(long, requires = "webhook")]
+    secret: Option<String>,
 }
 
 pub async fn run_listen_cmd(cmd: &ListenCmd, target: String) -> Result<(), String> {
+    if let Some(port) = cmd.webhook {
+        return webhook::run_webhook_stub(port, cmd.secret.clone()).await;
+    }
+
     let mut ids: Vec<String> = cmd.device_ids.clone();
     if cmd.device_ids.len() == 1 && cmd.device_ids[0] == "." {
         ids = serde_json::from_str(get_stdin_from_pipe().as_str()).unwrap();
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use libs::models::webhook::{EVENT_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use libs::utils::auth::verify_message;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+// Larger bodies are refused, events are small
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Local http stub printing the events redox posts, to test webhooks
+pub async fn run_webhook_stub(port: u16, secret: Option<String>) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .map_err(|e| format!("Error: {:?}", e))?;
+    println!("Listen to webhooks on http://127.0.0.1:{}", port);
+
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        let secret = secret.clone();
+        tokio::spawn(async move {
+            if let Err(e) = receive_webhook(stream, secret.as_deref()).await {
+                eprintln!("Error: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn receive_webhook(stream: TcpStream, secret: Option<&str>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.trim_end().split_once(':') {
+            headers.insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let length: usize = headers
+        .get("content-length")
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY_SIZE {
+        return respond(reader, "413 Payload Too Large").await;
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    let verified = secret.map(|secret| {
+        let timestamp = headers.get(TIMESTAMP_HEADER).and_then(|x| x.parse().ok());
+        match (timestamp, headers.get(SIGNATURE_HEADER)) {
+            (Some(timestamp), Some(signature)) => {
+                verify_message(secret, timestamp, &body, signature)
+            }
+            _ => false,
+        }
+    });
+    let event = serde_json::from_slice::<Value>(&body)
+        .map(|x| serde_json::to_string_pretty(&x).unwrap())
+        .unwrap_or_else(|_| String::from_utf8_lossy(&body).to_string());
+    println!(
+        "{} {} signature: {}",
+        request_line.trim_end(),
+        headers.get(EVENT_HEADER).map_or("", |x| x.as_str()),
+        match verified {
+            Some(true) => "valid",
+            Some(false) => "INVALID",
+            None => "not checked",
+        }
+    );
+    println!("{}", event);
+
+    if verified == Some(false) {
+        respond(reader, "401 Unauthorized").await
+    } else {
+        respond(reader, "200 OK").await
+    }
+}
+
+async fn respond(reader: BufReader<TcpStream>, status: &str) -> std::io::Result<()> {
+    let mut stream = reader.into_inner();
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                status
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await
+}
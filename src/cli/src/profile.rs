@@ -0,0 +1,49 @@
+use std::{collections::HashMap, env, path::PathBuf};
+
+use libs::utils::config::{setup_config, FileFormat};
+use serde::Deserialize;
+
+const APP_NAME: &str = "mir";
+const PROFILE_FILE_PATH: &str = ".mir/config.yaml";
+
+// Profiles are read from ~/.mir/config.yaml, ex:
+// current_profile: local
+// profiles:
+//   local:
+//     redox_target: localhost:5047
+//     token: <bearer token>
+//     tenant: iot
+#[derive(Debug, Deserialize, Default)]
+pub struct MirConfig {
+    #[serde(default)]
+    pub current_profile: String,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Profile {
+    pub target: Option<String>,
+    pub redox_target: Option<String>,
+    pub token: Option<String>,
+    pub tenant: Option<String>,
+}
+
+pub fn load_profile(name: Option<&str>) -> Result<Profile, String> {
+    let path = env::var("HOME")
+        .map(|home| PathBuf::from(home).join(PROFILE_FILE_PATH))
+        .ok()
+        .filter(|path| path.exists());
+    let config: MirConfig = setup_config(APP_NAME, FileFormat::YAML, path.as_ref())
+        .map_err(|e| format!("Error: invalid mir config {:?}", e))?;
+
+    let name = name.unwrap_or(config.current_profile.as_str());
+    if name.is_empty() {
+        return Ok(Profile::default());
+    }
+    config
+        .profiles
+        .get(name)
+        .cloned()
+        .ok_or(format!("Error: profile '{}' not found", name))
+}
@@ -0,0 +1,53 @@
+use libs::utils::tenant::TENANT_HEADER;
+use reqwest::{Client, RequestBuilder};
+
+// Http client to Redox carrying the caller credentials and tenant
+#[derive(Debug, Clone)]
+pub struct Redox {
+    pub addr: String,
+    pub token: Option<String>,
+    pub tenant: Option<String>,
+    client: Client,
+}
+
+impl Redox {
+    pub fn new(addr: String, token: Option<String>, tenant: Option<String>) -> Self {
+        Self {
+            addr,
+            token,
+            tenant,
+            client: Client::new(),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.client.get(self.url(path)))
+    }
+
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.client.post(self.url(path)))
+    }
+
+    pub fn put(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.client.put(self.url(path)))
+    }
+
+    pub fn delete(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.client.delete(self.url(path)))
+    }
+
+    fn with_auth(&self, req: RequestBuilder) -> RequestBuilder {
+        let req = match &self.tenant {
+            Some(tenant) => req.header(TENANT_HEADER, tenant),
+            None => req,
+        };
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+}
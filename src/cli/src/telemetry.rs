@@ -0,0 +1,23 @@
+use clap::{command, Args, Subcommand};
+
+use crate::redox::Redox;
+
+pub mod query;
+
+#[derive(Args)]
+pub struct TelemetryCmd {
+    #[command(subcommand)]
+    pub command: TelemetryCmds,
+}
+
+#[derive(Subcommand)]
+pub enum TelemetryCmds {
+    /// query stored telemetry of a device
+    Query(query::QueryCmd),
+}
+
+pub async fn run_telemetry_cmd(telemetry_cmd: &TelemetryCmd, redox: &Redox) -> Result<(), String> {
+    match &telemetry_cmd.command {
+        TelemetryCmds::Query(query_cmd) => query::run_query_cmd(query_cmd, redox).await,
+    }
+}
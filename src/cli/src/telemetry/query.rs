@@ -0,0 +1,102 @@
+use chrono::{DateTime, Duration, Utc};
+use clap::Args;
+use libs::models::device_model::SensorKind;
+use libs::models::telemetry_query::{Aggregation, TelemetryQuery};
+use serde_json::Value;
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct QueryCmd {
+    /// device to query
+    device_id: String,
+
+    /// only this sensor, all sensors of the kind if not set
+    #[arg(short, long)]
+    sensor_id: Option<i64>,
+
+    /// kind of the values to query
+    #[arg(short, long, value_enum, default_value_t = SensorKind::Float)]
+    kind: SensorKind,
+
+    /// start of the range, RFC 3339 or a duration ago like 30m, 6h, 2d [default: 1h]
+    #[arg(long)]
+    from: Option<String>,
+
+    /// end of the range, RFC 3339 or a duration ago [default: now]
+    #[arg(long)]
+    to: Option<String>,
+
+    /// aggregate the values of each bucket, raw values if not set
+    #[arg(short, long, value_enum)]
+    aggregation: Option<Aggregation>,
+
+    /// width of the aggregation buckets like 30s, 5m, 1h [default: 1m]
+    #[arg(short, long)]
+    bucket: Option<String>,
+
+    /// maximum number of points returned
+    #[arg(short, long)]
+    limit: Option<usize>,
+}
+
+pub async fn run_query_cmd(query_cmd: &QueryCmd, redox: &Redox) -> Result<(), String> {
+    let now = Utc::now();
+    let query = TelemetryQuery {
+        device_id: query_cmd.device_id.clone(),
+        sensor_id: query_cmd.sensor_id,
+        kind: query_cmd.kind.clone(),
+        from: query_cmd
+            .from
+            .as_deref()
+            .map(|x| parse_time(x, now))
+            .transpose()?,
+        to: query_cmd
+            .to
+            .as_deref()
+            .map(|x| parse_time(x, now))
+            .transpose()?,
+        aggregation: query_cmd.aggregation,
+        bucket: query_cmd.bucket.clone(),
+        limit: query_cmd.limit,
+    };
+
+    let series = query_telemetry(redox, &query)
+        .await
+        .map_err(|e| format!("Error: {:?}", e))?;
+    print!("{}", serde_json::to_string_pretty(&series).unwrap());
+
+    Ok(())
+}
+
+async fn query_telemetry(redox: &Redox, query: &TelemetryQuery) -> Result<Value, reqwest::Error> {
+    redox
+        .get("/telemetry")
+        .query(query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await
+}
+
+// Nanoseconds since epoch of a date or of a duration before now
+fn parse_time(value: &str, now: DateTime<Utc>) -> Result<i64, String> {
+    if let Ok(x) = DateTime::parse_from_rfc3339(value) {
+        return Ok(x.timestamp_nanos());
+    }
+    let invalid = || format!("Error: invalid time '{}'", value);
+    if !value.is_ascii() {
+        return Err(invalid());
+    }
+    let (count, unit) = value.split_at(value.len().saturating_sub(1));
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let ago = match unit {
+        "s" => Duration::seconds(count),
+        "m" => Duration::minutes(count),
+        "h" => Duration::hours(count),
+        "d" => Duration::days(count),
+        _ => return Err(invalid()),
+    };
+    Ok((now - ago).timestamp_nanos())
+}
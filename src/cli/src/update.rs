@@ -1,6 +1,12 @@
 use clap::{command, Args, Subcommand};
 
+use crate::redox::Redox;
+
 pub mod device;
+pub mod key;
+pub mod ping;
+pub mod silence;
+pub mod status;
 
 #[derive(Args)]
 pub struct UpdateCmd {
@@ -12,10 +18,22 @@ pub struct UpdateCmd {
 pub enum UpdateCmds {
     /// create device
     Device(device::DeviceCmd),
+    /// rotate device key, the previous key stays valid until the next rotation
+    Key(key::KeyCmd),
+    /// enable, disable, block or unblock devices
+    Status(status::StatusCmd),
+    /// silence the alerts of rules for a while
+    Silence(silence::SilenceCmd),
+    /// send a signed ping event to webhooks to test them
+    Ping(ping::PingCmd),
 }
 
-pub async fn run_update_cmd(update_cmd: &UpdateCmd, target: String) -> Result<(), String> {
+pub async fn run_update_cmd(update_cmd: &UpdateCmd, redox: &Redox) -> Result<(), String> {
     match &update_cmd.command {
-        UpdateCmds::Device(device_cmd) => device::run_device_cmd(device_cmd, target).await,
+        UpdateCmds::Device(device_cmd) => device::run_device_cmd(device_cmd, redox).await,
+        UpdateCmds::Key(key_cmd) => key::run_key_cmd(key_cmd, redox).await,
+        UpdateCmds::Status(status_cmd) => status::run_status_cmd(status_cmd, redox).await,
+        UpdateCmds::Silence(silence_cmd) => silence::run_silence_cmd(silence_cmd, redox).await,
+        UpdateCmds::Ping(ping_cmd) => ping::run_ping_cmd(ping_cmd, redox).await,
     }
 }
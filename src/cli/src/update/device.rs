@@ -1,9 +1,10 @@
 use clap::Args;
 use libs::models::device_twin::{Properties, TargetProperties};
 use libs::utils::cli::get_stdin_from_pipe;
-use reqwest::Client;
 use serde_json::{json, Value};
 
+use crate::redox::Redox;
+
 #[derive(Args)]
 pub struct DeviceCmd {
     /// list of devices to print. If empty, print all devices. If . read from stdin.
@@ -25,7 +26,7 @@ pub struct DeviceCmd {
     reported: bool,
 }
 
-pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<(), String> {
+pub async fn run_device_cmd(device_cmd: &DeviceCmd, redox: &Redox) -> Result<(), String> {
     let ids: Vec<String> = device_cmd.device_ids.clone();
     let payload: Properties;
     if device_cmd.properties.is_none() {
@@ -36,10 +37,9 @@ pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<()
 
     let mut devices = json!([]);
     for id in ids {
-        let mut device =
-            update_device_request(target.clone(), &device_cmd.target, &payload, id.as_str())
-                .await
-                .map_err(|e| format!("Error: {:?}", e))?;
+        let mut device = update_device_request(redox, &device_cmd.target, &payload, id.as_str())
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
 
         let twin = if let Some(x) = device.as_object_mut() {
             x
@@ -73,20 +73,14 @@ pub async fn run_device_cmd(device_cmd: &DeviceCmd, target: String) -> Result<()
 }
 
 async fn update_device_request(
-    url: String,
+    redox: &Redox,
     target: &TargetProperties,
     properties: &Properties,
     device_id: &str,
 ) -> Result<Value, reqwest::Error> {
-    let url = format!(
-        "http://{}/devicetwins/{}?device_id={}",
-        url,
-        target.as_str(),
-        device_id
-    );
+    let path = format!("/devicetwins/{}?device_id={}", target.as_str(), device_id);
 
-    let client = Client::new();
-    let resp = client.put(url).json(&properties).send().await?;
+    let resp = redox.put(&path).json(&properties).send().await?;
     let x = resp.json::<Value>().await?;
     // TODO: add device id call ?device_id=maxi2
 
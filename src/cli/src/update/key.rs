@@ -0,0 +1,37 @@
+use clap::Args;
+use libs::utils::cli::get_stdin_from_pipe;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct KeyCmd {
+    /// list of devices to rotate the key of. If . read from stdin.
+    device_ids: Vec<String>,
+}
+
+pub async fn run_key_cmd(key_cmd: &KeyCmd, redox: &Redox) -> Result<(), String> {
+    let mut ids: Vec<String> = key_cmd.device_ids.clone();
+    if key_cmd.device_ids.len() == 1 && key_cmd.device_ids[0] == "." {
+        ids = serde_json::from_str(get_stdin_from_pipe().as_str()).unwrap();
+    }
+
+    let mut keys = json!([]);
+    for id in ids {
+        let key = rotate_key_request(redox, id.as_str())
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        keys.as_array_mut().unwrap().push(key);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&keys).unwrap());
+
+    Ok(())
+}
+
+async fn rotate_key_request(redox: &Redox, device_id: &str) -> Result<Value, reqwest::Error> {
+    let path = format!("/devicetwins/keys?device_id={}", device_id);
+    let resp = redox.post(&path).send().await?.error_for_status()?;
+
+    Ok(resp.json::<Value>().await?)
+}
@@ -0,0 +1,31 @@
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct PingCmd {
+    /// list of webhooks to send a signed ping event to
+    webhook_ids: Vec<String>,
+}
+
+pub async fn run_ping_cmd(ping_cmd: &PingCmd, redox: &Redox) -> Result<(), String> {
+    let mut deliveries = json!([]);
+    for id in ping_cmd.webhook_ids.iter() {
+        let delivery = ping_request(redox, id)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        deliveries.as_array_mut().unwrap().push(delivery);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&deliveries).unwrap());
+
+    Ok(())
+}
+
+async fn ping_request(redox: &Redox, webhook_id: &str) -> Result<Value, reqwest::Error> {
+    let path = format!("/webhooks/ping?webhook_id={}", webhook_id);
+    let resp = redox.post(&path).send().await?.error_for_status()?;
+
+    resp.json::<Value>().await
+}
@@ -0,0 +1,54 @@
+use clap::Args;
+use libs::models::alert::SilenceAlertReq;
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct SilenceCmd {
+    /// list of alert rules to silence
+    rule_ids: Vec<String>,
+
+    /// only silence the alerts of this device
+    #[arg(short, long)]
+    device_id: Option<String>,
+
+    /// seconds the alerts stay silenced, 0 lifts the silence
+    #[arg(short, long, default_value_t = 3600)]
+    for_secs: u64,
+}
+
+pub async fn run_silence_cmd(silence_cmd: &SilenceCmd, redox: &Redox) -> Result<(), String> {
+    let req = SilenceAlertReq {
+        device_id: silence_cmd.device_id.clone().unwrap_or_default(),
+        duration_secs: silence_cmd.for_secs,
+    };
+
+    let mut rules = json!([]);
+    for id in silence_cmd.rule_ids.iter() {
+        let rule = silence_request(redox, id, &req)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        rules.as_array_mut().unwrap().push(rule);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&rules).unwrap());
+
+    Ok(())
+}
+
+async fn silence_request(
+    redox: &Redox,
+    rule_id: &str,
+    req: &SilenceAlertReq,
+) -> Result<Value, reqwest::Error> {
+    let path = format!("/alertrules/silence?rule_id={}", rule_id);
+    let resp = redox
+        .put(&path)
+        .json(req)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(resp.json::<Value>().await?)
+}
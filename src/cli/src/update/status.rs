@@ -0,0 +1,56 @@
+use clap::Args;
+use libs::{
+    models::device_twin::{DeviceStatusReq, StatusAction},
+    utils::cli::get_stdin_from_pipe,
+};
+use serde_json::{json, Value};
+
+use crate::redox::Redox;
+
+#[derive(Args)]
+pub struct StatusCmd {
+    /// list of devices to change the status of. If . read from stdin.
+    device_ids: Vec<String>,
+
+    /// a blocked device can only be unblocked
+    #[arg(short, long, value_enum)]
+    action: StatusAction,
+}
+
+pub async fn run_status_cmd(status_cmd: &StatusCmd, redox: &Redox) -> Result<(), String> {
+    let mut ids: Vec<String> = status_cmd.device_ids.clone();
+    if status_cmd.device_ids.len() == 1 && status_cmd.device_ids[0] == "." {
+        ids = serde_json::from_str(get_stdin_from_pipe().as_str()).unwrap();
+    }
+
+    let mut statuses = json!([]);
+    for id in ids {
+        let status = update_status_request(redox, id.as_str(), &status_cmd.action)
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        statuses.as_array_mut().unwrap().push(status);
+    }
+
+    print!("{}", serde_json::to_string_pretty(&statuses).unwrap());
+
+    Ok(())
+}
+
+async fn update_status_request(
+    redox: &Redox,
+    device_id: &str,
+    action: &StatusAction,
+) -> Result<Value, reqwest::Error> {
+    let path = format!("/devicetwins/status?device_id={}", device_id);
+    let req = DeviceStatusReq {
+        action: action.clone(),
+    };
+    let resp = redox
+        .put(&path)
+        .json(&req)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(resp.json::<Value>().await?)
+}
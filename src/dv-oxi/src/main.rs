@@ -1,18 +1,24 @@
-use lapin::types::ShortString;
-use libs::models::device_twin::Properties;
-use libs::models::telemetry::Telemetry;
-use libs::shipyard::oxi::{builder::MirShipyard, oxi::Oxi};
+use libs::models::telemetry::{Metrics, Telemetry};
+use libs::shipyard::oxi::{builder::MirShipyard, error::OxiBuilderError, oxi::Oxi};
 use libs::utils::telemetry::{PyramidTelemetryGenerator, TelemetryGenerator};
 use log::{debug, error, info};
+use serde::Deserialize;
 use serde_json::json;
 use thiserror::Error as ThisError;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 #[derive(ThisError, Debug)]
 enum Error {}
 
+// Desired properties that don't match are reported back to mir
+#[derive(Debug, Deserialize)]
+struct DesiredProperties {
+    #[serde(default)]
+    telemetry_interval_secs: Option<u64>,
+}
+
 //const RMQ_TWIN_EXCHANGE_NAME: &str = "iot-twin";
 //const RMQ_DEVICE_EXCHANGE_NAME: &str = "iot-devices";
 //const RMQ_TWIN_META_QUEUE_NAME: &str = "iot-q-twin-meta";
@@ -45,33 +51,45 @@ async fn main() -> Result<(), String> {
         .with_cli()
         .with_config_file("")
         .with_device_id("0x992wf===")
+        // Unsigned when empty, pass it with --device-key or the config file,
+        // `mir update key <device_id>` gives the device a new one
+        .with_device_key("")
         .with_mir_server("")
         .with_thread_count(7)
         .with_logger("info")
+        .with_log_forwarding("warn", 10)
+        .with_twin_cache("./twin.json")
+        .with_heartbeat_interval(30)
+        .with_firmware_version("0.1.0")
+        .with_metadata("board", "rpi4")
         .build();
 
-    let mut oxi = if let Err(x) = oxi_builder {
-        return Err(format!("error initializing Oxi: {}", x));
-    } else {
-        oxi_builder.unwrap()
+    let mut oxi = match oxi_builder {
+        Ok(x) => x,
+        // --help and --version end up here as well
+        Err(OxiBuilderError::InvalidCli(x)) => x.exit(),
+        Err(x) => return Err(format!("error initializing Oxi: {}", x)),
     };
 
     // Do setup
-    let cloned_oxi = oxi.clone();
-    oxi.add_desired_properties_handler(move |x: Option<Properties>, _opts: Option<ShortString>| {
+    oxi.add_desired_handler(|oxi: Oxi, x: DesiredProperties| async move {
         info!("{:?}", x);
-        let cloned_cloned_oxi = cloned_oxi.clone();
-        tokio::spawn(async move {
-            let req = cloned_cloned_oxi
-                .send_reported_properties_request(Properties {
-                    properties: json!({ "battery": "included", "random": Uuid::new_v4() }),
-                    version: 7,
-                })
-                .await;
-            if let Err(e) = req {
-                error!("error sending reported properties request: {}", e);
-            }
-        });
+        // Only what changed, oxi merges it with the last reported properties
+        let req = oxi
+            .report(json!({
+                "battery": "included",
+                "random": Uuid::new_v4(),
+                "telemetry_interval_secs": x.telemetry_interval_secs,
+            }))
+            .await;
+        if let Err(e) = req {
+            error!("error sending reported properties request: {}", e);
+        }
+        info!("not reported yet: {:?}", oxi.pending_desired_properties());
+    });
+
+    oxi.add_status_handler(|x| {
+        info!("status is now {:?} ({:?})", x.status, x.status_reason);
     });
 
     // Connect to mir
@@ -93,6 +111,19 @@ async fn main() -> Result<(), String> {
         }
     });
 
+    let cloned_token = token.clone();
+    let oxi_clone = oxi.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = send_metrics(oxi_clone) => {
+                debug!("device shuting down...");
+            }
+        }
+    });
+
     // Wait for shutdown signal
     info!("Press ctrl+c to shutdown.");
     match tokio::signal::ctrl_c().await {
@@ -123,3 +154,18 @@ async fn send_random_telemetry(oxi: Oxi) {
         sleep(Duration::from_secs(5)).await;
     }
 }
+
+async fn send_metrics(oxi: Oxi) {
+    let started = Instant::now();
+    loop {
+        let metrics = Metrics {
+            uptime: started.elapsed().as_secs(),
+            ..Default::default()
+        };
+        if let Err(error) = oxi.send_metrics(metrics).await {
+            error!("{}", error);
+        }
+
+        sleep(Duration::from_secs(60)).await;
+    }
+}
@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use libs::clients::amqp::BatchConsumer;
+use libs::models::device_model::DeviceModelCache;
+use libs::models::device_twin::DeviceStatusCache;
+use log::{info, trace, warn};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::datapoint::DroppedValues;
+use crate::row::Row;
+use crate::sensor::{MetadataTracker, SensorSettings};
+use crate::sink::TelemetrySink;
+use crate::Error;
+
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BatchSettings {
+    // Flush when the buffer holds that many rows
+    pub max_rows: usize,
+    // Flush when the buffer holds that many bytes
+    pub max_bytes: usize,
+    // Flush a non empty buffer at the latest after that long
+    pub linger_ms: u64,
+    // Unacknowledged deliveries per consumer, bounds memory while the sink is slow
+    pub prefetch_count: u16,
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        Self {
+            max_rows: 1000,
+            max_bytes: 1024 * 1024,
+            linger_ms: 500,
+            prefetch_count: 1000,
+        }
+    }
+}
+
+// What a message needs to turn into rows
+pub struct RowContext {
+    pub tenant: String,
+    pub models: DeviceModelCache,
+    pub dropped: DroppedValues,
+    pub sensors: SensorSettings,
+    pub metadata: MetadataTracker,
+}
+
+// A message of a device stream stored by flux
+pub trait IntoRows: Send {
+    fn device_id(&self) -> &str;
+
+    fn into_rows(self, context: &RowContext) -> Vec<Row>;
+}
+
+// Accumulates the rows of many device messages in the sink,
+// they are acknowledged once the sink accepted the flush
+pub struct TelemetryBatch {
+    index: usize,
+    context: RowContext,
+    statuses: DeviceStatusCache,
+    settings: BatchSettings,
+    sink: Box<dyn TelemetrySink>,
+    messages: usize,
+    rows: usize,
+    deadline: Instant,
+}
+
+impl TelemetryBatch {
+    pub async fn connect(
+        index: usize,
+        context: RowContext,
+        statuses: DeviceStatusCache,
+        mut sink: Box<dyn TelemetrySink>,
+        settings: BatchSettings,
+    ) -> Self {
+        connect_with_backoff(index, sink.as_mut()).await;
+        Self {
+            index,
+            context,
+            statuses,
+            settings,
+            sink,
+            messages: 0,
+            rows: 0,
+            deadline: Instant::now(),
+        }
+    }
+
+    pub fn prefetch_count(&self) -> u16 {
+        self.settings.prefetch_count
+    }
+}
+
+#[async_trait]
+impl<T: IntoRows + 'static> BatchConsumer<T> for TelemetryBatch {
+    type Error = Error;
+
+    fn add(&mut self, msg: T) -> Result<(), Error> {
+        // Messages of disabled devices are acknowledged with the batch but not stored
+        let rows = if self.statuses.is_enabled(msg.device_id()) {
+            msg.into_rows(&self.context)
+        } else {
+            warn!(
+                "{}: dropped message from disabled device '{}', {} dropped so far",
+                self.index,
+                msg.device_id(),
+                self.statuses.count_dropped()
+            );
+            vec![]
+        };
+        if !rows.is_empty() {
+            self.sink.write(&rows)?;
+        }
+
+        if self.messages == 0 {
+            self.deadline = Instant::now() + Duration::from_millis(self.settings.linger_ms);
+        }
+        self.messages += 1;
+        self.rows += rows.len();
+        Ok(())
+    }
+
+    fn is_full(&self) -> bool {
+        self.rows >= self.settings.max_rows || self.sink.pending_bytes() >= self.settings.max_bytes
+    }
+
+    fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    // Consumption is paused while flushing and reconnecting, prefetch does the rest
+    async fn flush(&mut self) -> Result<(), Error> {
+        let messages = std::mem::take(&mut self.messages);
+        let rows = std::mem::take(&mut self.rows);
+        if rows == 0 {
+            return Ok(());
+        }
+
+        match self.sink.flush().await {
+            Ok(()) => {
+                trace!(
+                    "{}: flushed {} rows of {} messages",
+                    self.index,
+                    rows,
+                    messages
+                );
+                Ok(())
+            }
+            Err(error) => {
+                self.sink.clear();
+                self.context.metadata.clear();
+                // A connection is unusable after a failed flush, the batch
+                // is requeued once connected again
+                connect_with_backoff(self.index, self.sink.as_mut()).await;
+                Err(error)
+            }
+        }
+    }
+}
+
+async fn connect_with_backoff(index: usize, sink: &mut dyn TelemetrySink) {
+    let mut backoff = RECONNECT_MIN_BACKOFF;
+    loop {
+        match sink.connect().await {
+            Ok(()) => {
+                info!("{}: connected to {}", index, sink.name());
+                return;
+            }
+            Err(error) => {
+                warn!(
+                    "{}: can't connect to {}, retrying in {:?} {}",
+                    index,
+                    sink.name(),
+                    backoff,
+                    error
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use libs::models::{
+    device_model::DeviceModelCache,
+    telemetry::{DeviceTelemetryBatchRequest, DeviceTelemetryRequest, Telemetry},
+};
+
+use crate::batch::{IntoRows, RowContext};
+use crate::row::{Row, TypedValue};
+use crate::sensor::SensorColumns;
+
+const FLOAT_TABLE: &str = "Datapoint";
+const INT_TABLE: &str = "DatapointInt";
+const BOOL_TABLE: &str = "DatapointBool";
+const STRING_TABLE: &str = "DatapointString";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DropReason {
+    // Kind is not the one declared by the device model
+    KindMismatch,
+    // Same sensor sent with more than one kind in a payload
+    DuplicateSensor,
+    // NaN or infinite float
+    NotFinite,
+    InvalidTimestamp,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 4] = [
+        DropReason::KindMismatch,
+        DropReason::DuplicateSensor,
+        DropReason::NotFinite,
+        DropReason::InvalidTimestamp,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            DropReason::KindMismatch => "kind_mismatch",
+            DropReason::DuplicateSensor => "duplicate_sensor",
+            DropReason::NotFinite => "not_finite",
+            DropReason::InvalidTimestamp => "invalid_timestamp",
+        }
+    }
+}
+
+// Dropped values by reason, shared by all consumers
+#[derive(Debug, Clone, Default)]
+pub struct DroppedValues {
+    counts: Arc<[AtomicU64; 4]>,
+}
+
+impl DroppedValues {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, reason: DropReason, count: u64) {
+        self.counts[reason as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        DropReason::ALL.iter().map(|x| self.get(*x)).sum()
+    }
+}
+
+// Each kind has its own table so a sensor changing kind never conflicts
+// with the column type of a previous value
+fn table(value: &TypedValue) -> &'static str {
+    match value {
+        TypedValue::Float(_) => FLOAT_TABLE,
+        TypedValue::Int(_) => INT_TABLE,
+        TypedValue::Bool(_) => BOOL_TABLE,
+        TypedValue::String(_) => STRING_TABLE,
+    }
+}
+
+// A sensor keeps a single value per payload. The kind declared by the device
+// model wins, otherwise the first one found in floats, ints, bools then strings.
+pub fn resolve_values(
+    device_id: &str,
+    telemetry: Telemetry,
+    models: &DeviceModelCache,
+    dropped: &DroppedValues,
+) -> BTreeMap<i64, TypedValue> {
+    let candidates = telemetry
+        .floats
+        .into_iter()
+        .map(|(id, x)| (id, TypedValue::Float(x)))
+        .chain(
+            telemetry
+                .ints
+                .into_iter()
+                .map(|(id, x)| (id, TypedValue::Int(x))),
+        )
+        .chain(
+            telemetry
+                .bools
+                .into_iter()
+                .map(|(id, x)| (id, TypedValue::Bool(x))),
+        )
+        .chain(
+            telemetry
+                .strings
+                .into_iter()
+                .map(|(id, x)| (id, TypedValue::String(x))),
+        );
+
+    let mut values = BTreeMap::new();
+    for (sensor_id, value) in candidates {
+        if let TypedValue::Float(x) = value {
+            if !x.is_finite() {
+                dropped.add(DropReason::NotFinite, 1);
+                continue;
+            }
+        }
+        if let Some(definition) = models.sensor(device_id, sensor_id) {
+            if definition.kind != value.kind() {
+                dropped.add(DropReason::KindMismatch, 1);
+                continue;
+            }
+        }
+        match values.entry(sensor_id) {
+            Entry::Vacant(x) => {
+                x.insert(value);
+            }
+            Entry::Occupied(_) => dropped.add(DropReason::DuplicateSensor, 1),
+        }
+    }
+    values
+}
+
+// One row per value of the payload, sensors unknown to the device
+// model are stored without name and unit. The Device and Sensor rows
+// come first when the sensors of the device changed.
+fn resolve_rows(
+    context: &RowContext,
+    device_id: &str,
+    timestamp: i64,
+    telemetry: Telemetry,
+) -> Vec<Row> {
+    let values = resolve_values(device_id, telemetry, &context.models, &context.dropped);
+    if timestamp < 0 {
+        context
+            .dropped
+            .add(DropReason::InvalidTimestamp, values.len() as u64);
+        return vec![];
+    }
+
+    let mut rows = if context.sensors.metadata_tables && !values.is_empty() {
+        context
+            .metadata
+            .rows(&context.tenant, device_id, &context.models, timestamp)
+    } else {
+        vec![]
+    };
+    rows.extend(values.into_iter().map(|(sensor_id, value)| {
+        let definition = context.models.sensor(device_id, sensor_id);
+        let row = Row::new(table(&value), timestamp)
+            .symbol("tenant", &context.tenant)
+            .key("device_id", TypedValue::String(device_id.to_string()))
+            .key("sensor_id", TypedValue::Int(sensor_id));
+        let row = match context.sensors.denormalize {
+            SensorColumns::None => row,
+            SensorColumns::Field => row
+                .field(
+                    "sensor_name",
+                    definition
+                        .as_ref()
+                        .map(|x| TypedValue::String(x.name.clone())),
+                )
+                .field("unit", definition.map(|x| TypedValue::String(x.unit))),
+            // Symbols can't be missing
+            SensorColumns::Symbol => {
+                let (name, unit) =
+                    definition.map_or((String::new(), String::new()), |x| (x.name, x.unit));
+                row.symbol("sensor_name", name).symbol("unit", unit)
+            }
+        };
+        row.field("value", Some(value))
+    }));
+    rows
+}
+
+impl IntoRows for DeviceTelemetryRequest {
+    fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    fn into_rows(self, context: &RowContext) -> Vec<Row> {
+        resolve_rows(context, &self.device_id, self.timestamp, self.telemetry)
+    }
+}
+
+// Each reading keeps the time it was taken
+impl IntoRows for DeviceTelemetryBatchRequest {
+    fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    fn into_rows(self, context: &RowContext) -> Vec<Row> {
+        self.points
+            .into_iter()
+            .flat_map(|x| resolve_rows(context, &self.device_id, x.timestamp, x.telemetry))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libs::models::device_model::{DeviceModel, SensorDefinition, SensorKind};
+
+    fn telemetry() -> Telemetry {
+        Telemetry {
+            floats: [(1, 1.5), (2, 2.5)].into(),
+            ints: [(1, 10), (3, 30)].into(),
+            bools: [(1, true)].into(),
+            strings: [(1, "on".to_string())].into(),
+        }
+    }
+
+    fn models_with(kind: SensorKind) -> DeviceModelCache {
+        let models = DeviceModelCache::new();
+        models.replace_models(vec![DeviceModel {
+            model_id: "thermostat".to_string(),
+            version: 1,
+            sensors: vec![SensorDefinition {
+                id: 1,
+                kind,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }]);
+        models.replace_devices(vec![("dev-1".to_string(), "thermostat".to_string())]);
+        models
+    }
+
+    #[test]
+    fn first_kind_wins_without_model() {
+        let dropped = DroppedValues::new();
+        let values = resolve_values("dev-1", telemetry(), &DeviceModelCache::new(), &dropped);
+        assert_eq!(values.get(&1), Some(&TypedValue::Float(1.5)));
+        assert_eq!(values.get(&2), Some(&TypedValue::Float(2.5)));
+        assert_eq!(values.get(&3), Some(&TypedValue::Int(30)));
+        assert_eq!(dropped.get(DropReason::DuplicateSensor), 3);
+        assert_eq!(dropped.total(), 3);
+    }
+
+    #[test]
+    fn model_kind_wins() {
+        let dropped = DroppedValues::new();
+        let values = resolve_values(
+            "dev-1",
+            telemetry(),
+            &models_with(SensorKind::Bool),
+            &dropped,
+        );
+        assert_eq!(values.get(&1), Some(&TypedValue::Bool(true)));
+        assert_eq!(dropped.get(DropReason::KindMismatch), 3);
+        assert_eq!(dropped.get(DropReason::DuplicateSensor), 0);
+    }
+
+    #[test]
+    fn not_finite_float_leaves_the_sensor_to_the_next_kind() {
+        let dropped = DroppedValues::new();
+        let telemetry = Telemetry {
+            floats: [(1, f64::NAN)].into(),
+            ints: [(1, 10)].into(),
+            ..Default::default()
+        };
+        let values = resolve_values("dev-1", telemetry, &DeviceModelCache::new(), &dropped);
+        assert_eq!(values.get(&1), Some(&TypedValue::Int(10)));
+        assert_eq!(dropped.get(DropReason::NotFinite), 1);
+        assert_eq!(dropped.get(DropReason::DuplicateSensor), 0);
+    }
+
+    #[test]
+    fn same_result_whatever_the_map_order() {
+        let models = models_with(SensorKind::String);
+        let first = resolve_values("dev-1", telemetry(), &models, &DroppedValues::new());
+        for _ in 0..10 {
+            let values = resolve_values("dev-1", telemetry(), &models, &DroppedValues::new());
+            assert_eq!(values, first);
+        }
+        assert_eq!(first.get(&1), Some(&TypedValue::String("on".to_string())));
+    }
+}
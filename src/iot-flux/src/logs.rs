@@ -0,0 +1,30 @@
+use libs::models::telemetry::DeviceLogRequest;
+
+use crate::batch::{IntoRows, RowContext};
+use crate::row::{Row, TypedValue};
+
+const LOG_TABLE: &str = "DeviceLog";
+
+impl IntoRows for DeviceLogRequest {
+    fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    // Structured fields are kept together as a json object
+    fn into_rows(self, context: &RowContext) -> Vec<Row> {
+        let fields = if self.fields.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&self.fields)
+                .ok()
+                .map(TypedValue::String)
+        };
+        vec![Row::new(LOG_TABLE, self.timestamp)
+            .symbol("tenant", context.tenant.as_str())
+            .symbol("level", self.level.as_str())
+            .key("device_id", TypedValue::String(self.device_id))
+            .key("target", TypedValue::String(self.target))
+            .field("message", Some(TypedValue::String(self.message)))
+            .field("fields", fields)]
+    }
+}
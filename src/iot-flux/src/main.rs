@@ -1,20 +1,49 @@
-use futures::StreamExt;
-use lapin::types::ShortString;
-use lapin::{options::*, types::FieldTable};
+use lapin::{options::*, types::FieldTable, ExchangeKind};
 use libs::utils::setup_cli;
-use log::{debug, error, info, trace};
-use questdb::ingress::{Buffer, Sender, SenderBuilder, TimestampNanos};
+use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::num::ParseIntError;
 use std::path::PathBuf;
+use std::time::Duration;
+use surrealdb::engine::remote::ws::{Client, Ws};
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
 use thiserror::Error as ThisError;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
-use libs::clients::amqp::Amqp;
-use libs::models::telemetry::DeviceTelemetryRequest;
+use libs::clients::amqp::{
+    Amqp, AmqpSettings, ChannelSettings, ConsumerSettings, ExchangeSettings, QueueBindSettings,
+    QueueSettings,
+};
+use libs::models::device_key::DeviceKey;
+use libs::models::device_model::{DeviceModel, DeviceModelCache};
+use libs::models::device_twin::DeviceStatusCache;
+use libs::models::telemetry::{
+    DeviceLogRequest, DeviceMetricsRequest, DeviceSensorsRequest, DeviceTelemetryBatchRequest,
+    DeviceTelemetryRequest,
+};
+use libs::utils::auth::DeviceKeyCache;
 use libs::utils::config::{setup_config, FileFormat};
 use libs::utils::logger::setup_logger;
-use libs::utils::network;
+use libs::utils::serialization::SerializationKind;
+use libs::utils::tenant::{default_tenants, tenant_queue, tenant_routing_key};
+
+use crate::batch::{BatchSettings, IntoRows, RowContext, TelemetryBatch};
+use crate::datapoint::{DropReason, DroppedValues};
+use crate::sensor::{
+    get_declared_sensors_from_db, MetadataTracker, SensorRegistryHandler, SensorSettings,
+};
+use crate::sink::{build_sink, SinkSettings};
+
+mod batch;
+mod datapoint;
+mod logs;
+mod metrics;
+mod row;
+mod sensor;
+mod sink;
 
 #[derive(ThisError, Debug)]
 enum Error {
@@ -22,20 +51,123 @@ enum Error {
     ParseIntError(#[from] ParseIntError),
     #[error("put host error: {0}")]
     PutHostError(#[from] questdb::Error),
+    #[error("surrealdb error: {0}")]
+    SurrealDB(#[from] surrealdb::Error),
+    #[error("network error: {0}")]
+    Network(#[from] libs::utils::network::NetworkError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[error("parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("invalid settings: {0}")]
+    InvalidSettings(String),
+    #[error("{0} is not connected")]
+    NotConnected(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SurrealDb {
+    pub user: String,
+    pub password: String,
+    pub addr: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub log_level: String,
     pub amqp_addr: String,
-    pub questdb_addr: String,
     pub thread_count: usize,
+    pub surrealdb: SurrealDb,
+    #[serde(default)]
+    pub verify_signature: bool,
+    #[serde(default = "default_tenants")]
+    pub tenants: Vec<String>,
+    #[serde(default)]
+    pub batch: BatchSettings,
+    // Several sinks get the same rows
+    pub sinks: Vec<SinkSettings>,
+    #[serde(default)]
+    pub topic: TopicSettings,
+    #[serde(default)]
+    pub sensors: SensorSettings,
+}
+
+// One queue per device stream on the same exchange
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TopicSettings {
+    pub exchange: String,
+    pub telemetry: QueueTopic,
+    // Readings devices send together
+    pub telemetry_batch: QueueTopic,
+    pub logs: QueueTopic,
+    pub metrics: QueueTopic,
+    pub sensors: QueueTopic,
+}
+
+impl Default for TopicSettings {
+    fn default() -> Self {
+        Self {
+            exchange: RMQ_EXCHANGE_NAME.to_string(),
+            telemetry: QueueTopic::new(RMQ_TELEMETRY_QUEUE_NAME, RMQ_TELEMETRY_ROUTING_KEY),
+            telemetry_batch: QueueTopic::new(
+                RMQ_TELEMETRY_BATCH_QUEUE_NAME,
+                RMQ_TELEMETRY_BATCH_ROUTING_KEY,
+            ),
+            logs: QueueTopic::new(RMQ_LOGS_QUEUE_NAME, RMQ_LOGS_ROUTING_KEY),
+            metrics: QueueTopic::new(RMQ_METRICS_QUEUE_NAME, RMQ_METRICS_ROUTING_KEY),
+            sensors: QueueTopic::new(RMQ_SENSORS_QUEUE_NAME, RMQ_SENSORS_ROUTING_KEY),
+        }
+    }
+}
+
+// Queue and routing key are prefixed with the tenant
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueueTopic {
+    pub queue: String,
+    pub routing_key: String,
+}
+
+impl QueueTopic {
+    fn new(queue: &str, routing_key: &str) -> Self {
+        Self {
+            queue: queue.to_string(),
+            routing_key: routing_key.to_string(),
+        }
+    }
 }
 
 const APP_NAME: &str = "flux";
 const RMQ_EXCHANGE_NAME: &str = "iot-stream";
-const RMQ_QUEUE_NAME: &str = "iot-q-telemetry";
-const RMQ_PREFETCH_COUNT: u16 = 10;
+const RMQ_TELEMETRY_QUEUE_NAME: &str = "iot-q-telemetry";
+const RMQ_TELEMETRY_ROUTING_KEY: &str = "#.telemetry.v1";
+const RMQ_TELEMETRY_BATCH_QUEUE_NAME: &str = "iot-q-telemetry-batch";
+const RMQ_TELEMETRY_BATCH_ROUTING_KEY: &str = "#.telemetry_batch.v1";
+const RMQ_LOGS_QUEUE_NAME: &str = "iot-q-logs";
+const RMQ_LOGS_ROUTING_KEY: &str = "#.logs.v1";
+const RMQ_METRICS_QUEUE_NAME: &str = "iot-q-metrics";
+const RMQ_METRICS_ROUTING_KEY: &str = "#.metrics.v1";
+const RMQ_SENSORS_QUEUE_NAME: &str = "iot-q-sensors";
+const RMQ_SENSORS_ROUTING_KEY: &str = "#.sensors.v1";
+const SURREAL_DB_NAME: &str = "iot";
+const DEVICE_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const DEVICE_MODEL_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const DEVICE_STATUS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const DROPPED_VALUES_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+// Time given to consumers to flush and acknowledge their pending batch
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct DeviceModelAssignment {
+    device_id: String,
+    model_id: String,
+}
 
 #[tokio::main]
 async fn main() {
@@ -52,28 +184,31 @@ async fn main() {
     setup_logger(settings.log_level.clone()).unwrap();
     info!("{:?}", settings);
 
-    let amqp: Amqp = Amqp::new(settings.amqp_addr.clone(), settings.thread_count);
-    let host_port = network::parse_host_port(settings.questdb_addr.as_str()).unwrap();
-
-    for i in 0..settings.thread_count {
-        let cloned_token = token.clone();
-        let cloned_amqp = amqp.clone();
-        let mut sender = SenderBuilder::new(host_port.0.clone(), host_port.1.clone())
-            .connect()
-            .unwrap();
+    // Telemetry and telemetry batch consumers plus one logs, one metrics and
+    // one sensors consumer per tenant
+    let amqp: Amqp = Amqp::new(
+        settings.amqp_addr.clone(),
+        (settings.thread_count * 2 + 3) * settings.tenants.len(),
+    );
 
-        tokio::spawn(async move {
-            tokio::select! {
-                _ = cloned_token.cancelled() => {
-                    debug!("The token was shutdown")
-                }
-                _ = start_consuming_topic_queue(i, cloned_amqp,  move |payload| {
-                    push_to_puthost(&mut sender, payload)
-                }) => {
-                    debug!("device shuting down...");
-                }
+    // Values that can't be stored are dropped and counted per reason
+    let dropped = DroppedValues::new();
+    let cloned_token = token.clone();
+    let cloned_dropped = dropped.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
             }
-        });
+            _ = report_dropped_values(cloned_dropped) => {
+                debug!("dropped values report shuting down...");
+            }
+        }
+    });
+
+    let mut consumers = vec![];
+    for tenant in settings.tenants.iter() {
+        consumers.extend(start_tenant(tenant, &settings, &amqp, &dropped, &token).await);
     }
 
     match tokio::signal::ctrl_c().await {
@@ -85,171 +220,412 @@ async fn main() {
             eprintln!("Unable to listen for shutdown signal: {}", err);
         }
     }
+    // Consumers stop, flush and acknowledge what they hold before exiting
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, futures::future::join_all(consumers))
+        .await
+        .is_err()
+    {
+        warn!("consumers did not drain in time, unacknowledged messages will be redelivered");
+    }
     info!("Shutdown complete.");
 }
 
-async fn start_consuming_topic_queue(
-    index: usize,
-    amqp: Amqp,
-    mut callback: impl FnMut(DeviceTelemetryRequest) -> Result<(), Error>,
-) {
-    // TODO: Could implement better TCP Connection and Ch
-    // Get channel and declare topic, queue, binding and consumer
-    let channel = &amqp.get_channel().await.unwrap();
-    channel
-        .basic_qos(RMQ_PREFETCH_COUNT, BasicQosOptions::default())
+// Each tenant has its own surrealdb namespace, caches and queue
+async fn start_tenant(
+    tenant: &str,
+    settings: &Settings,
+    amqp: &Amqp,
+    dropped: &DroppedValues,
+    token: &CancellationToken,
+) -> Vec<JoinHandle<()>> {
+    let db = connect_surrealdb(&settings.surrealdb, tenant)
         .await
         .unwrap();
-    match amqp
-        .declare_exchange_with_channel(
-            channel,
-            RMQ_EXCHANGE_NAME,
-            lapin::ExchangeKind::Topic,
-            ExchangeDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await
-    {
-        Ok(()) => info!("{}: topic exchange <{}> declared", index, RMQ_EXCHANGE_NAME),
-        Err(error) => error!(
-            "{}: can't create topic exchange <{}> {}",
-            index, RMQ_EXCHANGE_NAME, error
-        ),
-    };
-    let queue = match amqp
-        .declare_queue_with_channel(
-            channel,
-            RMQ_QUEUE_NAME,
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await
-    {
-        Ok(queue) => {
-            info!("{}: metrics queue <{}> declared", index, queue.name());
-            queue
+
+    // Device models and sensors declared by devices give a name and unit to sensor ids
+    let models = DeviceModelCache::new();
+    if let Err(error) = load_device_models(&db, &models).await {
+        error!("can't load device models: {}", error);
+    }
+    info!("{}: loaded {} device models", tenant, models.len());
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_models = models.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = refresh_device_models(cloned_db, cloned_models) => {
+                debug!("device models refresh shuting down...");
+            }
         }
-        Err(error) => {
-            error!(
-                "{}: can't create metrics queue <{}> {}",
-                index, RMQ_QUEUE_NAME, error
-            );
-            panic!("{}", error)
+    });
+
+    // Telemetry of disabled devices is dropped
+    let statuses = DeviceStatusCache::new();
+    match get_disabled_device_ids_from_db(&db).await {
+        Ok(x) => statuses.replace_all(x),
+        Err(error) => error!("can't load device statuses: {}", error),
+    }
+    info!("{}: loaded {} disabled devices", tenant, statuses.len());
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_statuses = statuses.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = refresh_device_statuses(cloned_db, cloned_statuses) => {
+                debug!("device statuses refresh shuting down...");
+            }
         }
-    };
+    });
 
-    match amqp
-        .bind_queue_with_channel(
-            channel,
-            queue.name().as_str(),
-            RMQ_EXCHANGE_NAME,
-            "#.telemetry.v1",
-            QueueBindOptions::default(),
-            FieldTable::default(),
-        )
-        .await
-    {
-        Ok(()) => info!(
-            "{}: topic exchange <{}> and metric queue <{}> binded",
-            index,
-            RMQ_EXCHANGE_NAME,
-            queue.name()
-        ),
-        Err(error) => {
-            error!(
-                "{}: can't create binding <{}> <{}> {}",
-                index, RMQ_EXCHANGE_NAME, RMQ_QUEUE_NAME, error
-            );
-            panic!("{}", error)
+    // Device keys are owned by redox, flux only keeps a refreshed copy to verify telemetry
+    let verifier = if settings.verify_signature {
+        let keys = DeviceKeyCache::with_db(db.clone());
+        match get_device_keys_from_db(&db).await {
+            Ok(x) => keys.replace_all(x),
+            Err(error) => error!("can't load device keys: {}", error),
         }
+        info!("{}: loaded {} device keys", tenant, keys.len());
+
+        let cloned_token = token.clone();
+        let cloned_db = db.clone();
+        let cloned_keys = keys.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cloned_token.cancelled() => {
+                    debug!("The token was shutdown")
+                }
+                _ = refresh_device_keys(cloned_db, cloned_keys) => {
+                    debug!("device keys refresh shuting down...");
+                }
+            }
+        });
+        Some(keys)
+    } else {
+        None
+    };
+
+    let stream = StreamContext {
+        tenant: tenant.to_string(),
+        amqp: amqp.clone(),
+        verifier,
+        models,
+        statuses,
+        dropped: dropped.clone(),
     };
+    let mut handler = SensorRegistryHandler {
+        db: db.clone(),
+        models: stream.models.clone(),
+    };
+    let mut consumers = vec![];
+    for i in 0..settings.thread_count {
+        consumers.push(start_stream::<DeviceTelemetryRequest>(
+            i,
+            "telemetry",
+            settings.topic.telemetry.clone(),
+            settings,
+            &stream,
+            token,
+        ));
+        consumers.push(start_stream::<DeviceTelemetryBatchRequest>(
+            i,
+            "telemetry-batch",
+            settings.topic.telemetry_batch.clone(),
+            settings,
+            &stream,
+            token,
+        ));
+    }
+    consumers.push(start_stream::<DeviceLogRequest>(
+        0,
+        "logs",
+        settings.topic.logs.clone(),
+        settings,
+        &stream,
+        token,
+    ));
+    consumers.push(start_stream::<DeviceMetricsRequest>(
+        0,
+        "metrics",
+        settings.topic.metrics.clone(),
+        settings,
+        &stream,
+        token,
+    ));
 
-    let mut consumer = match amqp
-        .create_consumer_with_channel(
-            channel,
-            RMQ_QUEUE_NAME,
-            "",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
+    let cloned_token = token.clone();
+    let cloned_amqp = amqp.clone();
+    let cloned_verifier = stream.verifier.clone();
+    let exchange = settings.topic.exchange.clone();
+    let queue = QueueTopic {
+        queue: tenant_queue(tenant, &settings.topic.sensors.queue),
+        routing_key: tenant_routing_key(tenant, &settings.topic.sensors.routing_key),
+    };
+    consumers.push(tokio::spawn(async move {
+        start_consuming_sensors_queue(
+            cloned_amqp,
+            cloned_verifier,
+            exchange,
+            queue,
+            &mut handler,
+            cloned_token,
         )
-        .await
-    {
-        Ok(consumer) => {
-            info!("{}: consumer <{}> declared", index, consumer.tag());
-            info!(
-                "{}: consumer <{}> to queue <{}> binded",
-                index,
-                consumer.tag(),
-                queue.name()
-            );
-            consumer
-        }
-        Err(error) => {
-            error!(
-                "{}: can't bind consumer and queue <{}> {}",
-                index,
-                queue.name(),
-                error
-            );
-            panic!("{}", error)
-        }
+        .await;
+        debug!("sensors shuting down...");
+    }));
+    consumers
+}
+
+// What the consumers of a tenant share
+struct StreamContext {
+    tenant: String,
+    amqp: Amqp,
+    verifier: Option<DeviceKeyCache>,
+    models: DeviceModelCache,
+    statuses: DeviceStatusCache,
+    dropped: DroppedValues,
+}
+
+fn start_stream<T>(
+    index: usize,
+    kind: &str,
+    queue: QueueTopic,
+    settings: &Settings,
+    stream: &StreamContext,
+    token: &CancellationToken,
+) -> JoinHandle<()>
+where
+    T: IntoRows + DeserializeOwned + std::fmt::Debug + 'static,
+{
+    let cloned_token = token.clone();
+    let cloned_amqp = stream.amqp.clone();
+    let cloned_verifier = stream.verifier.clone();
+    let cloned_statuses = stream.statuses.clone();
+    let context = RowContext {
+        tenant: stream.tenant.clone(),
+        models: stream.models.clone(),
+        dropped: stream.dropped.clone(),
+        sensors: settings.sensors.clone(),
+        metadata: MetadataTracker::new(),
     };
+    let cloned_batch = settings.batch.clone();
+    let exchange = settings.topic.exchange.clone();
+    let queue = QueueTopic {
+        queue: tenant_queue(&stream.tenant, &queue.queue),
+        routing_key: tenant_routing_key(&stream.tenant, &queue.routing_key),
+    };
+    let sink = build_sink(
+        &settings.sinks,
+        &format!("{}-{}-{}", stream.tenant, kind, index),
+    )
+    .unwrap();
 
-    // Consumer liscening to topic queue exchange
-    info!("{}: consumer <{}> is liscening", index, consumer.tag());
-    while let Some(delivery) = consumer.next().await {
-        if let Ok(delivery) = delivery {
-            let payload: Vec<u8> = delivery.data.clone();
-            let uncompressed_message = match delivery
-                .properties
-                .content_encoding()
-                .clone()
-                .unwrap_or_else(|| ShortString::from(""))
-                .as_str()
-            {
-                "br" => Amqp::decompress_message_as_str(payload),
-                _ => Ok(String::from_utf8(payload).unwrap()),
+    // The consumer watches the token itself to drain its batch on shutdown
+    tokio::spawn(async move {
+        let mut batch = tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown");
+                return;
             }
-            .unwrap();
-
-            let device_payload: DeviceTelemetryRequest =
-                serde_json::from_str(&uncompressed_message).unwrap();
-            debug!("{}: {:?}", index, device_payload);
-            callback(device_payload).unwrap();
-            match channel
-                .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                .await
-            {
-                Ok(()) => trace!(
-                    "{}: acknowledged message <{}>",
-                    index,
-                    delivery.delivery_tag
-                ),
-                Err(error) => error!(
-                    "{}: can't acknowledge message <{}> {}",
-                    index, delivery.delivery_tag, error
-                ),
-            };
+            batch = TelemetryBatch::connect(index, context, cloned_statuses, sink, cloned_batch) => batch,
         };
+        start_consuming_topic_queue::<T>(
+            index,
+            cloned_amqp,
+            cloned_verifier,
+            exchange,
+            queue,
+            &mut batch,
+            cloned_token,
+        )
+        .await;
+        debug!("device shuting down...");
+    })
+}
+
+async fn connect_surrealdb(settings: &SurrealDb, tenant: &str) -> Result<Surreal<Client>, Error> {
+    let db = Surreal::new::<Ws>(settings.addr.as_str()).await?;
+    db.signin(Root {
+        username: &settings.user,
+        password: &settings.password,
+    })
+    .await?;
+    db.use_ns(tenant).use_db(SURREAL_DB_NAME).await?;
+    info!("{}: connected to SurrealDb", tenant);
+    Ok(db)
+}
+
+async fn get_device_keys_from_db(db: &Surreal<Client>) -> Result<Vec<DeviceKey>, Error> {
+    let keys: Vec<DeviceKey> = db.select("device_key").await?;
+    Ok(keys)
+}
+
+async fn refresh_device_keys(db: Surreal<Client>, keys: DeviceKeyCache) {
+    let mut interval = tokio::time::interval(DEVICE_KEY_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match get_device_keys_from_db(&db).await {
+            Ok(x) => keys.replace_all(x),
+            Err(error) => error!("can't refresh device keys: {}", error),
+        }
     }
-    debug!("{}: Shutting down...", index);
-}
-
-fn push_to_puthost(sender: &mut Sender, payload: DeviceTelemetryRequest) -> Result<(), Error> {
-    let mut buffer = Buffer::new();
-    let timestamp = payload.timestamp;
-    let device_id = payload.device_id;
-    for sensor in payload.telemetry.floats {
-        let sensor_id = sensor.0;
-        let value = sensor.1;
-        buffer
-            .table("Datapoint")?
-            .column_str("device_id", device_id.to_string())?
-            .column_i64("sensor_id", sensor_id)?
-            .column_f64("value", value)?
-            .at(TimestampNanos::new(timestamp).unwrap())?;
-    }
-    sender.flush(&mut buffer)?;
+}
 
+async fn load_device_models(db: &Surreal<Client>, models: &DeviceModelCache) -> Result<(), Error> {
+    let device_models: Vec<DeviceModel> = db.select("device_model").await?;
+    let mut results = db
+        .query("SELECT meta_properties.device_id AS device_id, meta_properties.model_id AS model_id FROM device_twin")
+        .await?;
+    let assignments: Vec<DeviceModelAssignment> = results.take(0)?;
+    let declared = get_declared_sensors_from_db(db).await?;
+
+    models.replace_models(device_models);
+    models.replace_devices(
+        assignments
+            .into_iter()
+            .map(|x| (x.device_id, x.model_id))
+            .collect(),
+    );
+    models.replace_declared(
+        declared
+            .into_iter()
+            .map(|x| (x.device_id, x.sensors))
+            .collect(),
+    );
     Ok(())
 }
+
+async fn refresh_device_models(db: Surreal<Client>, models: DeviceModelCache) {
+    let mut interval = tokio::time::interval(DEVICE_MODEL_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(error) = load_device_models(&db, &models).await {
+            error!("can't refresh device models: {}", error);
+        }
+    }
+}
+
+async fn get_disabled_device_ids_from_db(db: &Surreal<Client>) -> Result<Vec<String>, Error> {
+    let mut results = db
+        .query("SELECT VALUE meta_properties.device_id FROM device_twin WHERE meta_properties.status != 'Enabled'")
+        .await?;
+    let device_ids: Vec<String> = results.take(0)?;
+    Ok(device_ids)
+}
+
+async fn refresh_device_statuses(db: Surreal<Client>, statuses: DeviceStatusCache) {
+    let mut interval = tokio::time::interval(DEVICE_STATUS_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match get_disabled_device_ids_from_db(&db).await {
+            Ok(x) => statuses.replace_all(x),
+            Err(error) => error!("can't refresh device statuses: {}", error),
+        }
+    }
+}
+
+async fn report_dropped_values(dropped: DroppedValues) {
+    let mut interval = tokio::time::interval(DROPPED_VALUES_REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if dropped.total() == 0 {
+            continue;
+        }
+        let counts: Vec<String> = DropReason::ALL
+            .iter()
+            .map(|x| format!("{}={}", x.as_str(), dropped.get(*x)))
+            .collect();
+        warn!(
+            "dropped {} telemetry values so far ({})",
+            dropped.total(),
+            counts.join(", ")
+        );
+    }
+}
+
+async fn start_consuming_topic_queue<T>(
+    index: usize,
+    amqp: Amqp,
+    verifier: Option<DeviceKeyCache>,
+    exchange: String,
+    topic: QueueTopic,
+    batch: &mut TelemetryBatch,
+    token: CancellationToken,
+) where
+    T: IntoRows + DeserializeOwned + std::fmt::Debug + 'static,
+{
+    let settings = amqp_settings(&exchange, &topic, batch.prefetch_count(), verifier);
+    debug!("{}: Starting {}...", index, topic.queue);
+    amqp.consume_topic_queue_in_batches::<T, _>(
+        index,
+        settings,
+        SerializationKind::Json,
+        batch,
+        token,
+    )
+    .await;
+    debug!("{}: Shutting down {}...", index, topic.queue);
+}
+
+// Declarations are rare, a single consumer per tenant stores them one at a time
+async fn start_consuming_sensors_queue(
+    amqp: Amqp,
+    verifier: Option<DeviceKeyCache>,
+    exchange: String,
+    topic: QueueTopic,
+    handler: &mut SensorRegistryHandler,
+    token: CancellationToken,
+) {
+    let settings = amqp_settings(&exchange, &topic, 1, verifier);
+    debug!("Starting {}...", topic.queue);
+    amqp.consume_topic_queue_with_handler::<DeviceSensorsRequest, _>(
+        0,
+        settings,
+        SerializationKind::Json,
+        handler,
+        token,
+    )
+    .await;
+    debug!("Shutting down {}...", topic.queue);
+}
+
+fn amqp_settings<'a>(
+    exchange: &'a str,
+    topic: &'a QueueTopic,
+    prefetch_count: u16,
+    verifier: Option<DeviceKeyCache>,
+) -> AmqpSettings<'a> {
+    AmqpSettings {
+        channel: ChannelSettings {
+            prefetch_count,
+            options: BasicQosOptions::default(),
+        },
+        exchange: ExchangeSettings {
+            name: exchange,
+            kind: ExchangeKind::Topic,
+            options: ExchangeDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue: QueueSettings {
+            name: topic.queue.as_str(),
+            options: QueueDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue_bind: QueueBindSettings {
+            routing_key: topic.routing_key.as_str(),
+            options: QueueBindOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        consumer: ConsumerSettings {
+            consumer_tag: "",
+            options: BasicConsumeOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        verifier,
+    }
+}
@@ -0,0 +1,29 @@
+use libs::models::telemetry::DeviceMetricsRequest;
+
+use crate::batch::{IntoRows, RowContext};
+use crate::row::{Row, TypedValue};
+
+const METRICS_TABLE: &str = "DeviceMetrics";
+
+impl IntoRows for DeviceMetricsRequest {
+    fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    fn into_rows(self, context: &RowContext) -> Vec<Row> {
+        let metrics = self.metrics;
+        vec![Row::new(METRICS_TABLE, self.timestamp)
+            .symbol("tenant", context.tenant.as_str())
+            .key("device_id", TypedValue::String(self.device_id))
+            .field("cpu_usage", Some(TypedValue::Float(metrics.cpu_usage)))
+            .field("memory_used", Some(int(metrics.memory_used)))
+            .field("memory_total", Some(int(metrics.memory_total)))
+            .field("uptime", Some(int(metrics.uptime)))
+            .field("queue_depth", Some(int(metrics.queue_depth)))]
+    }
+}
+
+// Sinks store signed integers
+fn int(value: u64) -> TypedValue {
+    TypedValue::Int(i64::try_from(value).unwrap_or(i64::MAX))
+}
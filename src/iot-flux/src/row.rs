@@ -0,0 +1,96 @@
+use libs::models::device_model::SensorKind;
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedValue {
+    Float(f64),
+    Int(i64),
+    Bool(bool),
+    String(String),
+}
+
+impl TypedValue {
+    pub fn kind(&self) -> SensorKind {
+        match self {
+            TypedValue::Float(_) => SensorKind::Float,
+            TypedValue::Int(_) => SensorKind::Int,
+            TypedValue::Bool(_) => SensorKind::Bool,
+            TypedValue::String(_) => SensorKind::String,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            TypedValue::Float(x) => json!(x),
+            TypedValue::Int(x) => json!(x),
+            TypedValue::Bool(x) => json!(x),
+            TypedValue::String(x) => json!(x),
+        }
+    }
+}
+
+// One record ready to be stored, the unit written by every sink. Rows
+// of a table always have the same columns in the same order.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub table: &'static str,
+    // Low cardinality strings, questdb symbols and influx tags
+    pub symbols: Vec<(&'static str, String)>,
+    // Identify the series, typed columns in questdb and influx tags
+    pub keys: Vec<(&'static str, TypedValue)>,
+    // Influx fields, a missing value is skipped or left empty
+    pub fields: Vec<(&'static str, Option<TypedValue>)>,
+    // Nanoseconds since epoch
+    pub timestamp: i64,
+}
+
+impl Row {
+    pub fn new(table: &'static str, timestamp: i64) -> Self {
+        Self {
+            table,
+            symbols: vec![],
+            keys: vec![],
+            fields: vec![],
+            timestamp,
+        }
+    }
+
+    pub fn symbol(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.symbols.push((name, value.into()));
+        self
+    }
+
+    pub fn key(mut self, name: &'static str, value: TypedValue) -> Self {
+        self.keys.push((name, value));
+        self
+    }
+
+    pub fn field(mut self, name: &'static str, value: Option<TypedValue>) -> Self {
+        self.fields.push((name, value));
+        self
+    }
+
+    // Every column but the timestamp, with whether it can be missing
+    pub fn columns(&self) -> Vec<(&'static str, Option<TypedValue>, bool)> {
+        self.symbols
+            .iter()
+            .map(|(name, x)| (*name, Some(TypedValue::String(x.clone())), false))
+            .chain(
+                self.keys
+                    .iter()
+                    .map(|(name, x)| (*name, Some(x.clone()), false)),
+            )
+            .chain(self.fields.iter().map(|(name, x)| (*name, x.clone(), true)))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        object.insert("table".to_string(), json!(self.table));
+        object.insert("timestamp".to_string(), json!(self.timestamp));
+        for (name, value, _) in self.columns() {
+            object.insert(name.to_string(), value.map_or(Value::Null, |x| x.to_json()));
+        }
+        Value::Object(object)
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use libs::clients::amqp::MessageHandler;
+use libs::models::device_model::{DeviceModelCache, SensorDefinition};
+use libs::models::telemetry::DeviceSensorsRequest;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::row::{Row, TypedValue};
+use crate::Error;
+
+const DEVICE_TABLE: &str = "Device";
+const SENSOR_TABLE: &str = "Sensor";
+const DECLARED_SENSORS_TABLE: &str = "device_sensors";
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SensorSettings {
+    // Write the Device and Sensor tables when the sensors of a device change
+    pub metadata_tables: bool,
+    // How sensor_name and unit are copied on every datapoint
+    pub denormalize: SensorColumns,
+}
+
+impl Default for SensorSettings {
+    fn default() -> Self {
+        Self {
+            metadata_tables: true,
+            denormalize: SensorColumns::Field,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorColumns {
+    // Only in the Sensor table
+    None,
+    // Strings, left empty for unknown sensors
+    Field,
+    // Questdb symbols and influx tags, empty for unknown sensors
+    Symbol,
+}
+
+// Sensors a device declared, kept so they survive a restart of flux
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeclaredSensors {
+    pub device_id: String,
+    pub sensors: Vec<SensorDefinition>,
+    pub update_time: i64,
+}
+
+// Stores the sensors declared by devices on join_fleet and shares them
+// with the telemetry consumers
+pub struct SensorRegistryHandler {
+    pub db: Surreal<Client>,
+    pub models: DeviceModelCache,
+}
+
+#[async_trait]
+impl MessageHandler<DeviceSensorsRequest> for SensorRegistryHandler {
+    type Error = Error;
+
+    async fn handle(&mut self, msg: DeviceSensorsRequest, _: &str) -> Result<(), Error> {
+        debug!("'{}' declared {} sensors", msg.device_id, msg.sensors.len());
+        let declared = DeclaredSensors {
+            device_id: msg.device_id.clone(),
+            sensors: msg.sensors,
+            update_time: Utc::now().timestamp_nanos(),
+        };
+        let _: Option<DeclaredSensors> = self
+            .db
+            .update((DECLARED_SENSORS_TABLE, msg.device_id.as_str()))
+            .content(declared.clone())
+            .await?;
+        self.models.declare(&declared.device_id, declared.sensors);
+        Ok(())
+    }
+}
+
+pub async fn get_declared_sensors_from_db(
+    db: &Surreal<Client>,
+) -> Result<Vec<DeclaredSensors>, Error> {
+    let declared: Vec<DeclaredSensors> = db.select(DECLARED_SENSORS_TABLE).await?;
+    Ok(declared)
+}
+
+// Devices whose metadata rows were written by a consumer, with a hash of
+// their model and sensors to write them again when they change
+#[derive(Debug, Default)]
+pub struct MetadataTracker {
+    written: Mutex<HashMap<String, u64>>,
+}
+
+impl MetadataTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The rows were not stored, they are written again with the next telemetry
+    pub fn clear(&self) {
+        self.written.lock().unwrap().clear();
+    }
+
+    // Device and Sensor rows of a device, empty when already written. Both
+    // tables only grow, readers take the latest row with LATEST ON.
+    pub fn rows(
+        &self,
+        tenant: &str,
+        device_id: &str,
+        models: &DeviceModelCache,
+        timestamp: i64,
+    ) -> Vec<Row> {
+        let model_id = models.model_id(device_id);
+        let sensors = models.sensors(device_id);
+
+        let mut hasher = DefaultHasher::new();
+        model_id.hash(&mut hasher);
+        format!("{:?}", sensors).hash(&mut hasher);
+        let hash = hasher.finish();
+        if self
+            .written
+            .lock()
+            .unwrap()
+            .insert(device_id.to_string(), hash)
+            == Some(hash)
+        {
+            return vec![];
+        }
+
+        let device = Row::new(DEVICE_TABLE, timestamp)
+            .symbol("tenant", tenant)
+            .key("device_id", TypedValue::String(device_id.to_string()))
+            .field("model_id", model_id.map(TypedValue::String));
+        std::iter::once(device)
+            .chain(sensors.into_iter().map(|(sensor, source)| {
+                Row::new(SENSOR_TABLE, timestamp)
+                    .symbol("tenant", tenant)
+                    .key("device_id", TypedValue::String(device_id.to_string()))
+                    .key("sensor_id", TypedValue::Int(sensor.id))
+                    .field("sensor_name", Some(TypedValue::String(sensor.name)))
+                    .field(
+                        "sensor_type",
+                        Some(TypedValue::String(sensor.kind.as_str().to_string())),
+                    )
+                    .field("sensor_unit", Some(TypedValue::String(sensor.unit)))
+                    .field(
+                        "sensor_unit_multiplier",
+                        Some(TypedValue::Float(sensor.multiplier)),
+                    )
+                    .field(
+                        "sensor_description",
+                        Some(TypedValue::String(sensor.description)),
+                    )
+                    .field(
+                        "source",
+                        Some(TypedValue::String(source.as_str().to_string())),
+                    )
+            }))
+            .collect()
+    }
+}
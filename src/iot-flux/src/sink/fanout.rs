@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+
+use crate::row::Row;
+use crate::sink::TelemetrySink;
+use crate::Error;
+
+// Flushes of a failing sink are retried on a new connection before its
+// rows are dropped, a flush only fails when no sink stored the batch.
+// Requeueing a batch never stores rows twice.
+const FLUSH_ATTEMPTS: u32 = 3;
+const FLUSH_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+// Every sink gets every row
+pub struct FanOutSink {
+    sinks: Vec<Box<dyn TelemetrySink>>,
+}
+
+impl FanOutSink {
+    pub fn new(sinks: Vec<Box<dyn TelemetrySink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for FanOutSink {
+    fn name(&self) -> String {
+        let names: Vec<String> = self.sinks.iter().map(|x| x.name()).collect();
+        format!("fanout [{}]", names.join(", "))
+    }
+
+    async fn connect(&mut self) -> Result<(), Error> {
+        for sink in self.sinks.iter_mut() {
+            sink.connect().await?;
+        }
+        Ok(())
+    }
+
+    // Rows refused by a sink are still stored by the sinks before it
+    fn write(&mut self, rows: &[Row]) -> Result<(), Error> {
+        for sink in self.sinks.iter_mut() {
+            sink.write(rows)?;
+        }
+        Ok(())
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.sinks
+            .iter()
+            .map(|x| x.pending_bytes())
+            .max()
+            .unwrap_or(0)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        let mut stored = false;
+        let mut last_error = None;
+        for sink in self.sinks.iter_mut() {
+            match flush_with_retry(sink.as_mut()).await {
+                Ok(()) => stored = true,
+                Err(error) => {
+                    warn!("dropped the batch for {} {}", sink.name(), error);
+                    sink.clear();
+                    last_error = Some(error);
+                }
+            }
+        }
+        match last_error {
+            Some(error) if !stored => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    fn clear(&mut self) {
+        for sink in self.sinks.iter_mut() {
+            sink.clear();
+        }
+    }
+}
+
+// The rows stay in the sink until it flushed them or got cleared
+async fn flush_with_retry(sink: &mut dyn TelemetrySink) -> Result<(), Error> {
+    let mut backoff = FLUSH_RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let error = match sink.flush().await {
+            Ok(()) => return Ok(()),
+            Err(error) if attempt >= FLUSH_ATTEMPTS => return Err(error),
+            Err(error) => error,
+        };
+        warn!(
+            "can't flush {}, retrying in {:?} {}",
+            sink.name(),
+            backoff,
+            error
+        );
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+        if let Err(error) = sink.connect().await {
+            warn!("can't connect to {} {}", sink.name(), error);
+        }
+    }
+}
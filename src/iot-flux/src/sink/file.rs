@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+    TimestampNanosecondArray,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::error;
+use parquet::arrow::ArrowWriter;
+
+use crate::row::{Row, TypedValue};
+use crate::sink::influx::push_line;
+use crate::sink::{FileFormat, TelemetrySink};
+use crate::Error;
+
+const ROTATION_FORMAT: &str = "%Y%m%d%H";
+
+// Files are named <table>-<hour>-<instance>, a new set starts every hour.
+// Parquet files can't be appended to, each flush writes a complete part
+// numbered within the hour, a longer batch linger gives fewer parts.
+pub struct FileSink {
+    dir: PathBuf,
+    format: FileFormat,
+    instance: String,
+    rows: Vec<Row>,
+    bytes: usize,
+    // Hour and next parquet part of each table
+    parts: HashMap<&'static str, (String, usize)>,
+}
+
+// What a flush wrote to a table file, to undo it when another table fails
+enum Written {
+    // Length of the file before the rows were appended
+    Appended(PathBuf, u64),
+    // Temporary file renamed to the part once every table is written
+    Staged(PathBuf, PathBuf),
+}
+
+impl FileSink {
+    pub fn new(dir: PathBuf, format: FileFormat, instance: String) -> Self {
+        Self {
+            dir,
+            format,
+            instance,
+            rows: vec![],
+            bytes: 0,
+            parts: HashMap::new(),
+        }
+    }
+
+    fn path(&self, table: &str, hour: &str, suffix: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}-{}-{}{}.{}",
+            table,
+            hour,
+            self.instance,
+            suffix,
+            self.format.extension()
+        ))
+    }
+
+    fn append_text(&self, table: &str, hour: &str, rows: &[&Row]) -> Result<Written, Error> {
+        let path = self.path(table, hour, "");
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut text = String::new();
+        match self.format {
+            FileFormat::Csv => {
+                if len == 0 {
+                    if let Some(row) = rows.first() {
+                        push_csv_header(&mut text, row);
+                    }
+                }
+                for row in rows {
+                    push_csv_line(&mut text, row);
+                }
+            }
+            _ => {
+                for row in rows {
+                    push_line(&mut text, row);
+                }
+            }
+        }
+        let written = Written::Appended(path, len);
+        if let Err(error) = file.write_all(text.as_bytes()) {
+            rollback(&[written]);
+            return Err(error.into());
+        }
+        Ok(written)
+    }
+
+    // The folder is only looked at for the first part of an hour, parts left
+    // by a previous run must not be overwritten
+    fn next_part(&mut self, table: &'static str, hour: &str) -> PathBuf {
+        let next = match self.parts.get(table) {
+            Some((part_hour, next)) if part_hour == hour => *next,
+            _ => {
+                let mut next = 0;
+                while self.path(table, hour, &part_suffix(next)).exists() {
+                    next += 1;
+                }
+                next
+            }
+        };
+        self.parts.insert(table, (hour.to_string(), next + 1));
+        self.path(table, hour, &part_suffix(next))
+    }
+
+    fn stage_parquet(&self, path: PathBuf, rows: &[&Row]) -> Result<Written, Error> {
+        let batch = record_batch(rows)?;
+        let staged = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        let written = Written::Staged(staged.clone(), path);
+
+        // The footer is only written on close, the part is readable once renamed
+        let result = File::create(&staged)
+            .map_err(Error::from)
+            .and_then(|file| Ok(ArrowWriter::try_new(file, batch.schema(), None)?))
+            .and_then(|mut writer| {
+                writer.write(&batch)?;
+                writer.close()?;
+                Ok(())
+            });
+        if let Err(error) = result {
+            rollback(&[written]);
+            return Err(error);
+        }
+        Ok(written)
+    }
+
+    // Either every table is written or none, a failed flush is replayed
+    fn write_files(&mut self) -> Result<(), Error> {
+        let hour = Utc::now().format(ROTATION_FORMAT).to_string();
+        let mut parts = HashMap::new();
+        if self.format == FileFormat::Parquet {
+            let names: BTreeSet<&'static str> = self.rows.iter().map(|x| x.table).collect();
+            for table in names {
+                parts.insert(table, self.next_part(table, &hour));
+            }
+        }
+        let mut tables: BTreeMap<&'static str, Vec<&Row>> = BTreeMap::new();
+        for row in self.rows.iter() {
+            tables.entry(row.table).or_default().push(row);
+        }
+
+        let mut written = vec![];
+        for (table, rows) in tables {
+            let result = match self.format {
+                FileFormat::Parquet => self.stage_parquet(parts.remove(table).unwrap(), &rows),
+                _ => self.append_text(table, &hour, &rows),
+            };
+            match result {
+                Ok(x) => written.push(x),
+                Err(error) => {
+                    rollback(&written);
+                    return Err(error);
+                }
+            }
+        }
+        for x in written.iter() {
+            if let Written::Staged(staged, path) = x {
+                if let Err(error) = std::fs::rename(staged, path) {
+                    rollback(&written);
+                    return Err(error.into());
+                }
+            }
+        }
+
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for FileSink {
+    fn name(&self) -> String {
+        format!(
+            "{} files in {}",
+            self.format.extension(),
+            self.dir.to_string_lossy()
+        )
+    }
+
+    async fn connect(&mut self) -> Result<(), Error> {
+        std::fs::create_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    fn write(&mut self, rows: &[Row]) -> Result<(), Error> {
+        for row in rows {
+            self.bytes += std::mem::size_of::<Row>()
+                + row
+                    .columns()
+                    .iter()
+                    .map(|(_, value, _)| match value {
+                        Some(TypedValue::String(x)) => x.len(),
+                        _ => std::mem::size_of::<TypedValue>(),
+                    })
+                    .sum::<usize>();
+            self.rows.push(row.clone());
+        }
+        Ok(())
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        tokio::task::block_in_place(|| self.write_files())?;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.rows.clear();
+        self.bytes = 0;
+    }
+}
+
+fn part_suffix(part: usize) -> String {
+    match part {
+        0 => String::new(),
+        x => format!("-{}", x),
+    }
+}
+
+fn rollback(written: &[Written]) {
+    for x in written {
+        let result = match x {
+            Written::Appended(path, len) => OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_len(*len)),
+            // Already renamed when the temporary file is gone
+            Written::Staged(staged, _) if staged.exists() => std::fs::remove_file(staged),
+            Written::Staged(_, path) => std::fs::remove_file(path),
+        };
+        if let Err(error) = result {
+            error!("can't roll back a failed flush {}", error);
+        }
+    }
+}
+
+fn push_csv_header(text: &mut String, row: &Row) {
+    text.push_str("timestamp");
+    for (name, _, _) in row.columns() {
+        text.push(',');
+        text.push_str(name);
+    }
+    text.push('\n');
+}
+
+// A missing value is left empty
+fn push_csv_line(text: &mut String, row: &Row) {
+    text.push_str(&row.timestamp.to_string());
+    for (_, value, _) in row.columns() {
+        text.push(',');
+        match value {
+            Some(TypedValue::Float(x)) => text.push_str(&x.to_string()),
+            Some(TypedValue::Int(x)) => text.push_str(&x.to_string()),
+            Some(TypedValue::Bool(x)) => text.push_str(&x.to_string()),
+            Some(TypedValue::String(x)) => text.push_str(&escape_csv(&x)),
+            None => {}
+        }
+    }
+    text.push('\n');
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// All rows of a table share their columns, the type of a column is the
+// one of its first value
+fn record_batch(rows: &[&Row]) -> Result<RecordBatch, Error> {
+    let row_columns: Vec<_> = rows.iter().map(|x| x.columns()).collect();
+    let mut columns: Vec<(&str, ArrayRef, bool)> = vec![(
+        "timestamp",
+        Arc::new(
+            TimestampNanosecondArray::from_iter_values(rows.iter().map(|x| x.timestamp))
+                .with_timezone("UTC"),
+        ),
+        false,
+    )];
+    let Some(first) = row_columns.first() else {
+        return Ok(RecordBatch::try_from_iter_with_nullable(columns)?);
+    };
+
+    for (index, (name, _, nullable)) in first.iter().enumerate() {
+        let values: Vec<Option<&TypedValue>> = row_columns
+            .iter()
+            .map(|x| x.get(index).and_then(|(_, value, _)| value.as_ref()))
+            .collect();
+        let array: ArrayRef = match values.iter().flatten().next() {
+            Some(TypedValue::Float(_)) => {
+                Arc::new(Float64Array::from_iter(values.iter().map(|x| match x {
+                    Some(TypedValue::Float(v)) => Some(*v),
+                    _ => None,
+                })))
+            }
+            Some(TypedValue::Int(_)) => {
+                Arc::new(Int64Array::from_iter(values.iter().map(|x| match x {
+                    Some(TypedValue::Int(v)) => Some(*v),
+                    _ => None,
+                })))
+            }
+            Some(TypedValue::Bool(_)) => {
+                Arc::new(BooleanArray::from_iter(values.iter().map(|x| match x {
+                    Some(TypedValue::Bool(v)) => Some(*v),
+                    _ => None,
+                })))
+            }
+            _ => Arc::new(StringArray::from_iter(values.iter().map(|x| match x {
+                Some(TypedValue::String(v)) => Some(v.as_str()),
+                _ => None,
+            }))),
+        };
+        columns.push((name, array, *nullable));
+    }
+    Ok(RecordBatch::try_from_iter_with_nullable(columns)?)
+}
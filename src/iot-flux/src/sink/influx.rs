@@ -0,0 +1,142 @@
+use std::fmt::Write;
+
+use async_trait::async_trait;
+use reqwest::Client;
+
+use crate::row::{Row, TypedValue};
+use crate::sink::TelemetrySink;
+use crate::Error;
+
+pub struct InfluxSink {
+    url: String,
+    org: String,
+    bucket: String,
+    token: String,
+    client: Client,
+    lines: String,
+}
+
+impl InfluxSink {
+    pub fn new(url: String, org: String, bucket: String, token: String) -> Self {
+        Self {
+            url,
+            org,
+            bucket,
+            token,
+            client: Client::new(),
+            lines: String::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for InfluxSink {
+    fn name(&self) -> String {
+        format!("influx {}", self.url)
+    }
+
+    async fn connect(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, rows: &[Row]) -> Result<(), Error> {
+        for row in rows {
+            push_line(&mut self.lines, row);
+        }
+        Ok(())
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.lines.len()
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let mut request = self
+            .client
+            .post(format!("{}/api/v2/write", self.url.trim_end_matches('/')))
+            .query(&[
+                ("org", self.org.as_str()),
+                ("bucket", self.bucket.as_str()),
+                ("precision", "ns"),
+            ])
+            .body(self.lines.clone());
+        if !self.token.is_empty() {
+            request = request.header("Authorization", format!("Token {}", self.token));
+        }
+        request.send().await?.error_for_status()?;
+        self.lines.clear();
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+    }
+}
+
+// Influx line protocol, symbols and keys are tags, the rest fields
+pub fn push_line(lines: &mut String, row: &Row) {
+    let fields: Vec<String> = row
+        .fields
+        .iter()
+        .filter_map(|(name, value)| {
+            value
+                .as_ref()
+                .map(|x| format!("{}={}", escape(name, &[',', '=', ' ']), field_value(x)))
+        })
+        .collect();
+    // A line needs at least one field
+    if fields.is_empty() {
+        return;
+    }
+
+    lines.push_str(&escape(row.table, &[',', ' ']));
+    let tags = row
+        .symbols
+        .iter()
+        .map(|(name, x)| (*name, x.clone()))
+        .chain(row.keys.iter().map(|(name, x)| (*name, tag_value(x))));
+    for (name, value) in tags {
+        // Empty tag values are not valid line protocol
+        if !value.is_empty() {
+            let _ = write!(
+                lines,
+                ",{}={}",
+                escape(name, &[',', '=', ' ']),
+                escape(&value, &[',', '=', ' '])
+            );
+        }
+    }
+    let _ = writeln!(lines, " {} {}", fields.join(","), row.timestamp);
+}
+
+fn tag_value(value: &TypedValue) -> String {
+    match value {
+        TypedValue::Float(x) => x.to_string(),
+        TypedValue::Int(x) => x.to_string(),
+        TypedValue::Bool(x) => x.to_string(),
+        TypedValue::String(x) => x.clone(),
+    }
+}
+
+fn field_value(value: &TypedValue) -> String {
+    match value {
+        TypedValue::Float(x) => format!("{:?}", x),
+        TypedValue::Int(x) => format!("{}i", x),
+        TypedValue::Bool(x) => x.to_string(),
+        TypedValue::String(x) => format!("\"{}\"", escape(x, &['"', '\\'])),
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
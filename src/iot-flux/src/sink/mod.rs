@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::row::Row;
+use crate::Error;
+
+use self::fanout::FanOutSink;
+use self::file::FileSink;
+use self::influx::InfluxSink;
+use self::questdb::QuestDbSink;
+use self::stdout::StdoutSink;
+
+mod fanout;
+mod file;
+mod influx;
+mod questdb;
+mod stdout;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkSettings {
+    // QuestDB over the influx line protocol on tcp
+    QuestDb {
+        addr: String,
+    },
+    // Local files rotated every hour, one per table and consumer. Parquet
+    // gets a part per flush as its files can't be appended to.
+    File {
+        dir: PathBuf,
+        format: FileFormat,
+    },
+    // InfluxDB v2 write api
+    Influx {
+        url: String,
+        org: String,
+        bucket: String,
+        #[serde(default)]
+        token: String,
+    },
+    // JSON lines on stdout, for debugging
+    Stdout,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Csv,
+    Parquet,
+    Influx,
+}
+
+impl FileFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Parquet => "parquet",
+            FileFormat::Influx => "lp",
+        }
+    }
+}
+
+// Rows are written between flushes and must only be considered
+// stored once flush returned successfully
+#[async_trait]
+pub trait TelemetrySink: Send {
+    fn name(&self) -> String;
+
+    // Open the connection or files, called before the first write and after a failed flush
+    async fn connect(&mut self) -> Result<(), Error>;
+
+    // Add the rows of one message to the next flush, either all of them or none
+    fn write(&mut self, rows: &[Row]) -> Result<(), Error>;
+
+    // Size of what is waiting for the next flush
+    fn pending_bytes(&self) -> usize;
+
+    async fn flush(&mut self) -> Result<(), Error>;
+
+    // Forget what is waiting for the next flush
+    fn clear(&mut self);
+}
+
+// Several sinks are fanned out, instance tells consumers apart in file names
+pub fn build_sink(
+    settings: &[SinkSettings],
+    instance: &str,
+) -> Result<Box<dyn TelemetrySink>, Error> {
+    let mut sinks = settings
+        .iter()
+        .map(|x| build_one_sink(x, instance))
+        .collect::<Result<Vec<_>, _>>()?;
+    match sinks.len() {
+        0 => Err(Error::InvalidSettings("no sink configured".to_string())),
+        1 => Ok(sinks.remove(0)),
+        _ => Ok(Box::new(FanOutSink::new(sinks))),
+    }
+}
+
+fn build_one_sink(
+    settings: &SinkSettings,
+    instance: &str,
+) -> Result<Box<dyn TelemetrySink>, Error> {
+    Ok(match settings {
+        SinkSettings::QuestDb { addr } => Box::new(QuestDbSink::new(addr)?),
+        SinkSettings::File { dir, format } => {
+            Box::new(FileSink::new(dir.clone(), *format, instance.to_string()))
+        }
+        SinkSettings::Influx {
+            url,
+            org,
+            bucket,
+            token,
+        } => Box::new(InfluxSink::new(
+            url.clone(),
+            org.clone(),
+            bucket.clone(),
+            token.clone(),
+        )),
+        SinkSettings::Stdout => Box::new(StdoutSink::new()),
+    })
+}
@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+use libs::utils::network;
+use questdb::ingress::{Buffer, Sender, SenderBuilder, TimestampNanos};
+
+use crate::row::{Row, TypedValue};
+use crate::sink::TelemetrySink;
+use crate::Error;
+
+pub struct QuestDbSink {
+    host_port: (String, u16),
+    sender: Option<Sender>,
+    buffer: Buffer,
+}
+
+impl QuestDbSink {
+    pub fn new(addr: &str) -> Result<Self, Error> {
+        Ok(Self {
+            host_port: network::parse_host_port(addr)?,
+            sender: None,
+            buffer: Buffer::new(),
+        })
+    }
+
+    fn write_rows(&mut self, rows: &[Row]) -> Result<(), Error> {
+        for row in rows {
+            self.buffer.table(row.table)?;
+            for (name, value) in row.symbols.iter() {
+                self.buffer.symbol(*name, value.as_str())?;
+            }
+            for (name, value) in row.keys.iter() {
+                self.column(name, value)?;
+            }
+            // A missing field is left null
+            for (name, value) in row.fields.iter() {
+                if let Some(value) = value {
+                    self.column(name, value)?;
+                }
+            }
+            self.buffer.at(TimestampNanos::new(row.timestamp)?)?;
+        }
+        Ok(())
+    }
+
+    fn column(&mut self, name: &str, value: &TypedValue) -> Result<(), Error> {
+        match value {
+            TypedValue::Float(x) => self.buffer.column_f64(name, *x)?,
+            TypedValue::Int(x) => self.buffer.column_i64(name, *x)?,
+            TypedValue::Bool(x) => self.buffer.column_bool(name, *x)?,
+            TypedValue::String(x) => self.buffer.column_str(name, x.as_str())?,
+        };
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for QuestDbSink {
+    fn name(&self) -> String {
+        format!("questdb {}:{}", self.host_port.0, self.host_port.1)
+    }
+
+    async fn connect(&mut self) -> Result<(), Error> {
+        self.sender = None;
+        let sender = tokio::task::block_in_place(|| {
+            SenderBuilder::new(self.host_port.0.clone(), self.host_port.1).connect()
+        })?;
+        self.sender = Some(sender);
+        Ok(())
+    }
+
+    fn write(&mut self, rows: &[Row]) -> Result<(), Error> {
+        self.buffer.set_marker()?;
+        if let Err(error) = self.write_rows(rows) {
+            self.buffer.rewind_to_marker()?;
+            return Err(error);
+        }
+        self.buffer.clear_marker();
+        Ok(())
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        let Some(sender) = self.sender.as_mut() else {
+            return Err(Error::NotConnected(self.name()));
+        };
+        tokio::task::block_in_place(|| sender.flush(&mut self.buffer))?;
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.buffer.clear();
+    }
+}
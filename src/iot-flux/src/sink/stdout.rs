@@ -0,0 +1,56 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+
+use crate::row::Row;
+use crate::sink::TelemetrySink;
+use crate::Error;
+
+pub struct StdoutSink {
+    lines: String,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        Self {
+            lines: String::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for StdoutSink {
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+
+    async fn connect(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, rows: &[Row]) -> Result<(), Error> {
+        let mut lines = String::new();
+        for row in rows {
+            lines.push_str(&serde_json::to_string(&row.to_json())?);
+            lines.push('\n');
+        }
+        self.lines.push_str(&lines);
+        Ok(())
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.lines.len()
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(self.lines.as_bytes())?;
+        stdout.flush()?;
+        self.lines.clear();
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+    }
+}
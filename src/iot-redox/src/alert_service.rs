@@ -0,0 +1,555 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use chrono::Utc;
+use libs::models::alert::{
+    Alert, AlertCondition, AlertEvent, AlertEventKind, AlertRule, AlertSilence, AlertState,
+    NewAlertRuleReq, SilenceAlertReq,
+};
+use libs::models::telemetry::{DeviceTelemetryBatchRequest, DeviceTelemetryRequest};
+use serde::Deserialize;
+use serde_json::Value;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::twin_service::TwinServiceError;
+
+#[derive(Debug, Deserialize)]
+struct DeviceTags {
+    device_id: String,
+    tags: Option<Value>,
+}
+
+#[derive(Debug, Default)]
+struct TrackedAlert {
+    alert: Alert,
+    // Last value seen, rates are computed against it
+    previous: Option<(i64, f64)>,
+}
+
+#[derive(Debug, Default)]
+struct AlertEngineState {
+    // Rules from the config, a stored rule with the same id replaces them
+    config_rules: Vec<AlertRule>,
+    stored_rules: Vec<AlertRule>,
+    tags: HashMap<String, Value>,
+    alerts: HashMap<String, TrackedAlert>,
+}
+
+impl AlertEngineState {
+    fn rules(&self) -> Vec<AlertRule> {
+        self.config_rules
+            .iter()
+            .filter(|x| !self.stored_rules.iter().any(|y| y.rule_id == x.rule_id))
+            .chain(self.stored_rules.iter())
+            .cloned()
+            .collect()
+    }
+
+    // Alerts of rules that no longer exist are forgotten without event
+    fn prune_alerts(&mut self) {
+        let rules = self.rules();
+        self.alerts
+            .retain(|_, x| rules.iter().any(|rule| rule.rule_id == x.alert.rule_id));
+    }
+}
+
+// Rules, device tags and alert states of a tenant, shared by the
+// telemetry consumers and the api
+#[derive(Debug, Clone, Default)]
+pub struct AlertEngine {
+    state: Arc<RwLock<AlertEngineState>>,
+}
+
+impl AlertEngine {
+    pub fn new(config_rules: Vec<AlertRule>) -> Self {
+        Self {
+            state: Arc::new(RwLock::new(AlertEngineState {
+                config_rules,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<AlertRule> {
+        self.state.read().unwrap().rules()
+    }
+
+    pub fn replace_rules(&self, rules: Vec<AlertRule>) {
+        let mut state = self.state.write().unwrap();
+        state.stored_rules = rules;
+        state.prune_alerts();
+    }
+
+    pub fn upsert_rule(&self, rule: AlertRule) {
+        let mut state = self.state.write().unwrap();
+        state.stored_rules.retain(|x| x.rule_id != rule.rule_id);
+        state.stored_rules.push(rule);
+        state.prune_alerts();
+    }
+
+    pub fn remove_rule(&self, rule_id: &str) {
+        let mut state = self.state.write().unwrap();
+        state.stored_rules.retain(|x| x.rule_id != rule_id);
+        state.prune_alerts();
+    }
+
+    pub fn replace_tags(&self, tags: HashMap<String, Value>) {
+        self.state.write().unwrap().tags = tags;
+    }
+
+    pub fn alerts(&self) -> Vec<Alert> {
+        let state = self.state.read().unwrap();
+        let rules = state.rules();
+        let now = Utc::now().timestamp_nanos();
+        let mut alerts: Vec<Alert> = state
+            .alerts
+            .values()
+            .map(|x| {
+                let mut alert = x.alert.clone();
+                alert.silenced = rules.iter().any(|rule| {
+                    rule.rule_id == alert.rule_id && rule.is_silenced(&alert.device_id, now)
+                });
+                alert
+            })
+            .collect();
+        alerts.sort_by(|a, b| a.alert_id.cmp(&b.alert_id));
+        alerts
+    }
+
+    // Only state changes give an event, a silenced alert still changes
+    // state but its events are not returned
+    pub fn evaluate(&self, payload: &DeviceTelemetryRequest) -> Vec<AlertEvent> {
+        let mut state = self.state.write().unwrap();
+        let rules = state.rules();
+        let tags = state.tags.get(&payload.device_id).cloned();
+        let mut events = vec![];
+        for rule in rules.iter().filter(|x| x.matches_tags(tags.as_ref())) {
+            let value = payload
+                .telemetry
+                .floats
+                .get(&rule.sensor_id)
+                .copied()
+                .or_else(|| {
+                    payload
+                        .telemetry
+                        .ints
+                        .get(&rule.sensor_id)
+                        .map(|x| *x as f64)
+                });
+            let Some(value) = value.filter(|x| x.is_finite()) else {
+                continue;
+            };
+
+            let alert_id = format!("{}:{}", rule.rule_id, payload.device_id);
+            let tracked = state
+                .alerts
+                .entry(alert_id.clone())
+                .or_insert_with(|| TrackedAlert {
+                    alert: Alert {
+                        alert_id,
+                        rule_id: rule.rule_id.clone(),
+                        device_id: payload.device_id.clone(),
+                        sensor_id: rule.sensor_id,
+                        ..Default::default()
+                    },
+                    previous: None,
+                });
+            let event = update_alert(rule, tracked, payload.timestamp, value);
+            if let Some(event) = event {
+                if !rule.is_silenced(&payload.device_id, payload.timestamp) {
+                    events.push(event);
+                }
+            }
+        }
+        events
+    }
+}
+
+impl AlertEngine {
+    // Points are evaluated in the order they were taken, not the one they
+    // were batched in
+    pub fn evaluate_batch(&self, payload: &DeviceTelemetryBatchRequest) -> Vec<AlertEvent> {
+        let mut points: Vec<_> = payload.points.iter().collect();
+        points.sort_by_key(|x| x.timestamp);
+        points
+            .into_iter()
+            .flat_map(|point| {
+                self.evaluate(&DeviceTelemetryRequest {
+                    device_id: payload.device_id.clone(),
+                    timestamp: point.timestamp,
+                    telemetry: point.telemetry.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+fn update_alert(
+    rule: &AlertRule,
+    tracked: &mut TrackedAlert,
+    timestamp: i64,
+    value: f64,
+) -> Option<AlertEvent> {
+    // An out of order value doesn't move the rate reference back in time
+    let previous = tracked.previous;
+    if previous.map_or(true, |x| x.0 < timestamp) {
+        tracked.previous = Some((timestamp, value));
+    }
+    let measured = if rule.condition.is_rate() {
+        // Out of order or duplicated values don't give a rate
+        let (previous_time, previous_value) = previous.filter(|x| x.0 < timestamp)?;
+        (value - previous_value) / ((timestamp - previous_time) as f64 / 1e9)
+    } else {
+        value
+    };
+    let holds = match rule.condition {
+        AlertCondition::Above | AlertCondition::RateAbove => measured > rule.threshold,
+        AlertCondition::Below | AlertCondition::RateBelow => measured < rule.threshold,
+    };
+
+    let alert = &mut tracked.alert;
+    alert.value = measured;
+    let kind = match (holds, alert.state) {
+        (true, AlertState::Ok) => {
+            alert.state = AlertState::Pending;
+            alert.since = timestamp;
+            None
+        }
+        (false, AlertState::Firing) => {
+            alert.state = AlertState::Ok;
+            alert.since = 0;
+            Some(AlertEventKind::Resolved)
+        }
+        (false, _) => {
+            alert.state = AlertState::Ok;
+            alert.since = 0;
+            None
+        }
+        _ => None,
+    };
+    let kind = kind.or_else(|| {
+        let elapsed = timestamp - alert.since;
+        if alert.state == AlertState::Pending && elapsed >= rule.for_secs as i64 * 1_000_000_000 {
+            alert.state = AlertState::Firing;
+            alert.fired_time = timestamp;
+            Some(AlertEventKind::Fired)
+        } else {
+            None
+        }
+    })?;
+
+    Some(AlertEvent {
+        kind,
+        alert_id: alert.alert_id.clone(),
+        rule_id: rule.rule_id.clone(),
+        device_id: alert.device_id.clone(),
+        sensor_id: rule.sensor_id,
+        condition: rule.condition,
+        threshold: rule.threshold,
+        value: measured,
+        timestamp,
+    })
+}
+
+pub fn new_alert_rule(payload: NewAlertRuleReq) -> AlertRule {
+    AlertRule {
+        id: None,
+        rule_id: payload.rule_id,
+        description: payload.description,
+        tags: payload.tags,
+        sensor_id: payload.sensor_id,
+        condition: payload.condition,
+        threshold: payload.threshold,
+        for_secs: payload.for_secs,
+        silences: vec![],
+        update_time: Utc::now().timestamp_nanos(),
+    }
+}
+
+pub async fn get_alert_rules_from_db(
+    db: &Surreal<Client>,
+) -> Result<Vec<AlertRule>, surrealdb::Error> {
+    let rules: Vec<AlertRule> = db.select("alert_rule").await?;
+    Ok(rules)
+}
+
+// Creating a rule that already exists replaces it, silences are kept
+pub async fn create_alert_rule_in_db(
+    db: &Surreal<Client>,
+    payload: NewAlertRuleReq,
+) -> Result<Option<AlertRule>, TwinServiceError> {
+    if payload.rule_id.is_empty() {
+        return Err(TwinServiceError::Msg("rule_id is required".to_string()));
+    }
+    let rule_id = payload.rule_id.clone();
+    let current: Option<AlertRule> = db.select(("alert_rule", rule_id.as_str())).await?;
+    let mut rule = new_alert_rule(payload);
+
+    let saved: Option<AlertRule> = match current {
+        Some(current) => {
+            rule.silences = current.silences;
+            db.update(("alert_rule", rule_id.as_str()))
+                .content(rule)
+                .await?
+        }
+        None => {
+            db.create(("alert_rule", rule_id.as_str()))
+                .content(rule)
+                .await?
+        }
+    };
+    Ok(saved)
+}
+
+pub async fn delete_alert_rule_in_db(
+    db: &Surreal<Client>,
+    rule_id: &str,
+) -> Result<Option<AlertRule>, TwinServiceError> {
+    let deleted: Option<AlertRule> = db.delete(("alert_rule", rule_id)).await?;
+    Ok(deleted)
+}
+
+// Rules from the config are copied in the db the first time they are silenced
+pub async fn silence_alert_rule_in_db(
+    db: &Surreal<Client>,
+    engine: &AlertEngine,
+    rule_id: &str,
+    payload: &SilenceAlertReq,
+) -> Result<Option<AlertRule>, TwinServiceError> {
+    let Some(mut rule) = engine.rules().into_iter().find(|x| x.rule_id == rule_id) else {
+        return Err(TwinServiceError::RecordNotFound(rule_id.to_string()));
+    };
+    let now = Utc::now().timestamp_nanos();
+    rule.id = None;
+    rule.silences
+        .retain(|x| x.until > now && x.device_id != payload.device_id);
+    if payload.duration_secs > 0 {
+        rule.silences.push(AlertSilence {
+            device_id: payload.device_id.clone(),
+            until: now + payload.duration_secs as i64 * 1_000_000_000,
+        });
+    }
+
+    let saved: Option<AlertRule> = db.update(("alert_rule", rule_id)).content(rule).await?;
+    Ok(saved)
+}
+
+pub async fn get_device_tags_from_db(
+    db: &Surreal<Client>,
+) -> Result<HashMap<String, Value>, surrealdb::Error> {
+    let mut results = db
+        .query("SELECT meta_properties.device_id AS device_id, tag_properties.properties AS tags FROM device_twin")
+        .await?;
+    let tags: Vec<DeviceTags> = results.take(0)?;
+    Ok(tags
+        .into_iter()
+        .filter_map(|x| x.tags.map(|tags| (x.device_id, tags)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libs::models::telemetry::{Telemetry, TelemetryPoint};
+    use serde_json::json;
+
+    const SENSOR_ID: i64 = 2;
+    const SECOND: i64 = 1_000_000_000;
+
+    fn rule(condition: AlertCondition, threshold: f64, for_secs: u64) -> AlertRule {
+        AlertRule {
+            rule_id: "hot".to_string(),
+            sensor_id: SENSOR_ID,
+            condition,
+            threshold,
+            for_secs,
+            ..Default::default()
+        }
+    }
+
+    fn telemetry(timestamp: i64, value: f64) -> DeviceTelemetryRequest {
+        DeviceTelemetryRequest {
+            device_id: "dev-1".to_string(),
+            timestamp,
+            telemetry: Telemetry {
+                floats: HashMap::from([(SENSOR_ID, value)]),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn kinds(events: Vec<AlertEvent>) -> Vec<AlertEventKind> {
+        events.into_iter().map(|x| x.kind).collect()
+    }
+
+    fn state(engine: &AlertEngine) -> AlertState {
+        engine.alerts()[0].state
+    }
+
+    #[test]
+    fn fires_once_the_condition_held_for_secs() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::Above, 80.0, 60)]);
+
+        assert!(engine.evaluate(&telemetry(0, 90.0)).is_empty());
+        assert_eq!(state(&engine), AlertState::Pending);
+        assert!(engine.evaluate(&telemetry(30 * SECOND, 90.0)).is_empty());
+        assert_eq!(state(&engine), AlertState::Pending);
+        assert_eq!(
+            kinds(engine.evaluate(&telemetry(60 * SECOND, 90.0))),
+            vec![AlertEventKind::Fired]
+        );
+        assert_eq!(state(&engine), AlertState::Firing);
+        assert!(engine.evaluate(&telemetry(90 * SECOND, 90.0)).is_empty());
+    }
+
+    #[test]
+    fn fires_right_away_without_for_secs() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::Below, 10.0, 0)]);
+
+        let events = engine.evaluate(&telemetry(0, 5.0));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AlertEventKind::Fired);
+        assert_eq!(events[0].alert_id, "hot:dev-1");
+        assert_eq!(events[0].value, 5.0);
+    }
+
+    #[test]
+    fn pending_alert_goes_back_to_ok_without_event() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::Above, 80.0, 60)]);
+
+        engine.evaluate(&telemetry(0, 90.0));
+        assert!(engine.evaluate(&telemetry(30 * SECOND, 70.0)).is_empty());
+        assert_eq!(state(&engine), AlertState::Ok);
+        // The wait starts over
+        assert!(engine.evaluate(&telemetry(61 * SECOND, 90.0)).is_empty());
+        assert_eq!(state(&engine), AlertState::Pending);
+    }
+
+    #[test]
+    fn firing_alert_resolves() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::Above, 80.0, 0)]);
+
+        engine.evaluate(&telemetry(0, 90.0));
+        assert_eq!(
+            kinds(engine.evaluate(&telemetry(SECOND, 70.0))),
+            vec![AlertEventKind::Resolved]
+        );
+        assert_eq!(state(&engine), AlertState::Ok);
+        assert!(engine.evaluate(&telemetry(2 * SECOND, 70.0)).is_empty());
+    }
+
+    #[test]
+    fn rate_is_per_second_between_consecutive_values() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::RateAbove, 5.0, 0)]);
+
+        // No rate without a previous value
+        assert!(engine.evaluate(&telemetry(0, 10.0)).is_empty());
+        assert_eq!(state(&engine), AlertState::Ok);
+        // 4 per second
+        assert!(engine.evaluate(&telemetry(2 * SECOND, 18.0)).is_empty());
+        // 10 per second
+        let events = engine.evaluate(&telemetry(3 * SECOND, 28.0));
+        assert_eq!(kinds(events.clone()), vec![AlertEventKind::Fired]);
+        assert_eq!(events[0].value, 10.0);
+    }
+
+    #[test]
+    fn out_of_order_values_are_ignored_by_rates() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::RateAbove, 5.0, 0)]);
+
+        engine.evaluate(&telemetry(10 * SECOND, 10.0));
+        // Older and duplicated values give no rate
+        assert!(engine.evaluate(&telemetry(5 * SECOND, 100.0)).is_empty());
+        assert!(engine.evaluate(&telemetry(10 * SECOND, 100.0)).is_empty());
+        // Rate against the value at 10s, not the late one
+        let events = engine.evaluate(&telemetry(11 * SECOND, 12.0));
+        assert!(events.is_empty());
+        assert_eq!(engine.alerts()[0].value, 2.0);
+        assert_eq!(
+            kinds(engine.evaluate(&telemetry(12 * SECOND, 30.0))),
+            vec![AlertEventKind::Fired]
+        );
+    }
+
+    #[test]
+    fn silenced_alert_changes_state_without_event() {
+        let mut silenced = rule(AlertCondition::Above, 80.0, 0);
+        silenced.silences = vec![AlertSilence {
+            device_id: "dev-1".to_string(),
+            until: 100 * SECOND,
+        }];
+        let engine = AlertEngine::new(vec![silenced]);
+
+        assert!(engine.evaluate(&telemetry(0, 90.0)).is_empty());
+        assert_eq!(state(&engine), AlertState::Firing);
+        // Resolved after the silence ended
+        assert_eq!(
+            kinds(engine.evaluate(&telemetry(200 * SECOND, 70.0))),
+            vec![AlertEventKind::Resolved]
+        );
+    }
+
+    #[test]
+    fn silence_of_another_device_does_not_apply() {
+        let mut silenced = rule(AlertCondition::Above, 80.0, 0);
+        silenced.silences = vec![AlertSilence {
+            device_id: "dev-2".to_string(),
+            until: 100 * SECOND,
+        }];
+        let engine = AlertEngine::new(vec![silenced]);
+
+        assert_eq!(
+            kinds(engine.evaluate(&telemetry(0, 90.0))),
+            vec![AlertEventKind::Fired]
+        );
+    }
+
+    #[test]
+    fn rules_only_apply_to_devices_with_their_tags() {
+        let mut tagged = rule(AlertCondition::Above, 80.0, 0);
+        tagged.tags = HashMap::from([("building".to_string(), json!(43))]);
+        let engine = AlertEngine::new(vec![tagged]);
+
+        assert!(engine.evaluate(&telemetry(0, 90.0)).is_empty());
+        engine.replace_tags(HashMap::from([(
+            "dev-1".to_string(),
+            json!({"building": 43}),
+        )]));
+        assert_eq!(
+            kinds(engine.evaluate(&telemetry(SECOND, 90.0))),
+            vec![AlertEventKind::Fired]
+        );
+    }
+
+    #[test]
+    fn batch_points_are_evaluated_in_time_order() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::Above, 80.0, 60)]);
+        let point = |timestamp: i64, value: f64| TelemetryPoint {
+            timestamp,
+            telemetry: telemetry(timestamp, value).telemetry,
+        };
+        let batch = DeviceTelemetryBatchRequest {
+            device_id: "dev-1".to_string(),
+            timestamp: 90 * SECOND,
+            points: vec![
+                point(60 * SECOND, 90.0),
+                point(0, 90.0),
+                point(30 * SECOND, 90.0),
+            ],
+        };
+
+        let events = engine.evaluate_batch(&batch);
+        assert_eq!(kinds(events.clone()), vec![AlertEventKind::Fired]);
+        assert_eq!(events[0].timestamp, 60 * SECOND);
+    }
+
+    #[test]
+    fn stored_rule_replaces_config_rule() {
+        let engine = AlertEngine::new(vec![rule(AlertCondition::Above, 80.0, 0)]);
+        engine.upsert_rule(rule(AlertCondition::Above, 95.0, 0));
+
+        assert!(engine.evaluate(&telemetry(0, 90.0)).is_empty());
+        assert_eq!(engine.rules().len(), 1);
+        assert_eq!(engine.rules()[0].threshold, 95.0);
+    }
+}
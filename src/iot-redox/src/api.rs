@@ -1,27 +1,70 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
+    body::Bytes,
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::IntoResponse,
     Json,
 };
 use log::{debug, error, info, warn};
 use serde_json::{json, Value};
 use surrealdb::{engine::remote::ws::Client, Surreal};
-use libs::models::device_twin::{MetaProperties, NewDeviceReq, Properties, Record, TargetProperties};
+use libs::models::alert::{AlertState, NewAlertRuleReq, SilenceAlertReq};
+use libs::models::device_model::NewDeviceModelReq;
+use libs::models::device_twin::{DeviceStatusCache, DeviceStatusReq, MetaProperties, NewDeviceReq, Properties, Record, TargetProperties};
+use libs::models::telemetry::DeviceStatusNotification;
+use libs::models::enrollment::NewEnrollmentGroupReq;
+use libs::models::ota::NewCampaignReq;
+use libs::models::route::NewRouteReq;
+use libs::models::telemetry_query::TelemetryQuery;
+use libs::models::upload::UploadState;
+use libs::models::webhook::{NewWebhookReq, WebhookEventKind};
 use libs::clients::amqp::Amqp;
+use libs::utils::auth::DeviceKeyCache;
+use libs::utils::tenant::tenant_queue;
 
+use crate::alert_service::*;
+use crate::enrollment_service::*;
+use crate::model_service::*;
+use crate::ota_service::*;
+use crate::query_service::{query_telemetry, QueryServiceError, QuestDb};
+use crate::route_service::*;
+use crate::tenant::Tenant;
 use crate::twin_service::*;
+use crate::upload_service::*;
+use crate::webhook_service::*;
 
 pub struct ApiState {
+    pub tenant: String,
     pub amqp: Amqp,
     pub db: Surreal<Client>,
+    pub keys: DeviceKeyCache,
+    pub statuses: DeviceStatusCache,
+    pub questdb: QuestDb,
+    pub alerts: AlertEngine,
+    pub webhooks: WebhookDispatcher,
+    pub uploads_dir: PathBuf,
+    pub artifacts_dir: PathBuf,
 }
 
 const DEVICE_ID_KEY: &str = "device_id";
+const GROUP_ID_KEY: &str = "group_id";
+const MODEL_ID_KEY: &str = "model_id";
+const ROUTE_ID_KEY: &str = "route_id";
+const RULE_ID_KEY: &str = "rule_id";
+const STATE_KEY: &str = "state";
+const WEBHOOK_ID_KEY: &str = "webhook_id";
+const UPLOAD_ID_KEY: &str = "upload_id";
+const ARTIFACT_ID_KEY: &str = "artifact_id";
+const VERSION_KEY: &str = "version";
+const CHECKSUM_KEY: &str = "checksum";
+const CAMPAIGN_ID_KEY: &str = "campaign_id";
+const LIMIT_KEY: &str = "limit";
+const DEFAULT_DELIVERY_LIMIT: usize = 100;
 
 pub async fn get_records(
-    State(state): State<Arc<ApiState>>,
+    Tenant(state): Tenant,
     Query(_params): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, StatusCode> {
     let twins: &Vec<Record> = &state.db.select("device_twin").await.map_err(|error| {
@@ -34,7 +77,7 @@ pub async fn get_records(
 }
 
 pub async fn get_device_twins(
-    State(state): State<Arc<ApiState>>,
+    Tenant(state): Tenant,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, StatusCode> {
     let mut device_id = "".to_string();
@@ -53,7 +96,7 @@ pub async fn get_device_twins(
 }
 
 pub async fn get_device_twins_properties(
-    State(state): State<Arc<ApiState>>,
+    Tenant(state): Tenant,
     Path(target): Path<TargetProperties>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, StatusCode> {
@@ -103,7 +146,7 @@ pub async fn get_device_twins_properties(
 }
 
 pub async fn update_device_twins_properties(
-    State(state): State<Arc<ApiState>>,
+    Tenant(state): Tenant,
     Path(target): Path<TargetProperties>,
     Query(params): Query<HashMap<String, String>>,
     Json(payload): Json<Properties>,
@@ -126,6 +169,12 @@ pub async fn update_device_twins_properties(
     )
     .await;
 
+    // Don't forward properties the device model rejects
+    if let Err(error @ TwinServiceError::InvalidProperties(..)) = &updated_twin_result {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+
     let twin = if let Err(_) = updated_twin_result {
         //return Ok(Json(json!({ "result": 200 })));
         // TODO: proper return when surrealdb is fixed
@@ -134,6 +183,15 @@ pub async fn update_device_twins_properties(
         updated_twin_result.unwrap()
     };
 
+    let event = match target {
+        TargetProperties::Desired => Some(WebhookEventKind::DesiredUpdated),
+        TargetProperties::Tag => Some(WebhookEventKind::TagUpdated),
+        _ => None,
+    };
+    if let (Some(event), Some(_)) = (event, &twin) {
+        state.webhooks.notify(event, &device_id, json!(payload));
+    }
+
     // Send msg to device with update properties if its desired
     // Create reusable channel
     if target.clone() == TargetProperties::Desired {
@@ -141,7 +199,7 @@ pub async fn update_device_twins_properties(
         let str_payload = serde_json::to_string(&payload).unwrap();
         match state
             .amqp
-            .send_message(&str_payload, "", device_id.as_str())
+            .send_message(&str_payload, "", &tenant_queue(&state.tenant, &device_id))
             .await
         {
             Ok(x) => {
@@ -157,12 +215,13 @@ pub async fn update_device_twins_properties(
 }
 
 pub async fn create_device_twins(
-    State(state): State<Arc<ApiState>>,
+    Tenant(state): Tenant,
     Json(payload): Json<NewDeviceReq>,
 ) -> Result<Json<Value>, StatusCode> {
     debug!("create_device_twin");
     dbg!(&payload);
 
+    let device_id = payload.device_id.clone();
     let created = create_device_twins_in_db(state.db.clone(), payload).await;
 
     if let Err(error) = created {
@@ -171,6 +230,33 @@ pub async fn create_device_twins(
     }
 
     dbg!(&created);
+    if let Some(meta) = created
+        .as_ref()
+        .ok()
+        .and_then(|x| x.as_ref())
+        .and_then(|x| x.meta_properties.as_ref())
+    {
+        state.statuses.update(meta);
+    }
+
+    // The key is only ever returned here and on rotation
+    let key = match create_device_key_in_db(&state.db, device_id.as_str(), "").await {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            error!("no device key created for '{device_id}'");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Err(error) => {
+            error!("Error: {}", error);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    state.keys.insert(key.clone());
+
+    let mut twin = json!(created.unwrap());
+    if let Some(x) = twin.as_object_mut() {
+        x.insert("device_key".to_string(), json!(key.primary_key));
+    }
     //Ok(Json(json!({ "result": created })))
     //
 
@@ -182,11 +268,11 @@ pub async fn create_device_twins(
     //    Err(e) => json!(e),
     //};
 
-    Ok(Json(json!(created.unwrap())))
+    Ok(Json(twin))
 }
 
 pub async fn delete_device_twins(
-    State(state): State<Arc<ApiState>>,
+    Tenant(state): Tenant,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, StatusCode> {
     debug!("delete_device_twin");
@@ -200,7 +286,674 @@ pub async fn delete_device_twins(
         warn!("{}", json!(error.to_string()));
         return Ok(Json(json!(error.to_string())));
     }
+    if let Err(error) = delete_device_key_in_db(&state.db, device_id.as_str()).await {
+        warn!("{}", json!(error.to_string()));
+    }
+    state.keys.remove(device_id.as_str());
+    state.statuses.remove(device_id.as_str());
     let x = twins.as_ref().unwrap();
 
     Ok(Json(json!(x)))
 }
+
+pub async fn rotate_device_key(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("rotate_device_key");
+    let mut device_id = "".to_string();
+    if params.contains_key(DEVICE_ID_KEY) {
+        device_id = params[DEVICE_ID_KEY].clone();
+    }
+
+    let key = match rotate_device_key_in_db(&state.db, device_id.as_str()).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(TwinServiceError::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND),
+        Err(error) => {
+            error!("Error: {}", error);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    state.keys.insert(key.clone());
+    info!("rotated device key for '{device_id}'");
+
+    Ok(Json(json!({
+        "device_id": key.device_id,
+        "device_key": key.primary_key,
+        "rotated_time": key.rotated_time,
+    })))
+}
+
+pub async fn update_device_status(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+    Json(payload): Json<DeviceStatusReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("update_device_status");
+    let mut device_id = "".to_string();
+    if params.contains_key(DEVICE_ID_KEY) {
+        device_id = params[DEVICE_ID_KEY].clone();
+    }
+
+    let updated = update_device_status_in_db(&state.db, device_id.as_str(), &payload.action).await;
+    let meta = match updated {
+        Ok(Some(twin)) => twin.meta_properties.unwrap_or_default(),
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            return Ok(Json(json!(error.to_string())));
+        }
+    };
+    state.statuses.update(&meta);
+    info!(
+        "device '{}' is now {:?} ({:?})",
+        device_id, meta.status, meta.status_reason
+    );
+
+    // Let the device know, it is dropped if the device is not connected
+    let notification = DeviceStatusNotification {
+        device_id: device_id.clone(),
+        timestamp: chrono::Utc::now().timestamp_nanos(),
+        status: meta.status.clone(),
+        status_reason: meta.status_reason.clone(),
+    };
+    let str_payload = serde_json::to_string(&notification).unwrap();
+    if let Err(e) = state
+        .amqp
+        .send_message(&str_payload, "", &tenant_queue(&state.tenant, &device_id))
+        .await
+    {
+        error!("{:?}", e);
+    }
+
+    Ok(Json(json!(meta)))
+}
+
+pub async fn get_enrollment_groups(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut group_id = "".to_string();
+    if params.contains_key(GROUP_ID_KEY) {
+        group_id = params[GROUP_ID_KEY].clone();
+    }
+    let mut groups = get_enrollment_groups_from_db(&state.db, group_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // The secret is only ever returned at creation
+    groups
+        .iter_mut()
+        .for_each(|group| group.attestation_secret.clear());
+
+    Ok(Json(json!(groups)))
+}
+
+pub async fn create_enrollment_group(
+    Tenant(state): Tenant,
+    Json(payload): Json<NewEnrollmentGroupReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("create_enrollment_group");
+    let created = create_enrollment_group_in_db(&state.db, payload).await;
+    if let Err(error) = created {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+
+    Ok(Json(json!(created.unwrap())))
+}
+
+pub async fn delete_enrollment_group(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("delete_enrollment_group");
+    let mut group_id = "".to_string();
+    if params.contains_key(GROUP_ID_KEY) {
+        group_id = params[GROUP_ID_KEY].clone();
+    }
+
+    let deleted = delete_enrollment_group_in_db(&state.db, group_id.as_str()).await;
+    if let Err(error) = deleted {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+    let mut group = deleted.unwrap();
+    if let Some(x) = group.as_mut() {
+        x.attestation_secret.clear();
+    }
+
+    Ok(Json(json!(group)))
+}
+
+pub async fn get_device_models(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut model_id = "".to_string();
+    if params.contains_key(MODEL_ID_KEY) {
+        model_id = params[MODEL_ID_KEY].clone();
+    }
+    let models = get_device_models_from_db(&state.db, model_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(json!(models)))
+}
+
+pub async fn create_device_model(
+    Tenant(state): Tenant,
+    Json(payload): Json<NewDeviceModelReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("create_device_model");
+    let created = create_device_model_in_db(&state.db, payload).await;
+    if let Err(error) = created {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+
+    Ok(Json(json!(created.unwrap())))
+}
+
+pub async fn delete_device_model(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("delete_device_model");
+    let mut model_id = "".to_string();
+    if params.contains_key(MODEL_ID_KEY) {
+        model_id = params[MODEL_ID_KEY].clone();
+    }
+
+    let deleted = delete_device_model_in_db(&state.db, model_id.as_str()).await;
+    if let Err(error) = deleted {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+
+    Ok(Json(json!(deleted.unwrap())))
+}
+
+pub async fn get_telemetry(
+    Tenant(state): Tenant,
+    Query(query): Query<TelemetryQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let series = query_telemetry(&state.questdb, &state.tenant, query)
+        .await
+        .map_err(|error| match error {
+            QueryServiceError::InvalidQuery(_) => {
+                warn!("{}", error);
+                StatusCode::BAD_REQUEST
+            }
+            _ => {
+                error!("Error: {}", error);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    Ok(Json(json!(series)))
+}
+
+pub async fn get_alert_rules(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut rules = state.alerts.rules();
+    if let Some(rule_id) = params.get(RULE_ID_KEY) {
+        rules.retain(|x| &x.rule_id == rule_id);
+    }
+
+    Ok(Json(json!(rules)))
+}
+
+pub async fn create_alert_rule(
+    Tenant(state): Tenant,
+    Json(payload): Json<NewAlertRuleReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("create_alert_rule");
+    let created = create_alert_rule_in_db(&state.db, payload).await;
+    let rule = match created {
+        Ok(x) => x,
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            return Ok(Json(json!(error.to_string())));
+        }
+    };
+    if let Some(x) = rule.as_ref() {
+        state.alerts.upsert_rule(x.clone());
+    }
+
+    Ok(Json(json!(rule)))
+}
+
+pub async fn delete_alert_rule(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("delete_alert_rule");
+    let mut rule_id = "".to_string();
+    if params.contains_key(RULE_ID_KEY) {
+        rule_id = params[RULE_ID_KEY].clone();
+    }
+
+    let deleted = delete_alert_rule_in_db(&state.db, rule_id.as_str()).await;
+    if let Err(error) = deleted {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+    state.alerts.remove_rule(rule_id.as_str());
+
+    Ok(Json(json!(deleted.unwrap())))
+}
+
+// A zero duration lifts the silence
+pub async fn silence_alert_rule(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+    Json(payload): Json<SilenceAlertReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("silence_alert_rule");
+    let mut rule_id = "".to_string();
+    if params.contains_key(RULE_ID_KEY) {
+        rule_id = params[RULE_ID_KEY].clone();
+    }
+
+    let silenced =
+        silence_alert_rule_in_db(&state.db, &state.alerts, rule_id.as_str(), &payload).await;
+    let rule = match silenced {
+        Ok(x) => x,
+        Err(TwinServiceError::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND),
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            return Ok(Json(json!(error.to_string())));
+        }
+    };
+    if let Some(x) = rule.as_ref() {
+        state.alerts.upsert_rule(x.clone());
+    }
+    info!("silenced rule '{}' for {}s", rule_id, payload.duration_secs);
+
+    Ok(Json(json!(rule)))
+}
+
+pub async fn get_alerts(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut alerts = state.alerts.alerts();
+    if let Some(rule_id) = params.get(RULE_ID_KEY) {
+        alerts.retain(|x| &x.rule_id == rule_id);
+    }
+    if let Some(device_id) = params.get(DEVICE_ID_KEY) {
+        alerts.retain(|x| &x.device_id == device_id);
+    }
+    // Firing alerts only unless another state is asked
+    let wanted: AlertState = match params.get(STATE_KEY) {
+        Some(x) => serde_json::from_value(json!(x)).map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => AlertState::Firing,
+    };
+    alerts.retain(|x| x.state == wanted);
+
+    Ok(Json(json!(alerts)))
+}
+
+pub async fn get_routes(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut route_id = "".to_string();
+    if params.contains_key(ROUTE_ID_KEY) {
+        route_id = params[ROUTE_ID_KEY].clone();
+    }
+    let routes = get_routes_from_db(&state.db, route_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(json!(routes)))
+}
+
+// The router picks up route changes on its next refresh
+pub async fn create_route(
+    Tenant(state): Tenant,
+    Json(payload): Json<NewRouteReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("create_route");
+    let created = create_route_in_db(&state.db, payload).await;
+    if let Err(error) = created {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+
+    Ok(Json(json!(created.unwrap())))
+}
+
+pub async fn delete_route(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("delete_route");
+    let mut route_id = "".to_string();
+    if params.contains_key(ROUTE_ID_KEY) {
+        route_id = params[ROUTE_ID_KEY].clone();
+    }
+
+    let deleted = delete_route_in_db(&state.db, route_id.as_str()).await;
+    if let Err(error) = deleted {
+        warn!("{}", json!(error.to_string()));
+        return Ok(Json(json!(error.to_string())));
+    }
+
+    Ok(Json(json!(deleted.unwrap())))
+}
+
+// Secrets are only returned when the webhook is created
+pub async fn get_webhooks(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut webhook_id = "".to_string();
+    if params.contains_key(WEBHOOK_ID_KEY) {
+        webhook_id = params[WEBHOOK_ID_KEY].clone();
+    }
+    let mut webhooks = get_webhooks_from_db(&state.db, webhook_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    for webhook in webhooks.iter_mut() {
+        webhook.secret.clear();
+    }
+
+    Ok(Json(json!(webhooks)))
+}
+
+pub async fn create_webhook(
+    Tenant(state): Tenant,
+    Json(payload): Json<NewWebhookReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("create_webhook");
+    let created = create_webhook_in_db(&state.db, payload).await;
+    let webhook = match created {
+        Ok(x) => x,
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            return Ok(Json(json!(error.to_string())));
+        }
+    };
+    if let Some(x) = webhook.as_ref() {
+        state.webhooks.upsert_webhook(x.clone());
+    }
+
+    Ok(Json(json!(webhook)))
+}
+
+pub async fn delete_webhook(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("delete_webhook");
+    let mut webhook_id = "".to_string();
+    if params.contains_key(WEBHOOK_ID_KEY) {
+        webhook_id = params[WEBHOOK_ID_KEY].clone();
+    }
+
+    let deleted = delete_webhook_in_db(&state.db, webhook_id.as_str()).await;
+    let mut webhook = match deleted {
+        Ok(x) => x,
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            return Ok(Json(json!(error.to_string())));
+        }
+    };
+    state.webhooks.remove_webhook(webhook_id.as_str());
+    if let Some(x) = webhook.as_mut() {
+        x.secret.clear();
+    }
+
+    Ok(Json(json!(webhook)))
+}
+
+pub async fn get_webhook_deliveries(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let webhook_id = params.get(WEBHOOK_ID_KEY).cloned().unwrap_or_default();
+    let limit = match params.get(LIMIT_KEY) {
+        Some(x) => x.parse::<usize>().map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => DEFAULT_DELIVERY_LIMIT,
+    };
+    let deliveries = get_webhook_deliveries_from_db(&state.db, webhook_id.as_str(), limit)
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(json!(deliveries)))
+}
+
+// Sends a ping event right away and returns its delivery, retries included
+pub async fn ping_webhook(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("ping_webhook");
+    let webhook_id = params.get(WEBHOOK_ID_KEY).cloned().unwrap_or_default();
+    let webhook = get_webhooks_from_db(&state.db, webhook_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .find(|x| x.webhook_id == webhook_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let event = state
+        .webhooks
+        .new_event(WebhookEventKind::Ping, "", Value::Null);
+    let delivery = state.webhooks.deliver(&webhook, &event).await;
+    if let Err(error) = create_webhook_delivery_in_db(&state.db, delivery.clone()).await {
+        error!("can't log webhook delivery: {}", error);
+    }
+
+    Ok(Json(json!(delivery)))
+}
+
+pub async fn get_uploads(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let device_id = params.get(DEVICE_ID_KEY).cloned().unwrap_or_default();
+    let upload_id = params.get(UPLOAD_ID_KEY).cloned().unwrap_or_default();
+    let uploads = get_uploads_from_db(&state.db, device_id.as_str(), upload_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(json!(uploads)))
+}
+
+// Content of a complete upload, named after the file of the device
+pub async fn get_upload_content(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let upload_id = params
+        .get(UPLOAD_ID_KEY)
+        .filter(|x| !x.is_empty())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let upload = get_uploads_from_db(&state.db, "", upload_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .find(|x| x.state == UploadState::Complete)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let content = tokio::fs::read(upload_path(&state.uploads_dir, &upload.upload_id))
+        .await
+        .map_err(|error| {
+            error!("can't read upload '{}': {}", upload.upload_id, error);
+            StatusCode::NOT_FOUND
+        })?;
+
+    let file_name = upload.name.rsplit(['/', '\\']).next().unwrap_or_default();
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                String::from("application/octet-stream"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name.replace('"', "")),
+            ),
+        ],
+        content,
+    ))
+}
+
+pub async fn get_artifacts(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let artifact_id = params.get(ARTIFACT_ID_KEY).cloned().unwrap_or_default();
+    let artifacts = get_artifacts_from_db(&state.db, artifact_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(json!(artifacts)))
+}
+
+// The body is the artifact content, the checksum is verified when given
+pub async fn create_artifact(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("create_artifact");
+    let param = |key: &str| params.get(key).cloned().unwrap_or_default();
+    let created = create_artifact_in_db(
+        &state.db,
+        &state.artifacts_dir,
+        param(ARTIFACT_ID_KEY).as_str(),
+        param(VERSION_KEY).as_str(),
+        param(CHECKSUM_KEY).as_str(),
+        &body,
+    )
+    .await;
+    match created {
+        Ok(x) => Ok(Json(json!(x))),
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            Ok(Json(json!(error.to_string())))
+        }
+    }
+}
+
+pub async fn delete_artifact(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("delete_artifact");
+    let artifact_id = params.get(ARTIFACT_ID_KEY).cloned().unwrap_or_default();
+    let deleted =
+        delete_artifact_in_db(&state.db, &state.artifacts_dir, artifact_id.as_str()).await;
+    match deleted {
+        Ok(x) => Ok(Json(json!(x))),
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            Ok(Json(json!(error.to_string())))
+        }
+    }
+}
+
+pub async fn get_campaigns(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let campaign_id = params.get(CAMPAIGN_ID_KEY).cloned().unwrap_or_default();
+    let campaigns = get_campaigns_from_db(&state.db, campaign_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(json!(campaigns)))
+}
+
+// Sends the desired properties with the firmware section to every targeted device
+pub async fn create_campaign(
+    Tenant(state): Tenant,
+    Json(payload): Json<NewCampaignReq>,
+) -> Result<Json<Value>, StatusCode> {
+    debug!("create_campaign");
+    let (campaign, desired) = match create_campaign_in_db(&state.db, payload).await {
+        Ok(x) => x,
+        Err(error) => {
+            warn!("{}", json!(error.to_string()));
+            return Ok(Json(json!(error.to_string())));
+        }
+    };
+
+    for (device_id, properties) in desired {
+        state.webhooks.notify(
+            WebhookEventKind::DesiredUpdated,
+            &device_id,
+            json!(properties),
+        );
+        let str_payload = serde_json::to_string(&properties).unwrap();
+        if let Err(e) = state
+            .amqp
+            .send_message(&str_payload, "", &tenant_queue(&state.tenant, &device_id))
+            .await
+        {
+            error!("{:?}", e);
+        }
+    }
+    info!(
+        "campaign {} sent to {} devices",
+        campaign.campaign_id,
+        campaign.device_ids.len()
+    );
+
+    Ok(Json(json!(campaign)))
+}
+
+pub async fn get_campaign_status(
+    Tenant(state): Tenant,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let campaign_id = params
+        .get(CAMPAIGN_ID_KEY)
+        .filter(|x| !x.is_empty())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let status = get_campaign_status_from_db(&state.db, campaign_id.as_str())
+        .await
+        .map_err(|error| {
+            error!("Error: {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(json!(status)))
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, Method, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use libs::utils::auth::{secret_eq, verify_token, Role};
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::tenant::request_tenant;
+
+const API_KEY_HEADER: &str = "x-api-key";
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+    pub role: Role,
+    // Empty gives access to every tenant
+    #[serde(default)]
+    pub tenant: String,
+}
+
+// Without a token secret nor api keys every request is rejected
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthSettings {
+    pub token_secret: String,
+    pub api_keys: Vec<ApiKey>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub identity: String,
+    pub role: Role,
+    pub tenant: String,
+}
+
+impl AuthSettings {
+    pub fn is_empty(&self) -> bool {
+        self.token_secret.is_empty() && self.api_keys.iter().all(|x| x.key.is_empty())
+    }
+}
+
+pub async fn authenticate<B>(
+    State(settings): State<Arc<AuthSettings>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    let caller = resolve_caller(&settings, &req).ok_or_else(|| {
+        warn!("unauthenticated {} {}", req.method(), req.uri().path());
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    let tenant = request_tenant(req.headers());
+    if !caller.tenant.is_empty() && caller.tenant != tenant {
+        warn!("{} is not allowed on tenant {}", caller.identity, tenant);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let required = required_role(req.method(), req.uri().path());
+    if caller.role < required {
+        warn!(
+            "{} with role {} is not allowed to {} {}",
+            caller.identity,
+            caller.role.as_str(),
+            req.method(),
+            req.uri()
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if req.method() == Method::GET {
+        debug!("{} {} {}", caller.identity, req.method(), req.uri());
+    } else {
+        info!("{} {} {}", caller.identity, req.method(), req.uri());
+    }
+
+    req.extensions_mut().insert(caller);
+    Ok(next.run(req).await)
+}
+
+// Api keys and tokens can both be sent as a bearer, the api key header is
+// there for tools that can't set the authorization header
+fn resolve_caller<B>(settings: &AuthSettings, req: &Request<B>) -> Option<Caller> {
+    let headers = req.headers();
+    let credential = headers
+        .get(API_KEY_HEADER)
+        .or_else(|| headers.get(AUTHORIZATION))
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.strip_prefix(BEARER_PREFIX).unwrap_or(x))?;
+
+    if let Some(api_key) = settings
+        .api_keys
+        .iter()
+        .find(|x| !x.key.is_empty() && secret_eq(&x.key, credential))
+    {
+        return Some(Caller {
+            identity: format!("apikey:{}", api_key.name),
+            role: api_key.role,
+            tenant: api_key.tenant.clone(),
+        });
+    }
+
+    if settings.token_secret.is_empty() {
+        return None;
+    }
+    match verify_token(&settings.token_secret, credential) {
+        Ok(claims) => Some(Caller {
+            identity: format!("token:{}", claims.sub),
+            role: claims.role,
+            tenant: claims.tenant,
+        }),
+        Err(error) => {
+            warn!("rejected bearer token: {}", error);
+            None
+        }
+    }
+}
+
+// Reader can look, operator can change desired properties and tags
+// and silence alerts, admin can create and delete twins or touch anything else
+fn required_role(method: &Method, path: &str) -> Role {
+    match *method {
+        Method::GET | Method::HEAD => Role::Reader,
+        Method::PUT
+            if path.ends_with("/desired")
+                || path.ends_with("/tag")
+                || path.ends_with("/silence") =>
+        {
+            Role::Operator
+        }
+        _ => Role::Admin,
+    }
+}
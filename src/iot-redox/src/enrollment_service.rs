@@ -0,0 +1,134 @@
+use chrono::Utc;
+use libs::models::device_twin::{
+    ConnectionState, DeviceTwin, MetaProperties, Properties, Status, StatusReason,
+};
+use libs::models::enrollment::{EnrollmentGroup, NewEnrollmentGroupReq};
+use libs::models::telemetry::{DeviceProvisionRequest, DeviceProvisionResponse};
+use libs::utils::auth::{generate_device_key, verify_attestation, DeviceKeyCache};
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::model_service::get_latest_model_version_from_db;
+use crate::twin_service::*;
+
+pub async fn get_enrollment_groups_from_db(
+    db: &Surreal<Client>,
+    group_id: &str,
+) -> Result<Vec<EnrollmentGroup>, TwinServiceError> {
+    if !group_id.is_empty() {
+        let group: Option<EnrollmentGroup> = db.select(("enrollment_group", group_id)).await?;
+        return Ok(group.into_iter().collect());
+    }
+
+    let groups: Vec<EnrollmentGroup> = db.select("enrollment_group").await?;
+    Ok(groups)
+}
+
+pub async fn create_enrollment_group_in_db(
+    db: &Surreal<Client>,
+    payload: NewEnrollmentGroupReq,
+) -> Result<Option<EnrollmentGroup>, TwinServiceError> {
+    let group = EnrollmentGroup {
+        id: None,
+        group_id: payload.group_id.clone(),
+        model_id: payload.model_id,
+        attestation_secret: generate_device_key(),
+        tag_properties: payload.tag_properties,
+        desired_properties: payload.desired_properties,
+    };
+
+    let created: Option<EnrollmentGroup> = db
+        .create(("enrollment_group", payload.group_id.as_str()))
+        .content(group)
+        .await?;
+    Ok(created)
+}
+
+pub async fn delete_enrollment_group_in_db(
+    db: &Surreal<Client>,
+    group_id: &str,
+) -> Result<Option<EnrollmentGroup>, TwinServiceError> {
+    let deleted: Option<EnrollmentGroup> = db.delete(("enrollment_group", group_id)).await?;
+    Ok(deleted)
+}
+
+// The registration id becomes the device id. Only the group that provisioned
+// a device can provision it again, its key is then rotated so a device that
+// lost its reply can retry without the stored key being handed out.
+pub async fn provision_device_in_db(
+    db: &Surreal<Client>,
+    keys: &DeviceKeyCache,
+    payload: &DeviceProvisionRequest,
+) -> Result<DeviceProvisionResponse, TwinServiceError> {
+    let group: Option<EnrollmentGroup> = db
+        .select(("enrollment_group", payload.group_id.as_str()))
+        .await?;
+    let group = group.ok_or(TwinServiceError::RecordNotFound(payload.group_id.clone()))?;
+
+    verify_attestation(
+        &group.attestation_secret,
+        &payload.registration_id,
+        payload.timestamp,
+        &payload.signature,
+    )
+    .map_err(|e| TwinServiceError::Msg(e.to_string()))?;
+
+    let device_id = payload.registration_id.as_str();
+    let twin = get_device_twins_with_id_from_db(db, device_id).await?;
+    let key = get_device_key_from_db(db, device_id).await?;
+    if (twin.is_some() || key.is_some())
+        && key.as_ref().map(|x| x.group_id.as_str()) != Some(group.group_id.as_str())
+    {
+        return Err(TwinServiceError::Msg(format!(
+            "device {} was not provisioned by group {}",
+            device_id, group.group_id
+        )));
+    }
+    if let Some(meta) = twin.as_ref().and_then(|x| x.meta_properties.as_ref()) {
+        if !meta.is_enabled() {
+            return Err(TwinServiceError::Disabled(device_id.to_string()));
+        }
+    }
+    if twin.is_none() {
+        let now = Utc::now().timestamp_nanos();
+        let model_version = get_latest_model_version_from_db(db, &group.model_id).await?;
+        let twin = DeviceTwin {
+            id: None,
+            meta_properties: Some(MetaProperties {
+                device_id: device_id.to_string(),
+                model_id: group.model_id.clone(),
+                model_version,
+                status: Status::Enabled,
+                status_reason: StatusReason::Registered,
+                status_update_time: now,
+                connection_state: ConnectionState::Disconnected,
+                last_activity_time: now,
+                version: 1,
+                desired_error: None,
+                metadata: Default::default(),
+            }),
+            tag_properties: Some(Properties {
+                properties: group.tag_properties.clone(),
+                version: 1,
+            }),
+            desired_properties: Some(Properties {
+                properties: group.desired_properties.clone(),
+                version: 1,
+            }),
+            reported_properties: Some(Properties::default()),
+        };
+        let _: Option<DeviceTwin> = db.create(("device_twin", device_id)).content(twin).await?;
+    }
+
+    let key = match key {
+        Some(_) => rotate_device_key_in_db(db, device_id).await?,
+        None => create_device_key_in_db(db, device_id, &group.group_id).await?,
+    }
+    .ok_or(TwinServiceError::RecordNotFound(device_id.to_string()))?;
+    keys.insert(key.clone());
+
+    Ok(DeviceProvisionResponse {
+        device_id: device_id.to_string(),
+        device_key: key.primary_key,
+        error: String::new(),
+    })
+}
@@ -1,18 +1,37 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::http::StatusCode;
-use axum::{routing::get, Router};
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{get, post, put},
+    Router,
+};
+use chrono::Utc;
 use lapin::types::ShortString;
 use lapin::ExchangeKind;
 use serde::Deserialize;
+use serde_json::{json, Value};
 use surrealdb::engine::remote::ws::{Client, Ws};
 use surrealdb::opt::auth::Root;
 use surrealdb::Surreal;
+pub mod alert_service;
 pub mod api;
+pub mod auth;
+pub mod enrollment_service;
+pub mod model_service;
+pub mod ota_service;
+pub mod query_service;
+pub mod route_service;
+pub mod tenant;
 pub mod twin_service;
+pub mod upload_service;
+pub mod webhook_service;
 
 use lapin::{options::*, types::FieldTable};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use thiserror::Error as ThisError;
 use tokio_util::sync::CancellationToken;
 
@@ -20,19 +39,31 @@ use libs::clients::amqp::{
     Amqp, AmqpSettings, ChannelSettings, ConsumerSettings, ExchangeSettings, QueueBindSettings,
     QueueSettings,
 };
-use libs::models::device_twin::TargetProperties;
+use libs::models::alert::{AlertEvent, NewAlertRuleReq};
+use libs::models::device_twin::{
+    ConnectionState, DesiredError, DeviceStatusCache, TargetProperties,
+};
+use libs::models::ota::{DeviceArtifactChunkRequest, DeviceArtifactChunkResponse};
 use libs::models::telemetry::{
-    DeviceDesiredRequest, DeviceHeartbeatRequest, DeviceReportedRequest,
+    DeviceDesiredErrorRequest, DeviceDesiredRequest, DeviceHeartbeatRequest,
+    DeviceProvisionRequest, DeviceProvisionResponse, DeviceReportedRequest,
+    DeviceTelemetryBatchRequest, DeviceTelemetryRequest,
 };
+use libs::models::upload::{DeviceUploadChunkRequest, DeviceUploadChunkResponse};
+use libs::models::webhook::WebhookEventKind;
+use libs::utils::auth::DeviceKeyCache;
 use libs::utils::cli::setup_cli;
 use libs::utils::config::{setup_config, FileFormat};
 use libs::utils::logger::setup_logger;
 use libs::utils::serialization::SerializationKind;
+use libs::utils::tenant::{default_tenants, tenant_queue, tenant_routing_key};
 
 #[derive(ThisError, Debug)]
 enum Error {
     #[error("surrealdb error: {0}")]
     SurrealDB(#[from] surrealdb::Error),
+    #[error("twin service error: {0}")]
+    TwinService(#[from] TwinServiceError),
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -40,6 +71,7 @@ pub struct ThreadCound {
     pub meta_queue: usize,
     pub reported_queue: usize,
     pub desired_queue: usize,
+    pub provision_queue: usize,
     pub web_srv_queues: usize,
 }
 
@@ -57,6 +89,42 @@ pub struct Settings {
     pub surrealdb: SurrealDb,
     pub thread_count: ThreadCound,
     pub web_srv_port: usize,
+    #[serde(default)]
+    pub auth: auth::AuthSettings,
+    #[serde(default)]
+    pub verify_signature: bool,
+    #[serde(default = "default_tenants")]
+    pub tenants: Vec<String>,
+    #[serde(default)]
+    pub questdb: query_service::QuestDbSettings,
+    #[serde(default)]
+    pub alerts: AlertSettings,
+    #[serde(default)]
+    pub webhooks: webhook_service::WebhookSettings,
+    #[serde(default)]
+    pub uploads: upload_service::UploadSettings,
+    #[serde(default)]
+    pub ota: ota_service::OtaSettings,
+}
+
+// Alert state is kept in memory, a single redox instance should run
+// the alert consumers of a tenant
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AlertSettings {
+    pub thread_count: usize,
+    // Applied to every tenant, rules created through the api replace
+    // the ones with the same id
+    pub rules: Vec<NewAlertRuleReq>,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            thread_count: 1,
+            rules: vec![],
+        }
+    }
 }
 
 const APP_NAME: &str = "redox";
@@ -68,12 +136,41 @@ const RMQ_TWIN_REPORTED_QUEUE_NAME: &str = "iot-q-reported";
 const RMQ_TWIN_REPORTED_ROUTING_KEY: &str = "#.reported.v1";
 const RMQ_TWIN_DESIRED_QUEUE_NAME: &str = "iot-q-desired";
 const RMQ_TWIN_DESIRED_ROUTING_KEY: &str = "#.desired.v1";
+const RMQ_TWIN_DESIRED_ERROR_QUEUE_NAME: &str = "iot-q-desired-error";
+const RMQ_TWIN_DESIRED_ERROR_ROUTING_KEY: &str = "#.desired_error.v1";
+const RMQ_TWIN_PROVISION_QUEUE_NAME: &str = "iot-q-provision";
+const RMQ_TWIN_PROVISION_ROUTING_KEY: &str = "#.provision.v1";
+const RMQ_TWIN_UPLOAD_QUEUE_NAME: &str = "iot-q-upload";
+const RMQ_TWIN_UPLOAD_ROUTING_KEY: &str = "#.upload.v1";
+const RMQ_TWIN_ARTIFACT_QUEUE_NAME: &str = "iot-q-artifact";
+const RMQ_TWIN_ARTIFACT_ROUTING_KEY: &str = "#.artifact.v1";
+
+const RMQ_STREAM_EXCHANGE_NAME: &str = "iot-stream";
+const RMQ_ALERTS_QUEUE_NAME: &str = "iot-q-alerts";
+const RMQ_TELEMETRY_ROUTING_KEY: &str = "#.telemetry.v1";
+const RMQ_ALERTS_BATCH_QUEUE_NAME: &str = "iot-q-alerts-batch";
+const RMQ_TELEMETRY_BATCH_ROUTING_KEY: &str = "#.telemetry_batch.v1";
+const RMQ_ALERTS_EXCHANGE_NAME: &str = "iot-alerts";
 
 const RMQ_PREFETCH_COUNT: u16 = 10;
 
+const SURREAL_DB_NAME: &str = "iot";
+
+// Keys are also updated on create, rotate and delete, this catches other redox instances
+const DEVICE_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const DEVICE_STATUS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const ALERT_RULE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const WEBHOOK_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 use std::path::PathBuf;
 
+use crate::alert_service::*;
+use crate::enrollment_service::*;
+use crate::ota_service::*;
 use crate::twin_service::*;
+use crate::upload_service::*;
+use crate::webhook_service::*;
 
 // https://www.cloudamqp.com/blog/part1-rabbitmq-best-practice.html
 // docker run --rm --pull always -p 80:8000 -v ./surrealdb:/opt/surrealdb/ surrealdb/surrealdb:latest start --log trace --user root --pass root file:/opt/surrealdb/iot.db
@@ -97,17 +194,163 @@ async fn main() -> Result<(), Error> {
     setup_logger(settings.log_level.clone()).unwrap();
     info!("{:?}", settings);
 
-    // Create amqp connection pool
+    // Create amqp connection pool, consumers are started for each tenant
     let amqp = Amqp::new(
         settings.amqp_addr.clone(),
-        settings.thread_count.meta_queue
+        (settings.thread_count.meta_queue
             + settings.thread_count.reported_queue
+            + settings.thread_count.provision_queue
+            + settings.alerts.thread_count * 2
+            + settings.uploads.thread_count
+            + settings.ota.thread_count
+            + 1)
+            * settings.tenants.len()
             + settings.thread_count.web_srv_queues
             + 3,
     );
 
+    // Alert events of every tenant, routed by tenant and kind
+    amqp.declare_exchange(
+        RMQ_ALERTS_EXCHANGE_NAME,
+        ExchangeKind::Topic,
+        ExchangeDeclareOptions::default(),
+        FieldTable::default(),
+    )
+    .await
+    .unwrap();
+
+    // Telemetry is stored by flux, redox only reads it back
+    let questdb = query_service::QuestDb::new(&settings.questdb);
+
+    let mut tenants = HashMap::new();
+    for tenant in settings.tenants.iter() {
+        let state = start_tenant(
+            tenant,
+            &settings,
+            amqp.clone(),
+            questdb.clone(),
+            token.clone(),
+        )
+        .await?;
+        tenants.insert(tenant.clone(), Arc::new(state));
+    }
+
+    // Web Server
+    let shared_state = Arc::new(tenant::Tenants { tenants });
+    if settings.auth.is_empty() {
+        warn!("no api key nor token secret in the auth settings, every api request is rejected");
+    }
+    let auth_settings = Arc::new(settings.auth.clone());
+    let srv = Router::new()
+        .route(
+            "/devicetwins",
+            get(api::get_device_twins)
+                .post(api::create_device_twins)
+                //.put(api::update_device_twins)
+                .delete(api::delete_device_twins),
+        )
+        .route(
+            "/devicetwins/:target",
+            get(api::get_device_twins_properties).put(api::update_device_twins_properties),
+        )
+        .route("/devicetwins/records", get(api::get_records))
+        .route("/devicetwins/keys", post(api::rotate_device_key))
+        .route("/devicetwins/status", put(api::update_device_status))
+        .route(
+            "/enrollmentgroups",
+            get(api::get_enrollment_groups)
+                .post(api::create_enrollment_group)
+                .delete(api::delete_enrollment_group),
+        )
+        .route(
+            "/devicemodels",
+            get(api::get_device_models)
+                .post(api::create_device_model)
+                .delete(api::delete_device_model),
+        )
+        .route("/telemetry", get(api::get_telemetry))
+        .route(
+            "/alertrules",
+            get(api::get_alert_rules)
+                .post(api::create_alert_rule)
+                .delete(api::delete_alert_rule),
+        )
+        .route("/alertrules/silence", put(api::silence_alert_rule))
+        .route("/alerts", get(api::get_alerts))
+        .route(
+            "/routes",
+            get(api::get_routes)
+                .post(api::create_route)
+                .delete(api::delete_route),
+        )
+        .route(
+            "/webhooks",
+            get(api::get_webhooks)
+                .post(api::create_webhook)
+                .delete(api::delete_webhook),
+        )
+        .route("/webhooks/deliveries", get(api::get_webhook_deliveries))
+        .route("/webhooks/ping", post(api::ping_webhook))
+        .route("/uploads", get(api::get_uploads))
+        .route("/uploads/content", get(api::get_upload_content))
+        .route(
+            "/artifacts",
+            get(api::get_artifacts)
+                .post(api::create_artifact)
+                .delete(api::delete_artifact)
+                .layer(DefaultBodyLimit::max(settings.ota.max_artifact_bytes)),
+        )
+        .route("/campaigns", get(api::get_campaigns).post(api::create_campaign))
+        .route("/campaigns/status", get(api::get_campaign_status))
+        .with_state(shared_state)
+        .route_layer(middleware::from_fn_with_state(
+            auth_settings,
+            auth::authenticate,
+        ))
+        .route("/ready", get(ready))
+        .route("/alive", get(alive));
+
+    let cloned_token = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = async move {
+                info!("serving Axum on 0.0.0.0:{} 🚀", settings.web_srv_port);
+                axum::Server::bind(&format!("0.0.0.0:{}", settings.web_srv_port).parse().unwrap())
+                    .serve(srv.into_make_service())
+                    .await.unwrap();
+            } => {
+                debug!("device shuting down...");
+            }
+        }
+    });
+
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => {
+            info!("Shutting down...");
+            token.cancel();
+        }
+        Err(err) => {
+            eprintln!("Unable to listen for shutdown signal: {}", err);
+        }
+    }
+    info!("Shutdown complete.");
+    trace!("Exiting...");
+    Ok(())
+}
+
+// Each tenant has its own surrealdb namespace, caches and queues
+async fn start_tenant(
+    tenant: &str,
+    settings: &Settings,
+    amqp: Amqp,
+    questdb: query_service::QuestDb,
+    token: CancellationToken,
+) -> Result<api::ApiState, Error> {
     // Create surrealdb connection. Surreal create handles multiple connections using channel. See .with_capacity(0)
-    let db = Surreal::new::<Ws>(settings.surrealdb.addr)
+    let db = Surreal::new::<Ws>(settings.surrealdb.addr.as_str())
         .with_capacity(0)
         .await?;
     db.signin(Root {
@@ -115,21 +358,122 @@ async fn main() -> Result<(), Error> {
         password: &settings.surrealdb.password,
     })
     .await?;
-    db.use_ns("iot").use_db("iot").await?;
-    info!("connected to SurrealDb");
+    db.use_ns(tenant).use_db(SURREAL_DB_NAME).await?;
+    info!("{}: connected to SurrealDb", tenant);
+
+    // Device keys used to verify signed device messages
+    let backfilled = backfill_device_keys_in_db(&db).await?;
+    if backfilled > 0 {
+        warn!(
+            "{}: created {} missing device keys, rotate them to give them to the devices",
+            tenant, backfilled
+        );
+    }
+    let keys = DeviceKeyCache::with_db(db.clone());
+    keys.replace_all(get_device_keys_from_db(&db).await?);
+    info!("{}: loaded {} device keys", tenant, keys.len());
+    let verifier = if settings.verify_signature {
+        Some(keys.clone())
+    } else {
+        None
+    };
+
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_keys = keys.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = refresh_device_keys(cloned_db, cloned_keys) => {
+                debug!("device keys refresh shuting down...");
+            }
+        }
+    });
+
+    // Disabled devices, shared with the api so status changes apply right away
+    let statuses = DeviceStatusCache::new();
+    statuses.replace_all(get_disabled_device_ids_from_db(&db).await?);
+    info!("{}: loaded {} disabled devices", tenant, statuses.len());
+
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_statuses = statuses.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = refresh_device_statuses(cloned_db, cloned_statuses) => {
+                debug!("device statuses refresh shuting down...");
+            }
+        }
+    });
+
+    // Webhooks are notified of twin and connection events
+    let (webhooks, events) = WebhookDispatcher::new(tenant, &settings.webhooks);
+    refresh_webhooks(&db, &webhooks).await?;
+    info!("{}: loaded {} webhooks", tenant, webhooks.len());
+
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_webhooks = webhooks.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = refresh_webhooks_periodically(cloned_db, cloned_webhooks) => {
+                debug!("webhooks refresh shuting down...");
+            }
+        }
+    });
+
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_webhooks = webhooks.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = deliver_webhook_events(cloned_db, cloned_webhooks, events) => {
+                debug!("webhook deliveries shuting down...");
+            }
+        }
+    });
+
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_webhooks = webhooks.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = disconnect_inactive_devices_periodically(cloned_db, cloned_webhooks) => {
+                debug!("connection check shuting down...");
+            }
+        }
+    });
 
     // Task for Meta queue
     for i in 0..settings.thread_count.meta_queue {
         let cloned_token = token.clone();
         let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
         let cloned_db = db.clone();
+        let cloned_verifier = verifier.clone();
+        let cloned_statuses = statuses.clone();
+        let cloned_webhooks = webhooks.clone();
 
         tokio::spawn(async move {
             tokio::select! {
                 _ = cloned_token.cancelled() => {
                     debug!("The token was shutdown")
                 }
-                _ = start_consuming_topic_queue_meta(i, cloned_amqp, cloned_db) => {
+                _ = start_consuming_topic_queue_meta(i, cloned_tenant, cloned_amqp, cloned_db, cloned_verifier, cloned_statuses, cloned_webhooks) => {
                     debug!("device shuting down...");
                 }
             }
@@ -140,13 +484,17 @@ async fn main() -> Result<(), Error> {
     for i in 0..settings.thread_count.reported_queue {
         let cloned_token = token.clone();
         let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
         let cloned_db = db.clone();
+        let cloned_verifier = verifier.clone();
+        let cloned_statuses = statuses.clone();
+        let cloned_webhooks = webhooks.clone();
         tokio::spawn(async move {
             tokio::select! {
                 _ = cloned_token.cancelled() => {
                     debug!("The token was shutdown")
                 }
-                _ = start_consuming_topic_queue_reported(i, cloned_amqp, cloned_db) => {
+                _ = start_consuming_topic_queue_reported(i, cloned_tenant, cloned_amqp, cloned_db, cloned_verifier, cloned_statuses, cloned_webhooks) => {
                     debug!("device shuting down...");
                 }
             }
@@ -157,81 +505,481 @@ async fn main() -> Result<(), Error> {
     for i in 0..settings.thread_count.desired_queue {
         let cloned_token = token.clone();
         let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
         let cloned_db = db.clone();
+        let cloned_statuses = statuses.clone();
         tokio::spawn(async move {
             tokio::select! {
                 _ = cloned_token.cancelled() => {
                     debug!("The token was shutdown")
                 }
-                _ = start_consuming_topic_queue_desired(i, cloned_amqp, cloned_db) => {
+                _ = start_consuming_topic_queue_desired(i, cloned_tenant, cloned_amqp, cloned_db, cloned_statuses) => {
                     debug!("device shuting down...");
                 }
             }
         });
     }
 
-    // Web Server
-    let shared_state = Arc::new(api::ApiState {
-        amqp: amqp.clone(),
-        db: db.clone(),
+    // Task for Desired error queue, devices rarely reject their desired properties
+    let cloned_token = token.clone();
+    let cloned_amqp = amqp.clone();
+    let cloned_tenant = tenant.to_string();
+    let cloned_db = db.clone();
+    let cloned_verifier = verifier.clone();
+    let cloned_statuses = statuses.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = start_consuming_topic_queue_desired_error(cloned_tenant, cloned_amqp, cloned_db, cloned_verifier, cloned_statuses) => {
+                debug!("device shuting down...");
+            }
+        }
     });
-    let srv = Router::new()
-        .route("/ready", get(ready))
-        .route("/alive", get(alive))
-        .route(
-            "/devicetwins",
-            get(api::get_device_twins)
-                .post(api::create_device_twins)
-                //.put(api::update_device_twins)
-                .delete(api::delete_device_twins),
-        )
-        .route(
-            "/devicetwins/:target",
-            get(api::get_device_twins_properties).put(api::update_device_twins_properties),
-        )
-        .route("/devicetwins/records", get(api::get_records))
-        .with_state(shared_state);
+
+    // Task for Provision queue
+    for i in 0..settings.thread_count.provision_queue {
+        let cloned_token = token.clone();
+        let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
+        let cloned_db = db.clone();
+        let cloned_keys = keys.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cloned_token.cancelled() => {
+                    debug!("The token was shutdown")
+                }
+                _ = start_consuming_topic_queue_provision(i, cloned_tenant, cloned_amqp, cloned_db, cloned_keys) => {
+                    debug!("device shuting down...");
+                }
+            }
+        });
+    }
+
+    // Task for Upload queue, chunks are written to the tenant upload folder
+    let uploads_dir = settings.uploads.dir.join(tenant);
+    for i in 0..settings.uploads.thread_count {
+        let cloned_token = token.clone();
+        let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
+        let cloned_db = db.clone();
+        let cloned_verifier = verifier.clone();
+        let cloned_statuses = statuses.clone();
+        let cloned_settings = settings.uploads.clone();
+        let cloned_dir = uploads_dir.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cloned_token.cancelled() => {
+                    debug!("The token was shutdown")
+                }
+                _ = start_consuming_topic_queue_upload(i, cloned_tenant, cloned_amqp, cloned_db, cloned_verifier, cloned_statuses, cloned_settings, cloned_dir) => {
+                    debug!("device shuting down...");
+                }
+            }
+        });
+    }
+
+    // Task for Artifact queue, devices download the artifacts of their campaign
+    let artifacts_dir = settings.ota.dir.join(tenant);
+    for i in 0..settings.ota.thread_count {
+        let cloned_token = token.clone();
+        let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
+        let cloned_db = db.clone();
+        let cloned_verifier = verifier.clone();
+        let cloned_statuses = statuses.clone();
+        let cloned_settings = settings.ota.clone();
+        let cloned_dir = artifacts_dir.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cloned_token.cancelled() => {
+                    debug!("The token was shutdown")
+                }
+                _ = start_consuming_topic_queue_artifact(i, cloned_tenant, cloned_amqp, cloned_db, cloned_verifier, cloned_statuses, cloned_settings, cloned_dir) => {
+                    debug!("device shuting down...");
+                }
+            }
+        });
+    }
+
+    // Rules from the config and the db, device tags select the rules of a device
+    let alerts = AlertEngine::new(
+        settings
+            .alerts
+            .rules
+            .iter()
+            .cloned()
+            .map(new_alert_rule)
+            .collect(),
+    );
+    refresh_alerts(&db, &alerts).await?;
+    info!("{}: loaded {} alert rules", tenant, alerts.rules().len());
 
     let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_alerts = alerts.clone();
     tokio::spawn(async move {
         tokio::select! {
             _ = cloned_token.cancelled() => {
                 debug!("The token was shutdown")
             }
-            _ = async move {
-                info!("serving Axum on 0.0.0.0:{} 🚀", settings.web_srv_port);
-                axum::Server::bind(&format!("0.0.0.0:{}", settings.web_srv_port).parse().unwrap())
-                    .serve(srv.into_make_service())
-                    .await.unwrap();
-            } => {
-                debug!("device shuting down...");
+            _ = refresh_alerts_periodically(cloned_db, cloned_alerts) => {
+                debug!("alert rules refresh shuting down...");
             }
         }
     });
 
-    match tokio::signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Shutting down...");
-            token.cancel();
+    // Task for Alerts queue
+    for i in 0..settings.alerts.thread_count {
+        let cloned_token = token.clone();
+        let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
+        let cloned_verifier = verifier.clone();
+        let cloned_alerts = alerts.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cloned_token.cancelled() => {
+                    debug!("The token was shutdown")
+                }
+                _ = start_consuming_topic_queue_alerts(i, cloned_tenant, cloned_amqp, cloned_verifier, cloned_alerts) => {
+                    debug!("device shuting down...");
+                }
+            }
+        });
+
+        let cloned_token = token.clone();
+        let cloned_amqp = amqp.clone();
+        let cloned_tenant = tenant.to_string();
+        let cloned_verifier = verifier.clone();
+        let cloned_alerts = alerts.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cloned_token.cancelled() => {
+                    debug!("The token was shutdown")
+                }
+                _ = start_consuming_topic_queue_alerts_batch(i, cloned_tenant, cloned_amqp, cloned_verifier, cloned_alerts) => {
+                    debug!("device shuting down...");
+                }
+            }
+        });
+    }
+
+    Ok(api::ApiState {
+        tenant: tenant.to_string(),
+        amqp,
+        db,
+        keys,
+        statuses,
+        questdb,
+        alerts,
+        webhooks,
+        uploads_dir,
+        artifacts_dir,
+    })
+}
+
+async fn alive() -> String {
+    format!("{}", true)
+}
+
+async fn ready() -> String {
+    format!("{}", true)
+}
+
+async fn refresh_device_keys(db: Surreal<Client>, keys: DeviceKeyCache) {
+    let mut interval = tokio::time::interval(DEVICE_KEY_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match get_device_keys_from_db(&db).await {
+            Ok(x) => keys.replace_all(x),
+            Err(error) => error!("can't refresh device keys: {}", error),
         }
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
+    }
+}
+
+async fn refresh_device_statuses(db: Surreal<Client>, statuses: DeviceStatusCache) {
+    let mut interval = tokio::time::interval(DEVICE_STATUS_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match get_disabled_device_ids_from_db(&db).await {
+            Ok(x) => statuses.replace_all(x),
+            Err(error) => error!("can't refresh device statuses: {}", error),
         }
     }
-    info!("Shutdown complete.");
-    trace!("Exiting...");
+}
+
+async fn refresh_alerts(db: &Surreal<Client>, alerts: &AlertEngine) -> Result<(), Error> {
+    alerts.replace_rules(get_alert_rules_from_db(db).await?);
+    alerts.replace_tags(get_device_tags_from_db(db).await?);
     Ok(())
 }
 
-async fn alive() -> String {
-    format!("{}", true)
+async fn refresh_alerts_periodically(db: Surreal<Client>, alerts: AlertEngine) {
+    let mut interval = tokio::time::interval(ALERT_RULE_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(error) = refresh_alerts(&db, &alerts).await {
+            error!("can't refresh alert rules: {}", error);
+        }
+    }
+}
+
+async fn refresh_webhooks(db: &Surreal<Client>, webhooks: &WebhookDispatcher) -> Result<(), Error> {
+    webhooks.replace_webhooks(get_webhooks_from_db(db, "").await?);
+    webhooks.replace_tags(get_device_tags_from_db(db).await?);
+    Ok(())
+}
+
+// Also drops the deliveries older than the retention
+async fn refresh_webhooks_periodically(db: Surreal<Client>, webhooks: WebhookDispatcher) {
+    let mut interval = tokio::time::interval(WEBHOOK_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(error) = refresh_webhooks(&db, &webhooks).await {
+            error!("can't refresh webhooks: {}", error);
+        }
+        let retention = Duration::from_secs(webhooks.settings().delivery_retention_secs);
+        let before = Utc::now().timestamp_nanos() - retention.as_nanos() as i64;
+        if let Err(error) = prune_webhook_deliveries_in_db(&db, before).await {
+            error!("can't prune webhook deliveries: {}", error);
+        }
+    }
+}
+
+// Devices send a heartbeat every minute, missing a few disconnects them
+async fn disconnect_inactive_devices_periodically(
+    db: Surreal<Client>,
+    webhooks: WebhookDispatcher,
+) {
+    let mut interval = tokio::time::interval(CONNECTION_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let timeout = Duration::from_secs(webhooks.settings().disconnect_timeout_secs);
+        let since = Utc::now().timestamp_nanos() - timeout.as_nanos() as i64;
+        match disconnect_inactive_devices_in_db(&db, since).await {
+            Ok(device_ids) => {
+                for device_id in device_ids {
+                    info!("device '{}' disconnected", device_id);
+                    webhooks.notify(
+                        WebhookEventKind::DeviceDisconnected,
+                        &device_id,
+                        Value::Null,
+                    );
+                }
+            }
+            Err(error) => error!("can't disconnect inactive devices: {}", error),
+        }
+    }
+}
+
+async fn start_consuming_topic_queue_meta(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    db: Surreal<Client>,
+    verifier: Option<DeviceKeyCache>,
+    statuses: DeviceStatusCache,
+    webhooks: WebhookDispatcher,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_TWIN_HEARTHBEAT_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TWIN_HEATHBEAT_ROUTING_KEY);
+    let settings = AmqpSettings {
+        channel: ChannelSettings {
+            prefetch_count: RMQ_PREFETCH_COUNT,
+            options: BasicQosOptions::default(),
+        },
+        exchange: ExchangeSettings {
+            name: RMQ_TWIN_EXCHANGE_NAME,
+            kind: ExchangeKind::Topic,
+            options: ExchangeDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue: QueueSettings {
+            name: queue_name.as_str(),
+            options: QueueDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue_bind: QueueBindSettings {
+            routing_key: routing_key.as_str(),
+            options: QueueBindOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        consumer: ConsumerSettings {
+            consumer_tag: "",
+            options: BasicConsumeOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        verifier,
+    };
+    debug!("{}: Starting...", index);
+    amqp.consume_topic_queue(
+        index,
+        settings,
+        SerializationKind::Json,
+        move |payload, _| receive_hearthbeat_request(db.clone(), &statuses, &webhooks, payload),
+    )
+    .await;
+    debug!("{}: Shutting down...", index);
+}
+
+async fn start_consuming_topic_queue_reported(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    db: Surreal<Client>,
+    verifier: Option<DeviceKeyCache>,
+    statuses: DeviceStatusCache,
+    webhooks: WebhookDispatcher,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_TWIN_REPORTED_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TWIN_REPORTED_ROUTING_KEY);
+    let settings = AmqpSettings {
+        channel: ChannelSettings {
+            prefetch_count: RMQ_PREFETCH_COUNT,
+            options: BasicQosOptions::default(),
+        },
+        exchange: ExchangeSettings {
+            name: RMQ_TWIN_EXCHANGE_NAME,
+            kind: ExchangeKind::Topic,
+            options: ExchangeDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue: QueueSettings {
+            name: queue_name.as_str(),
+            options: QueueDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue_bind: QueueBindSettings {
+            routing_key: routing_key.as_str(),
+            options: QueueBindOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        consumer: ConsumerSettings {
+            consumer_tag: "",
+            options: BasicConsumeOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        verifier,
+    };
+
+    amqp.clone()
+        .consume_topic_queue(
+            index,
+            settings,
+            SerializationKind::Json,
+            move |payload, _| receive_reported_request(db.clone(), &statuses, &webhooks, payload),
+        )
+        .await;
+    debug!("{}: Shutting down...", index);
+}
+
+async fn start_consuming_topic_queue_desired(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    db: Surreal<Client>,
+    statuses: DeviceStatusCache,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_TWIN_DESIRED_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TWIN_DESIRED_ROUTING_KEY);
+    let settings = AmqpSettings {
+        channel: ChannelSettings {
+            prefetch_count: RMQ_PREFETCH_COUNT,
+            options: BasicQosOptions::default(),
+        },
+        exchange: ExchangeSettings {
+            name: RMQ_TWIN_EXCHANGE_NAME,
+            kind: ExchangeKind::Topic,
+            options: ExchangeDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue: QueueSettings {
+            name: queue_name.as_str(),
+            options: QueueDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue_bind: QueueBindSettings {
+            routing_key: routing_key.as_str(),
+            options: QueueBindOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        consumer: ConsumerSettings {
+            consumer_tag: "",
+            options: BasicConsumeOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        verifier: None,
+    };
+
+    amqp.clone()
+        .consume_topic_queue(
+            index,
+            settings,
+            SerializationKind::Json,
+            move |payload, reply_to| {
+                receive_desired_request(db.clone(), amqp.clone(), &statuses, payload, reply_to)
+            },
+        )
+        .await;
+    debug!("{}: Shutting down...", index);
 }
 
-async fn ready() -> String {
-    format!("{}", true)
+async fn start_consuming_topic_queue_desired_error(
+    tenant: String,
+    amqp: Amqp,
+    db: Surreal<Client>,
+    verifier: Option<DeviceKeyCache>,
+    statuses: DeviceStatusCache,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_TWIN_DESIRED_ERROR_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TWIN_DESIRED_ERROR_ROUTING_KEY);
+    let settings = AmqpSettings {
+        channel: ChannelSettings {
+            prefetch_count: RMQ_PREFETCH_COUNT,
+            options: BasicQosOptions::default(),
+        },
+        exchange: ExchangeSettings {
+            name: RMQ_TWIN_EXCHANGE_NAME,
+            kind: ExchangeKind::Topic,
+            options: ExchangeDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue: QueueSettings {
+            name: queue_name.as_str(),
+            options: QueueDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue_bind: QueueBindSettings {
+            routing_key: routing_key.as_str(),
+            options: QueueBindOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        consumer: ConsumerSettings {
+            consumer_tag: "",
+            options: BasicConsumeOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        verifier,
+    };
+
+    amqp.clone()
+        .consume_topic_queue(0, settings, SerializationKind::Json, move |payload, _| {
+            receive_desired_error_request(db.clone(), &statuses, payload)
+        })
+        .await;
+    debug!("Shutting down desired error consumer...");
 }
 
-async fn start_consuming_topic_queue_meta(index: usize, amqp: Amqp, db: Surreal<Client>) {
+async fn start_consuming_topic_queue_provision(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    db: Surreal<Client>,
+    keys: DeviceKeyCache,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_TWIN_PROVISION_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TWIN_PROVISION_ROUTING_KEY);
     let settings = AmqpSettings {
         channel: ChannelSettings {
             prefetch_count: RMQ_PREFETCH_COUNT,
@@ -244,12 +992,12 @@ async fn start_consuming_topic_queue_meta(index: usize, amqp: Amqp, db: Surreal<
             arguments: FieldTable::default(),
         },
         queue: QueueSettings {
-            name: RMQ_TWIN_HEARTHBEAT_QUEUE_NAME,
+            name: queue_name.as_str(),
             options: QueueDeclareOptions::default(),
             arguments: FieldTable::default(),
         },
         queue_bind: QueueBindSettings {
-            routing_key: RMQ_TWIN_HEATHBEAT_ROUTING_KEY,
+            routing_key: routing_key.as_str(),
             options: QueueBindOptions::default(),
             arguments: FieldTable::default(),
         },
@@ -258,19 +1006,42 @@ async fn start_consuming_topic_queue_meta(index: usize, amqp: Amqp, db: Surreal<
             options: BasicConsumeOptions::default(),
             arguments: FieldTable::default(),
         },
+        // Devices have no key yet, the request carries its own attestation
+        verifier: None,
     };
-    debug!("{}: Starting...", index);
-    amqp.consume_topic_queue(
-        index,
-        settings,
-        SerializationKind::Json,
-        move |payload, _| receive_hearthbeat_request(db.clone(), payload),
-    )
-    .await;
+
+    amqp.clone()
+        .consume_topic_queue(
+            index,
+            settings,
+            SerializationKind::Json,
+            move |payload, reply_to| {
+                receive_provision_request(
+                    db.clone(),
+                    amqp.clone(),
+                    keys.clone(),
+                    payload,
+                    reply_to,
+                )
+            },
+        )
+        .await;
     debug!("{}: Shutting down...", index);
 }
 
-async fn start_consuming_topic_queue_reported(index: usize, amqp: Amqp, db: Surreal<Client>) {
+#[allow(clippy::too_many_arguments)]
+async fn start_consuming_topic_queue_upload(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    db: Surreal<Client>,
+    verifier: Option<DeviceKeyCache>,
+    statuses: DeviceStatusCache,
+    upload_settings: UploadSettings,
+    dir: PathBuf,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_TWIN_UPLOAD_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TWIN_UPLOAD_ROUTING_KEY);
     let settings = AmqpSettings {
         channel: ChannelSettings {
             prefetch_count: RMQ_PREFETCH_COUNT,
@@ -283,12 +1054,12 @@ async fn start_consuming_topic_queue_reported(index: usize, amqp: Amqp, db: Surr
             arguments: FieldTable::default(),
         },
         queue: QueueSettings {
-            name: RMQ_TWIN_REPORTED_QUEUE_NAME,
+            name: queue_name.as_str(),
             options: QueueDeclareOptions::default(),
             arguments: FieldTable::default(),
         },
         queue_bind: QueueBindSettings {
-            routing_key: RMQ_TWIN_REPORTED_ROUTING_KEY,
+            routing_key: routing_key.as_str(),
             options: QueueBindOptions::default(),
             arguments: FieldTable::default(),
         },
@@ -297,6 +1068,7 @@ async fn start_consuming_topic_queue_reported(index: usize, amqp: Amqp, db: Surr
             options: BasicConsumeOptions::default(),
             arguments: FieldTable::default(),
         },
+        verifier,
     };
 
     amqp.clone()
@@ -304,13 +1076,35 @@ async fn start_consuming_topic_queue_reported(index: usize, amqp: Amqp, db: Surr
             index,
             settings,
             SerializationKind::Json,
-            move |payload, _| receive_reported_request(db.clone(), payload),
+            move |payload, reply_to| {
+                receive_upload_chunk_request(
+                    db.clone(),
+                    amqp.clone(),
+                    &statuses,
+                    upload_settings.clone(),
+                    dir.clone(),
+                    payload,
+                    reply_to,
+                )
+            },
         )
         .await;
     debug!("{}: Shutting down...", index);
 }
 
-async fn start_consuming_topic_queue_desired(index: usize, amqp: Amqp, db: Surreal<Client>) {
+#[allow(clippy::too_many_arguments)]
+async fn start_consuming_topic_queue_artifact(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    db: Surreal<Client>,
+    verifier: Option<DeviceKeyCache>,
+    statuses: DeviceStatusCache,
+    ota_settings: OtaSettings,
+    dir: PathBuf,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_TWIN_ARTIFACT_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TWIN_ARTIFACT_ROUTING_KEY);
     let settings = AmqpSettings {
         channel: ChannelSettings {
             prefetch_count: RMQ_PREFETCH_COUNT,
@@ -323,12 +1117,12 @@ async fn start_consuming_topic_queue_desired(index: usize, amqp: Amqp, db: Surre
             arguments: FieldTable::default(),
         },
         queue: QueueSettings {
-            name: RMQ_TWIN_DESIRED_QUEUE_NAME,
+            name: queue_name.as_str(),
             options: QueueDeclareOptions::default(),
             arguments: FieldTable::default(),
         },
         queue_bind: QueueBindSettings {
-            routing_key: RMQ_TWIN_DESIRED_ROUTING_KEY,
+            routing_key: routing_key.as_str(),
             options: QueueBindOptions::default(),
             arguments: FieldTable::default(),
         },
@@ -337,6 +1131,7 @@ async fn start_consuming_topic_queue_desired(index: usize, amqp: Amqp, db: Surre
             options: BasicConsumeOptions::default(),
             arguments: FieldTable::default(),
         },
+        verifier,
     };
 
     amqp.clone()
@@ -345,22 +1140,158 @@ async fn start_consuming_topic_queue_desired(index: usize, amqp: Amqp, db: Surre
             settings,
             SerializationKind::Json,
             move |payload, reply_to| {
-                receive_desired_request(db.clone(), amqp.clone(), payload, reply_to)
+                receive_artifact_chunk_request(
+                    db.clone(),
+                    amqp.clone(),
+                    &statuses,
+                    ota_settings.clone(),
+                    dir.clone(),
+                    payload,
+                    reply_to,
+                )
+            },
+        )
+        .await;
+    debug!("{}: Shutting down...", index);
+}
+
+// Binds its own queue on the telemetry stream, flux keeps consuming its copy
+async fn start_consuming_topic_queue_alerts(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    verifier: Option<DeviceKeyCache>,
+    alerts: AlertEngine,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_ALERTS_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TELEMETRY_ROUTING_KEY);
+    let settings = alerts_amqp_settings(&queue_name, &routing_key, verifier);
+
+    amqp.clone()
+        .consume_topic_queue(
+            index,
+            settings,
+            SerializationKind::Json,
+            move |payload: DeviceTelemetryRequest, _| {
+                publish_alert_events(amqp.clone(), &tenant, alerts.evaluate(&payload))
+            },
+        )
+        .await;
+    debug!("{}: Shutting down...", index);
+}
+
+// Devices batching their telemetry publish it on another routing key
+async fn start_consuming_topic_queue_alerts_batch(
+    index: usize,
+    tenant: String,
+    amqp: Amqp,
+    verifier: Option<DeviceKeyCache>,
+    alerts: AlertEngine,
+) {
+    let queue_name = tenant_queue(&tenant, RMQ_ALERTS_BATCH_QUEUE_NAME);
+    let routing_key = tenant_routing_key(&tenant, RMQ_TELEMETRY_BATCH_ROUTING_KEY);
+    let settings = alerts_amqp_settings(&queue_name, &routing_key, verifier);
+
+    amqp.clone()
+        .consume_topic_queue(
+            index,
+            settings,
+            SerializationKind::Json,
+            move |payload: DeviceTelemetryBatchRequest, _| {
+                publish_alert_events(amqp.clone(), &tenant, alerts.evaluate_batch(&payload))
             },
         )
         .await;
     debug!("{}: Shutting down...", index);
 }
 
+// Queues of their own on the telemetry stream, flux keeps consuming its copy
+fn alerts_amqp_settings<'a>(
+    queue_name: &'a str,
+    routing_key: &'a str,
+    verifier: Option<DeviceKeyCache>,
+) -> AmqpSettings<'a> {
+    AmqpSettings {
+        channel: ChannelSettings {
+            prefetch_count: RMQ_PREFETCH_COUNT,
+            options: BasicQosOptions::default(),
+        },
+        exchange: ExchangeSettings {
+            name: RMQ_STREAM_EXCHANGE_NAME,
+            kind: ExchangeKind::Topic,
+            options: ExchangeDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue: QueueSettings {
+            name: queue_name,
+            options: QueueDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue_bind: QueueBindSettings {
+            routing_key,
+            options: QueueBindOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        consumer: ConsumerSettings {
+            consumer_tag: "",
+            options: BasicConsumeOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        verifier,
+    }
+}
+
+// Messages of devices that are not enabled are acknowledged and dropped
+fn drop_if_disabled(statuses: &DeviceStatusCache, device_id: &str, kind: &str) -> bool {
+    if statuses.is_enabled(device_id) {
+        return false;
+    }
+    let dropped = statuses.count_dropped();
+    warn!(
+        "dropped {} message from disabled device '{}', {} dropped so far",
+        kind, device_id, dropped
+    );
+    true
+}
+
 fn receive_hearthbeat_request(
     db: Surreal<Client>,
+    statuses: &DeviceStatusCache,
+    webhooks: &WebhookDispatcher,
     payload: DeviceHeartbeatRequest,
 ) -> Result<(), Error> {
+    if drop_if_disabled(statuses, &payload.device_id, "hearthbeat") {
+        return Ok(());
+    }
     let device_id = payload.device_id.clone();
-    let ts = payload.timestamp.clone();
+    // Time of reception, a device clock running late would disconnect it
+    let ts = Utc::now().timestamp_nanos();
+    let webhooks = webhooks.clone();
+    let state = if payload.disconnecting {
+        ConnectionState::Disconnected
+    } else {
+        ConnectionState::Connected
+    };
+    let metadata = payload.metadata.clone();
     tokio::spawn(async move {
         // TODO: retry logic
-        let _ = update_hearthbeat_in_db(db, device_id, ts).await;
+        let previous = update_hearthbeat_in_db(db, device_id.clone(), ts, state, metadata).await;
+        if let Ok(Some(twin)) = previous {
+            let was_connected = twin.meta_properties.map_or(false, |x| {
+                matches!(x.connection_state, ConnectionState::Connected)
+            });
+            if payload.disconnecting && was_connected {
+                info!("device '{}' disconnected", device_id);
+                webhooks.notify(
+                    WebhookEventKind::DeviceDisconnected,
+                    &device_id,
+                    Value::Null,
+                );
+            } else if !payload.disconnecting && !was_connected {
+                info!("device '{}' connected", device_id);
+                webhooks.notify(WebhookEventKind::DeviceConnected, &device_id, Value::Null);
+            }
+        }
         //match resp {
         //    Ok(x) => debug!(
         //        "Updated hearthbeat for device '{}': {:?}",
@@ -383,9 +1314,13 @@ fn receive_hearthbeat_request(
 fn receive_desired_request(
     db: Surreal<Client>,
     amqp: Amqp,
+    statuses: &DeviceStatusCache,
     payload: DeviceDesiredRequest,
     reply_to: Option<ShortString>,
 ) -> Result<(), Error> {
+    if drop_if_disabled(statuses, &payload.device_id, "desired") {
+        return Ok(());
+    }
     let device_id = payload.device_id.clone();
     let _ts = payload.timestamp.clone();
     tokio::spawn(async move {
@@ -435,12 +1370,179 @@ fn receive_desired_request(
     Ok(())
 }
 
+fn receive_provision_request(
+    db: Surreal<Client>,
+    amqp: Amqp,
+    keys: DeviceKeyCache,
+    payload: DeviceProvisionRequest,
+    reply_to: Option<ShortString>,
+) -> Result<(), Error> {
+    tokio::spawn(async move {
+        let reply_queue = match reply_to {
+            Some(x) if !x.as_str().is_empty() => x,
+            _ => {
+                error!("No reply_to specified");
+                return;
+            }
+        };
+
+        // Always reply so the device does not wait for its timeout
+        let resp = match provision_device_in_db(&db, &keys, &payload).await {
+            Ok(x) => {
+                info!(
+                    "provisioned '{}' from group '{}'",
+                    x.device_id, payload.group_id
+                );
+                x
+            }
+            Err(error) => {
+                warn!(
+                    "can't provision '{}' from group '{}': {}",
+                    payload.registration_id, payload.group_id, error
+                );
+                DeviceProvisionResponse {
+                    error: error.to_string(),
+                    ..Default::default()
+                }
+            }
+        };
+
+        let str_resp = serde_json::to_string(&resp).unwrap();
+        if let Err(e) = amqp
+            .send_message(&str_resp, "", reply_queue.as_str())
+            .await
+        {
+            error!("{:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn receive_upload_chunk_request(
+    db: Surreal<Client>,
+    amqp: Amqp,
+    statuses: &DeviceStatusCache,
+    settings: UploadSettings,
+    dir: PathBuf,
+    payload: DeviceUploadChunkRequest,
+    reply_to: Option<ShortString>,
+) -> Result<(), Error> {
+    if drop_if_disabled(statuses, &payload.device_id, "upload") {
+        return Ok(());
+    }
+    tokio::spawn(async move {
+        let reply_queue = match reply_to {
+            Some(x) if !x.as_str().is_empty() => x,
+            _ => {
+                error!("No reply_to specified");
+                return;
+            }
+        };
+
+        // Always reply so the device does not wait for its timeout
+        let resp = match receive_upload_chunk_in_db(&db, &settings, &dir, &payload).await {
+            Ok(x) => {
+                if !x.error.is_empty() {
+                    warn!(
+                        "upload '{}' of '{}': {}",
+                        payload.name, payload.device_id, x.error
+                    );
+                } else if payload.file_checksum.is_some() {
+                    info!(
+                        "'{}' uploaded '{}', {} bytes",
+                        payload.device_id, payload.name, x.offset
+                    );
+                }
+                x
+            }
+            Err(error) => {
+                warn!(
+                    "can't store upload '{}' of '{}': {}",
+                    payload.name, payload.device_id, error
+                );
+                DeviceUploadChunkResponse {
+                    upload_id: payload.upload_id.clone(),
+                    error: error.to_string(),
+                    ..Default::default()
+                }
+            }
+        };
+
+        let str_resp = serde_json::to_string(&resp).unwrap();
+        if let Err(e) = amqp
+            .send_message(&str_resp, "", reply_queue.as_str())
+            .await
+        {
+            error!("{:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn receive_artifact_chunk_request(
+    db: Surreal<Client>,
+    amqp: Amqp,
+    statuses: &DeviceStatusCache,
+    settings: OtaSettings,
+    dir: PathBuf,
+    payload: DeviceArtifactChunkRequest,
+    reply_to: Option<ShortString>,
+) -> Result<(), Error> {
+    if drop_if_disabled(statuses, &payload.device_id, "artifact") {
+        return Ok(());
+    }
+    tokio::spawn(async move {
+        let reply_queue = match reply_to {
+            Some(x) if !x.as_str().is_empty() => x,
+            _ => {
+                error!("No reply_to specified");
+                return;
+            }
+        };
+
+        // Always reply so the device does not wait for its timeout
+        let resp = match read_artifact_chunk(&db, &settings, &dir, &payload).await {
+            Ok(x) => x,
+            Err(error) => {
+                warn!(
+                    "can't send artifact '{}' to '{}': {}",
+                    payload.artifact_id, payload.device_id, error
+                );
+                DeviceArtifactChunkResponse {
+                    artifact_id: payload.artifact_id.clone(),
+                    offset: payload.offset,
+                    error: error.to_string(),
+                    ..Default::default()
+                }
+            }
+        };
+
+        let str_resp = serde_json::to_string(&resp).unwrap();
+        if let Err(e) = amqp
+            .send_message(&str_resp, "", reply_queue.as_str())
+            .await
+        {
+            error!("{:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
 fn receive_reported_request(
     db: Surreal<Client>,
+    statuses: &DeviceStatusCache,
+    webhooks: &WebhookDispatcher,
     payload: DeviceReportedRequest,
 ) -> Result<(), Error> {
+    if drop_if_disabled(statuses, &payload.device_id, "reported") {
+        return Ok(());
+    }
     let device_id = payload.device_id.clone();
     let _ts = payload.timestamp.clone();
+    let webhooks = webhooks.clone();
     tokio::spawn(async move {
         // TODO: retry logic
 
@@ -457,14 +1559,82 @@ fn receive_reported_request(
             StatusCode::INTERNAL_SERVER_ERROR
         });
 
-        let _ = if let Err(_) = updated_twin_result {
+        let updated = if let Err(_) = updated_twin_result {
             //return Ok(Json(json!({ "result": 200 })));
             // TODO: proper return when surrealdb is fixed
             None
         } else {
             updated_twin_result.unwrap()
         };
+        if updated.is_some() {
+            webhooks.notify(
+                WebhookEventKind::ReportedUpdated,
+                &device_id,
+                json!(payload.reported_properties),
+            );
+        }
+    });
+
+    Ok(())
+}
+
+// Kept on the twin until the device reports another error
+fn receive_desired_error_request(
+    db: Surreal<Client>,
+    statuses: &DeviceStatusCache,
+    payload: DeviceDesiredErrorRequest,
+) -> Result<(), Error> {
+    if drop_if_disabled(statuses, &payload.device_id, "desired error") {
+        return Ok(());
+    }
+    warn!(
+        "'{}' can't apply desired properties version {}: {}",
+        payload.device_id, payload.version, payload.error
+    );
+    tokio::spawn(async move {
+        let desired_error = DesiredError {
+            version: payload.version,
+            error: payload.error,
+            timestamp: payload.timestamp,
+        };
+        if let Err(error) = update_desired_error_in_db(&db, &payload.device_id, desired_error).await
+        {
+            error!("can't store desired error: {}", error);
+        }
+    });
+
+    Ok(())
+}
+
+fn publish_alert_events(amqp: Amqp, tenant: &str, events: Vec<AlertEvent>) -> Result<(), Error> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let tenant = tenant.to_string();
+    tokio::spawn(async move {
+        for event in events {
+            publish_alert_event(&amqp, &tenant, &event).await;
+        }
     });
 
     Ok(())
 }
+
+// ex: iot.alert.fired.v1
+async fn publish_alert_event(amqp: &Amqp, tenant: &str, event: &AlertEvent) {
+    info!(
+        "alert {} {} on '{}', value {}",
+        event.alert_id,
+        event.kind.as_str(),
+        event.device_id,
+        event.value
+    );
+    let routing_key = tenant_routing_key(tenant, &format!("alert.{}.v1", event.kind.as_str()));
+    let str_event = serde_json::to_string(event).unwrap();
+    if let Err(e) = amqp
+        .send_message(&str_event, RMQ_ALERTS_EXCHANGE_NAME, &routing_key)
+        .await
+    {
+        error!("{:?}", e);
+    }
+}
@@ -0,0 +1,189 @@
+use chrono::Utc;
+use jsonschema::JSONSchema;
+use libs::models::device_model::{DeviceModel, NewDeviceModelReq};
+use libs::models::device_twin::{TargetProperties, RESERVED_DESIRED_KEY};
+use serde_json::Value;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::twin_service::TwinServiceError;
+
+// Every version of a model, or the latest version of each model
+pub async fn get_device_models_from_db(
+    db: &Surreal<Client>,
+    model_id: &str,
+) -> Result<Vec<DeviceModel>, TwinServiceError> {
+    if !model_id.is_empty() {
+        let mut results = db
+            .query("SELECT * FROM device_model WHERE model_id = $model_id ORDER BY version")
+            .bind(("model_id", model_id))
+            .await?;
+        let models: Vec<DeviceModel> = results.take(0)?;
+        return Ok(models);
+    }
+
+    let mut results = db
+        .query("SELECT * FROM device_model ORDER BY model_id, version DESC")
+        .await?;
+    let mut models: Vec<DeviceModel> = results.take(0)?;
+    models.dedup_by(|x, latest| x.model_id == latest.model_id);
+    Ok(models)
+}
+
+pub async fn get_device_model_from_db(
+    db: &Surreal<Client>,
+    model_id: &str,
+) -> Result<Option<DeviceModel>, TwinServiceError> {
+    let mut results = db
+        .query(
+            "SELECT * FROM device_model WHERE model_id = $model_id ORDER BY version DESC LIMIT 1",
+        )
+        .bind(("model_id", model_id))
+        .await?;
+    let model: Option<DeviceModel> = results.take(0)?;
+    Ok(model)
+}
+
+pub async fn get_device_model_version_from_db(
+    db: &Surreal<Client>,
+    model_id: &str,
+    version: usize,
+) -> Result<Option<DeviceModel>, TwinServiceError> {
+    let mut results = db
+        .query("SELECT * FROM type::thing('device_model', [$model_id, $version])")
+        .bind(("model_id", model_id))
+        .bind(("version", version))
+        .await?;
+    let model: Option<DeviceModel> = results.take(0)?;
+    Ok(model)
+}
+
+// Creating a model that already exists publishes a new version of it,
+// each version is its own record so twins pinned to older ones keep them
+pub async fn create_device_model_in_db(
+    db: &Surreal<Client>,
+    payload: NewDeviceModelReq,
+) -> Result<Option<DeviceModel>, TwinServiceError> {
+    for schema in [
+        &payload.desired_schema,
+        &payload.reported_schema,
+        &payload.commands_schema,
+    ] {
+        compile_schema(schema)?;
+    }
+
+    let current = get_device_model_from_db(db, payload.model_id.as_str()).await?;
+    let model = DeviceModel {
+        id: None,
+        model_id: payload.model_id,
+        version: current.as_ref().map_or(1, |x| x.version + 1),
+        description: payload.description,
+        sensors: payload.sensors,
+        desired_schema: payload.desired_schema,
+        reported_schema: payload.reported_schema,
+        commands_schema: payload.commands_schema,
+        update_time: Utc::now().timestamp_nanos(),
+    };
+
+    // Fails when the same version was published concurrently
+    let mut results = db
+        .query("CREATE type::thing('device_model', [$model_id, $version]) CONTENT $model")
+        .bind(("model_id", model.model_id.clone()))
+        .bind(("version", model.version))
+        .bind(("model", model))
+        .await?
+        .check()?;
+    let saved: Option<DeviceModel> = results.take(0)?;
+    Ok(saved)
+}
+
+// Deletes every version, returns the latest one
+pub async fn delete_device_model_in_db(
+    db: &Surreal<Client>,
+    model_id: &str,
+) -> Result<Option<DeviceModel>, TwinServiceError> {
+    let mut results = db
+        .query("DELETE device_model WHERE model_id = $model_id RETURN BEFORE")
+        .bind(("model_id", model_id))
+        .await?;
+    let deleted: Vec<DeviceModel> = results.take(0)?;
+    Ok(deleted.into_iter().max_by_key(|x| x.version))
+}
+
+// Version a new twin of the model is pinned to, 0 when it is not registered
+pub async fn get_latest_model_version_from_db(
+    db: &Surreal<Client>,
+    model_id: &str,
+) -> Result<usize, TwinServiceError> {
+    if model_id.is_empty() {
+        return Ok(0);
+    }
+    Ok(get_device_model_from_db(db, model_id)
+        .await?
+        .map_or(0, |x| x.version))
+}
+
+// Devices whose model is not registered are not validated. Version 0
+// follows the latest version of the model.
+pub async fn validate_properties_in_db(
+    db: &Surreal<Client>,
+    model_id: &str,
+    version: usize,
+    target: &TargetProperties,
+    properties: &Value,
+) -> Result<(), TwinServiceError> {
+    if model_id.is_empty() {
+        return Ok(());
+    }
+    let model = if version == 0 {
+        get_device_model_from_db(db, model_id).await?
+    } else {
+        get_device_model_version_from_db(db, model_id, version).await?
+    };
+    match model {
+        Some(model) => validate_properties(&model, target, properties),
+        None => Ok(()),
+    }
+}
+
+pub fn validate_properties(
+    model: &DeviceModel,
+    target: &TargetProperties,
+    properties: &Value,
+) -> Result<(), TwinServiceError> {
+    let schema = match target {
+        TargetProperties::Desired => &model.desired_schema,
+        TargetProperties::Reported => &model.reported_schema,
+        _ => return Ok(()),
+    };
+    let compiled = match compile_schema(schema)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    // Oxi settings are not part of the device model
+    let mut properties = properties.clone();
+    if let Some(x) = properties.as_object_mut() {
+        x.remove(RESERVED_DESIRED_KEY);
+    }
+
+    if let Err(errors) = compiled.validate(&properties) {
+        let errors: Vec<String> = errors
+            .map(|e| format!("'{}' {}", e.instance_path, e))
+            .collect();
+        return Err(TwinServiceError::InvalidProperties(
+            model.model_id.clone(),
+            model.version,
+            errors.join(", "),
+        ));
+    }
+    Ok(())
+}
+
+fn compile_schema(schema: &Value) -> Result<Option<JSONSchema>, TwinServiceError> {
+    if schema.is_null() {
+        return Ok(None);
+    }
+    JSONSchema::compile(schema)
+        .map(Some)
+        .map_err(|e| TwinServiceError::InvalidSchema(e.to_string()))
+}
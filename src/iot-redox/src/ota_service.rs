@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use libs::models::device_twin::{DeviceTwin, Properties, TargetProperties, RESERVED_DESIRED_KEY};
+use libs::models::ota::{
+    Artifact, Campaign, CampaignDeviceStatus, CampaignStatus, DeviceArtifactChunkRequest,
+    DeviceArtifactChunkResponse, FirmwareDesired, FirmwareReported, NewCampaignReq, FIRMWARE_KEY,
+};
+use libs::utils::checksum::checksum;
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use surrealdb::{engine::remote::ws::Client, Surreal};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::twin_service::{
+    get_device_twins_with_id_from_db, update_device_twins_properties_in_db, TwinServiceError,
+};
+
+// Artifacts are stored in a folder per tenant, named after their id
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OtaSettings {
+    pub dir: PathBuf,
+    pub thread_count: usize,
+    // Body limit of the artifact registration
+    pub max_artifact_bytes: usize,
+    // Devices asking for more get less
+    pub max_chunk_bytes: u64,
+}
+
+impl Default for OtaSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./artifacts"),
+            thread_count: 1,
+            max_artifact_bytes: 256 * 1024 * 1024,
+            max_chunk_bytes: 256 * 1024,
+        }
+    }
+}
+
+pub fn artifact_path(dir: &Path, artifact_id: &str) -> PathBuf {
+    dir.join(artifact_id)
+}
+
+pub async fn get_artifacts_from_db(
+    db: &Surreal<Client>,
+    artifact_id: &str,
+) -> Result<Vec<Artifact>, TwinServiceError> {
+    if !artifact_id.is_empty() {
+        let artifact: Option<Artifact> = db.select(("artifact", artifact_id)).await?;
+        return Ok(artifact.into_iter().collect());
+    }
+
+    let artifacts: Vec<Artifact> = db.select("artifact").await?;
+    Ok(artifacts)
+}
+
+// Artifacts can't be replaced, a device could be downloading it. The
+// checksum given by the operator is verified when set.
+pub async fn create_artifact_in_db(
+    db: &Surreal<Client>,
+    dir: &Path,
+    artifact_id: &str,
+    version: &str,
+    expected_checksum: &str,
+    content: &[u8],
+) -> Result<Option<Artifact>, TwinServiceError> {
+    if artifact_id.is_empty() || artifact_id.contains(['/', '\\']) || artifact_id.starts_with('.') {
+        return Err(TwinServiceError::Msg(format!(
+            "invalid artifact id '{}'",
+            artifact_id
+        )));
+    }
+    if version.is_empty() {
+        return Err(TwinServiceError::Msg(String::from("missing version")));
+    }
+    let content_checksum = checksum(content);
+    if !expected_checksum.is_empty() && !expected_checksum.eq_ignore_ascii_case(&content_checksum) {
+        return Err(TwinServiceError::Msg(format!(
+            "artifact checksum is {}, expected {}",
+            content_checksum, expected_checksum
+        )));
+    }
+    if !get_artifacts_from_db(db, artifact_id).await?.is_empty() {
+        return Err(TwinServiceError::Msg(format!(
+            "artifact {} already exists",
+            artifact_id
+        )));
+    }
+
+    // The record is created first, it fails when another create won the race
+    let artifact = Artifact {
+        id: None,
+        artifact_id: artifact_id.to_string(),
+        version: version.to_string(),
+        checksum: content_checksum,
+        size: content.len() as u64,
+        create_time: Utc::now().timestamp_nanos(),
+    };
+    let created: Option<Artifact> = db
+        .create(("artifact", artifact_id))
+        .content(artifact)
+        .await?;
+
+    if let Err(error) = write_artifact(dir, artifact_id, content).await {
+        let _: Option<Artifact> = db.delete(("artifact", artifact_id)).await?;
+        return Err(TwinServiceError::Msg(error.to_string()));
+    }
+    Ok(created)
+}
+
+// Written aside then renamed, the file is never seen half written
+async fn write_artifact(dir: &Path, artifact_id: &str, content: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    let tmp = dir.join(format!(".{}.tmp", artifact_id));
+    fs::write(&tmp, content).await?;
+    if let Err(error) = fs::rename(&tmp, artifact_path(dir, artifact_id)).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(error);
+    }
+    Ok(())
+}
+
+pub async fn delete_artifact_in_db(
+    db: &Surreal<Client>,
+    dir: &Path,
+    artifact_id: &str,
+) -> Result<Option<Artifact>, TwinServiceError> {
+    let deleted: Option<Artifact> = db.delete(("artifact", artifact_id)).await?;
+    if deleted.is_some() {
+        let _ = fs::remove_file(artifact_path(dir, artifact_id)).await;
+    }
+    Ok(deleted)
+}
+
+// Empty past the end of the artifact
+pub async fn read_artifact_chunk(
+    db: &Surreal<Client>,
+    settings: &OtaSettings,
+    dir: &Path,
+    payload: &DeviceArtifactChunkRequest,
+) -> Result<DeviceArtifactChunkResponse, TwinServiceError> {
+    let artifact: Option<Artifact> = db
+        .select(("artifact", payload.artifact_id.as_str()))
+        .await?;
+    let artifact = artifact.ok_or(TwinServiceError::RecordNotFound(
+        payload.artifact_id.clone(),
+    ))?;
+
+    let length = payload
+        .length
+        .min(settings.max_chunk_bytes)
+        .min(artifact.size.saturating_sub(payload.offset));
+    let mut content = vec![0; length as usize];
+    if length > 0 {
+        let mut file = File::open(artifact_path(dir, &artifact.artifact_id))
+            .await
+            .map_err(|e| TwinServiceError::Msg(e.to_string()))?;
+        file.seek(SeekFrom::Start(payload.offset))
+            .await
+            .map_err(|e| TwinServiceError::Msg(e.to_string()))?;
+        file.read_exact(&mut content)
+            .await
+            .map_err(|e| TwinServiceError::Msg(e.to_string()))?;
+    }
+
+    let mut response = DeviceArtifactChunkResponse {
+        artifact_id: artifact.artifact_id,
+        offset: payload.offset,
+        size: artifact.size,
+        ..Default::default()
+    };
+    response.set_content(&content);
+    Ok(response)
+}
+
+pub async fn get_campaigns_from_db(
+    db: &Surreal<Client>,
+    campaign_id: &str,
+) -> Result<Vec<Campaign>, TwinServiceError> {
+    if !campaign_id.is_empty() {
+        let campaign: Option<Campaign> = db.select(("campaign", campaign_id)).await?;
+        return Ok(campaign.into_iter().collect());
+    }
+
+    let campaigns: Vec<Campaign> = db.select("campaign").await?;
+    Ok(campaigns)
+}
+
+// Sets the firmware section of the desired properties of every targeted
+// device, returns the campaign and the desired properties to send. Devices
+// whose model rejects the update are left out.
+pub async fn create_campaign_in_db(
+    db: &Surreal<Client>,
+    payload: NewCampaignReq,
+) -> Result<(Campaign, Vec<(String, Properties)>), TwinServiceError> {
+    // Empty ids would list every campaign or artifact
+    if payload.campaign_id.is_empty() {
+        return Err(TwinServiceError::Msg(String::from("missing campaign id")));
+    }
+    if payload.artifact_id.is_empty() {
+        return Err(TwinServiceError::Msg(String::from("missing artifact id")));
+    }
+    let existing: Option<Campaign> = db
+        .select(("campaign", payload.campaign_id.as_str()))
+        .await?;
+    if existing.is_some() {
+        return Err(TwinServiceError::Msg(format!(
+            "campaign {} already exists",
+            payload.campaign_id
+        )));
+    }
+    let artifact: Option<Artifact> = db
+        .select(("artifact", payload.artifact_id.as_str()))
+        .await?;
+    let artifact = artifact.ok_or(TwinServiceError::RecordNotFound(
+        payload.artifact_id.clone(),
+    ))?;
+    let firmware = FirmwareDesired {
+        campaign_id: payload.campaign_id.clone(),
+        artifact_id: artifact.artifact_id.clone(),
+        version: artifact.version.clone(),
+        checksum: artifact.checksum.clone(),
+        size: artifact.size,
+    };
+
+    let twins: Vec<DeviceTwin> = db.select("device_twin").await?;
+    let mut updated = vec![];
+    for twin in twins {
+        let Some(meta) = &twin.meta_properties else {
+            continue;
+        };
+        let tags = twin.tag_properties.as_ref().map(|x| &x.properties);
+        if !payload
+            .target
+            .matches(&meta.device_id, &meta.model_id, tags)
+        {
+            continue;
+        }
+
+        let mut desired = twin.desired_properties.clone().unwrap_or_default();
+        set_firmware(&mut desired.properties, &firmware);
+        desired.version += 1;
+        match update_device_twins_properties_in_db(
+            db.clone(),
+            &meta.device_id,
+            &TargetProperties::Desired,
+            &desired,
+        )
+        .await
+        {
+            // The patch can be applied and still return an error, see
+            // update_device_twins_properties_in_db
+            Ok(_) | Err(TwinServiceError::Msg(_)) => {
+                updated.push((meta.device_id.clone(), desired));
+            }
+            Err(error) => warn!(
+                "campaign {} left out '{}': {}",
+                payload.campaign_id, meta.device_id, error
+            ),
+        }
+    }
+    if updated.is_empty() {
+        return Err(TwinServiceError::Msg(format!(
+            "no device targeted by campaign {}",
+            payload.campaign_id
+        )));
+    }
+
+    let campaign = Campaign {
+        id: None,
+        campaign_id: payload.campaign_id.clone(),
+        artifact_id: artifact.artifact_id,
+        version: artifact.version,
+        target: payload.target,
+        device_ids: updated.iter().map(|(x, _)| x.clone()).collect(),
+        create_time: Utc::now().timestamp_nanos(),
+    };
+    let created: Option<Campaign> = db
+        .create(("campaign", payload.campaign_id.as_str()))
+        .content(campaign.clone())
+        .await?;
+    Ok((created.unwrap_or(campaign), updated))
+}
+
+// States reported by the devices for this campaign, a device that moved on
+// to another campaign or was deleted stays pending
+pub async fn get_campaign_status_from_db(
+    db: &Surreal<Client>,
+    campaign_id: &str,
+) -> Result<Option<CampaignStatus>, TwinServiceError> {
+    let Some(campaign) = get_campaigns_from_db(db, campaign_id).await?.pop() else {
+        return Ok(None);
+    };
+
+    let mut states = HashMap::new();
+    let mut devices = vec![];
+    for device_id in &campaign.device_ids {
+        let reported = get_device_twins_with_id_from_db(db, device_id)
+            .await?
+            .and_then(|x| x.reported_properties)
+            .and_then(|x| FirmwareReported::from_properties(&x.properties))
+            .filter(|x| x.campaign_id == campaign.campaign_id);
+        let status = match reported {
+            Some(x) => {
+                *states.entry(x.state).or_insert(0) += 1;
+                CampaignDeviceStatus {
+                    device_id: device_id.clone(),
+                    state: Some(x.state),
+                    progress: x.progress,
+                    error: x.error,
+                }
+            }
+            None => CampaignDeviceStatus {
+                device_id: device_id.clone(),
+                ..Default::default()
+            },
+        };
+        devices.push(status);
+    }
+
+    Ok(Some(CampaignStatus {
+        campaign_id: campaign.campaign_id,
+        artifact_id: campaign.artifact_id,
+        version: campaign.version,
+        total: devices.len(),
+        pending: devices.iter().filter(|x| x.state.is_none()).count(),
+        states,
+        devices,
+    }))
+}
+
+fn set_firmware(properties: &mut Value, firmware: &FirmwareDesired) {
+    if !properties.is_object() {
+        *properties = json!({});
+    }
+    let reserved = properties
+        .as_object_mut()
+        .unwrap()
+        .entry(RESERVED_DESIRED_KEY)
+        .or_insert_with(|| json!({}));
+    if !reserved.is_object() {
+        *reserved = json!({});
+    }
+    reserved
+        .as_object_mut()
+        .unwrap()
+        .insert(FIRMWARE_KEY.to_string(), json!(firmware));
+}
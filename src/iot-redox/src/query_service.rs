@@ -0,0 +1,277 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use libs::models::device_model::SensorKind;
+use libs::models::telemetry_query::{Aggregation, TelemetryPoint, TelemetryQuery, TelemetrySeries};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error as ThisError;
+
+const DEFAULT_RANGE: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_BUCKET: &str = "1m";
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 10000;
+// Returned by questdb before flux stored the first value of a kind
+const MISSING_TABLE_ERROR: &str = "does not exist";
+
+#[derive(ThisError, Debug)]
+pub enum QueryServiceError {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("questdb error: {0}")]
+    QuestDb(String),
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct QuestDbSettings {
+    // Http endpoint of questdb, the one serving /exec
+    pub addr: String,
+}
+
+impl Default for QuestDbSettings {
+    fn default() -> Self {
+        Self {
+            addr: "http://localhost:9000".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExecResponse {
+    #[serde(default)]
+    dataset: Vec<Vec<Value>>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+// Read side of the datapoints flux writes, over the questdb rest api
+#[derive(Debug, Clone)]
+pub struct QuestDb {
+    addr: String,
+    client: Client,
+}
+
+impl QuestDb {
+    pub fn new(settings: &QuestDbSettings) -> Self {
+        Self {
+            addr: settings.addr.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    async fn exec(&self, sql: &str) -> Result<Vec<Vec<Value>>, QueryServiceError> {
+        let body = self
+            .client
+            .get(format!("{}/exec", self.addr))
+            .query(&[("query", sql)])
+            .send()
+            .await?
+            .bytes()
+            .await?;
+        let response: ExecResponse = serde_json::from_slice(&body)?;
+        match response.error {
+            Some(error) if error.contains(MISSING_TABLE_ERROR) => Ok(vec![]),
+            Some(error) => Err(QueryServiceError::QuestDb(error)),
+            None => Ok(response.dataset),
+        }
+    }
+}
+
+pub async fn query_telemetry(
+    questdb: &QuestDb,
+    tenant: &str,
+    query: TelemetryQuery,
+) -> Result<TelemetrySeries, QueryServiceError> {
+    if query.device_id.is_empty() {
+        return Err(QueryServiceError::InvalidQuery(
+            "device_id is required".to_string(),
+        ));
+    }
+    let to = query.to.unwrap_or_else(|| Utc::now().timestamp_nanos());
+    let from = query
+        .from
+        .unwrap_or(to.saturating_sub(DEFAULT_RANGE.as_nanos() as i64));
+    if from >= to {
+        return Err(QueryServiceError::InvalidQuery(
+            "from must be before to".to_string(),
+        ));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let bucket = match query.aggregation {
+        Some(aggregation) => {
+            if !aggregation.supports(&query.kind) {
+                return Err(QueryServiceError::InvalidQuery(format!(
+                    "{} is not supported on {:?} sensors",
+                    aggregation.as_str(),
+                    query.kind
+                )));
+            }
+            let bucket = query.bucket.clone().unwrap_or(DEFAULT_BUCKET.to_string());
+            validate_bucket(&bucket)?;
+            Some(bucket)
+        }
+        None => None,
+    };
+
+    let mut filters = vec![
+        format!("tenant = {}", quote(tenant)),
+        format!("device_id = {}", quote(&query.device_id)),
+        format!("timestamp >= {}", timestamp_literal(from)),
+        format!("timestamp < {}", timestamp_literal(to)),
+    ];
+    if let Some(sensor_id) = query.sensor_id {
+        filters.push(format!("sensor_id = {}", sensor_id));
+    }
+    // The most recent points are kept when the range holds more than the limit,
+    // they are returned in time order
+    let sql = match (&query.aggregation, &bucket) {
+        (Some(aggregation), Some(bucket)) => format!(
+            "SELECT * FROM (SELECT timestamp, sensor_id, {} value FROM {} WHERE {} SAMPLE BY {} ALIGN TO CALENDAR) ORDER BY timestamp DESC LIMIT {}",
+            aggregate(aggregation),
+            table(&query.kind),
+            filters.join(" AND "),
+            bucket,
+            limit
+        ),
+        _ => format!(
+            "SELECT timestamp, sensor_id, value FROM {} WHERE {} ORDER BY timestamp DESC LIMIT {}",
+            table(&query.kind),
+            filters.join(" AND "),
+            limit
+        ),
+    };
+
+    let mut points: Vec<TelemetryPoint> = questdb
+        .exec(&sql)
+        .await?
+        .into_iter()
+        .filter_map(|row| {
+            let [timestamp, sensor_id, value]: [Value; 3] = row.try_into().ok()?;
+            Some(TelemetryPoint {
+                timestamp: DateTime::parse_from_rfc3339(timestamp.as_str()?)
+                    .ok()?
+                    .timestamp_nanos(),
+                sensor_id: sensor_id.as_i64()?,
+                value,
+            })
+        })
+        .collect();
+    points.reverse();
+
+    Ok(TelemetrySeries {
+        device_id: query.device_id,
+        from,
+        to,
+        aggregation: query.aggregation,
+        bucket,
+        points,
+    })
+}
+
+// Tables written by flux, one per kind of value
+fn table(kind: &SensorKind) -> &str {
+    match kind {
+        SensorKind::Float => "Datapoint",
+        SensorKind::Int => "DatapointInt",
+        SensorKind::Bool => "DatapointBool",
+        SensorKind::String => "DatapointString",
+    }
+}
+
+fn aggregate(aggregation: &Aggregation) -> String {
+    match aggregation {
+        Aggregation::Count => "count()".to_string(),
+        x => format!("{}(value)", x.as_str()),
+    }
+}
+
+// A number followed by a unit questdb understands in SAMPLE BY
+fn validate_bucket(bucket: &str) -> Result<(), QueryServiceError> {
+    let valid = bucket.is_ascii() && {
+        let (count, unit) = bucket.split_at(bucket.len().saturating_sub(1));
+        // parse alone would take a sign
+        matches!(unit, "s" | "m" | "h" | "d")
+            && count.bytes().all(|x| x.is_ascii_digit())
+            && count.parse::<u32>().map_or(false, |x| x > 0)
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(QueryServiceError::InvalidQuery(format!(
+            "invalid bucket '{}', expected a number followed by s, m, h or d",
+            bucket
+        )))
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// Questdb stores microseconds
+fn timestamp_literal(nanos: i64) -> String {
+    format!(
+        "'{}'",
+        Utc.timestamp_nanos(nanos).format("%Y-%m-%dT%H:%M:%S%.6fZ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_is_a_count_and_a_unit() {
+        for bucket in ["1s", "30s", "5m", "1h", "7d", "120m"] {
+            assert!(validate_bucket(bucket).is_ok(), "{}", bucket);
+        }
+    }
+
+    #[test]
+    fn invalid_buckets_are_rejected() {
+        for bucket in [
+            "",
+            "m",
+            "0m",
+            "-1m",
+            "+5m",
+            "5",
+            "5w",
+            "5 m",
+            "1.5h",
+            "5mm",
+            "99999999999s",
+            "5m; DROP",
+            "５m",
+        ] {
+            assert!(
+                matches!(
+                    validate_bucket(bucket),
+                    Err(QueryServiceError::InvalidQuery(_))
+                ),
+                "{}",
+                bucket
+            );
+        }
+    }
+
+    #[test]
+    fn quote_escapes_single_quotes() {
+        assert_eq!(quote("dev-1"), "'dev-1'");
+        assert_eq!(quote("a' OR '1'='1"), "'a'' OR ''1''=''1'");
+    }
+
+    #[test]
+    fn timestamp_literal_is_in_microseconds() {
+        assert_eq!(
+            timestamp_literal(1_700_000_000_123_456_789),
+            "'2023-11-14T22:13:20.123456Z'"
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use chrono::Utc;
+use libs::models::route::{NewRouteReq, Route, RouteDestination};
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::twin_service::TwinServiceError;
+
+// Exchanges the router consumes, forwarding to them would loop messages
+const SOURCE_EXCHANGES: [&str; 2] = ["iot-stream", "iot-twin"];
+
+pub async fn get_routes_from_db(
+    db: &Surreal<Client>,
+    route_id: &str,
+) -> Result<Vec<Route>, TwinServiceError> {
+    if !route_id.is_empty() {
+        let route: Option<Route> = db.select(("route", route_id)).await?;
+        return Ok(route.into_iter().collect());
+    }
+
+    let routes: Vec<Route> = db.select("route").await?;
+    Ok(routes)
+}
+
+// Creating a route that already exists replaces it
+pub async fn create_route_in_db(
+    db: &Surreal<Client>,
+    payload: NewRouteReq,
+) -> Result<Option<Route>, TwinServiceError> {
+    validate_route(&payload)?;
+
+    let route_id = payload.route_id.clone();
+    let current: Option<Route> = db.select(("route", route_id.as_str())).await?;
+    let route = Route {
+        id: None,
+        route_id: payload.route_id,
+        description: payload.description,
+        routing_key: payload.routing_key,
+        tags: payload.tags,
+        predicate: payload.predicate,
+        destination: payload.destination,
+        enabled: payload.enabled,
+        update_time: Utc::now().timestamp_nanos(),
+    };
+
+    let saved: Option<Route> = if current.is_some() {
+        db.update(("route", route_id.as_str()))
+            .content(route)
+            .await?
+    } else {
+        db.create(("route", route_id.as_str()))
+            .content(route)
+            .await?
+    };
+    Ok(saved)
+}
+
+pub async fn delete_route_in_db(
+    db: &Surreal<Client>,
+    route_id: &str,
+) -> Result<Option<Route>, TwinServiceError> {
+    let deleted: Option<Route> = db.delete(("route", route_id)).await?;
+    Ok(deleted)
+}
+
+fn validate_route(payload: &NewRouteReq) -> Result<(), TwinServiceError> {
+    if payload.route_id.is_empty() {
+        return Err(TwinServiceError::Msg("route_id is required".to_string()));
+    }
+    if payload.routing_key.is_empty() {
+        return Err(TwinServiceError::Msg("routing_key is required".to_string()));
+    }
+    match &payload.destination {
+        RouteDestination::Amqp { exchange, .. } if exchange.is_empty() => Err(
+            TwinServiceError::Msg("destination exchange is required".to_string()),
+        ),
+        RouteDestination::Amqp { exchange, .. }
+            if SOURCE_EXCHANGES.contains(&exchange.as_str()) =>
+        {
+            Err(TwinServiceError::Msg(format!(
+                "can't route to '{}', the router consumes it",
+                exchange
+            )))
+        }
+        RouteDestination::Http { url, .. }
+            if !(url.starts_with("http://") || url.starts_with("https://")) =>
+        {
+            Err(TwinServiceError::Msg(format!("invalid url '{}'", url)))
+        }
+        RouteDestination::File { path } if path.is_empty() => Err(TwinServiceError::Msg(
+            "destination path is required".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,45 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use libs::utils::tenant::{DEFAULT_TENANT, TENANT_HEADER};
+use log::warn;
+
+use crate::api::ApiState;
+
+pub struct Tenants {
+    pub tenants: HashMap<String, Arc<ApiState>>,
+}
+
+// State of the tenant selected by the x-mir-tenant header
+pub struct Tenant(pub Arc<ApiState>);
+
+#[async_trait]
+impl FromRequestParts<Arc<Tenants>> for Tenant {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<Tenants>,
+    ) -> Result<Self, Self::Rejection> {
+        let tenant = request_tenant(&parts.headers);
+        match state.tenants.get(tenant) {
+            Some(x) => Ok(Tenant(x.clone())),
+            None => {
+                warn!("unknown tenant '{}'", tenant);
+                Err(StatusCode::NOT_FOUND)
+            }
+        }
+    }
+}
+
+pub fn request_tenant(headers: &HeaderMap) -> &str {
+    headers
+        .get(TENANT_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty())
+        .unwrap_or(DEFAULT_TENANT)
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
 use chrono::Utc;
@@ -7,9 +8,13 @@ use rand::{thread_rng, Rng};
 use surrealdb::sql::Thing;
 use surrealdb::{engine::remote::ws::Client, opt::PatchOp, Surreal};
 use thiserror::Error as ThisError;
+use libs::utils::auth::generate_device_key;
+use libs::models::device_key::DeviceKey;
 use libs::models::device_twin::DeviceTwin;
 use libs::models::device_twin::NewDeviceReq;
-use libs::models::device_twin::{ConnectionState, MetaProperties, Properties, StatusReason, TargetProperties};
+use libs::models::device_twin::{ConnectionState, DesiredError, DeviceMetadata, MetaProperties, Properties, Status, StatusAction, StatusReason, TargetProperties};
+
+use crate::model_service::{get_latest_model_version_from_db, validate_properties_in_db};
 
 const DEVICE_ID_KEY: &str = "device_id";
 
@@ -24,6 +29,14 @@ pub enum TwinServiceError {
     RecordNotFound(String),
     #[error("record version mistmatch: stored {0}, requested {1}")]
     RecordNewer(usize, usize),
+    #[error("invalid json schema: {0}")]
+    InvalidSchema(String),
+    #[error("properties don't match model {0} version {1}: {2}")]
+    InvalidProperties(String, usize, String),
+    #[error("device {0} is blocked, unblock it first")]
+    Blocked(String),
+    #[error("device {0} is disabled")]
+    Disabled(String),
 }
 
 pub async fn get_device_twins_from_db(
@@ -63,17 +76,21 @@ pub async fn create_device_twins_in_db(
     let id = Thing::from((String::from("device_twin"), payload.device_id.clone()));
     println!("id: {:?}", id);
 
+    let model_version = get_latest_model_version_from_db(&db, &payload.model_id).await?;
     let x = DeviceTwin {
         id: None,
         meta_properties: Some(MetaProperties {
             device_id: payload.device_id.clone(),
             model_id: payload.model_id,
+            model_version,
             status: payload.status,
             status_reason: StatusReason::Provisioned,
             status_update_time: Utc::now().timestamp_nanos(),
             connection_state: ConnectionState::Disconnected,
             last_activity_time: Utc::now().timestamp_nanos(),
             version: 1,
+            desired_error: None,
+            metadata: Default::default(),
         }),
         tag_properties: Some(Properties::default()),
         desired_properties: Some(Properties::default()),
@@ -118,6 +135,19 @@ pub async fn update_device_twins_properties_in_db(
                 properties.version,
             ));
         }
+
+        let (model_id, model_version) = device
+            .meta_properties
+            .map(|x| (x.model_id, x.model_version))
+            .unwrap_or_default();
+        validate_properties_in_db(
+            &db,
+            &model_id,
+            model_version,
+            target,
+            &properties.properties,
+        )
+        .await?;
     } else {
         return Err(TwinServiceError::RecordNotFound(device_id.to_string()));
     }
@@ -150,6 +180,52 @@ pub async fn delete_device_twins_in_db(
     Ok(deleted)
 }
 
+pub async fn update_device_status_in_db(
+    db: &Surreal<Client>,
+    device_id: &str,
+    action: &StatusAction,
+) -> Result<Option<DeviceTwin>, TwinServiceError> {
+    let device = get_device_twins_with_id_from_db(db, device_id)
+        .await?
+        .and_then(|x| x.meta_properties)
+        .ok_or(TwinServiceError::RecordNotFound(device_id.to_string()))?;
+
+    // Only unblocking takes a device out of blocked
+    let (status, status_reason) = match action {
+        StatusAction::Enable if device.status_reason == StatusReason::Blocked => {
+            return Err(TwinServiceError::Blocked(device_id.to_string()));
+        }
+        StatusAction::Enable => (Status::Enabled, device.status_reason),
+        StatusAction::Disable => (Status::Disabled, device.status_reason),
+        StatusAction::Block => (Status::Disabled, StatusReason::Blocked),
+        StatusAction::Unblock => (Status::Enabled, StatusReason::Unblocked),
+    };
+
+    let updated: Option<DeviceTwin> = db
+        .update(("device_twin", device_id))
+        .patch(PatchOp::replace("/meta_properties/status", status))
+        .patch(PatchOp::replace(
+            "/meta_properties/status_reason",
+            status_reason,
+        ))
+        .patch(PatchOp::replace(
+            "/meta_properties/status_update_time",
+            Utc::now().timestamp_nanos(),
+        ))
+        .await?;
+    Ok(updated)
+}
+
+pub async fn get_disabled_device_ids_from_db(
+    db: &Surreal<Client>,
+) -> Result<Vec<String>, surrealdb::Error> {
+    let mut results = db
+        .query("SELECT VALUE meta_properties.device_id FROM device_twin WHERE meta_properties.status != 'Enabled'")
+        .await?;
+    let device_ids: Vec<String> = results.take(0)?;
+    Ok(device_ids)
+}
+
 pub fn generate_threadsafe_random_string() -> String {
     let rng = Arc::new(Mutex::new(thread_rng()));
     let chars: String = {
@@ -164,24 +240,136 @@ pub fn generate_threadsafe_random_string() -> String {
     chars
 }
 
+// Returns the twin as it was before, to tell when the device reconnects
 pub async fn update_hearthbeat_in_db(
     db: Surreal<Client>,
     device_id: String,
     timestamp: i64,
+    state: ConnectionState,
+    metadata: DeviceMetadata,
 ) -> Result<Option<DeviceTwin>, TwinServiceError> {
     info!("Updating hearthbeat for device: {}", device_id);
+    let mut results = db
+        .query("UPDATE type::thing('device_twin', $device_id) SET meta_properties.last_activity_time = $timestamp, meta_properties.connection_state = $state, meta_properties.metadata = $metadata RETURN BEFORE")
+        .bind(("device_id", device_id))
+        .bind(("timestamp", timestamp))
+        .bind(("state", state))
+        .bind(("metadata", metadata))
+        .await?;
+    let previous: Option<DeviceTwin> = results.take(0)?;
+
+    Ok(previous)
+}
+
+pub async fn update_desired_error_in_db(
+    db: &Surreal<Client>,
+    device_id: &str,
+    desired_error: DesiredError,
+) -> Result<Option<DeviceTwin>, TwinServiceError> {
     let updated: Option<DeviceTwin> = db
         .update(("device_twin", device_id))
-        .patch(PatchOp::replace(
-            "/meta_properties/last_activity_time",
-            timestamp,
+        // Twins created before the field existed don't have it
+        .patch(PatchOp::add(
+            "/meta_properties/desired_error",
+            desired_error,
         ))
+        .await?;
+    Ok(updated)
+}
+
+// Connected devices without activity since the given time, returns the
+// ones that were disconnected
+pub async fn disconnect_inactive_devices_in_db(
+    db: &Surreal<Client>,
+    since: i64,
+) -> Result<Vec<String>, surrealdb::Error> {
+    let mut results = db
+        .query("UPDATE device_twin SET meta_properties.connection_state = $disconnected WHERE meta_properties.connection_state = $connected AND meta_properties.last_activity_time < $since RETURN AFTER")
+        .bind(("disconnected", ConnectionState::Disconnected))
+        .bind(("connected", ConnectionState::Connected))
+        .bind(("since", since))
+        .await?;
+    let twins: Vec<DeviceTwin> = results.take(0)?;
+    Ok(twins
+        .into_iter()
+        .filter_map(|x| x.meta_properties.map(|meta| meta.device_id))
+        .collect())
+}
+
+pub async fn get_device_keys_from_db(
+    db: &Surreal<Client>,
+) -> Result<Vec<DeviceKey>, surrealdb::Error> {
+    let keys: Vec<DeviceKey> = db.select("device_key").await?;
+    Ok(keys)
+}
+
+pub async fn get_device_key_from_db(
+    db: &Surreal<Client>,
+    device_id: &str,
+) -> Result<Option<DeviceKey>, surrealdb::Error> {
+    let key: Option<DeviceKey> = db.select(("device_key", device_id)).await?;
+    Ok(key)
+}
+
+pub async fn create_device_key_in_db(
+    db: &Surreal<Client>,
+    device_id: &str,
+    group_id: &str,
+) -> Result<Option<DeviceKey>, TwinServiceError> {
+    let key = DeviceKey {
+        id: None,
+        device_id: device_id.to_string(),
+        primary_key: generate_device_key(),
+        secondary_key: String::new(),
+        rotated_time: Utc::now().timestamp_nanos(),
+        group_id: group_id.to_string(),
+    };
+
+    let created: Option<DeviceKey> = db.create(("device_key", device_id)).content(key).await?;
+    Ok(created)
+}
+
+// Twins created before device keys existed get one so their key can be
+// rotated and handed to the device, returns how many keys were created
+pub async fn backfill_device_keys_in_db(db: &Surreal<Client>) -> Result<usize, TwinServiceError> {
+    let twins: Vec<DeviceTwin> = db.select("device_twin").await?;
+    let keys = get_device_keys_from_db(db).await?;
+    let known: HashSet<&str> = keys.iter().map(|x| x.device_id.as_str()).collect();
+
+    let mut created = 0;
+    for meta in twins.iter().filter_map(|x| x.meta_properties.as_ref()) {
+        if !known.contains(meta.device_id.as_str()) {
+            create_device_key_in_db(db, &meta.device_id, "").await?;
+            created += 1;
+        }
+    }
+    Ok(created)
+}
+
+// The current primary key stays valid as secondary until the next rotation
+pub async fn rotate_device_key_in_db(
+    db: &Surreal<Client>,
+    device_id: &str,
+) -> Result<Option<DeviceKey>, TwinServiceError> {
+    let current: Option<DeviceKey> = db.select(("device_key", device_id)).await?;
+    let current = current.ok_or(TwinServiceError::RecordNotFound(device_id.to_string()))?;
+
+    let rotated: Option<DeviceKey> = db
+        .update(("device_key", device_id))
+        .patch(PatchOp::replace("/secondary_key", current.primary_key))
+        .patch(PatchOp::replace("/primary_key", generate_device_key()))
         .patch(PatchOp::replace(
-            "/meta_properties/connection_state",
-            ConnectionState::Connected,
+            "/rotated_time",
+            Utc::now().timestamp_nanos(),
         ))
         .await?;
+    Ok(rotated)
+}
 
-    dbg!(&updated);
-    Ok(updated)
+pub async fn delete_device_key_in_db(
+    db: &Surreal<Client>,
+    device_id: &str,
+) -> Result<Option<DeviceKey>, TwinServiceError> {
+    let deleted: Option<DeviceKey> = db.delete(("device_key", device_id)).await?;
+    Ok(deleted)
 }
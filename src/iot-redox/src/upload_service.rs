@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use libs::models::upload::{
+    upload_id, DeviceUpload, DeviceUploadChunkRequest, DeviceUploadChunkResponse, UploadState,
+};
+use libs::utils::checksum::Checksum;
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::twin_service::TwinServiceError;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// Uploads are stored in a folder per tenant, named after their upload id
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct UploadSettings {
+    pub dir: PathBuf,
+    pub thread_count: usize,
+    // Larger uploads are refused, 0 is unlimited
+    pub max_size_bytes: u64,
+}
+
+impl Default for UploadSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./uploads"),
+            thread_count: 1,
+            max_size_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+pub fn upload_path(dir: &Path, upload_id: &str) -> PathBuf {
+    dir.join(upload_id)
+}
+
+// Most recent first
+pub async fn get_uploads_from_db(
+    db: &Surreal<Client>,
+    device_id: &str,
+    upload_id: &str,
+) -> Result<Vec<DeviceUpload>, TwinServiceError> {
+    if !upload_id.is_empty() {
+        let upload: Option<DeviceUpload> = db.select(("device_upload", upload_id)).await?;
+        return Ok(upload
+            .into_iter()
+            .filter(|x| device_id.is_empty() || x.device_id == device_id)
+            .collect());
+    }
+
+    let filter = if device_id.is_empty() {
+        ""
+    } else {
+        "WHERE device_id = $device_id"
+    };
+    let mut results = db
+        .query(format!(
+            "SELECT * FROM device_upload {} ORDER BY update_time DESC",
+            filter
+        ))
+        .bind(("device_id", device_id))
+        .await?;
+    let uploads: Vec<DeviceUpload> = results.take(0)?;
+    Ok(uploads)
+}
+
+// Appends the chunk when it starts where the stored content ends, the reply
+// gives the device the offset to continue from. A complete or failed upload
+// starts over with the next chunk.
+pub async fn receive_upload_chunk_in_db(
+    db: &Surreal<Client>,
+    settings: &UploadSettings,
+    dir: &Path,
+    payload: &DeviceUploadChunkRequest,
+) -> Result<DeviceUploadChunkResponse, TwinServiceError> {
+    if payload.upload_id != upload_id(&payload.device_id, &payload.name) {
+        return Err(TwinServiceError::Msg(String::from(
+            "upload id doesn't match the device and file name",
+        )));
+    }
+
+    let now = Utc::now().timestamp_nanos();
+    let stored: Option<DeviceUpload> = db
+        .select(("device_upload", payload.upload_id.as_str()))
+        .await?;
+    let mut upload = match stored {
+        Some(x) if x.state == UploadState::InProgress => x,
+        _ => DeviceUpload {
+            id: None,
+            upload_id: payload.upload_id.clone(),
+            device_id: payload.device_id.clone(),
+            name: payload.name.clone(),
+            size: 0,
+            checksum: String::new(),
+            state: UploadState::InProgress,
+            start_time: now,
+            update_time: now,
+        },
+    };
+    let mut response = DeviceUploadChunkResponse {
+        upload_id: payload.upload_id.clone(),
+        offset: upload.size,
+        state: upload.state,
+        error: String::new(),
+    };
+
+    let is_last = payload.file_checksum.is_some();
+    if payload.data.is_empty() && !is_last {
+        return Ok(response);
+    }
+    if payload.offset != upload.size {
+        response.error = format!("expected offset {}, got {}", upload.size, payload.offset);
+        return Ok(response);
+    }
+    let content = match payload.content() {
+        Some(x) => x,
+        None => {
+            response.error = String::from("chunk checksum mismatch");
+            return Ok(response);
+        }
+    };
+    let size = upload.size + content.len() as u64;
+    if settings.max_size_bytes > 0 && size > settings.max_size_bytes {
+        response.error = format!("upload is larger than {} bytes", settings.max_size_bytes);
+        return Ok(response);
+    }
+
+    let path = upload_path(dir, &payload.upload_id);
+    append(dir, &path, upload.size == 0, &content)
+        .await
+        .map_err(|e| TwinServiceError::Msg(e.to_string()))?;
+    upload.size = size;
+    upload.update_time = now;
+
+    if let Some(file_checksum) = &payload.file_checksum {
+        let checksum = file_checksum_of(&path)
+            .await
+            .map_err(|e| TwinServiceError::Msg(e.to_string()))?;
+        if &checksum == file_checksum {
+            upload.state = UploadState::Complete;
+            upload.checksum = checksum;
+        } else {
+            upload.state = UploadState::Failed;
+            upload.size = 0;
+            response.error = String::from("file checksum mismatch");
+            let _ = fs::remove_file(&path).await;
+        }
+    }
+
+    let _: Option<DeviceUpload> = db
+        .update(("device_upload", payload.upload_id.as_str()))
+        .content(&upload)
+        .await?;
+    response.offset = upload.size;
+    response.state = upload.state;
+    Ok(response)
+}
+
+// The first chunk replaces the content of a previous upload
+async fn append(dir: &Path, path: &Path, first: bool, content: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(!first)
+        .truncate(first)
+        .open(path)
+        .await?;
+    file.write_all(content).await?;
+    file.flush().await
+}
+
+async fn file_checksum_of(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut checksum = Checksum::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    loop {
+        let size = file.read(&mut buffer).await?;
+        if size == 0 {
+            return Ok(checksum.finalize());
+        }
+        checksum.update(&buffer[..size]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use libs::models::device_twin::DEFAULT_DISCONNECT_TIMEOUT_SECS;
+use libs::models::webhook::{
+    NewWebhookReq, Webhook, WebhookDelivery, WebhookEvent, WebhookEventKind, EVENT_HEADER,
+    SIGNATURE_HEADER, TIMESTAMP_HEADER,
+};
+use libs::utils::auth::{generate_device_key, sign_message};
+use log::{debug, error, warn};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+use tokio::sync::mpsc;
+
+use crate::twin_service::{generate_threadsafe_random_string, TwinServiceError};
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub timeout_ms: u64,
+    // Retries after the first attempt, on connection errors, 429 and 5xx
+    pub max_retries: u32,
+    // Doubled after every retry
+    pub backoff_ms: u64,
+    // Events waiting for delivery, new ones are dropped when full
+    pub queue_size: usize,
+    // Devices without heartbeat for that long are disconnected
+    pub disconnect_timeout_secs: u64,
+    pub delivery_retention_secs: u64,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 5000,
+            max_retries: 5,
+            backoff_ms: 1000,
+            queue_size: 1000,
+            disconnect_timeout_secs: DEFAULT_DISCONNECT_TIMEOUT_SECS,
+            delivery_retention_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct WebhookState {
+    webhooks: Vec<Webhook>,
+    tags: HashMap<String, Value>,
+}
+
+// Webhooks and device tags of a tenant, events are queued by the consumers
+// and the api and delivered in the background
+#[derive(Debug, Clone)]
+pub struct WebhookDispatcher {
+    tenant: String,
+    state: Arc<RwLock<WebhookState>>,
+    sender: mpsc::Sender<WebhookEvent>,
+    client: reqwest::Client,
+    settings: WebhookSettings,
+}
+
+impl WebhookDispatcher {
+    pub fn new(tenant: &str, settings: &WebhookSettings) -> (Self, mpsc::Receiver<WebhookEvent>) {
+        let (sender, receiver) = mpsc::channel(settings.queue_size.max(1));
+        let dispatcher = Self {
+            tenant: tenant.to_string(),
+            state: Arc::new(RwLock::new(WebhookState::default())),
+            sender,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(settings.timeout_ms))
+                .build()
+                .unwrap(),
+            settings: settings.clone(),
+        };
+        (dispatcher, receiver)
+    }
+
+    pub fn settings(&self) -> &WebhookSettings {
+        &self.settings
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().webhooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn replace_webhooks(&self, webhooks: Vec<Webhook>) {
+        self.state.write().unwrap().webhooks = webhooks;
+    }
+
+    pub fn upsert_webhook(&self, webhook: Webhook) {
+        let mut state = self.state.write().unwrap();
+        state
+            .webhooks
+            .retain(|x| x.webhook_id != webhook.webhook_id);
+        state.webhooks.push(webhook);
+    }
+
+    pub fn remove_webhook(&self, webhook_id: &str) {
+        self.state
+            .write()
+            .unwrap()
+            .webhooks
+            .retain(|x| x.webhook_id != webhook_id);
+    }
+
+    pub fn replace_tags(&self, tags: HashMap<String, Value>) {
+        self.state.write().unwrap().tags = tags;
+    }
+
+    pub fn new_event(&self, kind: WebhookEventKind, device_id: &str, data: Value) -> WebhookEvent {
+        WebhookEvent {
+            event_id: generate_threadsafe_random_string(),
+            kind,
+            tenant: self.tenant.clone(),
+            device_id: device_id.to_string(),
+            timestamp: Utc::now().timestamp_nanos(),
+            data,
+        }
+    }
+
+    // Never waits, the event is dropped when too many are pending
+    pub fn notify(&self, kind: WebhookEventKind, device_id: &str, data: Value) {
+        if self.is_empty() {
+            return;
+        }
+        let event = self.new_event(kind, device_id, data);
+        if let Err(error) = self.sender.try_send(event) {
+            warn!("dropped webhook event: {}", error);
+        }
+    }
+
+    fn matching(&self, event: &WebhookEvent) -> Vec<Webhook> {
+        let state = self.state.read().unwrap();
+        let tags = state.tags.get(&event.device_id);
+        state
+            .webhooks
+            .iter()
+            .filter(|x| x.matches(event, tags))
+            .cloned()
+            .collect()
+    }
+
+    // Posts until the endpoint accepts or the retries run out
+    pub async fn deliver(&self, webhook: &Webhook, event: &WebhookEvent) -> WebhookDelivery {
+        let body = serde_json::to_vec(event).unwrap();
+        let mut delivery = WebhookDelivery {
+            id: None,
+            webhook_id: webhook.webhook_id.clone(),
+            event_id: event.event_id.clone(),
+            kind: event.kind.as_str().to_string(),
+            device_id: event.device_id.clone(),
+            ..Default::default()
+        };
+        let mut backoff = Duration::from_millis(self.settings.backoff_ms);
+        loop {
+            delivery.attempts += 1;
+            // Signed on every attempt so receivers can reject old timestamps
+            let timestamp = Utc::now().timestamp_nanos();
+            let response = self
+                .client
+                .post(&webhook.url)
+                .header("content-type", "application/json")
+                .header(EVENT_HEADER, event.kind.as_str())
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(
+                    SIGNATURE_HEADER,
+                    sign_message(&webhook.secret, timestamp, &body),
+                )
+                .body(body.clone())
+                .send()
+                .await;
+
+            let retry = match response {
+                Ok(x) => {
+                    delivery.status_code = Some(x.status().as_u16());
+                    delivery.success = x.status().is_success();
+                    delivery.error = String::new();
+                    x.status().is_server_error() || x.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(error) => {
+                    delivery.status_code = None;
+                    delivery.error = error.to_string();
+                    error.is_connect() || error.is_timeout()
+                }
+            };
+            if delivery.success || !retry || delivery.attempts > self.settings.max_retries {
+                break;
+            }
+            debug!(
+                "webhook '{}' attempt {} failed, retry in {:?}",
+                webhook.webhook_id, delivery.attempts, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        delivery.timestamp = Utc::now().timestamp_nanos();
+        delivery
+    }
+}
+
+// Each webhook gets its own task so a slow endpoint doesn't hold back the others
+pub async fn deliver_webhook_events(
+    db: Surreal<Client>,
+    dispatcher: WebhookDispatcher,
+    mut receiver: mpsc::Receiver<WebhookEvent>,
+) {
+    while let Some(event) = receiver.recv().await {
+        for webhook in dispatcher.matching(&event) {
+            let db = db.clone();
+            let dispatcher = dispatcher.clone();
+            let event = event.clone();
+            tokio::spawn(async move {
+                let delivery = dispatcher.deliver(&webhook, &event).await;
+                if !delivery.success {
+                    warn!(
+                        "can't deliver {} to webhook '{}' after {} attempts: {}",
+                        delivery.kind,
+                        delivery.webhook_id,
+                        delivery.attempts,
+                        delivery
+                            .status_code
+                            .map_or(delivery.error.clone(), |x| format!("status {}", x))
+                    );
+                }
+                if let Err(error) = create_webhook_delivery_in_db(&db, delivery).await {
+                    error!("can't log webhook delivery: {}", error);
+                }
+            });
+        }
+    }
+}
+
+pub async fn get_webhooks_from_db(
+    db: &Surreal<Client>,
+    webhook_id: &str,
+) -> Result<Vec<Webhook>, TwinServiceError> {
+    if !webhook_id.is_empty() {
+        let webhook: Option<Webhook> = db.select(("webhook", webhook_id)).await?;
+        return Ok(webhook.into_iter().collect());
+    }
+
+    let webhooks: Vec<Webhook> = db.select("webhook").await?;
+    Ok(webhooks)
+}
+
+// Creating a webhook that already exists replaces it, its secret is kept
+// when none is given
+pub async fn create_webhook_in_db(
+    db: &Surreal<Client>,
+    payload: NewWebhookReq,
+) -> Result<Option<Webhook>, TwinServiceError> {
+    if payload.webhook_id.is_empty() {
+        return Err(TwinServiceError::Msg("webhook_id is required".to_string()));
+    }
+    if !(payload.url.starts_with("http://") || payload.url.starts_with("https://")) {
+        return Err(TwinServiceError::Msg(format!(
+            "invalid url '{}'",
+            payload.url
+        )));
+    }
+
+    let webhook_id = payload.webhook_id.clone();
+    let current: Option<Webhook> = db.select(("webhook", webhook_id.as_str())).await?;
+    let secret = match (&current, payload.secret.is_empty()) {
+        (Some(current), true) => current.secret.clone(),
+        (None, true) => generate_device_key(),
+        _ => payload.secret,
+    };
+    let webhook = Webhook {
+        id: None,
+        webhook_id: payload.webhook_id,
+        url: payload.url,
+        events: payload.events,
+        filter: payload.filter,
+        secret,
+        enabled: payload.enabled,
+        update_time: Utc::now().timestamp_nanos(),
+    };
+
+    let saved: Option<Webhook> = if current.is_some() {
+        db.update(("webhook", webhook_id.as_str()))
+            .content(webhook)
+            .await?
+    } else {
+        db.create(("webhook", webhook_id.as_str()))
+            .content(webhook)
+            .await?
+    };
+    Ok(saved)
+}
+
+pub async fn delete_webhook_in_db(
+    db: &Surreal<Client>,
+    webhook_id: &str,
+) -> Result<Option<Webhook>, TwinServiceError> {
+    let deleted: Option<Webhook> = db.delete(("webhook", webhook_id)).await?;
+    Ok(deleted)
+}
+
+// Most recent first
+pub async fn get_webhook_deliveries_from_db(
+    db: &Surreal<Client>,
+    webhook_id: &str,
+    limit: usize,
+) -> Result<Vec<WebhookDelivery>, TwinServiceError> {
+    let filter = if webhook_id.is_empty() {
+        ""
+    } else {
+        "WHERE webhook_id = $webhook_id"
+    };
+    let mut results = db
+        .query(format!(
+            "SELECT * FROM webhook_delivery {} ORDER BY timestamp DESC LIMIT $limit",
+            filter
+        ))
+        .bind(("webhook_id", webhook_id))
+        .bind(("limit", limit))
+        .await?;
+    let deliveries: Vec<WebhookDelivery> = results.take(0)?;
+    Ok(deliveries)
+}
+
+pub async fn create_webhook_delivery_in_db(
+    db: &Surreal<Client>,
+    delivery: WebhookDelivery,
+) -> Result<(), surrealdb::Error> {
+    let _: Vec<WebhookDelivery> = db.create("webhook_delivery").content(delivery).await?;
+    Ok(())
+}
+
+pub async fn prune_webhook_deliveries_in_db(
+    db: &Surreal<Client>,
+    before: i64,
+) -> Result<(), surrealdb::Error> {
+    db.query("DELETE webhook_delivery WHERE timestamp < $before")
+        .bind(("before", before))
+        .await?;
+    Ok(())
+}
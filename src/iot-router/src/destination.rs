@@ -0,0 +1,184 @@
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use libs::clients::amqp::Amqp;
+use libs::models::route::RouteDestination;
+use libs::utils::tenant::tenant_routing_key;
+use log::warn;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+use crate::Error;
+
+const ROUTING_KEY_HEADER: &str = "x-mir-routing-key";
+const TENANT_HEADER: &str = "x-mir-tenant";
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub timeout_ms: u64,
+    // Retries after the first attempt
+    pub max_retries: u32,
+    // Doubled after every retry
+    pub backoff_ms: u64,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 5000,
+            max_retries: 3,
+            backoff_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct FileSettings {
+    // File destinations can't leave it, each tenant gets its own folder in it
+    pub dir: PathBuf,
+}
+
+impl Default for FileSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./routed"),
+        }
+    }
+}
+
+// Sends messages to the destination of a route
+#[derive(Debug, Clone)]
+pub struct Forwarder {
+    tenant: String,
+    amqp: Amqp,
+    client: Client,
+    http: HttpSettings,
+    file: FileSettings,
+}
+
+impl Forwarder {
+    pub fn new(tenant: &str, amqp: Amqp, http: HttpSettings, file: FileSettings) -> Self {
+        Self {
+            tenant: tenant.to_string(),
+            amqp,
+            client: Client::builder()
+                .timeout(Duration::from_millis(http.timeout_ms))
+                .build()
+                .unwrap(),
+            http,
+            file,
+        }
+    }
+
+    pub async fn forward(
+        &self,
+        destination: &RouteDestination,
+        routing_key: &str,
+        payload: &Value,
+    ) -> Result<(), Error> {
+        match destination {
+            RouteDestination::Amqp {
+                exchange,
+                routing_key: target_key,
+            } => {
+                let target_key = match target_key {
+                    Some(x) => tenant_routing_key(&self.tenant, x),
+                    None => routing_key.to_string(),
+                };
+                self.amqp
+                    .send_message(&payload.to_string(), exchange, &target_key)
+                    .await?;
+                Ok(())
+            }
+            RouteDestination::Http { url, headers } => {
+                self.post(url, headers.iter(), routing_key, payload).await
+            }
+            RouteDestination::File { path } => self.append(path, routing_key, payload).await,
+        }
+    }
+
+    async fn post<'a>(
+        &self,
+        url: &str,
+        headers: impl Iterator<Item = (&'a String, &'a String)> + Clone,
+        routing_key: &str,
+        payload: &Value,
+    ) -> Result<(), Error> {
+        let mut backoff = Duration::from_millis(self.http.backoff_ms);
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .client
+                .post(url)
+                .header(ROUTING_KEY_HEADER, routing_key)
+                .header(TENANT_HEADER, &self.tenant)
+                .json(payload);
+            for (key, value) in headers.clone() {
+                request = request.header(key, value);
+            }
+
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) if is_retryable(response.status()) => {
+                    Error::HttpStatus(response.status().as_u16())
+                }
+                Ok(response) => return Err(Error::HttpStatus(response.status().as_u16())),
+                Err(error) if error.is_connect() || error.is_timeout() => Error::Http(error),
+                Err(error) => return Err(Error::Http(error)),
+            };
+            if attempt >= self.http.max_retries {
+                return Err(error);
+            }
+            attempt += 1;
+            warn!(
+                "can't post to {}, retry {} in {:?}: {}",
+                url, attempt, backoff, error
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    // One json line per message
+    async fn append(&self, path: &str, routing_key: &str, payload: &Value) -> Result<(), Error> {
+        let path = self.file_path(path)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let line = format!(
+            "{}\n",
+            json!({ "routing_key": routing_key, "payload": payload })
+        );
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    // Routes are written through the api, their path stays in the tenant folder
+    fn file_path(&self, path: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(path);
+        if relative
+            .components()
+            .any(|x| !matches!(x, Component::Normal(_)))
+        {
+            return Err(Error::InvalidDestination(format!(
+                "path '{}' must be relative to the file dir",
+                path
+            )));
+        }
+        Ok(self.file.dir.join(&self.tenant).join(relative))
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
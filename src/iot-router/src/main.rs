@@ -0,0 +1,356 @@
+use lapin::{options::*, types::FieldTable, ExchangeKind};
+use libs::utils::setup_cli;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use surrealdb::engine::remote::ws::{Client, Ws};
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
+use thiserror::Error as ThisError;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use libs::clients::amqp::{
+    Amqp, AmqpError, AmqpSettings, ChannelSettings, ConsumerSettings, ExchangeSettings,
+    QueueBindSettings, QueueSettings,
+};
+use libs::models::device_key::DeviceKey;
+use libs::models::device_twin::Status;
+use libs::models::route::Route;
+use libs::utils::auth::DeviceKeyCache;
+use libs::utils::config::{setup_config, FileFormat};
+use libs::utils::logger::setup_logger;
+use libs::utils::serialization::SerializationKind;
+use libs::utils::tenant::{default_tenants, tenant_queue, tenant_routing_key};
+
+use crate::destination::{FileSettings, Forwarder, HttpSettings};
+use crate::route::{RouteHandler, RouteTable};
+
+mod destination;
+mod route;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("surrealdb error: {0}")]
+    SurrealDB(#[from] surrealdb::Error),
+    #[error("amqp error: {0}")]
+    Amqp(#[from] AmqpError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("http status: {0}")]
+    HttpStatus(u16),
+    #[error("invalid destination: {0}")]
+    InvalidDestination(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SurrealDb {
+    pub user: String,
+    pub password: String,
+    pub addr: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Settings {
+    pub log_level: String,
+    pub amqp_addr: String,
+    pub thread_count: usize,
+    pub surrealdb: SurrealDb,
+    #[serde(default = "default_tenants")]
+    pub tenants: Vec<String>,
+    #[serde(default = "default_sources")]
+    pub sources: Vec<SourceSettings>,
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(default)]
+    pub file: FileSettings,
+}
+
+// An exchange the router consumes every message of a tenant from
+#[derive(Debug, Deserialize, Clone)]
+pub struct SourceSettings {
+    pub exchange: String,
+    pub queue: String,
+    // Messages published by the platform itself are not signed
+    #[serde(default)]
+    pub verify_signature: bool,
+}
+
+fn default_sources() -> Vec<SourceSettings> {
+    vec![SourceSettings {
+        exchange: RMQ_STREAM_EXCHANGE_NAME.to_string(),
+        queue: RMQ_ROUTER_QUEUE_NAME.to_string(),
+        verify_signature: false,
+    }]
+}
+
+const APP_NAME: &str = "router";
+const RMQ_STREAM_EXCHANGE_NAME: &str = "iot-stream";
+const RMQ_ROUTER_QUEUE_NAME: &str = "iot-q-router";
+const RMQ_ROUTER_ROUTING_KEY: &str = "#";
+const SURREAL_DB_NAME: &str = "iot";
+const DEVICE_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const ROUTE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+// Time given to consumers to finish forwarding the message they hold
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct DeviceTags {
+    device_id: String,
+    tags: Option<Value>,
+    status: Status,
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = setup_cli();
+
+    let token = CancellationToken::new();
+
+    let settings: Settings = setup_config(
+        APP_NAME,
+        FileFormat::YAML,
+        matches.get_one::<PathBuf>(libs::utils::cli::CONFIG_KEY),
+    )
+    .unwrap();
+    setup_logger(settings.log_level.clone()).unwrap();
+    info!("{:?}", settings);
+
+    // Consumers per source and tenant, plus channels to publish to amqp destinations
+    let consumer_count = settings.thread_count * settings.sources.len() * settings.tenants.len();
+    let amqp: Amqp = Amqp::new(settings.amqp_addr.clone(), consumer_count * 2);
+
+    let mut consumers = vec![];
+    for tenant in settings.tenants.iter() {
+        consumers.extend(start_tenant(tenant, &settings, &amqp, &token).await);
+    }
+
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => {
+            info!("Shutting down...");
+            token.cancel();
+        }
+        Err(err) => {
+            eprintln!("Unable to listen for shutdown signal: {}", err);
+        }
+    }
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, futures::future::join_all(consumers))
+        .await
+        .is_err()
+    {
+        warn!("consumers did not stop in time, unacknowledged messages will be redelivered");
+    }
+    info!("Shutdown complete.");
+}
+
+// Each tenant has its own surrealdb namespace, routes and queues
+async fn start_tenant(
+    tenant: &str,
+    settings: &Settings,
+    amqp: &Amqp,
+    token: &CancellationToken,
+) -> Vec<JoinHandle<()>> {
+    let db = connect_surrealdb(&settings.surrealdb, tenant)
+        .await
+        .unwrap();
+
+    // Routes are owned by redox, the router only keeps a refreshed copy
+    let routes = RouteTable::new();
+    if let Err(error) = load_routes(&db, tenant, &routes).await {
+        error!("can't load routes: {}", error);
+    }
+    info!("{}: loaded {} routes", tenant, routes.len());
+    let cloned_token = token.clone();
+    let cloned_db = db.clone();
+    let cloned_tenant = tenant.to_string();
+    let cloned_routes = routes.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cloned_token.cancelled() => {
+                debug!("The token was shutdown")
+            }
+            _ = refresh_routes(cloned_db, cloned_tenant, cloned_routes) => {
+                debug!("routes refresh shuting down...");
+            }
+        }
+    });
+
+    let keys = if settings.sources.iter().any(|x| x.verify_signature) {
+        let keys = DeviceKeyCache::with_db(db.clone());
+        match get_device_keys_from_db(&db).await {
+            Ok(x) => keys.replace_all(x),
+            Err(error) => error!("can't load device keys: {}", error),
+        }
+        info!("{}: loaded {} device keys", tenant, keys.len());
+
+        let cloned_token = token.clone();
+        let cloned_keys = keys.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cloned_token.cancelled() => {
+                    debug!("The token was shutdown")
+                }
+                _ = refresh_device_keys(db, cloned_keys) => {
+                    debug!("device keys refresh shuting down...");
+                }
+            }
+        });
+        Some(keys)
+    } else {
+        None
+    };
+
+    let forwarder = Forwarder::new(
+        tenant,
+        amqp.clone(),
+        settings.http.clone(),
+        settings.file.clone(),
+    );
+    let mut consumers = vec![];
+    for source in settings.sources.iter() {
+        let verifier = keys.clone().filter(|_| source.verify_signature);
+        for i in 0..settings.thread_count {
+            let cloned_token = token.clone();
+            let cloned_amqp = amqp.clone();
+            let cloned_verifier = verifier.clone();
+            let source = SourceSettings {
+                queue: tenant_queue(tenant, &source.queue),
+                ..source.clone()
+            };
+            let routing_key = tenant_routing_key(tenant, RMQ_ROUTER_ROUTING_KEY);
+            let mut handler = RouteHandler {
+                routes: routes.clone(),
+                forwarder: forwarder.clone(),
+            };
+            // The consumer watches the token itself to finish the message it holds
+            consumers.push(tokio::spawn(async move {
+                start_consuming_topic_queue(
+                    i,
+                    cloned_amqp,
+                    cloned_verifier,
+                    source,
+                    routing_key,
+                    &mut handler,
+                    cloned_token,
+                )
+                .await;
+                debug!("router shuting down...");
+            }));
+        }
+    }
+    consumers
+}
+
+async fn connect_surrealdb(settings: &SurrealDb, tenant: &str) -> Result<Surreal<Client>, Error> {
+    let db = Surreal::new::<Ws>(settings.addr.as_str()).await?;
+    db.signin(Root {
+        username: &settings.user,
+        password: &settings.password,
+    })
+    .await?;
+    db.use_ns(tenant).use_db(SURREAL_DB_NAME).await?;
+    info!("{}: connected to SurrealDb", tenant);
+    Ok(db)
+}
+
+async fn get_device_keys_from_db(db: &Surreal<Client>) -> Result<Vec<DeviceKey>, Error> {
+    let keys: Vec<DeviceKey> = db.select("device_key").await?;
+    Ok(keys)
+}
+
+async fn refresh_device_keys(db: Surreal<Client>, keys: DeviceKeyCache) {
+    let mut interval = tokio::time::interval(DEVICE_KEY_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match get_device_keys_from_db(&db).await {
+            Ok(x) => keys.replace_all(x),
+            Err(error) => error!("can't refresh device keys: {}", error),
+        }
+    }
+}
+
+async fn load_routes(db: &Surreal<Client>, tenant: &str, routes: &RouteTable) -> Result<(), Error> {
+    let stored: Vec<Route> = db.select("route").await?;
+    let mut results = db
+        .query("SELECT meta_properties.device_id AS device_id, tag_properties.properties AS tags, meta_properties.status AS status FROM device_twin")
+        .await?;
+    let tags: Vec<DeviceTags> = results.take(0)?;
+
+    routes.replace_routes(tenant, stored);
+    routes.replace_disabled(
+        tags.iter()
+            .filter(|x| x.status != Status::Enabled)
+            .map(|x| x.device_id.clone())
+            .collect(),
+    );
+    routes.replace_tags(
+        tags.into_iter()
+            .filter_map(|x| x.tags.map(|tags| (x.device_id, tags)))
+            .collect::<HashMap<_, _>>(),
+    );
+    Ok(())
+}
+
+async fn refresh_routes(db: Surreal<Client>, tenant: String, routes: RouteTable) {
+    let mut interval = tokio::time::interval(ROUTE_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(error) = load_routes(&db, &tenant, &routes).await {
+            error!("can't refresh routes: {}", error);
+        }
+    }
+}
+
+async fn start_consuming_topic_queue(
+    index: usize,
+    amqp: Amqp,
+    verifier: Option<DeviceKeyCache>,
+    source: SourceSettings,
+    routing_key: String,
+    handler: &mut RouteHandler,
+    token: CancellationToken,
+) {
+    let settings = AmqpSettings {
+        channel: ChannelSettings {
+            prefetch_count: 1,
+            options: BasicQosOptions::default(),
+        },
+        exchange: ExchangeSettings {
+            name: source.exchange.as_str(),
+            kind: ExchangeKind::Topic,
+            options: ExchangeDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue: QueueSettings {
+            name: source.queue.as_str(),
+            options: QueueDeclareOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        queue_bind: QueueBindSettings {
+            routing_key: routing_key.as_str(),
+            options: QueueBindOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        consumer: ConsumerSettings {
+            consumer_tag: "",
+            options: BasicConsumeOptions::default(),
+            arguments: FieldTable::default(),
+        },
+        verifier,
+    };
+    debug!("{}: Starting {}...", index, source.queue);
+    amqp.consume_topic_queue_with_handler::<Value, _>(
+        index,
+        settings,
+        SerializationKind::Json,
+        handler,
+        token,
+    )
+    .await;
+    debug!("{}: Shutting down {}...", index, source.queue);
+}
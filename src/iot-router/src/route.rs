@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use libs::clients::amqp::MessageHandler;
+use libs::models::device_twin::DeviceStatusCache;
+use libs::models::route::Route;
+use libs::utils::tenant::tenant_routing_key;
+use log::{trace, warn};
+use serde_json::Value;
+
+use crate::destination::Forwarder;
+
+// Routes, device tags and statuses of a tenant, refreshed from surrealdb
+#[derive(Debug, Clone, Default)]
+pub struct RouteTable {
+    routes: Arc<RwLock<Vec<Route>>>,
+    tags: Arc<RwLock<HashMap<String, Value>>>,
+    statuses: DeviceStatusCache,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Route patterns are stored without the tenant
+    pub fn replace_routes(&self, tenant: &str, routes: Vec<Route>) {
+        *self.routes.write().unwrap() = routes
+            .into_iter()
+            .map(|mut x| {
+                x.routing_key = tenant_routing_key(tenant, &x.routing_key);
+                x
+            })
+            .collect();
+    }
+
+    pub fn replace_tags(&self, tags: HashMap<String, Value>) {
+        *self.tags.write().unwrap() = tags;
+    }
+
+    pub fn replace_disabled(&self, disabled_device_ids: Vec<String>) {
+        self.statuses.replace_all(disabled_device_ids);
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Messages of disabled devices match no route
+    pub fn matching(&self, routing_key: &str, payload: &Value) -> Vec<Route> {
+        let device_id = payload.get("device_id").and_then(Value::as_str);
+        if let Some(device_id) = device_id.filter(|x| !self.statuses.is_enabled(x)) {
+            warn!(
+                "dropped {} from disabled device '{}', {} dropped so far",
+                routing_key,
+                device_id,
+                self.statuses.count_dropped()
+            );
+            return vec![];
+        }
+        let tags = device_id.and_then(|x| self.tags.read().unwrap().get(x).cloned());
+        self.routes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|x| x.matches(routing_key, tags.as_ref(), payload))
+            .cloned()
+            .collect()
+    }
+}
+
+// A message that can't be forwarded is logged and dropped, it is not
+// requeued so one failing destination doesn't stall the others
+pub struct RouteHandler {
+    pub routes: RouteTable,
+    pub forwarder: Forwarder,
+}
+
+#[async_trait]
+impl MessageHandler<Value> for RouteHandler {
+    type Error = Infallible;
+
+    async fn handle(&mut self, msg: Value, routing_key: &str) -> Result<(), Self::Error> {
+        for route in self.routes.matching(routing_key, &msg) {
+            trace!("{} matched route '{}'", routing_key, route.route_id);
+            if let Err(error) = self
+                .forwarder
+                .forward(&route.destination, routing_key, &msg)
+                .await
+            {
+                warn!(
+                    "can't forward {} with route '{}': {}",
+                    routing_key, route.route_id, error
+                );
+            }
+        }
+        Ok(())
+    }
+}
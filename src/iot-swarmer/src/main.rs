@@ -14,6 +14,7 @@ use libs::clients::amqp::Amqp;
 use libs::models::telemetry::DeviceTelemetryRequest;
 use libs::utils::config::{setup_config, FileFormat};
 use libs::utils::logger::setup_logger;
+use libs::utils::tenant::{default_tenant, tenant_routing_key};
 
 use device::LiveDevice;
 use libs::utils::setup_cli;
@@ -29,12 +30,15 @@ pub struct Settings {
     pub log_level: String,
     pub amqp_addr: String,
     pub amqp_conn_count: usize,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
 }
 
 // https://blog.logrocket.com/configuration-management-in-rust-web-services/
 // https://tokio.rs/tokio/topics/shutdown
 
 const APP_NAME: &str = "swarmer";
+const RMQ_STREAM_ROUTING_KEY: &str = "swarm.telemetry.v1";
 
 #[tokio::main]
 async fn main() {
@@ -71,12 +75,13 @@ async fn main() {
             let y = device.clone();
             let cloned_token = token.clone();
             let cloned_amqp = amqp.clone();
+            let routing_key = tenant_routing_key(&settings.tenant, RMQ_STREAM_ROUTING_KEY);
             tokio::spawn(async move {
                 tokio::select! {
                     _ = cloned_token.cancelled() => {
                         debug!("The token was shutdown")
                     }
-                    _ = start_device(cloned_amqp, i, global_index, y) => {
+                    _ = start_device(cloned_amqp, routing_key, i, global_index, y) => {
                         debug!("device shuting down...");
                     }
                 }
@@ -97,7 +102,13 @@ async fn main() {
     info!("Shutdown complete.");
 }
 
-async fn start_device(amqp: Amqp, index: u32, global_index: i64, template: Device) {
+async fn start_device(
+    amqp: Amqp,
+    routing_key: String,
+    index: u32,
+    global_index: i64,
+    template: Device,
+) {
     // Create virtual device
     let mut device = LiveDevice::from_template(&template, index, global_index).unwrap();
 
@@ -120,7 +131,7 @@ async fn start_device(amqp: Amqp, index: u32, global_index: i64, template: Devic
         // Serialize & Send
         let str_payload = serde_json::to_string(&payload).unwrap();
         match amqp
-            .send_message(&str_payload, "iot-stream", "swarm.telemetry.v1")
+            .send_message(&str_payload, "iot-stream", &routing_key)
             .await
         {
             Ok(_) => trace!("message sent"),
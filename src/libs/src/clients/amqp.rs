@@ -2,26 +2,37 @@ use std::{
     error::Error,
     io::{Read, Write},
     string::FromUtf8Error,
+    time::Duration,
 };
 
+use async_trait::async_trait;
+
 use brotli::{CompressorWriter, Decompressor};
+use chrono::Utc;
 use deadpool_lapin::{Manager, Object, Pool, PoolError};
 use futures::StreamExt;
 use lapin::types::FieldTable;
 use lapin::{
+    message::Delivery,
     options::{
-        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
-        BasicQosOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+        BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+        BasicPublishOptions, BasicQosOptions, ExchangeDeclareOptions, QueueBindOptions,
+        QueueDeclareOptions,
     },
-    types::{ShortString, ShortUInt},
+    types::{AMQPValue, ShortString, ShortUInt},
     BasicProperties, Channel, ConnectionProperties, Consumer, ExchangeKind, Queue,
 };
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use serde::Deserialize;
 use thiserror::Error as ThisError;
+use tokio::time::Instant;
 use tokio_amqp::*;
+use tokio_util::sync::CancellationToken;
 
-use crate::utils::serialization::SerializationKind;
+use crate::utils::auth::{
+    sign_message, AuthError, DeviceKeyCache, DEVICE_ID_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+};
+use crate::utils::serialization::{SerializationError, SerializationKind};
 
 #[derive(ThisError, Debug)]
 pub enum AmqpError {
@@ -33,13 +44,23 @@ pub enum AmqpError {
     CompressError(#[from] std::io::Error),
     #[error("decompress error: {0}")]
     DecompressError(#[from] FromUtf8Error),
+    #[error("no reply received in time")]
+    ReplyTimeout,
+    #[error("serialization error: {0}")]
+    SerializationError(#[from] SerializationError),
+    #[error("signature error: {0}")]
+    SignatureError(#[from] AuthError),
 }
 
+// RabbitMQ pseudo queue to receive replies without declaring a queue
+const DIRECT_REPLY_TO_QUEUE: &str = "amq.rabbitmq.reply-to";
+
 //trace!("-> compressed {:?}, uncompressed {:?}", compressed_data.len(), payload.len());
 
 #[derive(Debug, Clone)]
 pub struct Amqp {
     pub pool: Pool,
+    pub addr: String,
 }
 
 #[derive(Debug, Clone)]
@@ -49,6 +70,8 @@ pub struct AmqpSettings<'a> {
     pub queue: QueueSettings<'a>,
     pub queue_bind: QueueBindSettings<'a>,
     pub consumer: ConsumerSettings<'a>,
+    // When set, messages not signed by a known device key are rejected
+    pub verifier: Option<DeviceKeyCache>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -86,15 +109,48 @@ pub struct ConsumerSettings<'a> {
     pub arguments: FieldTable,
 }
 
+// Messages are added to a batch and acknowledged all at once after a
+// successful flush, a failed flush requeues every message of the batch
+#[async_trait]
+pub trait BatchConsumer<T>: Send {
+    type Error: Error + Send;
+
+    // An error rejects this message only
+    fn add(&mut self, msg: T) -> Result<(), Self::Error>;
+
+    fn is_full(&self) -> bool;
+
+    // When the current batch must be flushed even if not full
+    fn deadline(&self) -> Instant;
+
+    async fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+// Messages are handled one at a time with their routing key and
+// acknowledged once handled, an error rejects the message
+#[async_trait]
+pub trait MessageHandler<T>: Send {
+    type Error: Error + Send;
+
+    async fn handle(&mut self, msg: T, routing_key: &str) -> Result<(), Self::Error>;
+}
+
+// Only the device id is read to match it against the signing device
+#[derive(Debug, Deserialize, Default)]
+struct DeviceIdentity {
+    #[serde(default)]
+    device_id: String,
+}
+
 impl Amqp {
     #[allow(deprecated)]
     pub fn new(url: String, pool_max_size: usize) -> Self {
-        let manager = Manager::new(url, ConnectionProperties::default().with_tokio());
+        let manager = Manager::new(url.clone(), ConnectionProperties::default().with_tokio());
         let pool: Pool = Pool::builder(manager)
             .max_size(pool_max_size)
             .build()
             .expect("can create pool");
-        Amqp { pool }
+        Amqp { pool, addr: url }
     }
 
     pub fn close(&self) {
@@ -267,6 +323,17 @@ impl Amqp {
         payload: &str,
         exchange: &str,
         routing_key: &str,
+    ) -> Result<&str, AmqpError> {
+        self.send_message_with_headers(payload, exchange, routing_key, FieldTable::default())
+            .await
+    }
+
+    pub async fn send_message_with_headers(
+        &self,
+        payload: &str,
+        exchange: &str,
+        routing_key: &str,
+        headers: FieldTable,
     ) -> Result<&str, AmqpError> {
         // Create message and compress using Brotli 10
         let compressed_payload = Amqp::compress_message(payload)?;
@@ -275,7 +342,9 @@ impl Amqp {
         let channel = self.get_channel().await?;
 
         // Set encoding type
-        let headers = BasicProperties::default().with_content_encoding("br".into());
+        let headers = BasicProperties::default()
+            .with_content_encoding("br".into())
+            .with_headers(headers);
         match channel
             .basic_publish(
                 exchange,
@@ -300,6 +369,49 @@ impl Amqp {
         Ok("OK")
     }
 
+    // Headers proving the payload was sent by the device owning the key
+    pub fn signature_headers(device_id: &str, device_key: &str, payload: &str) -> FieldTable {
+        let timestamp = Utc::now().timestamp_nanos();
+        let mut headers = FieldTable::default();
+        headers.insert(
+            DEVICE_ID_HEADER.into(),
+            AMQPValue::LongString(device_id.into()),
+        );
+        headers.insert(TIMESTAMP_HEADER.into(), AMQPValue::LongLongInt(timestamp));
+        headers.insert(
+            SIGNATURE_HEADER.into(),
+            AMQPValue::LongString(sign_message(device_key, timestamp, payload.as_bytes()).into()),
+        );
+        headers
+    }
+
+    // Payload must be the uncompressed message and device_id the one it claims to be from
+    pub fn verify_signature(
+        keys: &DeviceKeyCache,
+        properties: &BasicProperties,
+        payload: &[u8],
+        device_id: &str,
+    ) -> Result<(), AuthError> {
+        let headers = properties.headers().as_ref().ok_or(AuthError::Unsigned)?;
+        let header = |key: &str| headers.inner().get(key);
+        let signer = header(DEVICE_ID_HEADER)
+            .and_then(|x| x.as_long_string())
+            .map(|x| x.to_string())
+            .ok_or(AuthError::Unsigned)?;
+        let timestamp = header(TIMESTAMP_HEADER)
+            .and_then(|x| x.as_long_long_int())
+            .ok_or(AuthError::Unsigned)?;
+        let signature = header(SIGNATURE_HEADER)
+            .and_then(|x| x.as_long_string())
+            .map(|x| x.to_string())
+            .ok_or(AuthError::Unsigned)?;
+
+        if signer != device_id {
+            return Err(AuthError::DeviceMismatch(signer, device_id.to_string()));
+        }
+        keys.verify(&signer, timestamp, payload, &signature)
+    }
+
     pub async fn send_message_with_channel<'a>(
         channel: &'a Channel,
         payload: &'a str,
@@ -377,6 +489,89 @@ impl Amqp {
         Ok(String::from("OK"))
     }
 
+    // Rpc over direct reply-to, returns the uncompressed reply
+    pub async fn send_request(
+        &self,
+        payload: &str,
+        exchange: &str,
+        routing_key: &str,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, AmqpError> {
+        self.send_request_with_headers(
+            payload,
+            exchange,
+            routing_key,
+            timeout,
+            FieldTable::default(),
+        )
+        .await
+    }
+
+    pub async fn send_request_with_headers(
+        &self,
+        payload: &str,
+        exchange: &str,
+        routing_key: &str,
+        timeout: Duration,
+        headers: FieldTable,
+    ) -> Result<Vec<u8>, AmqpError> {
+        let compressed_payload = Amqp::compress_message(payload)?;
+        let channel = self.get_channel().await?;
+
+        // The channel is closed whatever happens to the request
+        let reply: Result<Delivery, AmqpError> = async {
+            // Reply consumer must exist on the same channel before publishing
+            let mut consumer = channel
+                .basic_consume(
+                    DIRECT_REPLY_TO_QUEUE,
+                    "",
+                    BasicConsumeOptions {
+                        no_ack: true,
+                        ..Default::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await?;
+
+            let properties = BasicProperties::default()
+                .with_content_encoding("br".into())
+                .with_reply_to(DIRECT_REPLY_TO_QUEUE.into())
+                .with_headers(headers);
+            channel
+                .basic_publish(
+                    exchange,
+                    routing_key,
+                    BasicPublishOptions::default(),
+                    &compressed_payload,
+                    properties,
+                )
+                .await?
+                .await?;
+
+            let delivery = tokio::time::timeout(timeout, consumer.next())
+                .await
+                .map_err(|_| AmqpError::ReplyTimeout)?
+                .ok_or(AmqpError::ReplyTimeout)??;
+            Ok(delivery)
+        }
+        .await;
+        if let Err(error) = channel.close(200, "OK").await {
+            error!("can't close reply channel: {}", error);
+        }
+        let delivery = reply?;
+
+        match delivery
+            .properties
+            .content_encoding()
+            .clone()
+            .unwrap_or_else(|| ShortString::from(""))
+            .as_str()
+        {
+            "br" => Amqp::decompress_message(delivery.data),
+            _ => Ok(delivery.data),
+        }
+    }
+
     pub async fn consume_topic_queue<T, E: Error>(
         &self,
         index: usize,
@@ -386,19 +581,226 @@ impl Amqp {
     ) where
         T: for<'a> Deserialize<'a> + std::fmt::Debug,
     {
+        let (channel, consumer) = self.declare_topic_consumer(index, &settings).await;
+
+        // Liscen to topic queue exchange
+        debug!("{}: consumer <{}> is liscening", index, consumer.tag());
+        self.listen(
+            &channel,
+            consumer,
+            serialization,
+            settings.verifier.as_ref(),
+            on_msg_callback,
+        )
+        .await;
+        debug!("{}: Shutting down...", index);
+    }
+
+    // Stops consuming when the token is cancelled, then flushes and
+    // acknowledges the pending batch before returning
+    pub async fn consume_topic_queue_in_batches<T, B: BatchConsumer<T>>(
+        &self,
+        index: usize,
+        settings: AmqpSettings<'_>,
+        serialization: SerializationKind,
+        batch: &mut B,
+        token: CancellationToken,
+    ) where
+        T: for<'a> Deserialize<'a> + std::fmt::Debug,
+    {
+        let (channel, mut consumer) = self.declare_topic_consumer(index, &settings).await;
+
+        debug!("{}: consumer <{}> is liscening", index, consumer.tag());
+        let mut last_delivery_tag: Option<u64> = None;
+        loop {
+            let delivery = tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep_until(batch.deadline()), if last_delivery_tag.is_some() => {
+                    Amqp::flush_batch(index, &channel, batch, &mut last_delivery_tag).await;
+                    continue;
+                }
+                delivery = consumer.next() => delivery,
+            };
+            let Some(delivery) = delivery else {
+                break;
+            };
+            let delivery = match delivery {
+                Ok(x) => x,
+                Err(error) => {
+                    error!("{}: can't receive message {}", index, error);
+                    continue;
+                }
+            };
+
+            let result =
+                Amqp::decode_delivery(&delivery, &serialization, settings.verifier.as_ref())
+                    .await
+                    .map_err(|x| x.to_string())
+                    .and_then(|msg: T| batch.add(msg).map_err(|x| x.to_string()));
+            match result {
+                Ok(()) => last_delivery_tag = Some(delivery.delivery_tag),
+                Err(error) => {
+                    warn!(
+                        "{}: rejected message <{}> {}",
+                        index, delivery.delivery_tag, error
+                    );
+                    Amqp::reject_message(&channel, delivery.delivery_tag).await;
+                }
+            }
+            if last_delivery_tag.is_some() && batch.is_full() {
+                Amqp::flush_batch(index, &channel, batch, &mut last_delivery_tag).await;
+            }
+        }
+
+        // Drain, no new message is delivered while the pending batch is flushed
+        if let Err(error) = channel
+            .basic_cancel(consumer.tag().as_str(), BasicCancelOptions::default())
+            .await
+        {
+            error!(
+                "{}: can't cancel consumer <{}> {}",
+                index,
+                consumer.tag(),
+                error
+            );
+        }
+        Amqp::flush_batch(index, &channel, batch, &mut last_delivery_tag).await;
+        debug!("{}: Shutting down...", index);
+    }
+
+    // Stops consuming when the token is cancelled, the message being
+    // handled is acknowledged before returning
+    pub async fn consume_topic_queue_with_handler<T, H: MessageHandler<T>>(
+        &self,
+        index: usize,
+        settings: AmqpSettings<'_>,
+        serialization: SerializationKind,
+        handler: &mut H,
+        token: CancellationToken,
+    ) where
+        T: for<'a> Deserialize<'a> + std::fmt::Debug + Send,
+    {
+        let (channel, mut consumer) = self.declare_topic_consumer(index, &settings).await;
+
+        debug!("{}: consumer <{}> is liscening", index, consumer.tag());
+        loop {
+            let delivery = tokio::select! {
+                _ = token.cancelled() => break,
+                delivery = consumer.next() => delivery,
+            };
+            let Some(delivery) = delivery else {
+                break;
+            };
+            let delivery = match delivery {
+                Ok(x) => x,
+                Err(error) => {
+                    error!("{}: can't receive message {}", index, error);
+                    continue;
+                }
+            };
+
+            let msg: T =
+                match Amqp::decode_delivery(&delivery, &serialization, settings.verifier.as_ref())
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(error) => {
+                        warn!(
+                            "{}: rejected message <{}> {}",
+                            index, delivery.delivery_tag, error
+                        );
+                        Amqp::reject_message(&channel, delivery.delivery_tag).await;
+                        continue;
+                    }
+                };
+            match handler.handle(msg, delivery.routing_key.as_str()).await {
+                Ok(()) => {
+                    if let Err(error) = channel
+                        .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+                        .await
+                    {
+                        error!(
+                            "{}: can't acknowledge message <{}> {}",
+                            index, delivery.delivery_tag, error
+                        );
+                    }
+                }
+                Err(error) => {
+                    warn!(
+                        "{}: rejected message <{}> {}",
+                        index, delivery.delivery_tag, error
+                    );
+                    Amqp::reject_message(&channel, delivery.delivery_tag).await;
+                }
+            }
+        }
+        debug!("{}: Shutting down...", index);
+    }
+
+    async fn flush_batch<T, B: BatchConsumer<T>>(
+        index: usize,
+        channel: &Channel,
+        batch: &mut B,
+        last_delivery_tag: &mut Option<u64>,
+    ) {
+        let Some(delivery_tag) = last_delivery_tag.take() else {
+            return;
+        };
+        match batch.flush().await {
+            Ok(()) => {
+                match channel
+                    .basic_ack(delivery_tag, BasicAckOptions { multiple: true })
+                    .await
+                {
+                    Ok(()) => trace!("{}: acknowledged messages up to <{}>", index, delivery_tag),
+                    Err(error) => error!(
+                        "{}: can't acknowledge messages up to <{}> {}",
+                        index, delivery_tag, error
+                    ),
+                }
+            }
+            Err(error) => {
+                error!(
+                    "{}: can't flush messages up to <{}> {}",
+                    index, delivery_tag, error
+                );
+                if let Err(error) = channel
+                    .basic_nack(
+                        delivery_tag,
+                        BasicNackOptions {
+                            multiple: true,
+                            requeue: true,
+                        },
+                    )
+                    .await
+                {
+                    error!(
+                        "{}: can't negative acknowledge messages up to <{}> {}",
+                        index, delivery_tag, error
+                    );
+                }
+            }
+        }
+    }
+
+    async fn declare_topic_consumer(
+        &self,
+        index: usize,
+        settings: &AmqpSettings<'_>,
+    ) -> (Channel, Consumer) {
         // Channel
-        let channel = &self.get_channel().await.unwrap();
+        let channel = self.get_channel().await.unwrap();
         channel
             .basic_qos(settings.channel.prefetch_count, settings.channel.options)
             .await
             .unwrap();
         match self
             .declare_exchange_with_channel(
-                channel,
+                &channel,
                 settings.exchange.name,
-                settings.exchange.kind,
+                settings.exchange.kind.clone(),
                 settings.exchange.options,
-                settings.exchange.arguments,
+                settings.exchange.arguments.clone(),
             )
             .await
         {
@@ -415,10 +817,10 @@ impl Amqp {
         // Queue
         let queue = match self
             .declare_queue_with_channel(
-                channel,
+                &channel,
                 settings.queue.name,
                 settings.queue.options,
-                settings.queue.arguments,
+                settings.queue.arguments.clone(),
             )
             .await
         {
@@ -438,12 +840,12 @@ impl Amqp {
         // Binding
         match self
             .bind_queue_with_channel(
-                channel,
+                &channel,
                 queue.name().as_str(),
                 settings.exchange.name,
                 settings.queue_bind.routing_key,
                 settings.queue_bind.options,
-                settings.queue_bind.arguments,
+                settings.queue_bind.arguments.clone(),
             )
             .await
         {
@@ -465,11 +867,11 @@ impl Amqp {
         // Consumer
         let consumer = match self
             .create_consumer_with_channel(
-                channel,
+                &channel,
                 settings.queue.name,
                 settings.consumer.consumer_tag,
                 settings.consumer.options,
-                settings.consumer.arguments,
+                settings.consumer.arguments.clone(),
             )
             .await
         {
@@ -493,11 +895,7 @@ impl Amqp {
             }
         };
 
-        // Liscen to topic queue exchange
-        debug!("{}: consumer <{}> is liscening", index, consumer.tag());
-        self.listen(channel, consumer, serialization, on_msg_callback)
-            .await;
-        debug!("{}: Shutting down...", index);
+        (channel, consumer)
     }
 
     pub async fn consume_queue<T, E: Error>(
@@ -558,7 +956,7 @@ impl Amqp {
             }
         };
         debug!("consumer <{}> is liscening", consumer.tag());
-        self.listen(channel, consumer, serialization, on_msg_callback)
+        self.listen(channel, consumer, serialization, None, on_msg_callback)
             .await;
     }
 
@@ -567,6 +965,7 @@ impl Amqp {
         channel: &Channel,
         mut consumer: Consumer,
         serialization: SerializationKind,
+        verifier: Option<&DeviceKeyCache>,
         mut on_msg_callback: impl FnMut(T, Option<ShortString>) -> Result<(), E>,
     ) where
         T: for<'a> Deserialize<'a> + std::fmt::Debug,
@@ -579,21 +978,15 @@ impl Amqp {
                     ShortString::from("")
                 };
 
-                let payload: Vec<u8> = delivery.data.clone();
-                let uncompressed_message = match delivery
-                    .properties
-                    .content_encoding()
-                    .clone()
-                    .unwrap_or_else(|| ShortString::from(""))
-                    .as_str()
-                {
-                    "br" => Amqp::decompress_message(payload),
-                    _ => Ok(payload),
-                }
-                .unwrap();
-
                 let deserialized_payload: T =
-                    serialization.from_vec(&uncompressed_message).unwrap();
+                    match Amqp::decode_delivery(&delivery, &serialization, verifier).await {
+                        Ok(x) => x,
+                        Err(error) => {
+                            warn!("rejected message <{}> {}", delivery.delivery_tag, error);
+                            Amqp::reject_message(channel, delivery.delivery_tag).await;
+                            continue;
+                        }
+                    };
 
                 match on_msg_callback(deserialized_payload, Some(reply_to)) {
                     Ok(()) => {
@@ -635,4 +1028,66 @@ impl Amqp {
             }
         }
     }
+
+    // Uncompress, verify the signature when a verifier is given and deserialize
+    pub async fn decode_delivery<T>(
+        delivery: &Delivery,
+        serialization: &SerializationKind,
+        verifier: Option<&DeviceKeyCache>,
+    ) -> Result<T, AmqpError>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        let payload: Vec<u8> = delivery.data.clone();
+        let uncompressed_message = match delivery
+            .properties
+            .content_encoding()
+            .clone()
+            .unwrap_or_else(|| ShortString::from(""))
+            .as_str()
+        {
+            "br" => Amqp::decompress_message(payload)?,
+            _ => payload,
+        };
+
+        if let Some(keys) = verifier {
+            let identity: DeviceIdentity = serialization.from_vec(&uncompressed_message)?;
+            let verify = || {
+                Amqp::verify_signature(
+                    keys,
+                    &delivery.properties,
+                    &uncompressed_message,
+                    &identity.device_id,
+                )
+            };
+            if let Err(error) = verify() {
+                // The key may have been created since the last refresh
+                let is_reloadable = matches!(error, AuthError::UnknownDevice(_));
+                if !is_reloadable || !keys.reload(&identity.device_id).await {
+                    return Err(error.into());
+                }
+                verify()?;
+            }
+        }
+        Ok(serialization.from_vec(&uncompressed_message)?)
+    }
+
+    // Negative acknowledge without requeue, the message can't ever be processed
+    pub async fn reject_message(channel: &Channel, delivery_tag: u64) {
+        if let Err(error) = channel
+            .basic_nack(
+                delivery_tag,
+                BasicNackOptions {
+                    multiple: false,
+                    requeue: false,
+                },
+            )
+            .await
+        {
+            error!(
+                "can't negative acknowledge message <{}> {}",
+                delivery_tag, error
+            );
+        }
+    }
 }
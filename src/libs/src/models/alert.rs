@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::Thing;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertCondition {
+    #[default]
+    Above,
+    Below,
+    // Change per second between two consecutive values
+    RateAbove,
+    RateBelow,
+}
+
+impl AlertCondition {
+    pub fn as_str(&self) -> &str {
+        match self {
+            AlertCondition::Above => "above",
+            AlertCondition::Below => "below",
+            AlertCondition::RateAbove => "rate_above",
+            AlertCondition::RateBelow => "rate_below",
+        }
+    }
+
+    pub fn is_rate(&self) -> bool {
+        matches!(self, AlertCondition::RateAbove | AlertCondition::RateBelow)
+    }
+}
+
+// Silences the alerts of a rule until the given time, for every
+// device when device_id is empty
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AlertSilence {
+    #[serde(default)]
+    pub device_id: String,
+    pub until: i64,
+}
+
+// ex: devices tagged building=43, sensor 2 above 80 for 5m
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AlertRule {
+    pub id: Option<Thing>,
+    pub rule_id: String,
+    pub description: String,
+    // Top level tag properties a device must have, every device if empty
+    pub tags: HashMap<String, Value>,
+    pub sensor_id: i64,
+    pub condition: AlertCondition,
+    pub threshold: f64,
+    // Seconds the condition must hold before the alert fires
+    pub for_secs: u64,
+    pub silences: Vec<AlertSilence>,
+    pub update_time: i64,
+}
+
+impl AlertRule {
+    pub fn matches_tags(&self, tags: Option<&Value>) -> bool {
+        self.tags
+            .iter()
+            .all(|(key, value)| tags.and_then(|x| x.get(key)) == Some(value))
+    }
+
+    pub fn is_silenced(&self, device_id: &str, timestamp: i64) -> bool {
+        self.silences
+            .iter()
+            .any(|x| x.until > timestamp && (x.device_id.is_empty() || x.device_id == device_id))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NewAlertRuleReq {
+    pub rule_id: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: HashMap<String, Value>,
+    pub sensor_id: i64,
+    #[serde(default)]
+    pub condition: AlertCondition,
+    pub threshold: f64,
+    #[serde(default)]
+    pub for_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SilenceAlertReq {
+    // Every device of the rule when empty
+    #[serde(default)]
+    pub device_id: String,
+    pub duration_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    #[default]
+    Ok,
+    // Condition holds but not for long enough yet
+    Pending,
+    Firing,
+}
+
+// Current state of a rule for one device
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Alert {
+    pub alert_id: String,
+    pub rule_id: String,
+    pub device_id: String,
+    pub sensor_id: i64,
+    pub state: AlertState,
+    pub value: f64,
+    // Time the condition started to hold, 0 when ok
+    pub since: i64,
+    pub fired_time: i64,
+    pub silenced: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertEventKind {
+    Fired,
+    Resolved,
+}
+
+impl AlertEventKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            AlertEventKind::Fired => "fired",
+            AlertEventKind::Resolved => "resolved",
+        }
+    }
+}
+
+// Published on the alerts exchange when an alert changes state
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlertEvent {
+    pub kind: AlertEventKind,
+    pub alert_id: String,
+    pub rule_id: String,
+    pub device_id: String,
+    pub sensor_id: i64,
+    pub condition: AlertCondition,
+    pub threshold: f64,
+    pub value: f64,
+    pub timestamp: i64,
+}
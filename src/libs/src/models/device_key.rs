@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+
+// Symmetric keys used by a device to sign its messages. On rotation the primary
+// key becomes the secondary so devices can be updated without downtime.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceKey {
+    pub id: Option<Thing>,
+    pub device_id: String,
+    pub primary_key: String,
+    pub secondary_key: String,
+    pub rotated_time: i64,
+    // Enrollment group that provisioned the device, empty when created by an operator
+    #[serde(default)]
+    pub group_id: String,
+}
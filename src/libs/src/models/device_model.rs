@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::Thing;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorKind {
+    #[default]
+    Float,
+    Int,
+    Bool,
+    String,
+}
+
+impl SensorKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SensorKind::Float => "float",
+            SensorKind::Int => "int",
+            SensorKind::Bool => "bool",
+            SensorKind::String => "string",
+        }
+    }
+}
+
+// Describes a sensor id found in the device telemetry
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SensorDefinition {
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub unit: String,
+    #[serde(default)]
+    pub kind: SensorKind,
+    #[serde(default)]
+    pub description: String,
+    // Raw values times multiplier give the value in unit
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+}
+
+impl Default for SensorDefinition {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            name: String::new(),
+            unit: String::new(),
+            kind: SensorKind::default(),
+            description: String::new(),
+            multiplier: default_multiplier(),
+        }
+    }
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+// Definition shared by every device twin with the same model_id.
+// Schemas are JSON-Schema, a null schema accepts anything.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceModel {
+    pub id: Option<Thing>,
+    pub model_id: String,
+    pub version: usize,
+    pub description: String,
+    pub sensors: Vec<SensorDefinition>,
+    pub desired_schema: Value,
+    pub reported_schema: Value,
+    pub commands_schema: Value,
+    pub update_time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NewDeviceModelReq {
+    pub model_id: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub sensors: Vec<SensorDefinition>,
+    #[serde(default)]
+    pub desired_schema: Value,
+    #[serde(default)]
+    pub reported_schema: Value,
+    #[serde(default)]
+    pub commands_schema: Value,
+}
+
+// Shared view of device models, which model each device uses and the
+// sensors devices declared themselves, so telemetry consumers can name
+// their sensors
+#[derive(Debug, Clone, Default)]
+pub struct DeviceModelCache {
+    models: Arc<RwLock<HashMap<String, DeviceModel>>>,
+    devices: Arc<RwLock<HashMap<String, String>>>,
+    declared: Arc<RwLock<HashMap<String, Vec<SensorDefinition>>>>,
+}
+
+impl DeviceModelCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Keeps the latest version of each model
+    pub fn replace_models(&self, models: Vec<DeviceModel>) {
+        let mut latest: HashMap<String, DeviceModel> = HashMap::new();
+        for model in models {
+            match latest.get(&model.model_id) {
+                Some(x) if x.version >= model.version => {}
+                _ => {
+                    latest.insert(model.model_id.clone(), model);
+                }
+            }
+        }
+        *self.models.write().unwrap() = latest;
+    }
+
+    // Pairs of device_id and model_id
+    pub fn replace_devices(&self, devices: Vec<(String, String)>) {
+        let mut cache = self.devices.write().unwrap();
+        *cache = devices.into_iter().collect();
+    }
+
+    // Pairs of device_id and the sensors it declared when joining
+    pub fn replace_declared(&self, declared: Vec<(String, Vec<SensorDefinition>)>) {
+        let mut cache = self.declared.write().unwrap();
+        *cache = declared.into_iter().collect();
+    }
+
+    pub fn declare(&self, device_id: &str, sensors: Vec<SensorDefinition>) {
+        self.declared
+            .write()
+            .unwrap()
+            .insert(device_id.to_string(), sensors);
+    }
+
+    pub fn model_id(&self, device_id: &str) -> Option<String> {
+        self.devices.read().unwrap().get(device_id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.models.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn model(&self, model_id: &str) -> Option<DeviceModel> {
+        self.models.read().unwrap().get(model_id).cloned()
+    }
+
+    // The model definition wins over what the device declared
+    pub fn sensor(&self, device_id: &str, sensor_id: i64) -> Option<SensorDefinition> {
+        self.model_sensors(device_id)
+            .into_iter()
+            .chain(self.declared_sensors(device_id))
+            .find(|x| x.id == sensor_id)
+    }
+
+    // Every sensor known for a device, sorted by id, with whether it comes
+    // from the model or was declared by the device
+    pub fn sensors(&self, device_id: &str) -> Vec<(SensorDefinition, SensorSource)> {
+        let mut sensors: Vec<(SensorDefinition, SensorSource)> = self
+            .model_sensors(device_id)
+            .into_iter()
+            .map(|x| (x, SensorSource::Model))
+            .collect();
+        for sensor in self.declared_sensors(device_id) {
+            if !sensors.iter().any(|(x, _)| x.id == sensor.id) {
+                sensors.push((sensor, SensorSource::Device));
+            }
+        }
+        sensors.sort_by_key(|(x, _)| x.id);
+        sensors
+    }
+
+    fn model_sensors(&self, device_id: &str) -> Vec<SensorDefinition> {
+        let devices = self.devices.read().unwrap();
+        let models = self.models.read().unwrap();
+        devices
+            .get(device_id)
+            .and_then(|x| models.get(x))
+            .map(|x| x.sensors.clone())
+            .unwrap_or_default()
+    }
+
+    fn declared_sensors(&self, device_id: &str) -> Vec<SensorDefinition> {
+        self.declared
+            .read()
+            .unwrap()
+            .get(device_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorSource {
+    Model,
+    Device,
+}
+
+impl SensorSource {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SensorSource::Model => "model",
+            SensorSource::Device => "device",
+        }
+    }
+}
@@ -1,3 +1,11 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -52,14 +60,14 @@ pub struct Record {
     id: Thing,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default, ValueEnum)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq, ValueEnum)]
 pub enum Status {
     #[default]
     Disabled,
     Enabled,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
 pub enum StatusReason {
     #[default]
     Provisioned,
@@ -88,12 +96,58 @@ pub struct DeviceTwin {
 pub struct MetaProperties {
     pub device_id: String,
     pub model_id: String,
+    // Version of the model the properties are validated against, pinned
+    // when the twin is created, 0 follows the latest
+    #[serde(default)]
+    pub model_version: usize,
     pub status: Status,
     pub status_reason: StatusReason,
     pub status_update_time: i64,
     pub connection_state: ConnectionState,
     pub last_activity_time: i64,
     pub version: usize,
+    // Last desired properties the device could not apply
+    #[serde(default)]
+    pub desired_error: Option<DesiredError>,
+    // Sent with the last heartbeat
+    #[serde(default)]
+    pub metadata: DeviceMetadata,
+}
+
+// Desired properties under this key configure oxi itself, they are not
+// validated against the device model
+pub const RESERVED_DESIRED_KEY: &str = "$mir";
+
+// Connected devices without heartbeat for that long are disconnected by mir,
+// unless configured otherwise
+pub const DEFAULT_DISCONNECT_TIMEOUT_SECS: u64 = 180;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct DeviceMetadata {
+    pub sdk_version: String,
+    pub firmware_version: String,
+    pub uptime_secs: u64,
+    pub ip: String,
+    // Messages waiting to be sent
+    pub queue_depth: usize,
+    pub heartbeat_interval_secs: u64,
+    // Set by the device developer
+    pub custom: HashMap<String, String>,
+}
+
+// Stale once the desired properties version is higher
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DesiredError {
+    pub version: usize,
+    pub error: String,
+    pub timestamp: i64,
+}
+
+impl MetaProperties {
+    pub fn is_enabled(&self) -> bool {
+        self.status == Status::Enabled
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -108,3 +162,72 @@ pub struct NewDeviceReq {
     pub model_id: String,
     pub status: Status,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusAction {
+    #[default]
+    Enable,
+    Disable,
+    Block,
+    Unblock,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceStatusReq {
+    pub action: StatusAction,
+}
+
+// Shared view of devices that are not enabled, their messages are dropped
+// by the consumers and counted
+#[derive(Debug, Clone, Default)]
+pub struct DeviceStatusCache {
+    disabled: Arc<RwLock<HashSet<String>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl DeviceStatusCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn replace_all(&self, disabled_device_ids: Vec<String>) {
+        let mut cache = self.disabled.write().unwrap();
+        *cache = disabled_device_ids.into_iter().collect();
+    }
+
+    pub fn update(&self, meta: &MetaProperties) {
+        let mut cache = self.disabled.write().unwrap();
+        if meta.is_enabled() {
+            cache.remove(&meta.device_id);
+        } else {
+            cache.insert(meta.device_id.clone());
+        }
+    }
+
+    pub fn remove(&self, device_id: &str) {
+        self.disabled.write().unwrap().remove(device_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.disabled.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Devices without a twin are not known to be disabled
+    pub fn is_enabled(&self, device_id: &str) -> bool {
+        !self.disabled.read().unwrap().contains(device_id)
+    }
+
+    // Returns the total of dropped messages
+    pub fn count_dropped(&self) -> u64 {
+        self.dropped.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
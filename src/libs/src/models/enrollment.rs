@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::Thing;
+
+// Template used by redox to create the twin of a device provisioning itself.
+// Devices prove they belong to the group with a key derived from the attestation secret.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EnrollmentGroup {
+    pub id: Option<Thing>,
+    pub group_id: String,
+    pub model_id: String,
+    pub attestation_secret: String,
+    pub tag_properties: Value,
+    pub desired_properties: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NewEnrollmentGroupReq {
+    pub group_id: String,
+    pub model_id: String,
+    #[serde(default)]
+    pub tag_properties: Value,
+    #[serde(default)]
+    pub desired_properties: Value,
+}
@@ -1,2 +1,11 @@
+pub mod alert;
+pub mod device_key;
+pub mod device_model;
 pub mod device_twin;
+pub mod enrollment;
+pub mod ota;
+pub mod route;
 pub mod telemetry;
+pub mod telemetry_query;
+pub mod upload;
+pub mod webhook;
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::Thing;
+
+use crate::models::device_twin::RESERVED_DESIRED_KEY;
+use crate::utils::checksum::checksum;
+
+// Section of the reserved desired and reported properties, ex:
+// {"$mir": {"firmware": {"campaign_id": "v2-rollout", ...}}}
+pub const FIRMWARE_KEY: &str = "firmware";
+
+// Firmware or software stored by redox and downloaded by the devices
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Artifact {
+    pub id: Option<Thing>,
+    pub artifact_id: String,
+    pub version: String,
+    // Hex sha256 of the content
+    pub checksum: String,
+    pub size: u64,
+    pub create_time: i64,
+}
+
+// Targets every device matching all the filters set
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CampaignTarget {
+    #[serde(default)]
+    pub device_ids: Vec<String>,
+    #[serde(default)]
+    pub model_id: String,
+    // Top level tag properties a device must have
+    #[serde(default)]
+    pub tags: HashMap<String, Value>,
+}
+
+impl CampaignTarget {
+    pub fn matches(&self, device_id: &str, model_id: &str, tags: Option<&Value>) -> bool {
+        (self.device_ids.is_empty() || self.device_ids.iter().any(|x| x == device_id))
+            && (self.model_id.is_empty() || self.model_id == model_id)
+            && self
+                .tags
+                .iter()
+                .all(|(key, value)| tags.and_then(|x| x.get(key)) == Some(value))
+    }
+}
+
+// Rollout of an artifact, the targeted devices are resolved on creation
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Campaign {
+    pub id: Option<Thing>,
+    pub campaign_id: String,
+    pub artifact_id: String,
+    pub version: String,
+    pub target: CampaignTarget,
+    pub device_ids: Vec<String>,
+    pub create_time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NewCampaignReq {
+    pub campaign_id: String,
+    pub artifact_id: String,
+    #[serde(default)]
+    pub target: CampaignTarget,
+}
+
+// Desired by mir, the device downloads the artifact when the campaign
+// differs from the one it reported
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct FirmwareDesired {
+    pub campaign_id: String,
+    pub artifact_id: String,
+    pub version: String,
+    pub checksum: String,
+    pub size: u64,
+}
+
+impl FirmwareDesired {
+    pub fn from_properties(properties: &Value) -> Option<Self> {
+        let value = properties.get(RESERVED_DESIRED_KEY)?.get(FIRMWARE_KEY)?;
+        serde_json::from_value(value.clone()).ok()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FirmwareState {
+    Downloading,
+    Verifying,
+    Applying,
+    Success,
+    Failed,
+}
+
+impl FirmwareState {
+    pub fn is_done(&self) -> bool {
+        matches!(self, FirmwareState::Success | FirmwareState::Failed)
+    }
+}
+
+// Reported by the device while it updates
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FirmwareReported {
+    pub campaign_id: String,
+    pub artifact_id: String,
+    pub version: String,
+    pub state: FirmwareState,
+    // Percent of the artifact downloaded
+    #[serde(default)]
+    pub progress: u8,
+    #[serde(default)]
+    pub error: String,
+}
+
+impl FirmwareReported {
+    pub fn from_properties(properties: &Value) -> Option<Self> {
+        let value = properties.get(RESERVED_DESIRED_KEY)?.get(FIRMWARE_KEY)?;
+        serde_json::from_value(value.clone()).ok()
+    }
+}
+
+// Devices without a reported state for the campaign are pending
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CampaignStatus {
+    pub campaign_id: String,
+    pub artifact_id: String,
+    pub version: String,
+    pub total: usize,
+    pub pending: usize,
+    pub states: HashMap<FirmwareState, usize>,
+    pub devices: Vec<CampaignDeviceStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CampaignDeviceStatus {
+    pub device_id: String,
+    pub state: Option<FirmwareState>,
+    pub progress: u8,
+    pub error: String,
+}
+
+// Asks for length bytes of the artifact from offset
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceArtifactChunkRequest {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub artifact_id: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceArtifactChunkResponse {
+    pub artifact_id: String,
+    pub offset: u64,
+    // Size of the whole artifact
+    pub size: u64,
+    // Base64 content, empty past the end
+    #[serde(default)]
+    pub data: String,
+    // Hex sha256 of the chunk content
+    #[serde(default)]
+    pub checksum: String,
+    #[serde(default)]
+    pub error: String,
+}
+
+impl DeviceArtifactChunkResponse {
+    pub fn set_content(&mut self, content: &[u8]) {
+        self.data = STANDARD.encode(content);
+        self.checksum = checksum(content);
+    }
+
+    // None when the data was altered on the way
+    pub fn content(&self) -> Option<Vec<u8>> {
+        let content = STANDARD.decode(&self.data).ok()?;
+        (checksum(&content) == self.checksum).then_some(content)
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::Thing;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PredicateOp {
+    #[default]
+    Exists,
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+}
+
+// Compares the payload value found at a json pointer, ex: /telemetry/floats/2 gt 80
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RoutePredicate {
+    pub pointer: String,
+    #[serde(default)]
+    pub op: PredicateOp,
+    #[serde(default)]
+    pub value: Value,
+}
+
+impl RoutePredicate {
+    pub fn matches(&self, payload: &Value) -> bool {
+        let Some(found) = payload.pointer(&self.pointer) else {
+            return false;
+        };
+        match self.op {
+            PredicateOp::Exists => true,
+            PredicateOp::Eq => found == &self.value,
+            PredicateOp::Ne => found != &self.value,
+            PredicateOp::Gt => compare(found, &self.value).map_or(false, |x| x > 0.0),
+            PredicateOp::Lt => compare(found, &self.value).map_or(false, |x| x < 0.0),
+        }
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> Option<f64> {
+    Some(left.as_f64()? - right.as_f64()?)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum RouteDestination {
+    // Keeps the routing key of the message when none is given
+    Amqp {
+        exchange: String,
+        #[serde(default)]
+        routing_key: Option<String>,
+    },
+    // POST of the payload, retried on connection errors and 5xx
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    // Json lines appended on the router host
+    File {
+        path: String,
+    },
+}
+
+impl Default for RouteDestination {
+    fn default() -> Self {
+        RouteDestination::File {
+            path: String::new(),
+        }
+    }
+}
+
+// A message is forwarded to the destination of every matching route
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Route {
+    pub id: Option<Thing>,
+    pub route_id: String,
+    pub description: String,
+    // Amqp topic pattern without the tenant, ex: #.telemetry.v1
+    pub routing_key: String,
+    // Top level tag properties a device must have, every device if empty
+    pub tags: HashMap<String, Value>,
+    pub predicate: Option<RoutePredicate>,
+    pub destination: RouteDestination,
+    pub enabled: bool,
+    pub update_time: i64,
+}
+
+impl Route {
+    pub fn matches(&self, routing_key: &str, tags: Option<&Value>, payload: &Value) -> bool {
+        self.enabled
+            && routing_key_matches(&self.routing_key, routing_key)
+            && self
+                .tags
+                .iter()
+                .all(|(key, value)| tags.and_then(|x| x.get(key)) == Some(value))
+            && self.predicate.as_ref().map_or(true, |x| x.matches(payload))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NewRouteReq {
+    pub route_id: String,
+    #[serde(default)]
+    pub description: String,
+    pub routing_key: String,
+    #[serde(default)]
+    pub tags: HashMap<String, Value>,
+    #[serde(default)]
+    pub predicate: Option<RoutePredicate>,
+    pub destination: RouteDestination,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+// Amqp topic matching, * is one word and # is zero or more words
+pub fn routing_key_matches(pattern: &str, routing_key: &str) -> bool {
+    let mut pattern: Vec<&str> = pattern.split('.').collect();
+    // Consecutive # match the same as one and would only add backtracking
+    pattern.dedup_by(|x, previous| *x == "#" && *previous == "#");
+    let words: Vec<&str> = routing_key.split('.').collect();
+    words_match(&pattern, &words)
+}
+
+fn words_match(pattern: &[&str], words: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => words.is_empty(),
+        Some((&"#", rest)) => (0..=words.len()).any(|i| words_match(rest, &words[i..])),
+        Some((first, rest)) => match words.split_first() {
+            Some((word, words)) => (*first == "*" || first == word) && words_match(rest, words),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn routing_key_patterns() {
+        for (pattern, routing_key, expected) in [
+            ("a.b.c", "a.b.c", true),
+            ("a.b.c", "a.b", false),
+            ("a.b", "a.b.c", false),
+            ("*.telemetry.v1", "oxi.telemetry.v1", true),
+            ("*.telemetry.v1", "telemetry.v1", false),
+            ("*.telemetry.v1", "a.oxi.telemetry.v1", false),
+            ("#.telemetry.v1", "telemetry.v1", true),
+            ("#.telemetry.v1", "t1.oxi.telemetry.v1", true),
+            ("#.telemetry.v1", "t1.oxi.telemetry_batch.v1", false),
+            ("t1.#", "t1", true),
+            ("t1.#", "t1.oxi.telemetry.v1", true),
+            ("t1.#", "t2.oxi.telemetry.v1", false),
+            ("#", "", true),
+            ("#", "a.b.c", true),
+            ("#.#", "a", true),
+            ("a.#.#.b", "a.b", true),
+            ("a.#.#.b", "a.x.y.b", true),
+            ("a.#.b.#.c", "a.x.b.y.c", true),
+            ("a.#.b.#.c", "a.x.c.y.b", false),
+            ("a.*.#", "a", false),
+            ("a.*.#", "a.b", true),
+        ] {
+            assert_eq!(
+                routing_key_matches(pattern, routing_key),
+                expected,
+                "{} {}",
+                pattern,
+                routing_key
+            );
+        }
+    }
+
+    #[test]
+    fn many_hashes_match_quickly() {
+        let pattern = vec!["#"; 64].join(".") + ".z";
+        let routing_key = vec!["a"; 64].join(".");
+        assert!(!routing_key_matches(&pattern, &routing_key));
+    }
+
+    #[test]
+    fn predicate_ops() {
+        let payload = json!({"floats": {"2": 85.5}, "strings": {"3": "on"}});
+        for (pointer, op, value, expected) in [
+            ("/floats/2", PredicateOp::Exists, json!(null), true),
+            ("/floats/9", PredicateOp::Exists, json!(null), false),
+            ("/floats/2", PredicateOp::Eq, json!(85.5), true),
+            ("/floats/2", PredicateOp::Eq, json!(80), false),
+            ("/strings/3", PredicateOp::Eq, json!("on"), true),
+            ("/strings/3", PredicateOp::Ne, json!("off"), true),
+            ("/strings/3", PredicateOp::Ne, json!("on"), false),
+            ("/floats/9", PredicateOp::Ne, json!(1), false),
+            ("/floats/2", PredicateOp::Gt, json!(80), true),
+            ("/floats/2", PredicateOp::Gt, json!(85.5), false),
+            ("/floats/2", PredicateOp::Lt, json!(90), true),
+            ("/floats/2", PredicateOp::Lt, json!(80), false),
+            ("/strings/3", PredicateOp::Gt, json!(1), false),
+            ("/floats/2", PredicateOp::Lt, json!("90"), false),
+        ] {
+            let predicate = RoutePredicate {
+                pointer: pointer.to_string(),
+                op,
+                value: value.clone(),
+            };
+            assert_eq!(
+                predicate.matches(&payload),
+                expected,
+                "{} {:?} {}",
+                pointer,
+                op,
+                value
+            );
+        }
+    }
+}
@@ -2,7 +2,8 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-use crate::models::device_twin::Properties;
+use crate::models::device_model::SensorDefinition;
+use crate::models::device_twin::{DeviceMetadata, Properties, Status, StatusReason};
 
 // TODO: Payload is a Generic so user can send whatever
 //       And they should not care about metadata of the payload
@@ -21,10 +22,105 @@ pub struct Telemetry {
     pub strings: HashMap<i64, String>,
 }
 
+// Readings collected by the device and sent together, each with the time
+// it was taken
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceTelemetryBatchRequest {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub points: Vec<TelemetryPoint>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TelemetryPoint {
+    pub timestamp: i64,
+    pub telemetry: Telemetry,
+}
+
+// Sensors a device declares when joining, used for those its model doesn't describe
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceSensorsRequest {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub sensors: Vec<SensorDefinition>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceLogRequest {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub level: LogLevel,
+    // Module or component that emitted the record
+    pub target: String,
+    pub message: String,
+    #[serde(default)]
+    pub fields: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceMetricsRequest {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub metrics: Metrics,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Metrics {
+    // Percent of all cores
+    pub cpu_usage: f64,
+    // Bytes
+    pub memory_used: u64,
+    pub memory_total: u64,
+    // Seconds since the device started
+    pub uptime: u64,
+    // Messages waiting to be sent by the device
+    pub queue_depth: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct DeviceHeartbeatRequest {
     pub device_id: String,
     pub timestamp: i64,
+    // Last one sent when leaving the fleet, the device is disconnected right away
+    #[serde(default)]
+    pub disconnecting: bool,
+    #[serde(default)]
+    pub metadata: DeviceMetadata,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -33,9 +129,45 @@ pub struct DeviceDesiredRequest {
     pub timestamp: i64,
 }
 
+// Sent by mir to the device queue when its status changes
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceStatusNotification {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub status: Status,
+    pub status_reason: StatusReason,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct DeviceReportedRequest {
     pub device_id: String,
     pub timestamp: i64,
     pub reported_properties: Properties,
 }
+
+// Sent by the device when the desired properties don't match what it expects
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceDesiredErrorRequest {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub version: usize,
+    pub error: String,
+}
+
+// Signature is made with the key derived from the enrollment group
+// attestation secret over the registration id
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceProvisionRequest {
+    pub registration_id: String,
+    pub group_id: String,
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceProvisionResponse {
+    pub device_id: String,
+    pub device_key: String,
+    // Empty when the device was provisioned
+    pub error: String,
+}
@@ -0,0 +1,76 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::device_model::SensorKind;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Last,
+    Count,
+}
+
+impl Aggregation {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Aggregation::Avg => "avg",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Last => "last",
+            Aggregation::Count => "count",
+        }
+    }
+
+    // Only last and count make sense for bools and strings
+    pub fn supports(&self, kind: &SensorKind) -> bool {
+        match self {
+            Aggregation::Last | Aggregation::Count => true,
+            _ => matches!(kind, SensorKind::Float | SensorKind::Int),
+        }
+    }
+}
+
+// Series of one device over a time range, raw values unless an
+// aggregation is given. Timestamps are nanoseconds since epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TelemetryQuery {
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor_id: Option<i64>,
+    #[serde(default)]
+    pub kind: SensorKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<Aggregation>,
+    // Width of an aggregation bucket: 30s, 5m, 1h, 1d
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TelemetryPoint {
+    pub timestamp: i64,
+    pub sensor_id: i64,
+    pub value: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TelemetrySeries {
+    pub device_id: String,
+    pub from: i64,
+    pub to: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<Aggregation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    pub points: Vec<TelemetryPoint>,
+}
@@ -0,0 +1,78 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+
+use crate::utils::checksum::checksum;
+
+// A piece of a file sent by a device, acknowledged with the offset mir has.
+// Chunks follow each other from that offset, an empty chunk only asks for it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceUploadChunkRequest {
+    pub device_id: String,
+    pub timestamp: i64,
+    pub upload_id: String,
+    pub name: String,
+    pub offset: u64,
+    // Base64 content
+    #[serde(default)]
+    pub data: String,
+    // Hex sha256 of the chunk content
+    #[serde(default)]
+    pub checksum: String,
+    // Set on the last chunk, hex sha256 of the whole file
+    #[serde(default)]
+    pub file_checksum: Option<String>,
+}
+
+impl DeviceUploadChunkRequest {
+    pub fn set_content(&mut self, content: &[u8]) {
+        self.data = STANDARD.encode(content);
+        self.checksum = checksum(content);
+    }
+
+    // None when the data was altered on the way
+    pub fn content(&self) -> Option<Vec<u8>> {
+        let content = STANDARD.decode(&self.data).ok()?;
+        (checksum(&content) == self.checksum).then_some(content)
+    }
+}
+
+// Uploads of a device are identified by their name, uploading a file with
+// the same name again replaces it
+pub fn upload_id(device_id: &str, name: &str) -> String {
+    checksum(format!("{}/{}", device_id, name).as_bytes())[..32].to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceUploadChunkResponse {
+    pub upload_id: String,
+    // Bytes stored so far, where the next chunk starts
+    pub offset: u64,
+    pub state: UploadState,
+    #[serde(default)]
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    #[default]
+    InProgress,
+    Complete,
+    // The file checksum didn't match, the next chunk starts over
+    Failed,
+}
+
+// Uploads index, the content is stored by redox next to it
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeviceUpload {
+    pub id: Option<Thing>,
+    pub upload_id: String,
+    pub device_id: String,
+    pub name: String,
+    pub size: u64,
+    pub checksum: String,
+    pub state: UploadState,
+    pub start_time: i64,
+    pub update_time: i64,
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::Thing;
+
+pub const SIGNATURE_HEADER: &str = "x-mir-signature";
+pub const TIMESTAMP_HEADER: &str = "x-mir-timestamp";
+pub const EVENT_HEADER: &str = "x-mir-event";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    DeviceConnected,
+    // No heartbeat received for a while
+    DeviceDisconnected,
+    ReportedUpdated,
+    DesiredUpdated,
+    TagUpdated,
+    // Sent on demand to test a webhook, whatever its events
+    Ping,
+}
+
+impl WebhookEventKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            WebhookEventKind::DeviceConnected => "device_connected",
+            WebhookEventKind::DeviceDisconnected => "device_disconnected",
+            WebhookEventKind::ReportedUpdated => "reported_updated",
+            WebhookEventKind::DesiredUpdated => "desired_updated",
+            WebhookEventKind::TagUpdated => "tag_updated",
+            WebhookEventKind::Ping => "ping",
+        }
+    }
+}
+
+// Devices a webhook is notified of, every device if empty
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct WebhookFilter {
+    #[serde(default)]
+    pub device_ids: Vec<String>,
+    // Top level tag properties a device must have
+    #[serde(default)]
+    pub tags: HashMap<String, Value>,
+}
+
+impl WebhookFilter {
+    pub fn matches(&self, device_id: &str, tags: Option<&Value>) -> bool {
+        (self.device_ids.is_empty() || self.device_ids.iter().any(|x| x == device_id))
+            && self
+                .tags
+                .iter()
+                .all(|(key, value)| tags.and_then(|x| x.get(key)) == Some(value))
+    }
+}
+
+// Events are posted as json, signed with the secret like device messages:
+// base64 hmac-sha256 of <timestamp>.<body>
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Webhook {
+    pub id: Option<Thing>,
+    pub webhook_id: String,
+    pub url: String,
+    // Every event if empty
+    pub events: Vec<WebhookEventKind>,
+    pub filter: WebhookFilter,
+    pub secret: String,
+    pub enabled: bool,
+    pub update_time: i64,
+}
+
+impl Webhook {
+    pub fn matches(&self, event: &WebhookEvent, tags: Option<&Value>) -> bool {
+        self.enabled
+            && (self.events.is_empty() || self.events.contains(&event.kind))
+            && self.filter.matches(&event.device_id, tags)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NewWebhookReq {
+    pub webhook_id: String,
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<WebhookEventKind>,
+    #[serde(default)]
+    pub filter: WebhookFilter,
+    // Generated when empty, only returned on creation
+    #[serde(default)]
+    pub secret: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookEvent {
+    pub event_id: String,
+    pub kind: WebhookEventKind,
+    pub tenant: String,
+    pub device_id: String,
+    pub timestamp: i64,
+    // Properties for updates, empty for connection events
+    pub data: Value,
+}
+
+// One per event and webhook, after the last attempt
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct WebhookDelivery {
+    pub id: Option<Thing>,
+    pub webhook_id: String,
+    pub event_id: String,
+    pub kind: String,
+    pub device_id: String,
+    pub attempts: u32,
+    pub status_code: Option<u16>,
+    pub error: String,
+    pub success: bool,
+    pub timestamp: i64,
+}
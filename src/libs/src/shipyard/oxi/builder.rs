@@ -1,19 +1,29 @@
 use std::{
+    collections::HashMap,
     path::PathBuf,
-    str::FromStr,
     sync::{Arc, Mutex},
 };
 
-use clap::ArgMatches;
-
 use crate::clients::amqp::Amqp;
+use crate::models::device_model::{SensorDefinition, SensorKind};
+use crate::shipyard::oxi::cli::{self, setup_oxi_cli};
 use crate::shipyard::oxi::error::OxiBuilderError;
-use crate::shipyard::oxi::oxi::Config;
+use crate::shipyard::oxi::heartbeat::Heartbeat;
+use crate::shipyard::oxi::log_forwarder::LogForwarder;
+use crate::shipyard::oxi::oxi::{Config, EnrollmentConfig, LogForwardingConfig};
+use crate::shipyard::oxi::telemetry::{
+    RateLimitConfig, RateLimiter, TelemetryBatchConfig, TelemetryBatcher,
+};
+use crate::shipyard::oxi::twin::TwinCache;
 use crate::{
     shipyard::oxi::oxi::Oxi,
-    utils::{config::FileFormat, setup_cli, setup_config, setup_logger},
+    utils::{
+        config::{setup_layered_config, FileFormat},
+        logger::{parse_level, setup_logger_with},
+        tenant::DEFAULT_TENANT,
+    },
 };
-use log::info;
+use log::{info, warn};
 
 //use crate::{
 //   error::OxiBuilderError,
@@ -24,10 +34,23 @@ use log::info;
 pub struct MirShipyard {
     config_file_path: Option<PathBuf>,
     device_id: Option<String>,
+    device_key: Option<String>,
+    enrollment: Option<EnrollmentConfig>,
+    tenant: Option<String>,
+    log_forwarding: Option<LogForwardingConfig>,
+    sensors: Vec<SensorDefinition>,
+    twin_path: Option<PathBuf>,
+    heartbeat_interval_secs: Option<u64>,
+    firmware_version: Option<String>,
+    metadata: HashMap<String, String>,
+    telemetry_batch: Option<TelemetryBatchConfig>,
+    telemetry_rate_limit: Option<RateLimitConfig>,
+    update_dir: Option<PathBuf>,
     mir_addr: Option<String>,
+    amqp: Option<Amqp>,
     thread_count: Option<usize>,
     log_level: Option<String>,
-    cli: Option<ArgMatches>,
+    cli: bool,
 }
 
 const APP_NAME: &str = "oxi";
@@ -37,10 +60,23 @@ impl MirShipyard {
         Self {
             config_file_path: None,
             device_id: None,
+            device_key: None,
+            enrollment: None,
+            tenant: None,
+            log_forwarding: None,
+            sensors: vec![],
+            twin_path: None,
+            heartbeat_interval_secs: None,
+            firmware_version: None,
+            metadata: HashMap::new(),
+            telemetry_batch: None,
+            telemetry_rate_limit: None,
+            update_dir: None,
             mir_addr: None,
+            amqp: None,
             thread_count: None,
             log_level: None,
-            cli: None,
+            cli: false,
         }
     }
 
@@ -48,8 +84,9 @@ impl MirShipyard {
         MirShipyard::new()
     }
 
+    // Every config field can be set with a flag, see --help
     pub fn with_cli(&mut self) -> &mut Self {
-        self.cli = Some(setup_cli());
+        self.cli = true;
         self
     }
 
@@ -59,9 +96,7 @@ impl MirShipyard {
         if filepath.is_empty() {
             return self;
         }
-        let x = PathBuf::from_str(filepath)
-            .unwrap_or_else(|_| panic!("Invalid config file path: {}", filepath));
-        self.config_file_path = Some(x);
+        self.config_file_path = Some(PathBuf::from(filepath));
         self
     }
 
@@ -81,6 +116,138 @@ impl MirShipyard {
         self
     }
 
+    pub fn with_device_key(&mut self, device_key: &str) -> &mut Self {
+        if device_key.is_empty() {
+            return self;
+        }
+        self.device_key = Some(device_key.to_string());
+        self
+    }
+
+    // Provision the device on join_fleet instead of using a device id
+    pub fn with_enrollment(
+        &mut self,
+        group_id: &str,
+        registration_id: &str,
+        derived_key: &str,
+    ) -> &mut Self {
+        if group_id.is_empty() || registration_id.is_empty() {
+            return self;
+        }
+        self.enrollment = Some(EnrollmentConfig {
+            group_id: group_id.to_string(),
+            registration_id: registration_id.to_string(),
+            derived_key: derived_key.to_string(),
+        });
+        self
+    }
+
+    pub fn with_tenant(&mut self, tenant: &str) -> &mut Self {
+        if tenant.is_empty() {
+            return self;
+        }
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    // Send the device log records up to level to mir, never more verbose than the logger
+    pub fn with_log_forwarding(&mut self, level: &str, max_per_second: u32) -> &mut Self {
+        if level.is_empty() {
+            return self;
+        }
+        self.log_forwarding = Some(LogForwardingConfig {
+            level: level.to_string(),
+            max_per_second,
+        });
+        self
+    }
+
+    // Declared to mir on join_fleet, for sensors the device model doesn't describe
+    pub fn with_sensor(&mut self, id: i64, name: &str, unit: &str, kind: SensorKind) -> &mut Self {
+        if name.is_empty() {
+            return self;
+        }
+        self.sensors.retain(|x| x.id != id);
+        self.sensors.push(SensorDefinition {
+            id,
+            name: name.to_string(),
+            unit: unit.to_string(),
+            kind,
+            ..Default::default()
+        });
+        self
+    }
+
+    // Keep the twin in a json file across restarts
+    pub fn with_twin_cache(&mut self, filepath: &str) -> &mut Self {
+        if filepath.is_empty() {
+            return self;
+        }
+        self.twin_path = Some(PathBuf::from(filepath));
+        self
+    }
+
+    // Seconds between heartbeats until mir asks for another interval,
+    // capped at MAX_HEARTBEAT_INTERVAL_SECS (120)
+    pub fn with_heartbeat_interval(&mut self, secs: u64) -> &mut Self {
+        if secs == 0 {
+            return self;
+        }
+        self.heartbeat_interval_secs = Some(secs);
+        self
+    }
+
+    pub fn with_firmware_version(&mut self, version: &str) -> &mut Self {
+        if version.is_empty() {
+            return self;
+        }
+        self.firmware_version = Some(version.to_string());
+        self
+    }
+
+    // Sent to mir with every heartbeat
+    pub fn with_metadata(&mut self, key: &str, value: &str) -> &mut Self {
+        if key.is_empty() {
+            return self;
+        }
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    // Send readings together, once max_points are queued or the first one
+    // waited max_delay_ms
+    pub fn with_telemetry_batching(&mut self, max_points: usize, max_delay_ms: u64) -> &mut Self {
+        if max_points == 0 {
+            return self;
+        }
+        self.telemetry_batch = Some(TelemetryBatchConfig {
+            max_points,
+            max_delay_ms,
+        });
+        self
+    }
+
+    // Refuse readings over rate_per_second on average, after bursts of burst
+    pub fn with_telemetry_rate_limit(&mut self, rate_per_second: f64, burst: u32) -> &mut Self {
+        if rate_per_second <= 0.0 {
+            return self;
+        }
+        self.telemetry_rate_limit = Some(RateLimitConfig {
+            rate_per_second,
+            burst,
+        });
+        self
+    }
+
+    // Folder the update artifacts are downloaded to
+    pub fn with_update_dir(&mut self, dirpath: &str) -> &mut Self {
+        if dirpath.is_empty() {
+            return self;
+        }
+        self.update_dir = Some(PathBuf::from(dirpath));
+        self
+    }
+
     pub fn with_thread_count(&mut self, count: usize) -> &mut Self {
         if count == 0 {
             return self;
@@ -97,19 +264,61 @@ impl MirShipyard {
         self
     }
 
+    // Share the connection pool of another oxi or of the application, the
+    // mir server address and thread count are then ignored
+    pub fn with_amqp(&mut self, amqp: &Amqp) -> &mut Self {
+        self.amqp = Some(amqp.clone());
+        self
+    }
+
     pub fn build(&mut self) -> Result<Oxi, OxiBuilderError> {
         let mut config = Config::default();
 
-        // Default < Builder < Configfile < Cli
+        // Default < Builder < Configfile < Env < Cli
 
         // Builder
         if let Some(x) = &self.device_id {
             config.device_id = x.to_string();
         }
+        if let Some(x) = &self.device_key {
+            config.device_key = x.to_string();
+        }
+        if let Some(x) = &self.enrollment {
+            config.enrollment = Some(x.clone());
+        }
+        if let Some(x) = &self.tenant {
+            config.tenant = x.to_string();
+        }
+        if let Some(x) = &self.log_forwarding {
+            config.log_forwarding = Some(x.clone());
+        }
+        if !self.sensors.is_empty() {
+            config.sensors = self.sensors.clone();
+        }
+        if let Some(x) = &self.twin_path {
+            config.twin_path = Some(x.clone());
+        }
+        if let Some(x) = &self.heartbeat_interval_secs {
+            config.heartbeat_interval_secs = x.to_owned();
+        }
+        if let Some(x) = &self.firmware_version {
+            config.firmware_version = x.to_string();
+        }
+        if !self.metadata.is_empty() {
+            config.metadata = self.metadata.clone();
+        }
+        if let Some(x) = &self.telemetry_batch {
+            config.telemetry_batch = Some(*x);
+        }
+        if let Some(x) = &self.telemetry_rate_limit {
+            config.telemetry_rate_limit = Some(*x);
+        }
+        if let Some(x) = &self.update_dir {
+            config.update_dir = Some(x.clone());
+        }
         if let Some(x) = &self.log_level {
             config.log_level = x.to_string();
         }
-        // Builder
         if let Some(x) = &self.thread_count {
             config.thread_count = x.to_owned();
         }
@@ -118,41 +327,91 @@ impl MirShipyard {
         }
 
         // Cli matches
-        if let Some(x) = &self.cli {
-            let y = x.get_one::<PathBuf>(crate::utils::cli::CONFIG_KEY);
-            if let Some(z) = y {
-                self.config_file_path = Some(z.clone());
-            }
-        }
+        let matches = if self.cli {
+            Some(
+                setup_oxi_cli()
+                    .try_get_matches()
+                    .map_err(OxiBuilderError::InvalidCli)?,
+            )
+        } else {
+            None
+        };
+        let args = matches.as_ref();
+        let config_file_path = args
+            .and_then(cli::config_file)
+            .or_else(|| self.config_file_path.clone());
+        let overrides = args.map(cli::overrides).unwrap_or_default();
 
-        // Configfile load
-        if let Some(x) = &self.config_file_path {
-            config = setup_config(APP_NAME, FileFormat::YAML, Some(x)).unwrap();
+        // Configfile, env and cli over the builder
+        config = setup_layered_config(
+            APP_NAME,
+            FileFormat::YAML,
+            &config,
+            config_file_path.as_ref(),
+            overrides,
+        )
+        .map_err(OxiBuilderError::InvalidConfig)?;
+        if let Some(sensors) = args.map(cli::sensors).filter(|x| !x.is_empty()) {
+            config.sensors = sensors;
         }
 
         // Logger init
+        let log_forwarder = config
+            .log_forwarding
+            .as_ref()
+            .map(|x| LogForwarder::new(parse_level(&x.level), x.max_per_second));
+        let extra_logger = log_forwarder
+            .clone()
+            .map(|x| Box::new(x) as Box<dyn log::Log>);
         if !config.log_level.is_empty() {
-            setup_logger(config.log_level.clone())
-                .unwrap_or_else(|e| panic!("Invalid logger configuration: {:?}", e));
-        } else if let Some(x) = &self.log_level {
-            setup_logger(x.to_string())
-                .unwrap_or_else(|e| panic!("Invalid logger configuration: {:?}", e));
+            match setup_logger_with(config.log_level.clone(), extra_logger) {
+                Ok(()) => (),
+                // Set by the application or another oxi, records go there
+                Err(fern::InitError::SetLoggerError(_)) => {
+                    if log_forwarder.is_some() {
+                        warn!("logger already set, log records are not forwarded to mir");
+                    }
+                }
+                Err(x) => return Err(OxiBuilderError::CantSetupLogger(x)),
+            }
+        }
+
+        if config.tenant.is_empty() {
+            config.tenant = DEFAULT_TENANT.to_string();
         }
 
         info!("{:?}", config);
 
-        if config.device_id.is_empty() {
+        if config.device_id.is_empty() && config.enrollment.is_none() {
             return Err(OxiBuilderError::NoDeviceId);
         }
 
-        if config.mir_addr.is_empty() {
+        if config.mir_addr.is_empty() && self.amqp.is_none() {
             return Err(OxiBuilderError::NoMirServer);
         }
 
+        let twin = TwinCache::new(config.twin_path.clone());
+        let heartbeat = Heartbeat::new(config.heartbeat_interval_secs);
+        let telemetry_batcher = config.telemetry_batch.map(TelemetryBatcher::new);
+        let telemetry_limiter = RateLimiter::new(config.telemetry_rate_limit.unwrap_or_default());
         Ok(Oxi {
-            amqp: Amqp::new(config.mir_addr.clone(), config.thread_count),
+            amqp: self
+                .amqp
+                .clone()
+                .unwrap_or_else(|| Amqp::new(config.mir_addr.clone(), config.thread_count)),
             config,
             desired_prop_callback: Arc::new(Mutex::new(Vec::new())),
+            desired_async_callback: Arc::new(Mutex::new(Vec::new())),
+            status_callback: Arc::new(Mutex::new(Vec::new())),
+            update_callback: Arc::new(Mutex::new(None)),
+            updating: Arc::new(Mutex::new(None)),
+            log_forwarder,
+            twin,
+            heartbeat,
+            telemetry_batcher,
+            telemetry_limiter,
+            tasks: Arc::new(Mutex::new(Default::default())),
+            shared_amqp: self.amqp.is_some(),
         })
     }
 }
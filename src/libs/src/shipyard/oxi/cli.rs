@@ -0,0 +1,182 @@
+use std::path::PathBuf;
+
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command, ValueEnum};
+
+use crate::models::device_model::{SensorDefinition, SensorKind};
+use crate::utils::cli::CONFIG_KEY;
+
+const METADATA_KEY: &str = "metadata";
+const SENSOR_KEY: &str = "sensor";
+
+// Config key, flag and help of the flags taking a single value
+const FLAGS: &[(&str, &str, &str)] = &[
+    (
+        "device_id",
+        "device-id",
+        "Device id, provisioned when empty",
+    ),
+    (
+        "device_key",
+        "device-key",
+        "Key signing the device messages",
+    ),
+    (
+        "tenant",
+        "tenant",
+        "Prefix of the routing keys and device queue",
+    ),
+    ("log_level", "log-level", "[Error|Warn|Info|Debug|Trace]"),
+    ("mir_addr", "mir-addr", "Amqp address of mir"),
+    ("thread_count", "thread-count", "Amqp connection pool size"),
+    ("twin_path", "twin-path", "Json file keeping the twin"),
+    (
+        "heartbeat_interval_secs",
+        "heartbeat-interval",
+        "Seconds between heartbeats",
+    ),
+    (
+        "firmware_version",
+        "firmware-version",
+        "Sent with heartbeats",
+    ),
+    (
+        "enrollment.group_id",
+        "enrollment-group-id",
+        "Enrollment group to provision from",
+    ),
+    (
+        "enrollment.registration_id",
+        "enrollment-registration-id",
+        "Registration id in the enrollment group",
+    ),
+    (
+        "enrollment.derived_key",
+        "enrollment-derived-key",
+        "Key derived from the enrollment group secret",
+    ),
+    (
+        "log_forwarding.level",
+        "log-forwarding-level",
+        "Most verbose level forwarded to mir",
+    ),
+    (
+        "log_forwarding.max_per_second",
+        "log-forwarding-max-per-second",
+        "Log records forwarded per second",
+    ),
+    (
+        "telemetry_batch.max_points",
+        "telemetry-batch-max-points",
+        "Readings sent together",
+    ),
+    (
+        "telemetry_batch.max_delay_ms",
+        "telemetry-batch-max-delay-ms",
+        "Longest wait before sending a batch",
+    ),
+    (
+        "telemetry_rate_limit.rate_per_second",
+        "telemetry-rate-per-second",
+        "Readings per second accepted on average",
+    ),
+    (
+        "telemetry_rate_limit.burst",
+        "telemetry-burst",
+        "Readings accepted at once",
+    ),
+    (
+        "update_dir",
+        "update-dir",
+        "Folder the update artifacts are downloaded to",
+    ),
+];
+
+pub fn setup_oxi_cli() -> Command {
+    let command = command!()
+        .arg(
+            Arg::new(CONFIG_KEY)
+                .short('c')
+                .long(CONFIG_KEY)
+                .value_name("FILE")
+                .help("Sets a custom config file")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new(METADATA_KEY)
+                .long(METADATA_KEY)
+                .value_name("KEY=VALUE")
+                .help("Key/value sent with heartbeats, repeatable")
+                .action(ArgAction::Append)
+                .value_parser(parse_key_value),
+        )
+        .arg(
+            Arg::new(SENSOR_KEY)
+                .long(SENSOR_KEY)
+                .value_name("ID:NAME[:UNIT[:KIND]]")
+                .help("Sensor declared on join_fleet, repeatable")
+                .action(ArgAction::Append)
+                .value_parser(parse_sensor),
+        );
+    FLAGS.iter().fold(command, |command, (key, flag, help)| {
+        command.arg(Arg::new(*key).long(*flag).help(*help))
+    })
+}
+
+pub fn config_file(matches: &ArgMatches) -> Option<PathBuf> {
+    matches.get_one::<PathBuf>(CONFIG_KEY).cloned()
+}
+
+// Config keys and values set on the command line
+pub fn overrides(matches: &ArgMatches) -> Vec<(String, String)> {
+    let flags = FLAGS.iter().filter_map(|(key, _, _)| {
+        matches
+            .get_one::<String>(key)
+            .map(|x| (key.to_string(), x.clone()))
+    });
+    let metadata = matches
+        .get_many::<(String, String)>(METADATA_KEY)
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| (format!("{}.{}", METADATA_KEY, key), value.clone()));
+    flags.chain(metadata).collect()
+}
+
+pub fn sensors(matches: &ArgMatches) -> Vec<SensorDefinition> {
+    matches
+        .get_many::<SensorDefinition>(SENSOR_KEY)
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", value)),
+    }
+}
+
+fn parse_sensor(value: &str) -> Result<SensorDefinition, String> {
+    let mut parts = value.splitn(4, ':');
+    let id = parts
+        .next()
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| format!("invalid sensor id in '{}'", value))?;
+    let name = parts
+        .next()
+        .filter(|x| !x.is_empty())
+        .ok_or_else(|| format!("missing sensor name in '{}'", value))?;
+    let unit = parts.next().unwrap_or_default();
+    let kind = match parts.next() {
+        Some(x) => SensorKind::from_str(x, true)?,
+        None => SensorKind::default(),
+    };
+    Ok(SensorDefinition {
+        id,
+        name: name.to_string(),
+        unit: unit.to_string(),
+        kind,
+        ..Default::default()
+    })
+}
@@ -1,31 +1,54 @@
 use std::{error, fmt};
 
+use config_sys::ConfigError;
+
 use crate::clients::amqp::AmqpError;
 
 #[derive(Debug)]
 pub enum OxiBuilderError {
     NoMirServer,
     NoDeviceId,
+    InvalidConfig(ConfigError),
+    // Also returned for --help and --version, clap::Error::exit prints them
+    InvalidCli(clap::Error),
+    CantSetupLogger(fern::InitError),
+    DuplicateDeviceId(String),
 }
 
 impl fmt::Display for OxiBuilderError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
+        match self {
             OxiBuilderError::NoDeviceId => {
                 write!(f, "missing the identifying key for your device")
             }
             OxiBuilderError::NoMirServer => {
                 write!(f, "missing the mir server address")
             }
+            OxiBuilderError::InvalidConfig(x) => {
+                write!(f, "invalid configuration: {x}")
+            }
+            OxiBuilderError::InvalidCli(x) => {
+                write!(f, "invalid command line: {x}")
+            }
+            OxiBuilderError::CantSetupLogger(x) => {
+                write!(f, "invalid logger configuration: {x}")
+            }
+            OxiBuilderError::DuplicateDeviceId(x) => {
+                write!(f, "device {x} is already in the gateway")
+            }
         }
     }
 }
 
 impl error::Error for OxiBuilderError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match *self {
+        match self {
             OxiBuilderError::NoDeviceId => None,
             OxiBuilderError::NoMirServer => None,
+            OxiBuilderError::InvalidConfig(x) => Some(x),
+            OxiBuilderError::InvalidCli(x) => Some(x),
+            OxiBuilderError::CantSetupLogger(x) => Some(x),
+            OxiBuilderError::DuplicateDeviceId(_) => None,
         }
     }
 }
@@ -38,8 +61,15 @@ pub enum OxiError {
     DataSent,
     HeathbeatSent,
     ReportedSent,
+    LogSent,
+    MetricsSent,
+    DesiredErrorSent,
+    TelemetryRateLimited,
     Unknown,
     CantRequestDesiredProperties(AmqpError),
+    CantProvision(String),
+    UploadFailed(String),
+    UpdateFailed(String),
 }
 
 impl fmt::Display for OxiError {
@@ -63,9 +93,30 @@ impl fmt::Display for OxiError {
             OxiError::ReportedSent => {
                 write!(f, "error sending reported properties")
             }
+            OxiError::LogSent => {
+                write!(f, "error sending log")
+            }
+            OxiError::MetricsSent => {
+                write!(f, "error sending metrics")
+            }
+            OxiError::DesiredErrorSent => {
+                write!(f, "error sending desired properties error")
+            }
+            OxiError::TelemetryRateLimited => {
+                write!(f, "telemetry refused by the rate limit")
+            }
             OxiError::CantRequestDesiredProperties(x) => {
                 write!(f, "error sending request for desired properties: {x}")
             }
+            OxiError::CantProvision(x) => {
+                write!(f, "error provisioning device: {x}")
+            }
+            OxiError::UploadFailed(x) => {
+                write!(f, "error uploading file: {x}")
+            }
+            OxiError::UpdateFailed(x) => {
+                write!(f, "error updating firmware: {x}")
+            }
         }
     }
 }
@@ -79,7 +130,14 @@ impl error::Error for OxiError {
             OxiError::DataSent => None,
             OxiError::HeathbeatSent => None,
             OxiError::ReportedSent => None,
+            OxiError::LogSent => None,
+            OxiError::MetricsSent => None,
+            OxiError::DesiredErrorSent => None,
+            OxiError::TelemetryRateLimited => None,
             OxiError::CantRequestDesiredProperties(_) => None,
+            OxiError::CantProvision(_) => None,
+            OxiError::UploadFailed(_) => None,
+            OxiError::UpdateFailed(_) => None,
         }
     }
 }
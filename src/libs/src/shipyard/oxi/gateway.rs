@@ -0,0 +1,82 @@
+use futures::future::join_all;
+use log::{error, info};
+
+use crate::clients::amqp::Amqp;
+use crate::shipyard::oxi::builder::MirShipyard;
+use crate::shipyard::oxi::error::{OxiBuilderError, OxiError};
+use crate::shipyard::oxi::oxi::Oxi;
+
+// Acts as many devices over one connection pool. Each device keeps its own
+// id, handlers, heartbeat and twin cache, give them different twin paths.
+// The logger is global, records go to the first device forwarding them.
+#[derive(Debug)]
+pub struct OxiGateway {
+    amqp: Amqp,
+    devices: Vec<Oxi>,
+}
+
+impl OxiGateway {
+    pub fn new(mir_addr: &str, thread_count: usize) -> Self {
+        Self::with_amqp(Amqp::new(mir_addr.to_string(), thread_count))
+    }
+
+    pub fn with_amqp(amqp: Amqp) -> Self {
+        Self {
+            amqp,
+            devices: vec![],
+        }
+    }
+
+    // Builder sharing the gateway connection pool
+    pub fn shipyard(&self) -> MirShipyard {
+        let mut shipyard = MirShipyard::new();
+        shipyard.with_amqp(&self.amqp);
+        shipyard
+    }
+
+    pub fn add_device(&mut self, oxi: Oxi) -> Result<&mut Oxi, OxiBuilderError> {
+        let device_id = &oxi.config.device_id;
+        if !device_id.is_empty() && self.device(device_id).is_some() {
+            return Err(OxiBuilderError::DuplicateDeviceId(device_id.clone()));
+        }
+        self.devices.push(oxi);
+        Ok(self.devices.last_mut().unwrap())
+    }
+
+    pub fn device(&self, device_id: &str) -> Option<&Oxi> {
+        self.devices
+            .iter()
+            .find(|x| x.config.device_id == device_id)
+    }
+
+    pub fn devices(&self) -> &[Oxi] {
+        &self.devices
+    }
+
+    // Every device tries to join, the first error is returned
+    pub async fn join_fleet(&mut self) -> Result<(), OxiError> {
+        let results = join_all(self.devices.iter_mut().map(|x| x.join_fleet())).await;
+        let mut errors = results.into_iter().filter_map(Result::err);
+        let first = errors.next();
+        for x in errors {
+            error!("error joining the fleet: {}", x);
+        }
+        match first {
+            Some(x) => Err(x),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn leave_fleet(&mut self) -> Result<(), OxiError> {
+        let results = join_all(self.devices.iter_mut().map(|x| x.leave_fleet())).await;
+        for x in results.into_iter().filter_map(Result::err) {
+            error!("error leaving the fleet: {}", x);
+        }
+        self.amqp.close();
+        info!(
+            "gateway has left the fleet with {} devices",
+            self.devices.len()
+        );
+        Ok(())
+    }
+}
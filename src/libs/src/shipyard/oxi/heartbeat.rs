@@ -0,0 +1,83 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde_json::Value;
+use tokio::sync::Notify;
+
+use crate::models::device_twin::{DEFAULT_DISCONNECT_TIMEOUT_SECS, RESERVED_DESIRED_KEY};
+
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 60;
+// Leaves room for one late heartbeat before mir disconnects the device
+pub const MAX_HEARTBEAT_INTERVAL_SECS: u64 = DEFAULT_DISCONNECT_TIMEOUT_SECS * 2 / 3;
+
+// Interval shared by the oxi clones, it can be changed while running
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    interval_secs: Arc<AtomicU64>,
+    changed: Arc<Notify>,
+    started: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval_secs: u64) -> Self {
+        Self {
+            interval_secs: Arc::new(AtomicU64::new(clamp(interval_secs))),
+            changed: Arc::new(Notify::new()),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn interval_secs(&self) -> u64 {
+        self.interval_secs.load(Ordering::Relaxed)
+    }
+
+    // A heartbeat is sent right away with the new interval
+    pub fn set_interval_secs(&self, interval_secs: u64) {
+        let interval_secs = clamp(interval_secs);
+        if self.interval_secs.swap(interval_secs, Ordering::Relaxed) != interval_secs {
+            info!("heartbeat interval is now {}s", interval_secs);
+            self.changed.notify_one();
+        }
+    }
+
+    // Since oxi was built
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Sleeps for the interval, woken up early when it changes
+    pub async fn wait(&self) {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(self.interval_secs())) => (),
+            _ = self.changed.notified() => (),
+        }
+    }
+}
+
+fn clamp(interval_secs: u64) -> u64 {
+    match interval_secs {
+        0 => DEFAULT_HEARTBEAT_INTERVAL_SECS,
+        x if x > MAX_HEARTBEAT_INTERVAL_SECS => {
+            warn!(
+                "heartbeat interval of {}s is above the maximum, using {}s",
+                x, MAX_HEARTBEAT_INTERVAL_SECS
+            );
+            MAX_HEARTBEAT_INTERVAL_SECS
+        }
+        x => x,
+    }
+}
+
+// Interval set by mir in the reserved desired properties
+pub fn desired_interval_secs(desired: &Value) -> Option<u64> {
+    desired
+        .get(RESERVED_DESIRED_KEY)?
+        .get("heartbeat_interval_secs")?
+        .as_u64()
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+use tokio::sync::mpsc;
+
+use crate::models::telemetry::{DeviceLogRequest, LogLevel};
+use crate::shipyard::oxi::oxi::Oxi;
+
+// Records waiting to be sent, new ones are dropped when full
+const LOG_QUEUE_CAPACITY: usize = 1000;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+// Records of the crates sending the logs would loop back to mir
+const INTERNAL_TARGETS: [&str; 7] = [
+    "libs",
+    "lapin",
+    "amq_protocol",
+    "pinky_swear",
+    "async_io",
+    "polling",
+    "tokio",
+];
+
+// Forwards the device log records to mir on the logs topic. Records are
+// queued until the device joined the fleet and rate limited per second.
+#[derive(Clone)]
+pub struct LogForwarder {
+    level: LevelFilter,
+    max_per_second: u32,
+    sender: Arc<Mutex<Option<mpsc::Sender<DeviceLogRequest>>>>,
+    window: Arc<Mutex<(Instant, u32)>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogForwarder {
+    pub fn new(level: LevelFilter, max_per_second: u32) -> Self {
+        Self {
+            level,
+            max_per_second,
+            sender: Arc::new(Mutex::new(None)),
+            window: Arc::new(Mutex::new((Instant::now(), 0))),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Records not forwarded because of the rate limit or a full queue
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Records queued and not yet sent
+    pub fn pending(&self) -> usize {
+        self.sender
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|x| x.max_capacity() - x.capacity())
+            .unwrap_or_default()
+    }
+
+    // The returned task ends once stopped and the queued records are sent
+    pub fn start(&self, oxi: Oxi) -> impl Future<Output = ()> + Send + 'static {
+        let (sender, mut receiver) = mpsc::channel::<DeviceLogRequest>(LOG_QUEUE_CAPACITY);
+        *self.sender.lock().unwrap() = Some(sender);
+        let dropped = self.dropped.clone();
+        async move {
+            let mut reported = 0;
+            while let Some(record) = receiver.recv().await {
+                if let Err(error) = oxi.send_log_request(record).await {
+                    eprintln!("can't forward log record: {}", error);
+                }
+                let count = dropped.load(Ordering::Relaxed);
+                if count > reported {
+                    let record = DeviceLogRequest {
+                        device_id: String::new(),
+                        timestamp: Utc::now().timestamp_nanos(),
+                        level: LogLevel::Warn,
+                        target: module_path!().to_string(),
+                        message: format!("{} log records dropped", count - reported),
+                        fields: HashMap::new(),
+                    };
+                    reported = count;
+                    if let Err(error) = oxi.send_log_request(record).await {
+                        eprintln!("can't forward log record: {}", error);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn stop(&self) {
+        *self.sender.lock().unwrap() = None;
+    }
+
+    fn within_rate_limit(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= RATE_LIMIT_WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        window.1 += 1;
+        window.1 <= self.max_per_second
+    }
+}
+
+impl Log for LogForwarder {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && !INTERNAL_TARGETS
+                .iter()
+                .any(|x| metadata.target().starts_with(x))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let sender = self.sender.lock().unwrap();
+        let Some(sender) = sender.as_ref() else {
+            return;
+        };
+        if !self.within_rate_limit() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let mut fields = HashMap::new();
+        if let Some(x) = record.module_path() {
+            fields.insert("module".to_string(), x.to_string());
+        }
+        if let Some(x) = record.file() {
+            fields.insert("file".to_string(), x.to_string());
+        }
+        if let Some(x) = record.line() {
+            fields.insert("line".to_string(), x.to_string());
+        }
+        let request = DeviceLogRequest {
+            device_id: String::new(),
+            timestamp: Utc::now().timestamp_nanos(),
+            level: record.level().into(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            fields,
+        };
+        if sender.try_send(request).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {}
+}
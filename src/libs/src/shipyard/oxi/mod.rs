@@ -1,3 +1,9 @@
 pub mod builder;
+pub mod cli;
 pub mod error;
+pub mod gateway;
+pub mod heartbeat;
+pub mod log_forwarder;
 pub mod oxi;
+pub mod telemetry;
+pub mod twin;
@@ -1,43 +1,103 @@
 //use crate::error::OxiError;
 use crate::shipyard::oxi::error::OxiError;
 use chrono::Utc;
+use futures::future::BoxFuture;
 use lapin::{
     options::{BasicConsumeOptions, QueueDeclareOptions},
     types::{FieldTable, ShortString},
 };
 
 use crate::models::{
-    device_twin::Properties,
+    device_model::SensorDefinition,
+    device_twin::{DeviceMetadata, Properties, StatusReason, RESERVED_DESIRED_KEY},
+    ota::{
+        DeviceArtifactChunkRequest, DeviceArtifactChunkResponse, FirmwareDesired, FirmwareReported,
+        FirmwareState, FIRMWARE_KEY,
+    },
     telemetry::{
-        DeviceDesiredRequest, DeviceHeartbeatRequest, DeviceReportedRequest,
-        DeviceTelemetryRequest, Telemetry,
+        DeviceDesiredErrorRequest, DeviceDesiredRequest, DeviceHeartbeatRequest, DeviceLogRequest,
+        DeviceMetricsRequest, DeviceProvisionRequest, DeviceProvisionResponse,
+        DeviceReportedRequest, DeviceSensorsRequest, DeviceStatusNotification,
+        DeviceTelemetryBatchRequest, DeviceTelemetryRequest, LogLevel, Metrics, Telemetry,
+        TelemetryPoint,
     },
+    upload::{upload_id, DeviceUploadChunkRequest, DeviceUploadChunkResponse, UploadState},
+};
+use crate::shipyard::oxi::heartbeat::{desired_interval_secs, Heartbeat};
+use crate::shipyard::oxi::log_forwarder::LogForwarder;
+use crate::shipyard::oxi::telemetry::{
+    desired_rate_limit, RateLimitConfig, RateLimiter, TelemetryBatchConfig, TelemetryBatcher,
 };
+use crate::shipyard::oxi::twin::TwinCache;
 use crate::{
     clients::amqp::{Amqp, AmqpError, ConsumerSettings, QueueSettings},
-    utils::serialization::SerializationKind,
+    utils::{
+        auth::sign_message,
+        checksum::Checksum,
+        network::local_ip,
+        serialization::SerializationKind,
+        tenant::{tenant_queue, tenant_routing_key},
+    },
 };
-use log::{debug, error, info};
-use serde::{Deserialize, Serialize};
+use log::{debug, error, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::{
+    collections::HashMap,
     fmt::{self, Error},
+    future::Future,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 use std::{option::Option, sync::Mutex};
-use tokio::time;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinSet,
+    time,
+};
+use tokio_util::sync::CancellationToken;
 
 const RMQ_STREAM_EXCHANGE_NAME: &str = "iot-stream";
 const RMQ_STREAM_ROUTING_KEY: &str = "oxi.telemetry.v1";
+const RMQ_STREAM_BATCH_ROUTING_KEY: &str = "oxi.telemetry_batch.v1";
+const RMQ_STREAM_LOGS_ROUTING_KEY: &str = "oxi.logs.v1";
+const RMQ_STREAM_METRICS_ROUTING_KEY: &str = "oxi.metrics.v1";
+const RMQ_STREAM_SENSORS_ROUTING_KEY: &str = "oxi.sensors.v1";
 
 const RMQ_TWIN_EXCHANGE_NAME: &str = "iot-twin";
 const RMQ_TWIN_HEARTHBEAT_ROUTING_KEY: &str = "oxi.hearthbeat.v1";
 const RMQ_TWIN_DESIRED_PROP_ROUTING_KEY: &str = "oxi.desired.v1";
+const RMQ_TWIN_DESIRED_ERROR_ROUTING_KEY: &str = "oxi.desired_error.v1";
 const RMQ_TWIN_REPORTED_PROP_ROUTING_KEY: &str = "oxi.reported.v1";
+const RMQ_TWIN_PROVISION_ROUTING_KEY: &str = "oxi.provision.v1";
+const RMQ_TWIN_UPLOAD_ROUTING_KEY: &str = "oxi.upload.v1";
+const RMQ_TWIN_ARTIFACT_ROUTING_KEY: &str = "oxi.artifact.v1";
 //const RMQ_TWIN_DESIRED_QUEUE_NAME: &str = "iot-q-twin-desired";
 //const RMQ_TWIN_REPORTED_QUEUE_NAME: &str = "iot-q-twin-reported";
 
-const HEARTHBEAT_INTERVAL: Duration = Duration::from_secs(60);
+const PROVISION_TIMEOUT: Duration = Duration::from_secs(30);
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+// Content sent per upload request, before base64
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+const ARTIFACT_TIMEOUT: Duration = Duration::from_secs(30);
+const ARTIFACT_CHUNK_SIZE: u64 = 64 * 1024;
+// Download progress is reported every that many percents
+const FIRMWARE_PROGRESS_STEP: u8 = 10;
+// Time given to the tasks to stop and the queued log records to be sent
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Awaited one after the other, in the order desired properties are received
+pub type AsyncDesiredCallback =
+    Box<dyn FnMut(Oxi, Properties) -> BoxFuture<'static, ()> + Send + Sync>;
+
+// Applies a downloaded and verified artifact, an error fails the update
+pub type UpdateCallback = Box<
+    dyn FnMut(Oxi, FirmwareDesired, PathBuf) -> BoxFuture<'static, Result<(), String>>
+        + Send
+        + Sync,
+>;
 
 pub struct Oxi {
     pub config: Config,
@@ -45,6 +105,36 @@ pub struct Oxi {
     // TODO: could offer Fn instead of FnMut as well
     pub desired_prop_callback:
         Arc<Mutex<Vec<Box<dyn FnMut(Option<Properties>, Option<ShortString>) + Send + Sync>>>>,
+    pub desired_async_callback: Arc<Mutex<Vec<AsyncDesiredCallback>>>,
+    pub status_callback: Arc<Mutex<Vec<Box<dyn FnMut(DeviceStatusNotification) + Send + Sync>>>>,
+    pub update_callback: Arc<Mutex<Option<UpdateCallback>>>,
+    // Campaign of the firmware update in progress, one at a time
+    pub(crate) updating: Arc<Mutex<Option<String>>>,
+    // Set when the device log records are forwarded to mir
+    pub log_forwarder: Option<LogForwarder>,
+    pub twin: TwinCache,
+    pub heartbeat: Heartbeat,
+    // Set when readings are sent in batches
+    pub telemetry_batcher: Option<TelemetryBatcher>,
+    pub telemetry_limiter: RateLimiter,
+    pub(crate) tasks: Arc<Mutex<OxiTasks>>,
+    // Injected with MirShipyard::with_amqp, left open when leaving
+    pub(crate) shared_amqp: bool,
+}
+
+// Background tasks started by join_fleet and stopped by leave_fleet
+#[derive(Default)]
+pub(crate) struct OxiTasks {
+    token: CancellationToken,
+    set: JoinSet<()>,
+}
+
+// Messages mir sends to the device queue
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DeviceMessage {
+    Desired(Properties),
+    Status(DeviceStatusNotification),
 }
 
 impl Clone for Oxi {
@@ -53,6 +143,17 @@ impl Clone for Oxi {
             config: self.config.clone(),
             amqp: self.amqp.clone(),
             desired_prop_callback: Arc::new(Mutex::new(Vec::new())),
+            desired_async_callback: self.desired_async_callback.clone(),
+            status_callback: self.status_callback.clone(),
+            update_callback: self.update_callback.clone(),
+            updating: self.updating.clone(),
+            log_forwarder: self.log_forwarder.clone(),
+            twin: self.twin.clone(),
+            heartbeat: self.heartbeat.clone(),
+            telemetry_batcher: self.telemetry_batcher.clone(),
+            telemetry_limiter: self.telemetry_limiter.clone(),
+            tasks: self.tasks.clone(),
+            shared_amqp: self.shared_amqp,
         };
         cloned
             .desired_prop_callback
@@ -75,12 +176,67 @@ impl fmt::Debug for Oxi {
     }
 }
 
-#[derive(Debug, Default, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub device_id: String,
+    #[serde(default)]
+    pub device_key: String,
+    // Prefix of the routing keys and device queue, default to iot
+    #[serde(default)]
+    pub tenant: String,
     pub log_level: String,
     pub mir_addr: String,
     pub thread_count: usize,
+    // Zero-touch provisioning, used when no device id is set
+    #[serde(default)]
+    pub enrollment: Option<EnrollmentConfig>,
+    // Forward the device log records to mir
+    #[serde(default)]
+    pub log_forwarding: Option<LogForwardingConfig>,
+    // Names and units of the telemetry sensor ids, declared on join_fleet
+    #[serde(default)]
+    pub sensors: Vec<SensorDefinition>,
+    // Json file keeping the twin across restarts. Without it the reported
+    // version starts over and mir rejects updates until it catches up.
+    #[serde(default)]
+    pub twin_path: Option<PathBuf>,
+    // Seconds between heartbeats, default to 60 and capped at 120 so mir
+    // doesn't disconnect the device. Mir can change it with the
+    // heartbeat_interval_secs desired property under $mir.
+    #[serde(default)]
+    pub heartbeat_interval_secs: u64,
+    // Sent with every heartbeat
+    #[serde(default)]
+    pub firmware_version: String,
+    // Key/values sent with every heartbeat
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    // Readings are sent together instead of one message each
+    #[serde(default)]
+    pub telemetry_batch: Option<TelemetryBatchConfig>,
+    // Readings over the limit are refused. Mir can change it with the
+    // telemetry_rate_limit desired property under $mir.
+    #[serde(default)]
+    pub telemetry_rate_limit: Option<RateLimitConfig>,
+    // Folder the update artifacts are downloaded to, default to the temp dir
+    #[serde(default)]
+    pub update_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogForwardingConfig {
+    // Most verbose level forwarded, [Error|Warn|Info|Debug|Trace]
+    pub level: String,
+    // Records over the limit are dropped and counted
+    pub max_per_second: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct EnrollmentConfig {
+    pub group_id: String,
+    pub registration_id: String,
+    // Key derived from the enrollment group attestation secret
+    pub derived_key: String,
 }
 
 impl Oxi {
@@ -93,11 +249,51 @@ impl Oxi {
             .map_err(|_| OxiError::CantConnectToMir)?;
         debug!("{:?}", connect.status());
 
-        // Mata + heathbeat
-        setup_heartbeat_task(self.clone());
+        // Joining again after leaving starts new tasks
+        {
+            let mut tasks = self.tasks.lock().unwrap();
+            if tasks.token.is_cancelled() {
+                *tasks = OxiTasks::default();
+                *self.updating.lock().unwrap() = None;
+            }
+        }
+
+        if self.config.device_id.is_empty() {
+            let assignment = self.provision().await?;
+            info!("provisioned as {}", assignment.device_id);
+        }
+
+        // Mata + heathbeat, with the settings mir asked for last time
+        self.apply_reserved_desired(&self.twin.desired().properties);
+        self.spawn_task(heartbeat(self.clone()));
+
+        if let Some(batcher) = &self.telemetry_batcher {
+            self.spawn_task(flush_telemetry_on_delay(self.clone(), batcher.clone()));
+        }
+
+        // Log records are queued until now, they are sent until the queue
+        // is empty even when leaving
+        if let Some(forwarder) = &self.log_forwarder {
+            let task = forwarder.start(self.clone());
+            self.tasks.lock().unwrap().set.spawn(task);
+        }
+
+        // Telemetry can be stored with names and units from now on
+        if !self.config.sensors.is_empty() {
+            if let Err(x) = self.send_sensors_request().await {
+                error!("error declaring sensors: {}", x)
+            }
+        }
 
         // Setup receiving queue for mir -> device communication
-        setup_consume_message_received(self.clone(), self.desired_prop_callback.clone());
+        let (desired_sender, desired_receiver) = mpsc::unbounded_channel();
+        self.spawn_task(handle_async_desired(self.clone(), desired_receiver));
+        self.spawn_task(consume_message_received(
+            self.clone(),
+            self.desired_prop_callback.clone(),
+            desired_sender,
+            self.status_callback.clone(),
+        ));
 
         // Request initial desired properties from mir
         info!("sending desired properties initial request");
@@ -110,17 +306,115 @@ impl Oxi {
         Ok(())
     }
 
+    // Ask mir to create the twin from the enrollment group and take its assigned id and key
+    pub async fn provision(&mut self) -> Result<DeviceProvisionResponse, OxiError> {
+        let enrollment = self
+            .config
+            .enrollment
+            .clone()
+            .ok_or(OxiError::CantProvision(String::from(
+                "no enrollment configured",
+            )))?;
+
+        let timestamp = Utc::now().timestamp_nanos();
+        let payload = DeviceProvisionRequest {
+            registration_id: enrollment.registration_id.clone(),
+            group_id: enrollment.group_id.clone(),
+            timestamp,
+            signature: sign_message(
+                &enrollment.derived_key,
+                timestamp,
+                enrollment.registration_id.as_bytes(),
+            ),
+        };
+        let str_payload = serde_json::to_string(&payload).unwrap();
+        let reply = self
+            .amqp
+            .send_request(
+                &str_payload,
+                RMQ_TWIN_EXCHANGE_NAME,
+                &self.routing_key(RMQ_TWIN_PROVISION_ROUTING_KEY),
+                PROVISION_TIMEOUT,
+            )
+            .await
+            .map_err(|e| OxiError::CantProvision(e.to_string()))?;
+        let assignment: DeviceProvisionResponse =
+            serde_json::from_slice(&reply).map_err(|e| OxiError::CantProvision(e.to_string()))?;
+        if !assignment.error.is_empty() {
+            return Err(OxiError::CantProvision(assignment.error));
+        }
+
+        self.config.device_id = assignment.device_id.clone();
+        self.config.device_key = assignment.device_key.clone();
+        Ok(assignment)
+    }
+
+    // Stops the tasks, sends the queued log records and tells mir the
+    // device is disconnecting before closing the connection, unless shared
     pub async fn leave_fleet(&mut self) -> Result<(), OxiError> {
-        self.amqp.close();
+        if let Err(x) = self.flush_telemetry().await {
+            error!("error sending the last telemetry batch: {}", x);
+        }
+        if let Some(forwarder) = &self.log_forwarder {
+            forwarder.stop();
+        }
+        let mut set = {
+            let mut tasks = self.tasks.lock().unwrap();
+            tasks.token.cancel();
+            std::mem::replace(&mut tasks.set, JoinSet::new())
+        };
+        let stopped = time::timeout(LEAVE_TIMEOUT, async {
+            while set.join_next().await.is_some() {}
+        })
+        .await;
+        if stopped.is_err() {
+            warn!("tasks did not stop in time, aborting them");
+            set.abort_all();
+        }
+
+        // Mir disconnects the device now instead of waiting for missed heartbeats
+        if let Err(x) = self.send_hearthbeat_request(true).await {
+            error!("error sending disconnection: {}", x);
+        }
+        if !self.shared_amqp {
+            self.amqp.close();
+        }
         info!("{} has left the fleet 🚀.", self.config.device_id);
         Ok(())
     }
 
+    // Cancelled when leaving the fleet
+    fn spawn_task(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let token = tasks.token.clone();
+        tasks.set.spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => (),
+                _ = task => (),
+            }
+        });
+    }
+
+    // Queued when batching, the batch is sent once full or old enough
     pub async fn send_telemetry(&self, telemetry: Telemetry) -> Result<&str, OxiError> {
+        if !self.telemetry_limiter.try_acquire() {
+            return Err(OxiError::TelemetryRateLimited);
+        }
+        let timestamp = Utc::now().timestamp_nanos();
+        if let Some(batcher) = &self.telemetry_batcher {
+            return match batcher.push(TelemetryPoint {
+                timestamp,
+                telemetry,
+            }) {
+                Some(points) => self.send_telemetry_batch(points).await,
+                None => Ok("OK"),
+            };
+        }
+
         // Wrap
         let payload = DeviceTelemetryRequest {
             device_id: self.config.device_id.clone(),
-            timestamp: Utc::now().timestamp_nanos(),
+            timestamp,
             telemetry,
         };
 
@@ -129,7 +423,168 @@ impl Oxi {
             .await
     }
 
+    // Sends the readings waiting in the batch
+    pub async fn flush_telemetry(&self) -> Result<&str, OxiError> {
+        let points = match &self.telemetry_batcher {
+            Some(batcher) => batcher.take(),
+            None => vec![],
+        };
+        if points.is_empty() {
+            return Ok("OK");
+        }
+        self.send_telemetry_batch(points).await
+    }
+
+    async fn send_telemetry_batch(&self, points: Vec<TelemetryPoint>) -> Result<&str, OxiError> {
+        let payload = DeviceTelemetryBatchRequest {
+            device_id: self.config.device_id.clone(),
+            timestamp: Utc::now().timestamp_nanos(),
+            points,
+        };
+        self.send_data_as_type(RMQ_STREAM_BATCH_ROUTING_KEY, payload)
+            .await
+    }
+
+    pub async fn send_log(
+        &self,
+        level: LogLevel,
+        target: &str,
+        message: &str,
+        fields: HashMap<String, String>,
+    ) -> Result<&str, OxiError> {
+        self.send_log_request(DeviceLogRequest {
+            device_id: String::new(),
+            timestamp: Utc::now().timestamp_nanos(),
+            level,
+            target: target.to_string(),
+            message: message.to_string(),
+            fields,
+        })
+        .await
+    }
+
+    // Device id is set from the config
+    pub async fn send_log_request(&self, mut request: DeviceLogRequest) -> Result<&str, OxiError> {
+        request.device_id = self.config.device_id.clone();
+        self.send_data_as_type(RMQ_STREAM_LOGS_ROUTING_KEY, request)
+            .await
+            .map_err(|_| OxiError::LogSent)
+    }
+
+    pub async fn send_metrics(&self, metrics: Metrics) -> Result<&str, OxiError> {
+        let payload = DeviceMetricsRequest {
+            device_id: self.config.device_id.clone(),
+            timestamp: Utc::now().timestamp_nanos(),
+            metrics,
+        };
+        self.send_data_as_type(RMQ_STREAM_METRICS_ROUTING_KEY, payload)
+            .await
+            .map_err(|_| OxiError::MetricsSent)
+    }
+
+    pub async fn send_sensors_request(&self) -> Result<&str, OxiError> {
+        let payload = DeviceSensorsRequest {
+            device_id: self.config.device_id.clone(),
+            timestamp: Utc::now().timestamp_nanos(),
+            sensors: self.config.sensors.clone(),
+        };
+        self.send_data_as_type(RMQ_STREAM_SENSORS_ROUTING_KEY, payload)
+            .await
+    }
+
     // TODO: Offer json serialization, msgpack, others
+    // Sends the file in chunks, resuming from the offset mir already has.
+    // The reader starts at the beginning of the file, the bytes mir has are
+    // read again for the file checksum.
+    pub async fn upload_file<R>(
+        &self,
+        name: &str,
+        mut reader: R,
+    ) -> Result<DeviceUploadChunkResponse, OxiError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let upload_error = |e: std::io::Error| OxiError::UploadFailed(e.to_string());
+        let mut request = DeviceUploadChunkRequest {
+            device_id: self.config.device_id.clone(),
+            upload_id: upload_id(&self.config.device_id, name),
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let resume_offset = self.send_upload_chunk(&mut request).await?.offset;
+
+        let mut checksum = Checksum::new();
+        let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < resume_offset {
+            let size = (resume_offset - offset).min(UPLOAD_CHUNK_SIZE as u64) as usize;
+            reader
+                .read_exact(&mut buffer[..size])
+                .await
+                .map_err(upload_error)?;
+            checksum.update(&buffer[..size]);
+            offset += size as u64;
+        }
+        if offset > 0 {
+            info!("resuming upload of {} after {} bytes", name, offset);
+        }
+
+        loop {
+            let size = reader.read(&mut buffer).await.map_err(upload_error)?;
+            if size == 0 {
+                break;
+            }
+            checksum.update(&buffer[..size]);
+            request.offset = offset;
+            request.set_content(&buffer[..size]);
+            let reply = self.send_upload_chunk(&mut request).await?;
+            offset += size as u64;
+            if reply.offset != offset {
+                return Err(OxiError::UploadFailed(format!(
+                    "mir has {} bytes, expected {}",
+                    reply.offset, offset
+                )));
+            }
+        }
+
+        request.offset = offset;
+        request.set_content(&[]);
+        request.file_checksum = Some(checksum.finalize());
+        let reply = self.send_upload_chunk(&mut request).await?;
+        if reply.state != UploadState::Complete {
+            return Err(OxiError::UploadFailed(String::from(
+                "upload is not complete",
+            )));
+        }
+        info!("{} uploaded, {} bytes", name, offset);
+        Ok(reply)
+    }
+
+    async fn send_upload_chunk(
+        &self,
+        request: &mut DeviceUploadChunkRequest,
+    ) -> Result<DeviceUploadChunkResponse, OxiError> {
+        request.timestamp = Utc::now().timestamp_nanos();
+        let payload = serde_json::to_string(request).unwrap();
+        let reply = self
+            .amqp
+            .send_request_with_headers(
+                &payload,
+                RMQ_TWIN_EXCHANGE_NAME,
+                &self.routing_key(RMQ_TWIN_UPLOAD_ROUTING_KEY),
+                UPLOAD_TIMEOUT,
+                self.signature_headers(&payload),
+            )
+            .await
+            .map_err(|e| OxiError::UploadFailed(e.to_string()))?;
+        let reply: DeviceUploadChunkResponse =
+            serde_json::from_slice(&reply).map_err(|e| OxiError::UploadFailed(e.to_string()))?;
+        if !reply.error.is_empty() {
+            return Err(OxiError::UploadFailed(reply.error));
+        }
+        Ok(reply)
+    }
+
     pub async fn send_data_as_type<T>(&self, routing_key: &str, data: T) -> Result<&str, OxiError>
     where
         T: Serialize,
@@ -144,8 +599,11 @@ impl Oxi {
         // Serialize & Send
         debug!("{:?}", data);
         match self
-            .amqp
-            .send_message(&data, RMQ_STREAM_EXCHANGE_NAME, routing_key)
+            .send_signed_message(
+                data,
+                RMQ_STREAM_EXCHANGE_NAME,
+                &self.routing_key(routing_key),
+            )
             .await
         {
             Ok(x) => Ok(x),
@@ -165,8 +623,8 @@ impl Oxi {
             &channel,
             str_payload.as_str(),
             RMQ_TWIN_EXCHANGE_NAME,
-            RMQ_TWIN_DESIRED_PROP_ROUTING_KEY,
-            self.config.device_id.as_str(),
+            &self.routing_key(RMQ_TWIN_DESIRED_PROP_ROUTING_KEY),
+            &self.device_queue(),
             String::from(""),
         )
         .await
@@ -176,21 +634,45 @@ impl Oxi {
         }
     }
 
-    async fn send_hearthbeat_request(&self) -> Result<&str, OxiError> {
+    // Tells mir the desired properties of that version can't be applied
+    pub async fn send_desired_error_request(
+        &self,
+        version: usize,
+        error: &str,
+    ) -> Result<&str, OxiError> {
+        let payload = DeviceDesiredErrorRequest {
+            device_id: self.config.device_id.clone(),
+            timestamp: Utc::now().timestamp_nanos(),
+            version,
+            error: error.to_string(),
+        };
+        let str_payload = serde_json::to_string(&payload).unwrap();
+        debug!("{:?}", str_payload);
+        self.send_signed_message(
+            &str_payload,
+            RMQ_TWIN_EXCHANGE_NAME,
+            &self.routing_key(RMQ_TWIN_DESIRED_ERROR_ROUTING_KEY),
+        )
+        .await
+        .map_err(|_| OxiError::DesiredErrorSent)
+    }
+
+    async fn send_hearthbeat_request(&self, disconnecting: bool) -> Result<&str, OxiError> {
         let payload = DeviceHeartbeatRequest {
             device_id: self.config.device_id.clone(),
             timestamp: Utc::now().timestamp_nanos(),
+            disconnecting,
+            metadata: self.device_metadata(),
         };
 
         // Serialize & Send
         let str_payload = serde_json::to_string(&payload).unwrap();
         debug!("{:?}", str_payload);
         match self
-            .amqp
-            .send_message(
+            .send_signed_message(
                 &str_payload,
                 RMQ_TWIN_EXCHANGE_NAME,
-                RMQ_TWIN_HEARTHBEAT_ROUTING_KEY,
+                &self.routing_key(RMQ_TWIN_HEARTHBEAT_ROUTING_KEY),
             )
             .await
         {
@@ -199,6 +681,254 @@ impl Oxi {
         }
     }
 
+    fn device_metadata(&self) -> DeviceMetadata {
+        DeviceMetadata {
+            sdk_version: env!("CARGO_PKG_VERSION").to_string(),
+            firmware_version: self.config.firmware_version.clone(),
+            uptime_secs: self.heartbeat.uptime().as_secs(),
+            ip: local_ip(&self.amqp.addr)
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            queue_depth: self
+                .log_forwarder
+                .as_ref()
+                .map(|x| x.pending())
+                .unwrap_or_default()
+                + self
+                    .telemetry_batcher
+                    .as_ref()
+                    .map(|x| x.pending())
+                    .unwrap_or_default(),
+            heartbeat_interval_secs: self.heartbeat.interval_secs(),
+            custom: self.config.metadata.clone(),
+        }
+    }
+
+    // Settings mir gives oxi itself under the reserved desired key
+    fn apply_reserved_desired(&self, desired: &Value) {
+        if let Some(x) = desired_interval_secs(desired) {
+            self.heartbeat.set_interval_secs(x);
+        }
+        if let Some(x) = desired_rate_limit(desired) {
+            self.telemetry_limiter.set_config(x);
+        }
+        if let Some(x) = FirmwareDesired::from_properties(desired) {
+            self.start_firmware_update(x);
+        }
+    }
+
+    // Runs in the background unless the campaign was already done, a
+    // campaign desired meanwhile starts once the current one is over
+    fn start_firmware_update(&self, firmware: FirmwareDesired) {
+        if self.update_callback.lock().unwrap().is_none() {
+            debug!(
+                "no update handler, campaign {} ignored",
+                firmware.campaign_id
+            );
+            return;
+        }
+        let reported = FirmwareReported::from_properties(&self.twin.reported().properties);
+        if reported.map_or(false, |x| {
+            x.campaign_id == firmware.campaign_id && x.state.is_done()
+        }) {
+            return;
+        }
+        {
+            let mut updating = self.updating.lock().unwrap();
+            if let Some(x) = updating.as_ref() {
+                if x != &firmware.campaign_id {
+                    info!("campaign {} waits for campaign {}", firmware.campaign_id, x);
+                }
+                return;
+            }
+            *updating = Some(firmware.campaign_id.clone());
+        }
+
+        let oxi = self.clone();
+        self.spawn_task(async move {
+            info!(
+                "updating to {} from campaign {}",
+                firmware.version, firmware.campaign_id
+            );
+            if let Err(x) = oxi.update_firmware(&firmware).await {
+                error!("{}", x);
+            }
+            *oxi.updating.lock().unwrap() = None;
+            if let Some(x) = FirmwareDesired::from_properties(&oxi.twin.desired().properties) {
+                oxi.start_firmware_update(x);
+            }
+        });
+    }
+
+    // Downloads the artifact, verifies its checksum and gives it to the
+    // update handler, each state is reported under the reserved key
+    pub async fn update_firmware(&self, firmware: &FirmwareDesired) -> Result<(), OxiError> {
+        let file_name = firmware.artifact_id.replace(['/', '\\'], "_");
+        let path = self
+            .config
+            .update_dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir)
+            .join(file_name);
+        let result = self.apply_artifact(firmware, &path).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        match &result {
+            Ok(()) => {
+                self.report_firmware(firmware, FirmwareState::Success, 100, "")
+                    .await
+            }
+            Err(x) => {
+                self.report_firmware(firmware, FirmwareState::Failed, 0, &x.to_string())
+                    .await
+            }
+        }
+        result
+    }
+
+    async fn apply_artifact(
+        &self,
+        firmware: &FirmwareDesired,
+        path: &Path,
+    ) -> Result<(), OxiError> {
+        self.report_firmware(firmware, FirmwareState::Downloading, 0, "")
+            .await;
+        let checksum = self.download_artifact(firmware, path).await?;
+
+        self.report_firmware(firmware, FirmwareState::Verifying, 100, "")
+            .await;
+        if checksum != firmware.checksum {
+            return Err(OxiError::UpdateFailed(String::from(
+                "artifact checksum mismatch",
+            )));
+        }
+
+        self.report_firmware(firmware, FirmwareState::Applying, 100, "")
+            .await;
+        let applied = self
+            .update_callback
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|cb| cb(self.clone(), firmware.clone(), path.to_path_buf()));
+        match applied {
+            Some(x) => x.await.map_err(OxiError::UpdateFailed),
+            None => Err(OxiError::UpdateFailed(String::from("no update handler"))),
+        }
+    }
+
+    // Returns the checksum of the downloaded content
+    async fn download_artifact(
+        &self,
+        firmware: &FirmwareDesired,
+        path: &Path,
+    ) -> Result<String, OxiError> {
+        let update_error = |e: std::io::Error| OxiError::UpdateFailed(e.to_string());
+        let mut file = tokio::fs::File::create(path).await.map_err(update_error)?;
+        let mut checksum = Checksum::new();
+        let mut offset = 0;
+        let mut reported = 0;
+        while offset < firmware.size {
+            let content = self
+                .request_artifact_chunk(&firmware.artifact_id, offset)
+                .await?;
+            if content.is_empty() {
+                return Err(OxiError::UpdateFailed(format!(
+                    "artifact ends at {} bytes, expected {}",
+                    offset, firmware.size
+                )));
+            }
+            checksum.update(&content);
+            file.write_all(&content).await.map_err(update_error)?;
+            offset += content.len() as u64;
+
+            let progress = (offset.min(firmware.size) * 100 / firmware.size) as u8;
+            if progress >= reported + FIRMWARE_PROGRESS_STEP {
+                self.report_firmware(firmware, FirmwareState::Downloading, progress, "")
+                    .await;
+                reported = progress;
+            }
+        }
+        file.flush().await.map_err(update_error)?;
+        Ok(checksum.finalize())
+    }
+
+    async fn request_artifact_chunk(
+        &self,
+        artifact_id: &str,
+        offset: u64,
+    ) -> Result<Vec<u8>, OxiError> {
+        let request = DeviceArtifactChunkRequest {
+            device_id: self.config.device_id.clone(),
+            timestamp: Utc::now().timestamp_nanos(),
+            artifact_id: artifact_id.to_string(),
+            offset,
+            length: ARTIFACT_CHUNK_SIZE,
+        };
+        let payload = serde_json::to_string(&request).unwrap();
+        let reply = self
+            .amqp
+            .send_request_with_headers(
+                &payload,
+                RMQ_TWIN_EXCHANGE_NAME,
+                &self.routing_key(RMQ_TWIN_ARTIFACT_ROUTING_KEY),
+                ARTIFACT_TIMEOUT,
+                self.signature_headers(&payload),
+            )
+            .await
+            .map_err(|e| OxiError::UpdateFailed(e.to_string()))?;
+        let reply: DeviceArtifactChunkResponse =
+            serde_json::from_slice(&reply).map_err(|e| OxiError::UpdateFailed(e.to_string()))?;
+        if !reply.error.is_empty() {
+            return Err(OxiError::UpdateFailed(reply.error));
+        }
+        reply.content().ok_or(OxiError::UpdateFailed(String::from(
+            "chunk checksum mismatch",
+        )))
+    }
+
+    async fn report_firmware(
+        &self,
+        firmware: &FirmwareDesired,
+        state: FirmwareState,
+        progress: u8,
+        error: &str,
+    ) {
+        let reported = FirmwareReported {
+            campaign_id: firmware.campaign_id.clone(),
+            artifact_id: firmware.artifact_id.clone(),
+            version: firmware.version.clone(),
+            state,
+            progress,
+            error: error.to_string(),
+        };
+        let update = json!({ RESERVED_DESIRED_KEY: { FIRMWARE_KEY: reported } });
+        if let Err(x) = self.report(update).await {
+            error!("error reporting firmware update state: {}", x);
+        }
+    }
+
+    // Last desired properties received from mir
+    pub fn desired_properties(&self) -> Properties {
+        self.twin.desired()
+    }
+
+    // Last reported properties sent to mir
+    pub fn reported_properties(&self) -> Properties {
+        self.twin.reported()
+    }
+
+    // Json pointers of the desired properties not yet reported with the same value
+    pub fn pending_desired_properties(&self) -> Vec<String> {
+        self.twin.pending_desired()
+    }
+
+    // Merges the update into the reported properties, a null removes the key,
+    // and sends them all with the next version
+    pub async fn report(&self, update: Value) -> Result<&str, OxiError> {
+        let properties = self.twin.merge_reported(update);
+        self.send_reported_properties_request(properties).await
+    }
+
     pub async fn send_reported_properties_request(
         &self,
         properties: Properties,
@@ -213,11 +943,10 @@ impl Oxi {
         let str_payload = serde_json::to_string(&payload).unwrap();
         debug!("{:?}", str_payload);
         match self
-            .amqp
-            .send_message(
+            .send_signed_message(
                 &str_payload,
                 RMQ_TWIN_EXCHANGE_NAME,
-                RMQ_TWIN_REPORTED_PROP_ROUTING_KEY,
+                &self.routing_key(RMQ_TWIN_REPORTED_PROP_ROUTING_KEY),
             )
             .await
         {
@@ -226,6 +955,39 @@ impl Oxi {
         }
     }
 
+    fn routing_key(&self, routing_key: &str) -> String {
+        tenant_routing_key(&self.config.tenant, routing_key)
+    }
+
+    fn device_queue(&self) -> String {
+        tenant_queue(&self.config.tenant, &self.config.device_id)
+    }
+
+    async fn send_signed_message(
+        &self,
+        payload: &str,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<&str, AmqpError> {
+        self.amqp
+            .send_message_with_headers(
+                payload,
+                exchange,
+                routing_key,
+                self.signature_headers(payload),
+            )
+            .await
+    }
+
+    // Without a device key, messages are sent unsigned
+    fn signature_headers(&self, payload: &str) -> FieldTable {
+        if self.config.device_key.is_empty() {
+            FieldTable::default()
+        } else {
+            Amqp::signature_headers(&self.config.device_id, &self.config.device_key, payload)
+        }
+    }
+
     pub fn add_desired_properties_handler(
         &mut self,
         callback: impl FnMut(Option<Properties>, Option<ShortString>) + Send + Sync + 'static,
@@ -237,60 +999,184 @@ impl Oxi {
             .unwrap()
             .push(Box::new(callback));
     }
+
+    // Receives the oxi, after provisioning, so the handler can report back
+    // without cloning it beforehand
+    pub fn add_desired_properties_handler_async<F, Fut>(&mut self, mut callback: F)
+    where
+        F: FnMut(Oxi, Properties) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.desired_async_callback.lock().unwrap().push(Box::new(
+            move |oxi, properties| -> BoxFuture<'static, ()> {
+                Box::pin(callback(oxi, properties))
+            },
+        ));
+    }
+
+    // Desired properties are deserialized into T, the ones that don't match
+    // are reported to mir with their version and not passed to the handler
+    pub fn add_desired_handler<T, F, Fut>(&mut self, mut callback: F)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: FnMut(Oxi, T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.add_desired_properties_handler_async(move |oxi, properties| {
+            let version = properties.version;
+            let handled = serde_json::from_value::<T>(properties.properties)
+                .map(|x| callback(oxi.clone(), x));
+            async move {
+                match handled {
+                    Ok(x) => x.await,
+                    Err(error) => {
+                        warn!(
+                            "desired properties version {} don't match: {}",
+                            version, error
+                        );
+                        if let Err(x) = oxi
+                            .send_desired_error_request(version, &error.to_string())
+                            .await
+                        {
+                            error!("{}", x);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // Called with the artifact of a firmware campaign once downloaded and
+    // verified, the file is removed when the handler returns. Without a
+    // handler, campaigns are ignored.
+    pub fn add_update_handler<F, Fut>(&mut self, mut callback: F)
+    where
+        F: FnMut(Oxi, FirmwareDesired, PathBuf) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        *self.update_callback.lock().unwrap() = Some(Box::new(
+            move |oxi, firmware, path| -> BoxFuture<'static, Result<(), String>> {
+                Box::pin(callback(oxi, firmware, path))
+            },
+        ));
+    }
+
+    // Called when mir enables, disables or blocks the device. Messages of a
+    // device that is not enabled are dropped by mir.
+    pub fn add_status_handler(
+        &mut self,
+        callback: impl FnMut(DeviceStatusNotification) + Send + Sync + 'static,
+    ) {
+        self.status_callback
+            .lock()
+            .unwrap()
+            .push(Box::new(callback));
+    }
 }
 
-fn setup_consume_message_received(
+async fn consume_message_received(
     oxi: Oxi,
     desired_prop_callback: Arc<
         Mutex<Vec<Box<dyn FnMut(Option<Properties>, Option<ShortString>) + Send + Sync>>>,
     >,
+    desired_sender: mpsc::UnboundedSender<Properties>,
+    status_callback: Arc<Mutex<Vec<Box<dyn FnMut(DeviceStatusNotification) + Send + Sync>>>>,
 ) {
-    tokio::spawn(async move {
-        info!("started consuming desired properties");
-        // TODO: add loop over listen for error restart
-        oxi.amqp
-            .consume_queue(
-                QueueSettings {
-                    name: oxi.config.device_id.as_str(),
-                    options: QueueDeclareOptions {
-                        exclusive: true,
-                        ..Default::default()
-                    },
-                    arguments: FieldTable::default(),
+    info!("started consuming desired properties");
+    let device_queue = oxi.device_queue();
+    let twin = oxi.twin.clone();
+    let cloned_oxi = oxi.clone();
+    // TODO: add loop over listen for error restart
+    oxi.amqp
+        .consume_queue(
+            QueueSettings {
+                name: device_queue.as_str(),
+                options: QueueDeclareOptions {
+                    exclusive: true,
+                    ..Default::default()
                 },
-                ConsumerSettings {
-                    consumer_tag: oxi.config.device_id.as_str(),
-                    options: BasicConsumeOptions {
-                        ..Default::default()
-                    },
-                    arguments: FieldTable::default(),
+                arguments: FieldTable::default(),
+            },
+            ConsumerSettings {
+                consumer_tag: oxi.config.device_id.as_str(),
+                options: BasicConsumeOptions {
+                    ..Default::default()
                 },
-                SerializationKind::Json,
-                move |payload: Option<Properties>, opt: Option<ShortString>| {
-                    info!("received desired properties message");
-                    let mut data = desired_prop_callback.lock().unwrap();
-                    for cb in &mut *data {
-                        cb(payload.clone(), opt.clone());
+                arguments: FieldTable::default(),
+            },
+            SerializationKind::Json,
+            move |payload: Option<DeviceMessage>, opt: Option<ShortString>| {
+                let payload = match payload {
+                    Some(DeviceMessage::Status(status)) => {
+                        if status.status_reason == StatusReason::Blocked {
+                            warn!("{} has been blocked by mir", status.device_id);
+                        } else {
+                            info!("received status {:?}", status.status);
+                        }
+                        let mut data = status_callback.lock().unwrap();
+                        for cb in &mut *data {
+                            cb(status.clone());
+                        }
+                        return Ok::<(), Error>(());
                     }
-                    Ok::<(), Error>(())
-                },
-            )
-            .await;
-        info!("stopped consuming desired properties");
-    });
+                    Some(DeviceMessage::Desired(properties)) => {
+                        if !twin.set_desired(properties.clone()) {
+                            debug!("ignored desired properties version {}", properties.version);
+                            return Ok::<(), Error>(());
+                        }
+                        cloned_oxi.apply_reserved_desired(&properties.properties);
+                        Some(properties)
+                    }
+                    None => None,
+                };
+                info!("received desired properties message");
+                let mut data = desired_prop_callback.lock().unwrap();
+                for cb in &mut *data {
+                    cb(payload.clone(), opt.clone());
+                }
+                if let Some(properties) = payload {
+                    let _ = desired_sender.send(properties);
+                }
+                Ok::<(), Error>(())
+            },
+        )
+        .await;
+    info!("stopped consuming desired properties");
 }
 
-fn setup_heartbeat_task(oxi: Oxi) {
-    info!("started heartbeat");
-    tokio::spawn(async move {
-        let mut interval = time::interval(HEARTHBEAT_INTERVAL);
+// Async handlers run on their own task so a slow one doesn't hold back the
+// device queue, properties wait in the channel meanwhile
+async fn handle_async_desired(oxi: Oxi, mut receiver: mpsc::UnboundedReceiver<Properties>) {
+    while let Some(properties) = receiver.recv().await {
+        let futures: Vec<BoxFuture<'static, ()>> = oxi
+            .desired_async_callback
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|cb| cb(oxi.clone(), properties.clone()))
+            .collect();
+        for future in futures {
+            future.await;
+        }
+    }
+}
 
-        loop {
-            interval.tick().await;
-            debug!("hearthbeat");
-            if let Err(x) = oxi.send_hearthbeat_request().await {
-                error!("error sending heartbeat: {}", x);
-            }
+async fn flush_telemetry_on_delay(oxi: Oxi, batcher: TelemetryBatcher) {
+    loop {
+        batcher.wait_for_delay().await;
+        if let Err(x) = oxi.flush_telemetry().await {
+            error!("error sending telemetry batch: {}", x);
         }
-    });
+    }
+}
+
+async fn heartbeat(oxi: Oxi) {
+    info!("started heartbeat");
+    loop {
+        debug!("hearthbeat");
+        if let Err(x) = oxi.send_hearthbeat_request(false).await {
+            error!("error sending heartbeat: {}", x);
+        }
+        oxi.heartbeat.wait().await;
+    }
 }
@@ -0,0 +1,272 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Notify;
+
+use crate::models::device_twin::RESERVED_DESIRED_KEY;
+use crate::models::telemetry::TelemetryPoint;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct TelemetryBatchConfig {
+    // Sent as soon as the batch holds that many readings
+    pub max_points: usize,
+    // Sent at the latest that long after its first reading
+    pub max_delay_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct RateLimitConfig {
+    // Readings per second on average, 0 is unlimited
+    pub rate_per_second: f64,
+    // Readings accepted at once after a quiet period
+    #[serde(default)]
+    pub burst: u32,
+}
+
+// Collects readings until the batch is full or old enough
+#[derive(Debug, Clone)]
+pub struct TelemetryBatcher {
+    config: TelemetryBatchConfig,
+    points: Arc<Mutex<Vec<TelemetryPoint>>>,
+    started: Arc<Notify>,
+}
+
+impl TelemetryBatcher {
+    pub fn new(config: TelemetryBatchConfig) -> Self {
+        Self {
+            config: TelemetryBatchConfig {
+                max_points: config.max_points.max(1),
+                ..config
+            },
+            points: Arc::new(Mutex::new(vec![])),
+            started: Arc::new(Notify::new()),
+        }
+    }
+
+    // Returns the batch once full
+    pub fn push(&self, point: TelemetryPoint) -> Option<Vec<TelemetryPoint>> {
+        let mut points = self.points.lock().unwrap();
+        if points.is_empty() {
+            self.started.notify_one();
+        }
+        points.push(point);
+        if points.len() >= self.config.max_points {
+            return Some(std::mem::take(&mut *points));
+        }
+        None
+    }
+
+    pub fn take(&self) -> Vec<TelemetryPoint> {
+        std::mem::take(&mut *self.points.lock().unwrap())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.points.lock().unwrap().len()
+    }
+
+    // Returns once a batch started and max_delay_ms went by
+    pub async fn wait_for_delay(&self) {
+        self.started.notified().await;
+        tokio::time::sleep(Duration::from_millis(self.config.max_delay_ms)).await;
+    }
+}
+
+// Token bucket shared by the oxi clones, mir can change it while running
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    config: RateLimitConfig,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                config,
+                tokens: capacity(&config),
+                refilled: Instant::now(),
+            })),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn config(&self) -> RateLimitConfig {
+        self.bucket.lock().unwrap().config
+    }
+
+    // The bucket starts full with the new capacity
+    pub fn set_config(&self, config: RateLimitConfig) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.config != config {
+            info!(
+                "telemetry rate limit is now {}/s with bursts of {}",
+                config.rate_per_second, config.burst
+            );
+            *bucket = TokenBucket {
+                config,
+                tokens: capacity(&config),
+                refilled: Instant::now(),
+            };
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.config.rate_per_second <= 0.0 {
+            return true;
+        }
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * bucket.config.rate_per_second).min(capacity(&bucket.config));
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    // Readings refused because of the rate limit
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn capacity(config: &RateLimitConfig) -> f64 {
+    f64::from(config.burst.max(1))
+}
+
+// Rate limit set by mir in the reserved desired properties
+pub fn desired_rate_limit(desired: &Value) -> Option<RateLimitConfig> {
+    let value = desired
+        .get(RESERVED_DESIRED_KEY)?
+        .get("telemetry_rate_limit")?;
+    serde_json::from_value(value.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(timestamp: i64) -> TelemetryPoint {
+        TelemetryPoint {
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    fn limiter(rate_per_second: f64, burst: u32) -> (RateLimiter, Instant) {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            rate_per_second,
+            burst,
+        });
+        let start = limiter.bucket.lock().unwrap().refilled;
+        (limiter, start)
+    }
+
+    #[test]
+    fn burst_is_accepted_then_exhausted() {
+        let (limiter, start) = limiter(1.0, 3);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at(start));
+        }
+        assert!(!limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start));
+        assert_eq!(limiter.dropped(), 2);
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let (limiter, start) = limiter(10.0, 5);
+        for _ in 0..5 {
+            assert!(limiter.try_acquire_at(start));
+        }
+        assert!(!limiter.try_acquire_at(start));
+
+        // 10/s gives a token every 100ms
+        let later = start + Duration::from_millis(250);
+        assert!(limiter.try_acquire_at(later));
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+    }
+
+    #[test]
+    fn refill_stops_at_the_burst() {
+        let (limiter, start) = limiter(10.0, 2);
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.try_acquire_at(later));
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let (limiter, start) = limiter(0.0, 0);
+        for _ in 0..1000 {
+            assert!(limiter.try_acquire_at(start));
+        }
+        assert_eq!(limiter.dropped(), 0);
+    }
+
+    #[test]
+    fn batch_is_returned_once_full() {
+        let batcher = TelemetryBatcher::new(TelemetryBatchConfig {
+            max_points: 3,
+            max_delay_ms: 60_000,
+        });
+        assert!(batcher.push(point(1)).is_none());
+        assert!(batcher.push(point(2)).is_none());
+        let batch = batcher.push(point(3)).unwrap();
+        assert_eq!(
+            batch.iter().map(|x| x.timestamp).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(batcher.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn partial_batch_is_taken_after_the_delay() {
+        let batcher = TelemetryBatcher::new(TelemetryBatchConfig {
+            max_points: 100,
+            max_delay_ms: 20,
+        });
+        assert!(batcher.push(point(1)).is_none());
+        assert!(batcher.push(point(2)).is_none());
+        tokio::time::timeout(Duration::from_secs(5), batcher.wait_for_delay())
+            .await
+            .unwrap();
+        assert_eq!(batcher.take().len(), 2);
+        assert_eq!(batcher.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn delay_does_not_start_without_points() {
+        let batcher = TelemetryBatcher::new(TelemetryBatchConfig {
+            max_points: 100,
+            max_delay_ms: 1,
+        });
+        let waited =
+            tokio::time::timeout(Duration::from_millis(50), batcher.wait_for_delay()).await;
+        assert!(waited.is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::models::device_twin::{Properties, RESERVED_DESIRED_KEY};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct LocalTwin {
+    desired: Properties,
+    reported: Properties,
+}
+
+// Last desired properties received and reported properties sent, saved
+// to a json file when a path is set so they survive a restart
+#[derive(Debug, Clone, Default)]
+pub struct TwinCache {
+    twin: Arc<Mutex<LocalTwin>>,
+    path: Option<PathBuf>,
+}
+
+impl TwinCache {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let twin = path.as_deref().map(load).unwrap_or_default();
+        Self {
+            twin: Arc::new(Mutex::new(twin)),
+            path,
+        }
+    }
+
+    pub fn desired(&self) -> Properties {
+        self.twin.lock().unwrap().desired.clone()
+    }
+
+    pub fn reported(&self) -> Properties {
+        self.twin.lock().unwrap().reported.clone()
+    }
+
+    // Older versions arrive when an update and the initial request cross,
+    // they are ignored
+    pub fn set_desired(&self, properties: Properties) -> bool {
+        let mut twin = self.twin.lock().unwrap();
+        if properties.version < twin.desired.version {
+            return false;
+        }
+        twin.desired = properties;
+        self.save(&twin);
+        true
+    }
+
+    // Merges the update into the reported properties like a json merge
+    // patch, a null removes the key, and returns them with the next version
+    pub fn merge_reported(&self, update: Value) -> Properties {
+        let mut twin = self.twin.lock().unwrap();
+        merge_patch(&mut twin.reported.properties, update);
+        twin.reported.version += 1;
+        self.save(&twin);
+        twin.reported.clone()
+    }
+
+    // Json pointers of the desired values not yet reported with the same
+    // value, the ones oxi applies itself are left out
+    pub fn pending_desired(&self) -> Vec<String> {
+        let twin = self.twin.lock().unwrap();
+        let mut pending = vec![];
+        diff_keys(
+            "",
+            &twin.desired.properties,
+            Some(&twin.reported.properties),
+            &mut pending,
+        );
+        let reserved = format!("/{}", RESERVED_DESIRED_KEY);
+        pending.retain(|x| x != &reserved && !x.starts_with(&format!("{}/", reserved)));
+        pending
+    }
+
+    fn save(&self, twin: &LocalTwin) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(error) = save(path, twin) {
+            error!("can't save twin to {}: {}", path.display(), error);
+        }
+    }
+}
+
+fn load(path: &Path) -> LocalTwin {
+    match fs::read(path).map(|x| serde_json::from_slice::<LocalTwin>(&x)) {
+        Ok(Ok(x)) => x,
+        Ok(Err(error)) => {
+            error!("ignored invalid twin in {}: {}", path.display(), error);
+            LocalTwin::default()
+        }
+        Err(error) => {
+            debug!("no twin loaded from {}: {}", path.display(), error);
+            LocalTwin::default()
+        }
+    }
+}
+
+// Written next to the file then renamed, a crash never leaves half a twin
+fn save(path: &Path, twin: &LocalTwin) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(twin)?)?;
+    fs::rename(tmp, path)
+}
+
+fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn diff_keys(pointer: &str, desired: &Value, reported: Option<&Value>, pending: &mut Vec<String>) {
+    match desired {
+        Value::Object(desired) => {
+            for (key, value) in desired {
+                let key_pointer =
+                    format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"));
+                diff_keys(
+                    &key_pointer,
+                    value,
+                    reported.and_then(|x| x.get(key)),
+                    pending,
+                );
+            }
+        }
+        // Nothing is desired
+        Value::Null => (),
+        _ if reported != Some(desired) => pending.push(pointer.to_string()),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn merged(mut target: Value, patch: Value) -> Value {
+        merge_patch(&mut target, patch);
+        target
+    }
+
+    fn diff(desired: Value, reported: Value) -> Vec<String> {
+        let mut pending = vec![];
+        diff_keys("", &desired, Some(&reported), &mut pending);
+        pending.sort();
+        pending
+    }
+
+    #[test]
+    fn merge_patch_adds_and_replaces_nested_keys() {
+        assert_eq!(
+            merged(
+                json!({"a": 1, "b": {"c": 2, "d": 3}}),
+                json!({"a": 5, "b": {"c": 4}, "e": [1]})
+            ),
+            json!({"a": 5, "b": {"c": 4, "d": 3}, "e": [1]})
+        );
+    }
+
+    #[test]
+    fn merge_patch_null_removes_key() {
+        assert_eq!(
+            merged(
+                json!({"a": 1, "b": {"c": 2, "d": 3}}),
+                json!({"a": null, "b": {"c": null}})
+            ),
+            json!({"b": {"d": 3}})
+        );
+        assert_eq!(merged(json!({"a": 1}), json!({"z": null})), json!({"a": 1}));
+    }
+
+    #[test]
+    fn merge_patch_replaces_non_objects() {
+        assert_eq!(merged(json!({"a": 1}), json!([1, 2])), json!([1, 2]));
+        assert_eq!(merged(json!("text"), json!({"a": 1})), json!({"a": 1}));
+        assert_eq!(
+            merged(json!({"a": 1}), json!({"a": {"b": 2}})),
+            json!({"a": {"b": 2}})
+        );
+    }
+
+    #[test]
+    fn diff_keys_lists_values_not_reported() {
+        assert_eq!(
+            diff(
+                json!({"same": 1, "changed": 2, "missing": 3, "nested": {"x": true, "y": "a"}}),
+                json!({"same": 1, "changed": 5, "nested": {"x": true, "y": "b"}})
+            ),
+            vec!["/changed", "/missing", "/nested/y"]
+        );
+    }
+
+    #[test]
+    fn diff_keys_ignores_null_and_extra_reported() {
+        assert!(diff(json!({"a": null}), json!({})).is_empty());
+        assert!(diff(json!({"a": 1}), json!({"a": 1, "b": 2})).is_empty());
+    }
+
+    #[test]
+    fn diff_keys_escapes_pointers() {
+        assert_eq!(
+            diff(json!({"a/b": 1, "c~d": 2}), json!({})),
+            vec!["/a~1b", "/c~0d"]
+        );
+    }
+
+    #[test]
+    fn pending_desired_leaves_out_reserved_keys() {
+        let cache = TwinCache::new(None);
+        cache.set_desired(Properties {
+            properties: json!({
+                "interval": 10,
+                RESERVED_DESIRED_KEY: {"heartbeat_interval_secs": 30},
+            }),
+            version: 1,
+        });
+
+        assert_eq!(cache.pending_desired(), vec!["/interval"]);
+        cache.merge_reported(json!({"interval": 10}));
+        assert!(cache.pending_desired().is_empty());
+    }
+
+    #[test]
+    fn older_desired_is_ignored() {
+        let cache = TwinCache::new(None);
+        assert!(cache.set_desired(Properties {
+            properties: json!({"a": 2}),
+            version: 2,
+        }));
+        assert!(!cache.set_desired(Properties {
+            properties: json!({"a": 1}),
+            version: 1,
+        }));
+        assert_eq!(cache.desired().properties, json!({"a": 2}));
+    }
+
+    #[test]
+    fn merge_reported_bumps_version() {
+        let cache = TwinCache::new(None);
+        cache.merge_reported(json!({"a": 1}));
+        let reported = cache.merge_reported(json!({"b": 2}));
+
+        assert_eq!(reported.version, 2);
+        assert_eq!(reported.properties, json!({"a": 1, "b": 2}));
+    }
+}
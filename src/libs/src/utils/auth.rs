@@ -0,0 +1,552 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use chrono::Utc;
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use log::warn;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+use thiserror::Error as ThisError;
+
+use crate::models::device_key::DeviceKey;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TOKEN_SEPARATOR: char = '.';
+const DEVICE_KEY_SIZE: usize = 32;
+// Signed messages older than this are considered replayed
+const MAX_MESSAGE_AGE: Duration = Duration::from_secs(300);
+// An unknown device reloads its key at most this often
+const MIN_KEY_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+// Reloads over the last interval, bounds the queries forged device ids can cause
+const MAX_KEY_RELOADS: usize = 100;
+
+pub const DEVICE_ID_HEADER: &str = "x-mir-device-id";
+pub const TIMESTAMP_HEADER: &str = "x-mir-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-mir-signature";
+
+#[derive(ThisError, Debug)]
+pub enum AuthError {
+    #[error("malformed token")]
+    MalformedToken,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("token expired")]
+    Expired,
+    #[error("decode error: {0}")]
+    DecodeError(#[from] base64::DecodeError),
+    #[error("claims error: {0}")]
+    ClaimsError(#[from] serde_json::Error),
+    #[error("message is not signed")]
+    Unsigned,
+    #[error("no key for device {0}")]
+    UnknownDevice(String),
+    #[error("message signed by {0} but sent for {1}")]
+    DeviceMismatch(String, String),
+    #[error("message timestamp outside of accepted window")]
+    Stale,
+}
+
+// Ordered from least to most privileged, a caller is allowed
+// everything a lower role is allowed
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Reader,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Role::Reader => "reader",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Role,
+    // Unix timestamp in seconds, 0 never expires
+    pub exp: i64,
+    // Empty gives access to every tenant
+    #[serde(default)]
+    pub tenant: String,
+}
+
+pub fn sign(secret: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn verify(secret: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac can take key of any size");
+    mac.update(data);
+    mac.verify_slice(signature).is_ok()
+}
+
+// Constant time, both secrets are signed with a random key so comparing the
+// macs tells nothing about where they differ
+pub fn secret_eq(secret: &str, candidate: &str) -> bool {
+    let mut key = [0u8; DEVICE_KEY_SIZE];
+    rand::thread_rng().fill_bytes(&mut key);
+    verify(&key, candidate.as_bytes(), &sign(&key, secret.as_bytes()))
+}
+
+// Token layout is <base64(claims)>.<base64(hmac(claims))>
+pub fn create_token(secret: &str, claims: &Claims) -> Result<String, AuthError> {
+    let encoded_claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+    let signature = URL_SAFE_NO_PAD.encode(sign(secret.as_bytes(), encoded_claims.as_bytes()));
+    Ok(format!("{encoded_claims}{TOKEN_SEPARATOR}{signature}"))
+}
+
+pub fn verify_token(secret: &str, token: &str) -> Result<Claims, AuthError> {
+    let (encoded_claims, signature) = token
+        .split_once(TOKEN_SEPARATOR)
+        .ok_or(AuthError::MalformedToken)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature)?;
+    if !verify(secret.as_bytes(), encoded_claims.as_bytes(), &signature) {
+        return Err(AuthError::InvalidSignature);
+    }
+
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(encoded_claims)?)?;
+    if claims.exp != 0 && claims.exp < Utc::now().timestamp() {
+        return Err(AuthError::Expired);
+    }
+    Ok(claims)
+}
+
+pub fn generate_device_key() -> String {
+    let mut key = [0u8; DEVICE_KEY_SIZE];
+    rand::thread_rng().fill_bytes(&mut key);
+    STANDARD.encode(key)
+}
+
+// Signature is hmac(key, <timestamp>.<payload>) so a payload can't be replayed
+// with another timestamp
+pub fn sign_message(key: &str, timestamp: i64, payload: &[u8]) -> String {
+    STANDARD.encode(sign(key.as_bytes(), &signed_content(timestamp, payload)))
+}
+
+pub fn verify_message(key: &str, timestamp: i64, payload: &[u8], signature: &str) -> bool {
+    match STANDARD.decode(signature) {
+        Ok(signature) => verify(
+            key.as_bytes(),
+            &signed_content(timestamp, payload),
+            &signature,
+        ),
+        Err(_) => false,
+    }
+}
+
+// Key a device gets from its enrollment group, computed at manufacturing
+// so devices never hold the group secret
+pub fn derive_device_key(attestation_secret: &str, registration_id: &str) -> String {
+    STANDARD.encode(sign(
+        attestation_secret.as_bytes(),
+        registration_id.as_bytes(),
+    ))
+}
+
+pub fn verify_attestation(
+    attestation_secret: &str,
+    registration_id: &str,
+    timestamp: i64,
+    signature: &str,
+) -> Result<(), AuthError> {
+    if is_stale(timestamp) {
+        return Err(AuthError::Stale);
+    }
+    let derived_key = derive_device_key(attestation_secret, registration_id);
+    if !verify_message(
+        &derived_key,
+        timestamp,
+        registration_id.as_bytes(),
+        signature,
+    ) {
+        return Err(AuthError::InvalidSignature);
+    }
+    Ok(())
+}
+
+fn is_stale(timestamp: i64) -> bool {
+    Utc::now().timestamp_nanos().abs_diff(timestamp) > MAX_MESSAGE_AGE.as_nanos() as u64
+}
+
+fn signed_content(timestamp: i64, payload: &[u8]) -> Vec<u8> {
+    let mut content = format!("{timestamp}{TOKEN_SEPARATOR}").into_bytes();
+    content.extend_from_slice(payload);
+    content
+}
+
+// Shared view of device keys used by consumers to verify device messages.
+// With a db, the key of an unknown device is reloaded so keys created since
+// the last refresh are accepted right away. Rotated keys wait for the refresh.
+#[derive(Clone, Default)]
+pub struct DeviceKeyCache {
+    keys: Arc<RwLock<HashMap<String, DeviceKey>>>,
+    db: Option<Surreal<Client>>,
+    reloads: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+// Keys are secrets, only their count is shown
+impl fmt::Debug for DeviceKeyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceKeyCache")
+            .field("keys", &self.len())
+            .field("reloads", &self.db.is_some())
+            .finish()
+    }
+}
+
+impl DeviceKeyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_db(db: Surreal<Client>) -> Self {
+        Self {
+            db: Some(db),
+            ..Self::default()
+        }
+    }
+
+    pub fn replace_all(&self, keys: Vec<DeviceKey>) {
+        let mut cache = self.keys.write().unwrap();
+        *cache = keys
+            .into_iter()
+            .map(|key| (key.device_id.clone(), key))
+            .collect();
+    }
+
+    pub fn insert(&self, key: DeviceKey) {
+        self.keys
+            .write()
+            .unwrap()
+            .insert(key.device_id.clone(), key);
+    }
+
+    pub fn remove(&self, device_id: &str) {
+        self.keys.write().unwrap().remove(device_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    // True when the stored key differs from the cached one
+    pub async fn reload(&self, device_id: &str) -> bool {
+        let Some(db) = &self.db else {
+            return false;
+        };
+        {
+            let now = Instant::now();
+            let mut reloads = self.reloads.lock().unwrap();
+            reloads.retain(|_, x| now.duration_since(*x) < MIN_KEY_RELOAD_INTERVAL);
+            if reloads.contains_key(device_id) || reloads.len() >= MAX_KEY_RELOADS {
+                return false;
+            }
+            reloads.insert(device_id.to_string(), now);
+        }
+
+        let stored: Option<DeviceKey> = match db.select(("device_key", device_id)).await {
+            Ok(x) => x,
+            Err(error) => {
+                warn!("can't reload device key of '{}': {}", device_id, error);
+                return false;
+            }
+        };
+        let Some(stored) = stored else {
+            return false;
+        };
+        let changed = self
+            .keys
+            .read()
+            .unwrap()
+            .get(device_id)
+            .map_or(true, |x| x.primary_key != stored.primary_key);
+        if changed {
+            self.insert(stored);
+        }
+        changed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn verify(
+        &self,
+        device_id: &str,
+        timestamp: i64,
+        payload: &[u8],
+        signature: &str,
+    ) -> Result<(), AuthError> {
+        if is_stale(timestamp) {
+            return Err(AuthError::Stale);
+        }
+
+        let cache = self.keys.read().unwrap();
+        let key = cache
+            .get(device_id)
+            .ok_or_else(|| AuthError::UnknownDevice(device_id.to_string()))?;
+        let is_valid = [&key.primary_key, &key.secondary_key]
+            .iter()
+            .filter(|x| !x.is_empty())
+            .any(|x| verify_message(x, timestamp, payload, signature));
+        if !is_valid {
+            return Err(AuthError::InvalidSignature);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "token-secret";
+    const KEY: &str = "device-key";
+
+    fn claims(exp: i64) -> Claims {
+        Claims {
+            sub: "ops".to_string(),
+            role: Role::Operator,
+            exp,
+            tenant: "iot".to_string(),
+        }
+    }
+
+    fn device_key(primary_key: &str, secondary_key: &str) -> DeviceKey {
+        DeviceKey {
+            device_id: "dev-1".to_string(),
+            primary_key: primary_key.to_string(),
+            secondary_key: secondary_key.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn now() -> i64 {
+        Utc::now().timestamp_nanos()
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let token = create_token(SECRET, &claims(0)).unwrap();
+        let verified = verify_token(SECRET, &token).unwrap();
+        assert_eq!(verified.sub, "ops");
+        assert_eq!(verified.role, Role::Operator);
+        assert_eq!(verified.tenant, "iot");
+    }
+
+    #[test]
+    fn token_with_future_expiry_is_valid() {
+        let token = create_token(SECRET, &claims(Utc::now().timestamp() + 60)).unwrap();
+        assert!(verify_token(SECRET, &token).is_ok());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let token = create_token(SECRET, &claims(Utc::now().timestamp() - 1)).unwrap();
+        assert!(matches!(
+            verify_token(SECRET, &token),
+            Err(AuthError::Expired)
+        ));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let token = create_token(SECRET, &claims(0)).unwrap();
+        let (_, signature) = token.split_once(TOKEN_SEPARATOR).unwrap();
+        let mut admin = claims(0);
+        admin.role = Role::Admin;
+        let encoded_admin = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&admin).unwrap());
+        let tampered = format!("{encoded_admin}{TOKEN_SEPARATOR}{signature}");
+
+        assert!(matches!(
+            verify_token(SECRET, &tampered),
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn token_signed_with_another_secret_is_rejected() {
+        let token = create_token("other-secret", &claims(0)).unwrap();
+        assert!(matches!(
+            verify_token(SECRET, &token),
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn malformed_token_is_rejected() {
+        assert!(matches!(
+            verify_token(SECRET, "no-separator"),
+            Err(AuthError::MalformedToken)
+        ));
+        assert!(matches!(
+            verify_token(SECRET, "claims.not base64!"),
+            Err(AuthError::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let timestamp = now();
+        let signature = sign_message(KEY, timestamp, b"payload");
+        assert!(verify_message(KEY, timestamp, b"payload", &signature));
+    }
+
+    #[test]
+    fn tampered_message_is_rejected() {
+        let timestamp = now();
+        let signature = sign_message(KEY, timestamp, b"payload");
+        assert!(!verify_message(KEY, timestamp, b"payloads", &signature));
+        assert!(!verify_message(KEY, timestamp + 1, b"payload", &signature));
+        assert!(!verify_message(
+            "other-key",
+            timestamp,
+            b"payload",
+            &signature
+        ));
+        assert!(!verify_message(KEY, timestamp, b"payload", "not base64!"));
+    }
+
+    #[test]
+    fn cache_accepts_primary_and_secondary_keys() {
+        let cache = DeviceKeyCache::new();
+        cache.insert(device_key("new-key", "old-key"));
+        let timestamp = now();
+
+        for key in ["new-key", "old-key"] {
+            let signature = sign_message(key, timestamp, b"payload");
+            assert!(cache
+                .verify("dev-1", timestamp, b"payload", &signature)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn cache_rejects_unknown_device_and_wrong_key() {
+        let cache = DeviceKeyCache::new();
+        cache.insert(device_key(KEY, ""));
+        let timestamp = now();
+        let signature = sign_message(KEY, timestamp, b"payload");
+
+        assert!(matches!(
+            cache.verify("dev-2", timestamp, b"payload", &signature),
+            Err(AuthError::UnknownDevice(_))
+        ));
+        let signature = sign_message("other-key", timestamp, b"payload");
+        assert!(matches!(
+            cache.verify("dev-1", timestamp, b"payload", &signature),
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn cache_ignores_empty_secondary_key() {
+        let cache = DeviceKeyCache::new();
+        cache.insert(device_key(KEY, ""));
+        let timestamp = now();
+        let signature = sign_message("", timestamp, b"payload");
+
+        assert!(matches!(
+            cache.verify("dev-1", timestamp, b"payload", &signature),
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn stale_message_is_rejected() {
+        let cache = DeviceKeyCache::new();
+        cache.insert(device_key(KEY, ""));
+        let age = MAX_MESSAGE_AGE.as_nanos() as i64 + 1_000_000_000;
+
+        for timestamp in [now() - age, now() + age] {
+            let signature = sign_message(KEY, timestamp, b"payload");
+            assert!(matches!(
+                cache.verify("dev-1", timestamp, b"payload", &signature),
+                Err(AuthError::Stale)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_without_db_does_not_reload() {
+        let cache = DeviceKeyCache::new();
+        assert!(!cache.reload("dev-1").await);
+    }
+
+    #[test]
+    fn derived_key_depends_on_secret_and_registration_id() {
+        let key = derive_device_key("group-secret", "dev-1");
+        assert_eq!(key, derive_device_key("group-secret", "dev-1"));
+        assert_ne!(key, derive_device_key("group-secret", "dev-2"));
+        assert_ne!(key, derive_device_key("other-secret", "dev-1"));
+    }
+
+    #[test]
+    fn attestation_round_trip() {
+        let key = derive_device_key("group-secret", "dev-1");
+        let timestamp = now();
+        let signature = sign_message(&key, timestamp, b"dev-1");
+        assert!(verify_attestation("group-secret", "dev-1", timestamp, &signature).is_ok());
+    }
+
+    #[test]
+    fn attestation_for_another_registration_id_is_rejected() {
+        let key = derive_device_key("group-secret", "dev-1");
+        let timestamp = now();
+        let signature = sign_message(&key, timestamp, b"dev-2");
+
+        assert!(matches!(
+            verify_attestation("group-secret", "dev-2", timestamp, &signature),
+            Err(AuthError::InvalidSignature)
+        ));
+        let signature = sign_message(&key, timestamp, b"dev-1");
+        assert!(matches!(
+            verify_attestation("other-secret", "dev-1", timestamp, &signature),
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn stale_attestation_is_rejected() {
+        let key = derive_device_key("group-secret", "dev-1");
+        let timestamp = now() - MAX_MESSAGE_AGE.as_nanos() as i64 - 1_000_000_000;
+        let signature = sign_message(&key, timestamp, b"dev-1");
+
+        assert!(matches!(
+            verify_attestation("group-secret", "dev-1", timestamp, &signature),
+            Err(AuthError::Stale)
+        ));
+    }
+
+    #[test]
+    fn secret_eq_compares_content() {
+        assert!(secret_eq("api-key", "api-key"));
+        assert!(!secret_eq("api-key", "api-kez"));
+        assert!(!secret_eq("api-key", "api-key-longer"));
+        assert!(!secret_eq("api-key", ""));
+    }
+}
@@ -0,0 +1,29 @@
+use sha2::{Digest, Sha256};
+
+// Hex sha256, updated piece by piece for files
+#[derive(Debug, Clone, Default)]
+pub struct Checksum(Sha256);
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finalize(self) -> String {
+        self.0
+            .finalize()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect()
+    }
+}
+
+pub fn checksum(data: &[u8]) -> String {
+    let mut checksum = Checksum::new();
+    checksum.update(data);
+    checksum.finalize()
+}
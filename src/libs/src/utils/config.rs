@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 
-use config_sys::{Config, ConfigError, Environment, File};
-use serde::Deserialize;
+use config_sys::{Config, ConfigError, Environment, File, FileSourceFile};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 const CONFIG_FOLDER_PATH_DEFAULT: &str = "./config/";
 const CONFIG_FILE_LOCAL_PREFIX: &str = "local_";
@@ -40,35 +40,13 @@ where
     T: Deserialize<'a>,
 {
     let mut s = Config::builder()
-        .add_source(
-            File::with_name(
-                format!(
-                    "{}{}.{}",
-                    CONFIG_FOLDER_PATH_DEFAULT,
-                    app_name,
-                    file_format.as_str()
-                )
-                .as_str(),
-            )
-            .required(false),
-        )
-        .add_source(
-            File::with_name(
-                format!(
-                    "{}{}{}.{}",
-                    CONFIG_FOLDER_PATH_DEFAULT,
-                    CONFIG_FILE_LOCAL_PREFIX,
-                    app_name,
-                    file_format.as_str()
-                )
-                .as_str(),
-            )
-            .required(false),
-        )
-        .add_source(
-            Environment::with_prefix(app_name.to_uppercase().as_str())
-                .separator(CONFIG_ENV_SEPARATOR),
-        );
+        .add_source(folder_file(app_name, &file_format, ""))
+        .add_source(folder_file(
+            app_name,
+            &file_format,
+            CONFIG_FILE_LOCAL_PREFIX,
+        ))
+        .add_source(environment(app_name));
 
     if let Some(extra_path) = extra_path {
         s = s.add_source(File::with_name(
@@ -76,6 +54,63 @@ where
         ));
     }
 
-    let c = s.build().unwrap();
+    let c = s.build()?;
     c.try_deserialize::<T>()
 }
+
+// Each field is taken from the highest layer setting it:
+// defaults < config folder files < extra file < environment < overrides.
+// Override keys are dotted paths, like log_forwarding.level.
+pub fn setup_layered_config<T>(
+    app_name: &str,
+    file_format: FileFormat,
+    defaults: &T,
+    extra_path: Option<&PathBuf>,
+    overrides: Vec<(String, String)>,
+) -> Result<T, ConfigError>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let mut s = Config::builder()
+        .add_source(Config::try_from(defaults)?)
+        .add_source(folder_file(app_name, &file_format, ""))
+        .add_source(folder_file(
+            app_name,
+            &file_format,
+            CONFIG_FILE_LOCAL_PREFIX,
+        ));
+
+    if let Some(extra_path) = extra_path {
+        s = s.add_source(File::with_name(
+            extra_path.as_path().display().to_string().as_str(),
+        ));
+    }
+    s = s.add_source(environment(app_name));
+    for (key, value) in overrides {
+        s = s.set_override(key, value)?;
+    }
+
+    s.build()?.try_deserialize::<T>()
+}
+
+fn folder_file(
+    app_name: &str,
+    file_format: &FileFormat,
+    prefix: &str,
+) -> File<FileSourceFile, config_sys::FileFormat> {
+    File::with_name(
+        format!(
+            "{}{}{}.{}",
+            CONFIG_FOLDER_PATH_DEFAULT,
+            prefix,
+            app_name,
+            file_format.as_str()
+        )
+        .as_str(),
+    )
+    .required(false)
+}
+
+fn environment(app_name: &str) -> Environment {
+    Environment::with_prefix(app_name.to_uppercase().as_str()).separator(CONFIG_ENV_SEPARATOR)
+}
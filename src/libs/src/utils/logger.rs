@@ -2,22 +2,22 @@ use fern::colors::{Color, ColoredLevelConfig};
 use std::time::SystemTime;
 
 pub fn setup_logger(log_level: String) -> Result<(), fern::InitError> {
-    let level = match log_level.to_lowercase().trim() {
-        "trace" => log::LevelFilter::Trace,
-        "debug" => log::LevelFilter::Debug,
-        "info" => log::LevelFilter::Info,
-        "warn" => log::LevelFilter::Warn,
-        "warning" => log::LevelFilter::Warn,
-        "error" => log::LevelFilter::Error,
-        _ => log::LevelFilter::Info,
-    };
+    setup_logger_with(log_level, None)
+}
+
+// Records go to stdout and, unformatted, to the extra logger when given
+pub fn setup_logger_with(
+    log_level: String,
+    extra: Option<Box<dyn log::Log>>,
+) -> Result<(), fern::InitError> {
+    let level = parse_level(&log_level);
 
     let colors = ColoredLevelConfig::new()
         .info(Color::Green)
         .debug(Color::Cyan)
         .trace(Color::Magenta);
 
-    fern::Dispatch::new()
+    let stdout = fern::Dispatch::new()
         .format(move |out, message, record| {
             out.finish(format_args!(
                 "[{} {} {}] {}",
@@ -27,9 +27,25 @@ pub fn setup_logger(log_level: String) -> Result<(), fern::InitError> {
                 message
             ))
         })
-        .level(level)
-        .chain(std::io::stdout())
-        //.chain(fern::log_file("output.log")?)
-        .apply()?;
+        .chain(std::io::stdout());
+    //.chain(fern::log_file("output.log")?)
+
+    let mut dispatch = fern::Dispatch::new().level(level).chain(stdout);
+    if let Some(x) = extra {
+        dispatch = dispatch.chain(x);
+    }
+    dispatch.apply()?;
     Ok(())
 }
+
+pub fn parse_level(log_level: &str) -> log::LevelFilter {
+    match log_level.to_lowercase().trim() {
+        "trace" => log::LevelFilter::Trace,
+        "debug" => log::LevelFilter::Debug,
+        "info" => log::LevelFilter::Info,
+        "warn" => log::LevelFilter::Warn,
+        "warning" => log::LevelFilter::Warn,
+        "error" => log::LevelFilter::Error,
+        _ => log::LevelFilter::Info,
+    }
+}
@@ -6,8 +6,11 @@ pub use logger::setup_logger;
 pub use network::parse_host_port;
 
 // This expose PostMQ after importing rabbitmq::PostMQ; in the clients
+pub mod auth;
+pub mod checksum;
 pub mod cli;
 pub mod config;
 pub mod logger;
 pub mod network;
 pub mod serialization;
+pub mod tenant;
@@ -1,3 +1,4 @@
+use std::net::{IpAddr, UdpSocket};
 use std::num::ParseIntError;
 use thiserror::Error as ThisError;
 
@@ -13,4 +14,19 @@ pub fn parse_host_port(host: &str) -> Result<(String, u16), NetworkError> {
     let port = parts.next().unwrap_or("");
     let port = port.parse::<u16>()?;
     Ok((host.to_string(), port))
-}
\ No newline at end of file
+}
+
+// Address of the interface used to reach the host of an amqp uri, nothing
+// is sent over the socket
+pub fn local_ip(amqp_addr: &str) -> Option<IpAddr> {
+    let authority = amqp_addr.split("://").last()?.split('/').next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:5672", host)
+    };
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(host).ok()?;
+    socket.local_addr().ok().map(|x| x.ip())
+}
@@ -8,6 +8,8 @@ pub enum SerializationError {
     ParseIntError(#[from] ParseIntError),
     #[error("serialization kind unkown")]
     Unkown(),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
 }
 
 pub enum SerializationKind {
@@ -38,7 +40,7 @@ impl SerializationKind {
         payload: &'a Vec<u8>,
     ) -> Result<T, SerializationError> {
         match self {
-            Self::Json => Ok(serde_json::from_slice(&payload).unwrap()),
+            Self::Json => Ok(serde_json::from_slice(payload)?),
             Self::MsgPack => todo!(),
             Self::Yaml => todo!(),
         }
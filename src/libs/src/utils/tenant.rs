@@ -0,0 +1,22 @@
+// Tenants share the brokers and databases but not their data. A tenant has
+// its own surrealdb namespace, routing keys and queues prefixed by its name.
+pub const DEFAULT_TENANT: &str = "iot";
+pub const TENANT_HEADER: &str = "x-mir-tenant";
+
+// ex: iot.oxi.telemetry.v1 or iot.#.telemetry.v1 for bindings
+pub fn tenant_routing_key(tenant: &str, routing_key: &str) -> String {
+    format!("{tenant}.{routing_key}")
+}
+
+// ex: iot.iot-q-telemetry or iot.<device_id> for device queues
+pub fn tenant_queue(tenant: &str, queue: &str) -> String {
+    format!("{tenant}.{queue}")
+}
+
+pub fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+pub fn default_tenants() -> Vec<String> {
+    vec![default_tenant()]
+}
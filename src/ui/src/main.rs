@@ -1,9 +1,15 @@
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use askama::Template;
-use axum::{routing::get, Router};
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use libs::models::device_model::DeviceModel;
 use libs::utils::setup_cli;
-use log::info;
+use libs::utils::tenant::default_tenant;
+use log::{error, info};
+use surrealdb::engine::remote::ws::{Client, Ws};
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
 
 use libs::utils::config::{setup_config, FileFormat};
 use libs::utils::logger::setup_logger;
@@ -19,6 +25,20 @@ async fn index() -> IndexTemplate<'static> {
     IndexTemplate { name: "world" }
 }
 
+#[derive(Template)]
+#[template(path = "models.html")]
+struct ModelsTemplate {
+    models: Vec<DeviceModel>,
+}
+
+async fn models(State(db): State<Arc<Surreal<Client>>>) -> Result<ModelsTemplate, StatusCode> {
+    let models: Vec<DeviceModel> = db.select("device_model").await.map_err(|error| {
+        error!("Error: {}", error);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(ModelsTemplate { models })
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct SurrealDb {
     pub user: String,
@@ -32,6 +52,8 @@ pub struct Settings {
     pub amqp_addr: String,
     pub surrealdb: SurrealDb,
     pub web_srv_port: usize,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
 }
 
 const APP_NAME: &str = "cockpit";
@@ -49,7 +71,24 @@ async fn main() {
     setup_logger(settings.log_level.clone()).unwrap();
     info!("{:?}", settings);
 
-    let app = Router::new().route("/", get(index));
+    let db = Surreal::new::<Ws>(settings.surrealdb.addr.as_str())
+        .await
+        .unwrap();
+    db.signin(Root {
+        username: &settings.surrealdb.user,
+        password: &settings.surrealdb.password,
+    })
+    .await
+    .unwrap();
+    db.use_ns(settings.tenant.as_str())
+        .use_db("iot")
+        .await
+        .unwrap();
+
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/models", get(models))
+        .with_state(Arc::new(db));
 
     let addr = format!("0.0.0.0:{}", settings.web_srv_port);
 